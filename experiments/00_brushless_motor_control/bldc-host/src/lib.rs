@@ -0,0 +1,194 @@
+//! Host side of the binary protocol (see `bldc::protocol`)
+//!
+//! [`Link`] sends requests to the motor control firmware and waits
+//! for the responses, over anything which reads and writes bytes
+//! (a serial port, or a simulated device in the tests). The text
+//! which the CLI writes on the same port is skipped.
+//!
+//! Streamed samples can be written as CSV with [`record`], and
+//! plotted with [`plot`].
+
+pub mod plot;
+pub mod record;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use bldc::protocol::{
+    ErrorCode, FrameDecoder, ProtocolError, Received, Request, Response, StatusReport,
+};
+use bldc::{Parameter, Sample, StreamFields};
+
+pub use bldc::protocol;
+
+/// The baud rate of the firmware's serial port
+pub const BAUD_RATE: u32 = 115_200;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the port failed (including timeouts)
+    Io(io::Error),
+    /// A corrupted or malformed frame was received
+    Protocol(ProtocolError),
+    /// The device rejected the request
+    Device(ErrorCode),
+    /// The device sent a response which does not answer the
+    /// request
+    Unexpected(Response),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Protocol(error) => write!(f, "received {}", error.name()),
+            Error::Device(error) => write!(f, "rejected: {}", error.name()),
+            Error::Unexpected(response) => write!(f, "unexpected response {response:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// A connection to the firmware
+pub struct Link<P> {
+    port: P,
+    decoder: FrameDecoder,
+    // Received bytes which have not been decoded yet
+    pending: Vec<u8>,
+}
+
+impl<P: Read + Write> Link<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            decoder: FrameDecoder::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    /// Send a request without waiting for the response
+    pub fn send(&mut self, request: &Request) -> io::Result<()> {
+        self.port.write_all(request.encode().as_bytes())?;
+        self.port.flush()
+    }
+
+    /// Wait for the next message from the device (fails with a
+    /// timeout if the port has one set)
+    pub fn receive(&mut self) -> Result<Response, Error> {
+        loop {
+            while !self.pending.is_empty() {
+                let byte = self.pending.remove(0);
+                match self.decoder.push(byte) {
+                    Some(Received::Payload(payload)) => {
+                        return Response::decode(payload).map_err(Error::Protocol)
+                    }
+                    Some(Received::Error(error)) => return Err(Error::Protocol(error)),
+                    Some(Received::Text(_)) | None => {}
+                }
+            }
+
+            let mut buffer = [0; 256];
+            let count = self.port.read(&mut buffer)?;
+            if count == 0 {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.pending.extend_from_slice(&buffer[..count]);
+        }
+    }
+
+    /// Send a request, and wait for its response
+    ///
+    /// Streamed samples received in the meantime are skipped, as
+    /// are status messages unless the request asks for the status.
+    pub fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        self.send(request)?;
+        loop {
+            match self.receive()? {
+                Response::Status(_) if *request != Request::GetStatus => {}
+                Response::Sample(_) => {}
+                Response::Error(error) => return Err(Error::Device(error)),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Send a request which is answered by [`Response::Ok`]
+    fn command(&mut self, request: Request) -> Result<(), Error> {
+        match self.request(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(Error::Unexpected(response)),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<(), Error> {
+        match self.request(&Request::Ping)? {
+            Response::Pong => Ok(()),
+            response => Err(Error::Unexpected(response)),
+        }
+    }
+
+    pub fn status(&mut self) -> Result<StatusReport, Error> {
+        match self.request(&Request::GetStatus)? {
+            Response::Status(status) => Ok(status),
+            response => Err(Error::Unexpected(response)),
+        }
+    }
+
+    pub fn arm(&mut self) -> Result<(), Error> {
+        self.command(Request::Arm)
+    }
+
+    pub fn disarm(&mut self) -> Result<(), Error> {
+        self.command(Request::Disarm)
+    }
+
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.command(Request::Start)
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.command(Request::Stop)
+    }
+
+    pub fn get(&mut self, parameter: Parameter) -> Result<f32, Error> {
+        match self.request(&Request::GetParameter(parameter))? {
+            Response::Parameter(p, value) if p == parameter => Ok(value),
+            response => Err(Error::Unexpected(response)),
+        }
+    }
+
+    pub fn set(&mut self, parameter: Parameter, value: f32) -> Result<(), Error> {
+        self.command(Request::SetParameter(parameter, value))
+    }
+
+    /// Have the device send a sample of the `fields` every
+    /// `period_ms` (0 stops), to be read by
+    /// [`next_sample`](Self::next_sample)
+    pub fn stream(&mut self, period_ms: u16, fields: StreamFields) -> Result<(), Error> {
+        self.command(Request::Stream { period_ms, fields })
+    }
+
+    /// Wait for the next streamed sample
+    pub fn next_sample(&mut self) -> Result<Sample, Error> {
+        loop {
+            if let Response::Sample(sample) = self.receive()? {
+                return Ok(sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Command-line tool for the binary protocol
+//!
+//! ```text
+//! bldc-host PORT COMMAND [ARGS]
+//! bldc-host plot CSV SVG [COLUMN...]
+//! ```
+//!
+//! See `usage` for the commands.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use bldc::protocol::StatusReport;
+use bldc::{Parameter, ParameterInfo, ParameterType};
+use bldc::{StreamField, StreamFields};
+use bldc_host::plot::plot_svg;
+use bldc_host::record::{csv_header, csv_row};
+use bldc_host::{Link, BAUD_RATE};
+
+fn usage() -> &'static str {
+    "Usage: bldc-host PORT COMMAND [ARGS]
+       bldc-host plot CSV SVG [COLUMN...]
+
+Commands:
+  ping                 check that the firmware answers
+  status               show the motor state and measurements
+  arm | disarm         turn the PWM outputs on or off
+  start | stop         start or stop the motor
+  get [NAME]           show a parameter (or all of them)
+  set NAME VALUE       set a parameter (a number, or an option name)
+  list                 show the parameters, with their ranges and defaults
+  reset [NAME]         set a parameter (or all of them) to its default
+  watch [PERIOD_MS [FIELDS]]
+                       print a CSV line of the FIELDS every PERIOD_MS
+  record FILE [PERIOD_MS [FIELDS [SECONDS]]]
+                       record the FIELDS every PERIOD_MS to a CSV file
+                       for SECONDS (10 by default)
+
+PERIOD_MS is 100 by default, and FIELDS is a comma-separated list of
+time, phases, neutral, step, duty, period, rpm, current, voltage,
+state and fault (all by default).
+
+plot draws the COLUMNs of a recording (all of them by default) as
+an SVG image."
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [plot, csv, svg, columns @ ..] = args.as_slice() {
+        if plot == "plot" {
+            return match run_plot(csv, svg, columns) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(error) => {
+                    eprintln!("{error}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+    }
+    let [path, command, args @ ..] = args.as_slice() else {
+        eprintln!("{}", usage());
+        return ExitCode::FAILURE;
+    };
+
+    let port = match serialport::new(path, BAUD_RATE)
+        .timeout(Duration::from_millis(500))
+        .open()
+    {
+        Ok(port) => port,
+        Err(error) => {
+            eprintln!("Cannot open {path}: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut link = Link::new(port);
+
+    match run(&mut link, command, args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run<P>(link: &mut Link<P>, command: &str, args: &[String]) -> Result<(), Box<dyn Error>>
+where
+    P: std::io::Read + std::io::Write,
+{
+    let parameter = |name: &str| {
+        Parameter::from_name(name).ok_or_else(|| {
+            let names: Vec<_> = Parameter::ALL.iter().map(|p| p.name()).collect();
+            format!("Unknown parameter {name} (expected {})", names.join(", "))
+        })
+    };
+
+    match (command, args) {
+        ("ping", []) => link.ping()?,
+        ("status", []) => print_status(&link.status()?),
+        ("arm", []) => link.arm()?,
+        ("disarm", []) => link.disarm()?,
+        ("start", []) => link.start()?,
+        ("stop", []) => link.stop()?,
+        ("get", []) => {
+            for parameter in Parameter::ALL {
+                let value = link.get(parameter)?;
+                println!("{} = {}", parameter.name(), show(&parameter.info(), value));
+            }
+        }
+        ("get", [name]) => {
+            let parameter = parameter(name)?;
+            let value = link.get(parameter)?;
+            println!("{}", show(&parameter.info(), value));
+        }
+        ("set", [name, value]) => {
+            let parameter = parameter(name)?;
+            let value = parameter
+                .info()
+                .parse(value)
+                .ok_or_else(|| format!("Invalid value {value}"))?;
+            link.set(parameter, value)?;
+        }
+        ("list", []) => {
+            for parameter in Parameter::ALL {
+                let info = parameter.info();
+                let value = link.get(parameter)?;
+                print!("{} = {}", info.name, show(&info, value));
+                if info.read_only {
+                    println!(" (read-only)");
+                } else {
+                    let range = match info.kind {
+                        ParameterType::Choice(options) => options.join("|"),
+                        _ => format!("{} to {}", info.min, info.max),
+                    };
+                    println!(" ({range}, default {})", show(&info, info.default));
+                }
+                println!("  {}", info.description);
+            }
+        }
+        ("reset", []) => {
+            for parameter in Parameter::ALL {
+                if !parameter.info().read_only {
+                    link.set(parameter, parameter.info().default)?;
+                }
+            }
+        }
+        ("reset", [name]) => {
+            let parameter = parameter(name)?;
+            link.set(parameter, parameter.info().default)?;
+        }
+        ("watch", [] | [_] | [_, _]) => {
+            let (period_ms, fields) = stream_args(args)?;
+            let mut stdout = std::io::stdout();
+            record(link, period_ms, fields, &mut stdout, None)?;
+        }
+        ("record", [path, args @ ..]) if args.len() <= 3 => {
+            let (period_ms, fields) = stream_args(args)?;
+            let seconds: f64 = match args.get(2) {
+                Some(seconds) => seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid duration {seconds}"))?,
+                None => 10.0,
+            };
+            let mut file = BufWriter::new(File::create(path)?);
+            record(
+                link,
+                period_ms,
+                fields,
+                &mut file,
+                Some(Duration::from_secs_f64(seconds)),
+            )?;
+            link.stream(0, fields)?;
+        }
+        _ => return Err(usage().into()),
+    }
+    Ok(())
+}
+
+/// The period and fields of the `watch` and `record` commands
+fn stream_args(args: &[String]) -> Result<(u16, StreamFields), Box<dyn Error>> {
+    let period_ms = match args.first() {
+        Some(period) => period
+            .parse()
+            .map_err(|_| format!("Invalid period {period}"))?,
+        None => 100,
+    };
+    if period_ms == 0 {
+        return Err("The period must be at least 1 ms".into());
+    }
+    let fields = match args.get(1) {
+        Some(list) => StreamFields::parse(list).ok_or_else(|| {
+            let names: Vec<_> = StreamField::ALL.iter().map(|f| f.name()).collect();
+            format!("Unknown field in {list} (expected {})", names.join(", "))
+        })?,
+        None => StreamFields::all(),
+    };
+    Ok((period_ms, fields))
+}
+
+/// Stream the fields, and write them as CSV lines until the
+/// duration is over (or forever)
+fn record<P>(
+    link: &mut Link<P>,
+    period_ms: u16,
+    fields: StreamFields,
+    out: &mut impl Write,
+    duration: Option<Duration>,
+) -> Result<(), Box<dyn Error>>
+where
+    P: std::io::Read + std::io::Write,
+{
+    link.stream(period_ms, fields)?;
+    writeln!(out, "{}", csv_header(fields))?;
+    let start = Instant::now();
+    while duration.is_none_or(|duration| start.elapsed() < duration) {
+        let sample = link.next_sample()?;
+        writeln!(out, "{}", csv_row(&sample))?;
+        // Keep what was recorded if the tool is interrupted
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn run_plot(csv: &str, svg: &str, columns: &[String]) -> Result<(), Box<dyn Error>> {
+    let columns: Vec<_> = columns.iter().map(String::as_str).collect();
+    let image = plot_svg(&std::fs::read_to_string(csv)?, &columns)?;
+    std::fs::write(svg, image)?;
+    Ok(())
+}
+
+/// A parameter value (the option for a choice), with its units
+fn show(info: &ParameterInfo, value: f32) -> String {
+    let value = match info.option(value) {
+        Some(option) => option.to_string(),
+        None => format!("{value:.*}", info.decimals as usize),
+    };
+    if info.units.is_empty() {
+        value
+    } else {
+        format!("{value} {}", info.units)
+    }
+}
+
+fn print_status(status: &StatusReport) {
+    println!(
+        "{}, {} ({}), {}",
+        if status.armed { "armed" } else { "disarmed" },
+        status.state.name(),
+        status.mode.name(),
+        status.direction.name()
+    );
+    print!("Step {}, duty {:.3}", status.step, status.duty);
+    if let Some(rpm) = status.target_rpm {
+        print!(", target {rpm:.0} RPM");
+    }
+    match status.step_period_us {
+        Some(period) => println!(", period {period} us"),
+        None => println!(),
+    }
+    print!("Neutral {}", status.neutral_voltage);
+    if let Some(current) = status.current {
+        print!(", current {current:.2} A");
+    }
+    if let Some(voltage) = status.bus_voltage {
+        print!(", supply {voltage:.2} V");
+    }
+    println!();
+    println!(
+        "Fault: {}",
+        status.fault.map_or("none", |fault| fault.name())
+    );
+}
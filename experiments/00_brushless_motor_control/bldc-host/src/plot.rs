@@ -0,0 +1,142 @@
+//! Plotting recorded samples
+//!
+//! [`plot_svg`] draws the columns of a CSV recording as stacked
+//! panels in an SVG image, one per column, against `time_ms` (or
+//! the line number if the time was not recorded).
+
+use std::fmt::Write;
+
+const WIDTH: f64 = 800.0;
+const PANEL_HEIGHT: f64 = 120.0;
+// Space for the labels on the left of the panels
+const MARGIN: f64 = 60.0;
+
+/// A recording parsed from CSV
+struct Table<'a> {
+    columns: Vec<&'a str>,
+    // Empty cells are None
+    rows: Vec<Vec<Option<f64>>>,
+}
+
+impl<'a> Table<'a> {
+    fn parse(csv: &'a str) -> Result<Self, String> {
+        let mut lines = csv.lines().map(str::trim).filter(|line| !line.is_empty());
+        let header = lines.next().ok_or("The recording is empty")?;
+        let columns: Vec<_> = header.split(',').collect();
+        let rows = lines
+            .map(|line| {
+                // Text cells (state, fault) are not plotted
+                let row: Vec<_> = line.split(',').map(|cell| cell.parse().ok()).collect();
+                if row.len() == columns.len() {
+                    Ok(row)
+                } else {
+                    Err(format!("Expected {} cells in {line}", columns.len()))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { columns, rows })
+    }
+
+    fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| *column == name)
+    }
+
+    fn values(&self, index: usize) -> impl Iterator<Item = Option<f64>> + '_ {
+        self.rows.iter().map(move |row| row[index])
+    }
+}
+
+/// The smallest and largest values, or None if there are none
+fn range(values: impl Iterator<Item = Option<f64>>) -> Option<(f64, f64)> {
+    values.flatten().fold(None, |range, value| match range {
+        Some((min, max)) => Some((value.min(min), value.max(max))),
+        None => Some((value, value)),
+    })
+}
+
+/// Draw the `columns` of a CSV recording (all the numeric ones but
+/// the time if none are given)
+pub fn plot_svg(csv: &str, columns: &[&str]) -> Result<String, String> {
+    let table = Table::parse(csv)?;
+    let time = table.column("time_ms");
+    let plotted: Vec<usize> = if columns.is_empty() {
+        (0..table.columns.len())
+            .filter(|&i| Some(i) != time && range(table.values(i)).is_some())
+            .collect()
+    } else {
+        columns
+            .iter()
+            .map(|name| {
+                table
+                    .column(name)
+                    .ok_or_else(|| format!("No column {name} in the recording"))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    if plotted.is_empty() {
+        return Err("Nothing to plot".into());
+    }
+
+    let x: Vec<Option<f64>> = match time {
+        Some(time) => table.values(time).collect(),
+        None => (0..table.rows.len()).map(|i| Some(i as f64)).collect(),
+    };
+    let (x_min, x_max) = range(x.iter().copied()).unwrap_or((0.0, 0.0));
+    let x_scale = (WIDTH - MARGIN) / (x_max - x_min).max(f64::EPSILON);
+
+    let height = PANEL_HEIGHT * plotted.len() as f64;
+    let mut svg = String::new();
+    // Writing to a String cannot fail
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" font-family="sans-serif" font-size="11">"#
+    );
+    for (panel, &index) in plotted.iter().enumerate() {
+        let top = PANEL_HEIGHT * panel as f64;
+        let (min, max) = range(table.values(index)).unwrap_or((0.0, 0.0));
+        // Leave a gap above and below the line
+        let y_scale = (PANEL_HEIGHT - 20.0) / (max - min).max(f64::EPSILON);
+        let bottom = top + PANEL_HEIGHT - 10.0;
+
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{MARGIN}" y="{top}" width="{}" height="{PANEL_HEIGHT}" fill="none" stroke="#ccc"/>"##,
+            WIDTH - MARGIN
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="2" y="{}">{}</text><text x="2" y="{}">{max}</text><text x="2" y="{bottom}">{min}</text>"#,
+            top + PANEL_HEIGHT / 2.0,
+            table.columns[index],
+            top + 14.0
+        );
+
+        // A missing value breaks the line
+        let mut points = Vec::new();
+        for (x, y) in x.iter().zip(table.values(index)) {
+            match (x, y) {
+                (Some(x), Some(y)) => points.push(format!(
+                    "{:.1},{:.1}",
+                    MARGIN + (x - x_min) * x_scale,
+                    bottom - (y - min) * y_scale
+                )),
+                _ => polyline(&mut svg, &mut points),
+            }
+        }
+        polyline(&mut svg, &mut points);
+    }
+    svg.push_str("</svg>\n");
+    Ok(svg)
+}
+
+/// Draw the points (if there are any), and clear them
+fn polyline(svg: &mut String, points: &mut Vec<String>) {
+    if !points.is_empty() {
+        let _ = writeln!(
+            svg,
+            r#"<polyline points="{}" fill="none" stroke="steelblue"/>"#,
+            points.join(" ")
+        );
+        points.clear();
+    }
+}
@@ -0,0 +1,58 @@
+//! Streamed samples as CSV
+//!
+//! The lines are the same as the firmware's `stream` command writes
+//! in CSV format, so both recordings can be plotted the same way.
+
+use std::fmt::Write;
+
+use bldc::{Sample, StreamField, StreamFields};
+
+/// The column headings of the selected fields
+pub fn csv_header(fields: StreamFields) -> String {
+    let columns: Vec<_> = fields
+        .iter()
+        .flat_map(|field| field.columns().iter().copied())
+        .collect();
+    columns.join(",")
+}
+
+/// The selected fields of a sample (a measurement which is not
+/// available is left empty)
+pub fn csv_row(sample: &Sample) -> String {
+    let mut row = String::new();
+    for (i, field) in sample.fields.iter().enumerate() {
+        if i > 0 {
+            row.push(',');
+        }
+        // Writing to a String cannot fail
+        let _ = match field {
+            StreamField::Time => write!(row, "{}", sample.time_ms),
+            StreamField::PhaseVoltages => {
+                let [a, b, c] = sample.phase_voltages;
+                write!(row, "{a},{b},{c}")
+            }
+            StreamField::Neutral => write!(row, "{}", sample.neutral_voltage),
+            StreamField::Step => write!(row, "{}", sample.step),
+            StreamField::Duty => write!(row, "{:.3}", sample.duty),
+            StreamField::Period => match sample.step_period_us {
+                Some(period) => write!(row, "{period}"),
+                None => Ok(()),
+            },
+            StreamField::Rpm => match sample.rpm {
+                Some(rpm) => write!(row, "{rpm:.0}"),
+                None => Ok(()),
+            },
+            StreamField::Current => match sample.current {
+                Some(current) => write!(row, "{current:.2}"),
+                None => Ok(()),
+            },
+            StreamField::BusVoltage => match sample.bus_voltage {
+                Some(voltage) => write!(row, "{voltage:.2}"),
+                None => Ok(()),
+            },
+            StreamField::State => write!(row, "{}", sample.state.name()),
+            StreamField::Fault => write!(row, "{}", sample.fault.map_or("none", |f| f.name())),
+        };
+    }
+    row
+}
@@ -0,0 +1,165 @@
+//! Tests of the host link against a simulated device, which
+//! answers the requests as the firmware does (with a mock
+//! controller), and echoes the text like the CLI
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use bldc::mock::{MockDriver, MockPwm, MockSampler};
+use bldc::protocol::{ErrorCode, FrameDecoder, Received, Request, Response};
+use bldc::{BrakeMode, Parameter, Sample, StartupState, StreamFields, ThreePhaseController};
+use bldc_host::{Error, Link};
+
+struct Device {
+    controller: ThreePhaseController<MockDriver, MockPwm, MockSampler>,
+    decoder: FrameDecoder,
+    // Bytes sent to the host
+    output: VecDeque<u8>,
+    // Whether to send a sample after each response (as if it was
+    // streamed)
+    streaming: bool,
+}
+
+impl Device {
+    fn new() -> Self {
+        Self {
+            controller: ThreePhaseController::new(
+                MockDriver::default(),
+                MockPwm::default(),
+                MockSampler::default(),
+            ),
+            decoder: FrameDecoder::new(),
+            output: VecDeque::new(),
+            streaming: false,
+        }
+    }
+
+    fn send(&mut self, response: Response) {
+        self.output.extend(response.encode().as_bytes());
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            let response = match self.decoder.push(byte) {
+                Some(Received::Text(byte)) => {
+                    self.output.push_back(byte);
+                    continue;
+                }
+                Some(Received::Payload(payload)) => match Request::decode(payload) {
+                    Ok(request) => request.respond(&mut self.controller),
+                    Err(error) => Response::Error(error.into()),
+                },
+                Some(Received::Error(error)) => Response::Error(error.into()),
+                None => continue,
+            };
+            self.send(response);
+            if self.streaming {
+                self.send(Response::Sample(Sample::from_controller(
+                    &self.controller,
+                    StreamFields::all(),
+                    0,
+                )));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Device {
+    /// Return a few bytes at a time, as a serial port would
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let count = buf.len().min(self.output.len()).min(7);
+        for byte in &mut buf[..count] {
+            *byte = self.output.pop_front().unwrap();
+        }
+        Ok(count)
+    }
+}
+
+#[test]
+fn sends_commands() {
+    let mut link = Link::new(Device::new());
+    link.ping().unwrap();
+
+    link.port_mut().controller.disarm();
+    assert!(matches!(
+        link.start(),
+        Err(Error::Device(ErrorCode::Disarmed))
+    ));
+    link.arm().unwrap();
+    link.start().unwrap();
+    assert_eq!(link.status().unwrap().state, StartupState::Starting);
+    link.stop().unwrap();
+    assert!(link.port().controller.startup.state() != StartupState::Starting);
+}
+
+#[test]
+fn sets_parameters() {
+    let mut link = Link::new(Device::new());
+    link.set(Parameter::BrakeMode, 1.0).unwrap();
+    assert_eq!(
+        link.port().controller.brake.config().mode,
+        BrakeMode::Active
+    );
+    assert_eq!(link.get(Parameter::BrakeMode).unwrap(), 1.0);
+
+    link.set(Parameter::MaxCurrent, 12.5).unwrap();
+    assert_eq!(link.get(Parameter::MaxCurrent).unwrap(), 12.5);
+    assert!(matches!(
+        link.set(Parameter::MaxCurrent, -1.0),
+        Err(Error::Device(ErrorCode::OutOfRange))
+    ));
+    assert_eq!(link.get(Parameter::MaxCurrent).unwrap(), 12.5);
+
+    link.set(Parameter::RampSteps, 500.0).unwrap();
+    assert_eq!(link.port().controller.startup.config().ramp_steps, 500);
+    assert!(matches!(
+        link.set(Parameter::HeapSize, 2048.0),
+        Err(Error::Device(ErrorCode::ReadOnly))
+    ));
+    assert_eq!(link.get(Parameter::HeapSize).unwrap(), 1024.0);
+}
+
+#[test]
+fn skips_text_and_streamed_samples() {
+    let mut link = Link::new(Device::new());
+
+    // Text typed into the CLI is echoed back between the frames
+    link.port_mut().write_all(b"status\r").unwrap();
+    link.port_mut().streaming = true;
+    link.set(Parameter::Duty, 0.2).unwrap();
+    assert_eq!(link.get(Parameter::Duty).unwrap(), 0.2);
+    // The samples show the duty cycle applied (none while stopped)
+    assert_eq!(link.next_sample().unwrap().duty, 0.0);
+
+    // Nothing more to receive
+    assert!(matches!(
+        link.receive(),
+        Err(Error::Io(error)) if error.kind() == io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+fn reports_corrupted_frames() {
+    let mut link = Link::new(Device::new());
+    let mut frame = Response::Pong.encode().as_bytes().to_vec();
+    frame[3] ^= 0x01;
+    link.port_mut().output.extend(frame);
+    link.port_mut().send(Response::Ok);
+
+    assert!(matches!(
+        link.receive(),
+        Err(Error::Protocol(bldc::ProtocolError::Crc))
+    ));
+    // The next frame is received
+    assert!(matches!(link.receive(), Ok(Response::Ok)));
+}
@@ -0,0 +1,51 @@
+//! Tests of the CSV recordings and their plots
+
+use bldc::{Sample, StartupState, StreamField, StreamFields};
+use bldc_host::plot::plot_svg;
+use bldc_host::record::{csv_header, csv_row};
+
+fn sample(time_ms: u32, duty: f32, rpm: Option<f32>) -> Sample {
+    Sample {
+        fields: StreamFields::empty()
+            .with(StreamField::Time)
+            .with(StreamField::Duty)
+            .with(StreamField::Rpm)
+            .with(StreamField::State),
+        time_ms,
+        duty,
+        rpm,
+        state: StartupState::Starting,
+        ..Sample::default()
+    }
+}
+
+#[test]
+fn writes_selected_fields() {
+    let fields = sample(0, 0.0, None).fields;
+    assert_eq!(csv_header(fields), "time_ms,duty,rpm,state");
+    assert_eq!(csv_row(&sample(12, 0.25, None)), "12,0.250,,starting");
+    assert_eq!(
+        csv_header(StreamFields::empty().with(StreamField::PhaseVoltages)),
+        "phase_a,phase_b,phase_c"
+    );
+}
+
+#[test]
+fn plots_numeric_columns() {
+    let mut csv = csv_header(sample(0, 0.0, None).fields) + "\n";
+    for (time, rpm) in [(0, None), (10, Some(1000.0)), (20, Some(1200.0))] {
+        csv += &(csv_row(&sample(time, 0.1, rpm)) + "\n");
+    }
+
+    // The duty and rpm (the state is text)
+    let svg = plot_svg(&csv, &[]).unwrap();
+    assert!(svg.starts_with("<svg"));
+    assert!(svg.contains(">duty<") && svg.contains(">rpm<"));
+    assert!(!svg.contains(">state<"));
+    assert_eq!(svg.matches("<polyline").count(), 2);
+
+    let svg = plot_svg(&csv, &["rpm"]).unwrap();
+    assert!(!svg.contains(">duty<"));
+    assert!(plot_svg(&csv, &["current_a"]).is_err());
+    assert!(plot_svg("time_ms,duty\n1,2,3\n", &[]).is_err());
+}
@@ -0,0 +1,48 @@
+//! Half-bridge inverter and phase voltage measurement model
+//!
+
+/// Inverter and ADC parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Inverter {
+    /// Supply voltage of the half bridges (V)
+    pub supply_voltage: f64,
+
+    /// Phase voltage divider ratio (ADC pin voltage / phase voltage)
+    pub divider_ratio: f64,
+
+    /// ADC reference voltage (V)
+    pub adc_reference: f64,
+
+    /// ADC resolution (bits)
+    pub adc_bits: u32,
+
+    /// Peak amplitude of uniform noise added to each ADC sample
+    /// (in ADC counts)
+    pub adc_noise: u16,
+}
+
+impl Inverter {
+    /// The breadboard setup: L298N half bridges at 12 V, phase
+    /// voltages divided by 10k/2k2 into the 12-bit ADC3
+    pub fn breadboard() -> Self {
+        Self {
+            supply_voltage: 12.0,
+            divider_ratio: 2.2 / (10.0 + 2.2),
+            adc_reference: 3.3,
+            adc_bits: 12,
+            adc_noise: 0,
+        }
+    }
+
+    /// Largest ADC value
+    pub fn adc_max(&self) -> u16 {
+        ((1u32 << self.adc_bits) - 1) as u16
+    }
+
+    /// Convert a phase voltage into the (noise-free) ADC value
+    pub fn to_adc(&self, phase_voltage: f64) -> u16 {
+        let pin_voltage = phase_voltage * self.divider_ratio;
+        let counts = pin_voltage / self.adc_reference * self.adc_max() as f64;
+        counts.round().clamp(0.0, self.adc_max() as f64) as u16
+    }
+}
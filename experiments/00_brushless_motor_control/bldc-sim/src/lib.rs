@@ -0,0 +1,15 @@
+//! Host-side simulation of a BLDC motor and inverter
+//!
+//! The [`Simulator`] combines a [`Motor`] model (electrical
+//! and mechanical dynamics of a three-phase star-connected
+//! BLDC) with an [`Inverter`] model (three half bridges driven
+//! by the six-step phase states or by PWM duty cycles, and the
+//! ADC measuring the phase voltages through a divider).
+
+pub mod inverter;
+pub mod motor;
+pub mod simulator;
+
+pub use inverter::Inverter;
+pub use motor::{BackEmf, Motor};
+pub use simulator::Simulator;
@@ -0,0 +1,110 @@
+//! Three-phase star-connected BLDC motor model
+//!
+
+use std::f64::consts::PI;
+
+/// Shape of the back-EMF waveform
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BackEmf {
+    /// Trapezoidal (concentrated windings, as assumed by six-step
+    /// commutation)
+    #[default]
+    Trapezoidal,
+    /// Sinusoidal (as assumed by field-oriented control)
+    Sinusoidal,
+}
+
+/// Motor parameters (SI units unless stated otherwise)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Motor {
+    /// Velocity constant (unloaded RPM per volt)
+    pub kv: f64,
+
+    /// Number of pole pairs (electrical revolutions per
+    /// mechanical revolution)
+    pub pole_pairs: u32,
+
+    /// Resistance of one phase (half the line-to-line value)
+    pub phase_resistance: f64,
+
+    /// Inductance of one phase
+    pub phase_inductance: f64,
+
+    /// Moment of inertia of the rotor and load (kg m^2)
+    pub inertia: f64,
+
+    /// Viscous friction (N m per rad/s)
+    pub friction: f64,
+
+    /// Shape of the back-EMF
+    pub back_emf: BackEmf,
+}
+
+impl Motor {
+    /// T-Motor P1604 3800KV (12N14P) with no propeller
+    pub fn p1604() -> Self {
+        Self {
+            kv: 3800.0,
+            pole_pairs: 7,
+            phase_resistance: 0.07,
+            phase_inductance: 10e-6,
+            inertia: 2e-7,
+            friction: 1e-7,
+            back_emf: BackEmf::Trapezoidal,
+        }
+    }
+
+    /// Back-EMF constant of one phase (peak volts per mechanical
+    /// rad/s). This is also the torque constant (N m per amp).
+    ///
+    /// The Kv rating gives the peak line-to-line back-EMF, which
+    /// is the sum of two phases in a six-step motor, or sqrt(3)
+    /// times one phase if the back-EMF is sinusoidal.
+    pub fn ke(&self) -> f64 {
+        let line_to_line = match self.back_emf {
+            BackEmf::Trapezoidal => 2.0,
+            BackEmf::Sinusoidal => 3.0f64.sqrt(),
+        };
+        60.0 / (2.0 * PI * self.kv) / line_to_line
+    }
+}
+
+/// Normalised trapezoidal back-EMF of phase A at electrical angle
+/// `theta` (radians)
+///
+/// The back-EMF is flat at +1 between 30 and 150 degrees, falls
+/// to -1 between 150 and 210 degrees, is flat at -1 until 330
+/// degrees, and rises back to +1 by 390 (30) degrees.
+pub fn trapezoid(theta: f64) -> f64 {
+    let degrees = theta.rem_euclid(2.0 * PI).to_degrees();
+    if degrees < 30.0 {
+        degrees / 30.0
+    } else if degrees < 150.0 {
+        1.0
+    } else if degrees < 210.0 {
+        (180.0 - degrees) / 30.0
+    } else if degrees < 330.0 {
+        -1.0
+    } else {
+        (degrees - 360.0) / 30.0
+    }
+}
+
+/// Normalised back-EMF of the three phases at electrical angle
+/// `theta`
+///
+/// The phases are ordered so that the commutation sequence of
+/// `bldc::MotorStep` turns the rotor in the positive direction.
+/// A sinusoidal back-EMF has the same phase as the fundamental of
+/// the trapezoid.
+pub fn back_emf_shape(back_emf: BackEmf, theta: f64) -> [f64; 3] {
+    let shape = match back_emf {
+        BackEmf::Trapezoidal => trapezoid,
+        BackEmf::Sinusoidal => f64::sin,
+    };
+    [
+        shape(theta),
+        shape(theta + 2.0 * PI / 3.0),
+        shape(theta - 2.0 * PI / 3.0),
+    ]
+}
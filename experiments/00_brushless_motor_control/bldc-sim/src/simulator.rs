@@ -0,0 +1,362 @@
+//! Simulation of a BLDC motor driven by a six-step inverter (or
+//! by three PWM outputs, as in field-oriented control)
+//!
+
+use bldc::PhaseState;
+
+use crate::inverter::Inverter;
+use crate::motor::{back_emf_shape, Motor};
+
+/// Integration time step (s)
+const TIME_STEP: f64 = 1e-6;
+
+/// Simulated motor and inverter
+///
+/// Set the phase states and duty cycle with
+/// [`Simulator::set_phases`] (as `ThreePhaseController::set_step`
+/// would), advance the simulation with [`Simulator::run`], and
+/// read the phase voltages the ADC would see with
+/// [`Simulator::adc_samples`].
+///
+/// For field-oriented control, set the duty cycles of all three
+/// half bridges with [`Simulator::set_duties`] instead, and read
+/// the currents with [`Simulator::phase_currents`].
+pub struct Simulator {
+    motor: Motor,
+    inverter: Inverter,
+
+    phase_states: [PhaseState; 3],
+    duty: f64,
+
+    // Duty cycles of the three half bridges, when they are all
+    // switching between the rails (instead of the phase states)
+    duties: Option<[f64; 3]>,
+
+    load_torque: f64,
+
+    // Simulation time in integration steps
+    ticks: u64,
+
+    // Mechanical angle (rad) and speed (rad/s)
+    angle: f64,
+    speed: f64,
+
+    // Phase currents (positive into the motor)
+    currents: [f64; 3],
+
+    // Phase terminal voltages, from the last time step
+    voltages: [f64; 3],
+
+    // State of the ADC noise generator
+    noise_state: u32,
+}
+
+impl Simulator {
+    pub fn new(motor: Motor, inverter: Inverter) -> Self {
+        Self {
+            motor,
+            inverter,
+            phase_states: [PhaseState::Floating; 3],
+            duty: 0.0,
+            duties: None,
+            load_torque: 0.0,
+            ticks: 0,
+            angle: 0.0,
+            speed: 0.0,
+            currents: [0.0; 3],
+            voltages: [0.0; 3],
+            noise_state: 0x1234_5678,
+        }
+    }
+
+    pub fn motor(&self) -> &Motor {
+        &self.motor
+    }
+
+    pub fn inverter(&self) -> &Inverter {
+        &self.inverter
+    }
+
+    /// Set the state of the three half bridges, and the duty
+    /// cycle of the line phase
+    pub fn set_phases(&mut self, phase_states: [PhaseState; 3], duty: f32) {
+        self.phase_states = phase_states;
+        self.duty = duty.clamp(0.0, 1.0) as f64;
+        self.duties = None;
+    }
+
+    /// Switch each half bridge between the high side and low side
+    /// (complementary PWM) at the given duty cycles
+    ///
+    /// The PWM period is assumed to be much shorter than the
+    /// electrical time constant, so each phase is driven at the
+    /// average voltage (duty cycle times the supply voltage).
+    pub fn set_duties(&mut self, duties: [f32; 3]) {
+        self.duties = Some(duties.map(|duty| duty.clamp(0.0, 1.0) as f64));
+    }
+
+    /// Set a constant load torque (N m) opposing the rotation
+    pub fn set_load_torque(&mut self, load_torque: f64) {
+        self.load_torque = load_torque;
+    }
+
+    /// Set the initial rotor position (mechanical angle, rad)
+    pub fn set_angle(&mut self, angle: f64) {
+        self.angle = angle;
+    }
+
+    /// Simulation time in microseconds
+    pub fn time_us(&self) -> u64 {
+        (self.ticks as f64 * TIME_STEP * 1e6).round() as u64
+    }
+
+    /// Electrical angle of the rotor (rad)
+    pub fn electrical_angle(&self) -> f64 {
+        self.angle * self.motor.pole_pairs as f64
+    }
+
+    /// Mechanical rotor speed in RPM
+    pub fn speed_rpm(&self) -> f64 {
+        self.speed * 60.0 / (2.0 * std::f64::consts::PI)
+    }
+
+    /// Phase currents (A, positive into the motor)
+    pub fn phase_currents(&self) -> [f64; 3] {
+        self.currents
+    }
+
+    /// Phase terminal voltages (V)
+    pub fn phase_voltages(&self) -> [f64; 3] {
+        self.voltages
+    }
+
+    /// Phase back-EMFs (V)
+    pub fn back_emf(&self) -> [f64; 3] {
+        self.shape()
+            .map(|shape| self.motor.ke() * self.speed * shape)
+    }
+
+    /// Electromagnetic torque (N m)
+    pub fn torque(&self) -> f64 {
+        let shape = self.shape();
+        self.motor.ke() * (0..3).map(|n| shape[n] * self.currents[n]).sum::<f64>()
+    }
+
+    /// The phase voltages as measured by the ADC (including noise)
+    pub fn adc_samples(&mut self) -> [u16; 3] {
+        let mut samples = self.voltages.map(|v| self.inverter.to_adc(v));
+        if self.inverter.adc_noise > 0 {
+            for sample in samples.iter_mut() {
+                let span = 2 * self.inverter.adc_noise as i32 + 1;
+                let noise =
+                    (self.next_random() % span as u32) as i32 - self.inverter.adc_noise as i32;
+                *sample = (*sample as i32 + noise).clamp(0, self.inverter.adc_max() as i32) as u16;
+            }
+        }
+        samples
+    }
+
+    /// Advance the simulation by `duration_us` microseconds
+    pub fn run(&mut self, duration_us: u32) {
+        let steps = (duration_us as f64 * 1e-6 / TIME_STEP).round() as u64;
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        let supply = self.inverter.supply_voltage;
+        let back_emf = self.back_emf();
+
+        // Voltage at each terminal that is connected to a rail
+        // (None if the phase is open). A floating phase which is
+        // still carrying current is clamped to one of the rails by
+        // the freewheeling diodes until the current decays.
+        let driven: [Option<f64>; 3] = match self.duties {
+            Some(duties) => duties.map(|duty| Some(duty * supply)),
+            None => core::array::from_fn(|n| match self.phase_states[n] {
+                PhaseState::Line if self.duty > 0.0 => Some(self.duty * supply),
+                PhaseState::Neutral => Some(0.0),
+                _ if self.currents[n] > 0.0 => Some(0.0),
+                _ if self.currents[n] < 0.0 => Some(supply),
+                _ => None,
+            }),
+        };
+
+        // The star point voltage follows from the phase currents
+        // summing to zero. With no current path, the phase
+        // voltage dividers pull the terminals towards ground.
+        let connected = driven.iter().filter(|v| v.is_some()).count();
+        let star = if connected == 0 {
+            -back_emf.iter().sum::<f64>() / 3.0
+        } else {
+            (0..3)
+                .filter_map(|n| driven[n].map(|v| v - back_emf[n]))
+                .sum::<f64>()
+                / connected as f64
+        };
+
+        for n in 0..3 {
+            self.voltages[n] = driven[n].unwrap_or(star + back_emf[n]).clamp(0.0, supply);
+        }
+
+        // Phase currents
+        if connected < 2 {
+            self.currents = [0.0; 3];
+        } else {
+            let (resistance, inductance) =
+                (self.motor.phase_resistance, self.motor.phase_inductance);
+            for n in 0..3 {
+                let Some(terminal) = driven[n] else {
+                    self.currents[n] = 0.0;
+                    continue;
+                };
+                let current = self.currents[n];
+                let di =
+                    (terminal - star - resistance * current - back_emf[n]) / inductance * TIME_STEP;
+                let next = current + di;
+
+                // Freewheeling diodes do not conduct in reverse
+                let freewheeling = self.duties.is_none()
+                    && (!matches!(self.phase_states[n], PhaseState::Line | PhaseState::Neutral)
+                        || (self.phase_states[n] == PhaseState::Line && self.duty == 0.0));
+                self.currents[n] = if freewheeling && next * current < 0.0 {
+                    0.0
+                } else {
+                    next
+                };
+            }
+        }
+
+        // Rotor dynamics. The load torque opposes motion, and
+        // holds the rotor still if it is larger than the drive.
+        let drive = self.torque() - self.motor.friction * self.speed;
+        let net = if self.speed == 0.0 && drive.abs() <= self.load_torque {
+            0.0
+        } else {
+            let direction = if self.speed != 0.0 {
+                self.speed.signum()
+            } else {
+                drive.signum()
+            };
+            drive - self.load_torque * direction
+        };
+
+        let previous_speed = self.speed;
+        self.speed += net / self.motor.inertia * TIME_STEP;
+        if previous_speed != 0.0 && self.speed * previous_speed < 0.0 && self.load_torque > 0.0 {
+            // Stopped by the load torque
+            self.speed = 0.0;
+        }
+        self.angle += self.speed * TIME_STEP;
+        self.ticks += 1;
+    }
+
+    // Normalised back-EMF of the three phases
+    fn shape(&self) -> [f64; 3] {
+        back_emf_shape(self.motor.back_emf, self.electrical_angle())
+    }
+
+    // Xorshift pseudo-random numbers (deterministic, for noise)
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motor::BackEmf;
+    use bldc::MotorStep;
+
+    fn simulator() -> Simulator {
+        Simulator::new(Motor::p1604(), Inverter::breadboard())
+    }
+
+    #[test]
+    fn free_running_back_emf_matches_kv() {
+        let mut sim = simulator();
+        let rpm = 12_000.0;
+        sim.speed = rpm * 2.0 * std::f64::consts::PI / 60.0;
+
+        // Line-to-line back-EMF peak is rpm / Kv
+        let expected = rpm / sim.motor().kv;
+        let mut peak: f64 = 0.0;
+        for _ in 0..1000 {
+            let e = sim.back_emf();
+            peak = peak.max(e[0] - e[1]);
+            sim.angle += 0.001;
+        }
+        assert!((peak - expected).abs() < 1e-6 * expected.max(1.0) + 1e-3);
+    }
+
+    #[test]
+    fn sinusoidal_back_emf_matches_kv() {
+        let motor = Motor {
+            back_emf: BackEmf::Sinusoidal,
+            ..Motor::p1604()
+        };
+        let mut sim = Simulator::new(motor, Inverter::breadboard());
+        let rpm = 12_000.0;
+        sim.speed = rpm * 2.0 * std::f64::consts::PI / 60.0;
+
+        let expected = rpm / sim.motor().kv;
+        let mut peak: f64 = 0.0;
+        for _ in 0..7000 {
+            let e = sim.back_emf();
+            assert!(e.iter().sum::<f64>().abs() < 1e-9);
+            peak = peak.max(e[0] - e[1]);
+            sim.angle += 0.001;
+        }
+        assert!((peak - expected).abs() < 1e-3 * expected);
+    }
+
+    #[test]
+    fn aligns_rotor_with_held_step() {
+        let mut sim = simulator();
+        sim.set_load_torque(1e-5);
+        sim.set_angle(0.3);
+        sim.set_phases(MotorStep::new().phase_states(), 0.05);
+        sim.run(200_000);
+
+        // With A+ B- applied, the torque is zero (and stable)
+        // where the back-EMF of A and B are equal, at 210 degrees
+        let angle = sim
+            .electrical_angle()
+            .rem_euclid(2.0 * std::f64::consts::PI)
+            .to_degrees();
+        assert!((angle - 210.0).abs() < 20.0, "angle {angle}");
+        assert!(sim.speed_rpm().abs() < 100.0);
+    }
+
+    #[test]
+    fn floating_phase_follows_back_emf() {
+        let mut sim = simulator();
+        sim.set_phases(MotorStep::new().phase_states(), 0.5);
+        sim.speed = 1000.0;
+        sim.run(10);
+
+        // Phase C is floating in step 0: its voltage is the star
+        // point plus its back-EMF (approximately, because the rotor
+        // has moved slightly since the voltages were computed)
+        let v = sim.phase_voltages();
+        let e = sim.back_emf();
+        let star = (v[0] - e[0] + v[1] - e[1]) / 2.0;
+        assert!((v[2] - (star + e[2]).clamp(0.0, 12.0)).abs() < 0.05);
+        assert_eq!(sim.phase_currents()[2], 0.0);
+    }
+
+    #[test]
+    fn adc_scaling() {
+        let inverter = Inverter::breadboard();
+        assert_eq!(inverter.to_adc(0.0), 0);
+        assert_eq!(inverter.to_adc(100.0), 4095);
+        let half_scale = 1.65 / inverter.divider_ratio;
+        assert_eq!(inverter.to_adc(half_scale), 2048);
+    }
+}
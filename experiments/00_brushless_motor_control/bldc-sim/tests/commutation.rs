@@ -0,0 +1,294 @@
+//! Closed-loop tests of the start-up sequence and sensorless
+//! commutation against the simulated motor
+
+use bldc::mock::{phase_states, MockDriver, MockPwm, MockSampler, MockTimer};
+use bldc::{
+    BrakeConfig, BrakeMode, CommutationTimer, Direction, MotorFault, PhaseState, StartupConfig,
+    StartupState, ThreePhaseController,
+};
+use bldc_sim::{Inverter, Motor, Simulator};
+
+/// Time between ADC samples (one PWM period)
+const SAMPLE_US: u32 = 20;
+
+/// Runs a motor instance against the simulated motor in the same
+/// way as the firmware: the ADC is sampled every PWM period, the
+/// zero crossings reschedule the commutation timer in closed
+/// loop, and the controller commutates whenever the timer expires.
+struct Driver {
+    sim: Simulator,
+    motor: bldc::Motor<MockDriver, MockPwm, MockSampler, MockTimer>,
+}
+
+impl Driver {
+    fn new(sim: Simulator, config: StartupConfig) -> Self {
+        let mut controller = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        );
+        // Carry on at the duty cycle at the end of the ramp
+        controller.set_duty(config.ramp_end_duty).unwrap();
+        controller.startup.set_config(config);
+        controller.startup.start();
+        controller.open_loop_step_us = Some(1000);
+
+        let mut timer = MockTimer::default();
+        timer.start(1000).unwrap();
+        Self {
+            sim,
+            motor: bldc::Motor::new(controller, timer),
+        }
+    }
+
+    fn run(&mut self, duration_us: u32) {
+        for _ in 0..duration_us / SAMPLE_US {
+            self.sample();
+        }
+    }
+
+    /// Advance by one PWM period
+    fn sample(&mut self) {
+        // The commutation timer may expire between samples
+        let mut remaining_us = SAMPLE_US;
+        loop {
+            let timer = &self.motor.timer;
+            let expiry_us = timer.timeout_us.saturating_sub(timer.elapsed_us);
+            if expiry_us > remaining_us {
+                break;
+            }
+            self.advance(expiry_us);
+            remaining_us -= expiry_us;
+
+            self.motor.on_commutation_timer();
+            let controller = &self.motor.controller;
+            let (states, duty) = phase_states(controller.driver(), controller.pwm());
+            self.sim.set_phases(states, duty);
+        }
+        self.advance(remaining_us);
+
+        self.motor.controller.sampler_mut().samples = self.sim.adc_samples();
+        self.motor.on_samples();
+    }
+
+    fn advance(&mut self, us: u32) {
+        self.sim.run(us);
+        self.motor.timer.advance(us);
+    }
+
+    /// Run until the start-up sequence ends (in closed loop or
+    /// with a fault), or the timeout expires
+    fn run_until_started(&mut self, timeout_us: u32) -> StartupState {
+        let mut elapsed = 0;
+        while elapsed < timeout_us {
+            self.run(1000);
+            elapsed += 1000;
+            let controller = &self.motor.controller;
+            if controller.startup.is_closed_loop() || controller.fault().is_some() {
+                return controller.startup.state();
+            }
+        }
+        self.motor.controller.startup.state()
+    }
+}
+
+fn config() -> StartupConfig {
+    StartupConfig {
+        align_duty: 0.1,
+        ramp_start_duty: 0.08,
+        ramp_end_duty: 0.08,
+        ramp_steps: 600,
+        ..Default::default()
+    }
+}
+
+fn simulator(adc_noise: u16) -> Simulator {
+    let mut sim = Simulator::new(
+        Motor::p1604(),
+        Inverter {
+            adc_noise,
+            ..Inverter::breadboard()
+        },
+    );
+    sim.set_load_torque(1e-4);
+    sim
+}
+
+#[test]
+fn starts_and_runs_in_closed_loop() {
+    let mut driver = Driver::new(simulator(0), config());
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+    assert_eq!(driver.motor.controller.startup.restarts(), 0);
+
+    // Stays synchronised, and the commutation period agrees
+    // with the actual rotor speed
+    driver.run(200_000);
+    assert!(driver.motor.controller.startup.is_closed_loop());
+    let step_period_us = driver
+        .motor
+        .controller
+        .zero_crossing
+        .step_period_us()
+        .unwrap() as f64;
+    let pole_pairs = driver.sim.motor().pole_pairs as f64;
+    let measured_rpm = 60e6 / (6.0 * step_period_us * pole_pairs);
+    let rpm = driver.sim.speed_rpm();
+    assert!(
+        (measured_rpm - rpm).abs() < 0.05 * rpm,
+        "measured {measured_rpm}, actual {rpm}"
+    );
+}
+
+#[test]
+fn speed_follows_duty_in_closed_loop() {
+    let mut driver = Driver::new(simulator(0), config());
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+    driver.run(200_000);
+    let slow = driver.sim.speed_rpm();
+
+    // Increase the duty cycle gradually (a sudden change
+    // accelerates the rotor too quickly for the commutation
+    // to follow)
+    for _ in 0..10 {
+        let duty = driver.motor.controller.duty();
+        driver.motor.controller.set_duty(duty + 0.01).unwrap();
+        driver.run(20_000);
+    }
+    driver.run(200_000);
+    assert!(driver.motor.controller.startup.is_closed_loop());
+    let fast = driver.sim.speed_rpm();
+    assert!(fast > 1.5 * slow, "slow {slow}, fast {fast}");
+}
+
+#[test]
+fn starts_with_noisy_adc() {
+    let mut driver = Driver::new(simulator(20), config());
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+    driver.run(200_000);
+    assert!(driver.motor.controller.startup.is_closed_loop());
+}
+
+#[test]
+fn desync_fault_when_rotor_blocked() {
+    let mut driver = Driver::new(simulator(0), config());
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+
+    // Block the rotor: synchronisation is lost, and the
+    // motor is shut down
+    driver.sim.set_load_torque(1.0);
+    driver.run(100_000);
+    let controller = &driver.motor.controller;
+    assert_eq!(controller.fault(), Some(MotorFault::Desync));
+    let (states, _) = phase_states(controller.driver(), controller.pwm());
+    assert_eq!(states, [PhaseState::Floating; 3]);
+    assert_eq!(driver.sim.phase_currents(), [0.0; 3]);
+}
+
+#[test]
+fn stall_fault_when_rotor_cannot_start() {
+    let mut sim = simulator(0);
+    sim.set_load_torque(1.0);
+    let mut driver = Driver::new(sim, config());
+    assert_eq!(driver.run_until_started(5_000_000), StartupState::Stopped);
+    assert_eq!(driver.motor.controller.fault(), Some(MotorFault::Stall));
+    assert_eq!(driver.motor.controller.startup.restarts(), 3);
+}
+
+#[test]
+fn runs_four_motors_independently() {
+    // Each motor has its own controller and commutation timer,
+    // and is sampled and commutated in turn (as the interrupts
+    // for each motor would be handled on one MCU)
+    let mut drivers: Vec<Driver> = (0..4)
+        .map(|n| {
+            let mut sim = simulator(0);
+            sim.set_angle(0.2 * n as f64);
+            Driver::new(sim, config())
+        })
+        .collect();
+
+    for _ in 0..(1_500_000 / SAMPLE_US) {
+        for driver in drivers.iter_mut() {
+            driver.sample();
+        }
+    }
+
+    // Vary the load on one motor only
+    drivers[3].sim.set_load_torque(1.0);
+    for _ in 0..(100_000 / SAMPLE_US) {
+        for driver in drivers.iter_mut() {
+            driver.sample();
+        }
+    }
+
+    for driver in &drivers[0..3] {
+        assert!(driver.motor.controller.startup.is_closed_loop());
+        assert_eq!(driver.motor.controller.startup.restarts(), 0);
+    }
+    assert!(!drivers[3].motor.controller.startup.is_closed_loop());
+}
+
+#[test]
+fn speed_control_tracks_target_rpm() {
+    let mut driver = Driver::new(simulator(0), config());
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+
+    for target in [6000.0f32, 9000.0, 5000.0] {
+        driver.motor.controller.speed.set_target_rpm(Some(target));
+        driver.run(1_000_000);
+        assert!(driver.motor.controller.startup.is_closed_loop());
+        let rpm = driver.sim.speed_rpm() as f32;
+        assert!(
+            (rpm - target).abs() < 0.03 * target,
+            "target {target}, actual {rpm}"
+        );
+    }
+}
+
+#[test]
+fn reverses_after_braking() {
+    let mut driver = Driver::new(simulator(0), config());
+    driver.motor.controller.brake.set_config(BrakeConfig {
+        mode: BrakeMode::Active,
+        ..Default::default()
+    });
+    assert_eq!(
+        driver.run_until_started(2_000_000),
+        StartupState::ClosedLoop
+    );
+    driver.run(200_000);
+    let forward = driver.sim.speed_rpm();
+    assert!(forward > 0.0);
+
+    // The shorted windings stop the motor, and it starts again the
+    // other way round
+    driver.motor.controller.set_direction(Direction::Reverse);
+    driver.run(100_000);
+    assert!(driver.sim.speed_rpm().abs() < 0.1 * forward);
+    assert_eq!(
+        driver.run_until_started(3_000_000),
+        StartupState::ClosedLoop
+    );
+    driver.run(200_000);
+    assert!(driver.motor.controller.startup.is_closed_loop());
+    let reverse = driver.sim.speed_rpm();
+    assert!(
+        (reverse + forward).abs() < 0.1 * forward,
+        "forward {forward}, reverse {reverse}"
+    );
+}
@@ -0,0 +1,192 @@
+//! Closed-loop tests of field-oriented control against the
+//! simulated motor (with a sinusoidal back-EMF)
+
+use bldc::foc::wrap_angle;
+use bldc::mock::{MockDriver, MockPwm, MockSampler};
+use bldc::{ControlMode, Direction, FocState, MotorFault, PhaseState, ThreePhaseController};
+use bldc_sim::{BackEmf, Inverter, Motor, Simulator};
+use std::f64::consts::PI;
+
+/// Time between current samples (one PWM period)
+const SAMPLE_US: u32 = 20;
+
+/// Runs a controller in FOC mode against the simulated motor: the
+/// phase currents are sampled at the start of every PWM period, and
+/// the duty cycles the controller sets are applied from the start
+/// of the next period (as with preloaded timer compare registers).
+struct Driver {
+    sim: Simulator,
+    controller: ThreePhaseController<MockDriver, MockPwm, MockSampler>,
+
+    // The duty cycles for this period and the next one (None for
+    // high-Z)
+    active: Option<[f32; 3]>,
+    loaded: Option<[f32; 3]>,
+}
+
+impl Driver {
+    fn new(sim: Simulator) -> Self {
+        let mut controller = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler {
+                phase_currents: Some([0.0; 3]),
+                ..MockSampler::default()
+            },
+        );
+        controller.set_mode(ControlMode::Foc).unwrap();
+        controller.check_bus_voltage(sim.inverter().supply_voltage as f32);
+        controller.set_duty(0.2).unwrap();
+        controller.startup.start();
+        Self {
+            sim,
+            controller,
+            active: None,
+            loaded: None,
+        }
+    }
+
+    fn run(&mut self, duration_us: u32) {
+        for _ in 0..duration_us / SAMPLE_US {
+            self.sample();
+        }
+    }
+
+    /// Advance by one PWM period
+    fn sample(&mut self) {
+        match self.active {
+            Some(duties) => self.sim.set_duties(duties),
+            None => self.sim.set_phases([PhaseState::Floating; 3], 0.0),
+        }
+        self.sim.run(SAMPLE_US);
+
+        let currents = self.sim.phase_currents().map(|current| current as f32);
+        self.controller.on_current_samples(currents, SAMPLE_US);
+        self.active = self.loaded;
+        self.loaded = self
+            .controller
+            .foc
+            .is_running()
+            .then(|| self.controller.pwm().duty);
+    }
+
+    /// Run until the observer takes over (or the start-up fails),
+    /// or the timeout expires
+    fn run_until_started(&mut self, timeout_us: u32) -> FocState {
+        let mut elapsed = 0;
+        while elapsed < timeout_us {
+            self.run(1000);
+            elapsed += 1000;
+            if self.controller.foc.state() != FocState::OpenLoop {
+                break;
+            }
+        }
+        self.controller.foc.state()
+    }
+}
+
+fn simulator() -> Simulator {
+    // With a propeller, the friction limits the speed (rather than
+    // the supply voltage)
+    let motor = Motor {
+        back_emf: BackEmf::Sinusoidal,
+        friction: 1e-6,
+        ..Motor::p1604()
+    };
+    let mut sim = Simulator::new(motor, Inverter::breadboard());
+    sim.set_load_torque(1e-4);
+    sim
+}
+
+#[test]
+fn starts_and_follows_rotor() {
+    let mut driver = Driver::new(simulator());
+    assert_eq!(driver.run_until_started(1_000_000), FocState::ClosedLoop);
+    driver.run(200_000);
+    assert_eq!(driver.controller.foc.state(), FocState::ClosedLoop);
+
+    // The observed flux is at 180 degrees to the phase A back-EMF
+    // peak
+    let angle = driver.controller.foc.angle() as f64;
+    let error = wrap_angle((angle - driver.sim.electrical_angle() - PI) as f32);
+    assert!(error.abs() < 0.05, "angle error {error}");
+
+    let pole_pairs = driver.sim.motor().pole_pairs as f64;
+    let measured_rpm = driver.controller.foc.speed() as f64 * 60.0 / (2.0 * PI * pole_pairs);
+    let rpm = driver.sim.speed_rpm();
+    assert!(
+        (measured_rpm - rpm).abs() < 0.05 * rpm,
+        "measured {measured_rpm}, actual {rpm}"
+    );
+
+    // The current is all in the q axis
+    let current = driver.controller.foc.current();
+    assert!(current.d.abs() < 0.1, "d current {}", current.d);
+    assert!(
+        (current.q - 0.2 * 4.0).abs() < 0.1,
+        "q current {}",
+        current.q
+    );
+}
+
+#[test]
+fn starts_in_reverse() {
+    let mut driver = Driver::new(simulator());
+    driver.controller.set_direction(Direction::Reverse);
+    assert_eq!(driver.run_until_started(1_000_000), FocState::ClosedLoop);
+    driver.run(200_000);
+    assert_eq!(driver.controller.foc.state(), FocState::ClosedLoop);
+    assert!(
+        driver.sim.speed_rpm() < -1000.0,
+        "{}",
+        driver.sim.speed_rpm()
+    );
+}
+
+#[test]
+fn speed_follows_duty() {
+    let mut driver = Driver::new(simulator());
+    assert_eq!(driver.run_until_started(1_000_000), FocState::ClosedLoop);
+    driver.run(1_000_000);
+    let slow = driver.sim.speed_rpm();
+
+    // The duty cycle sets the torque, so it can change suddenly
+    driver.controller.set_duty(0.4).unwrap();
+    driver.run(1_000_000);
+    assert_eq!(driver.controller.foc.state(), FocState::ClosedLoop);
+    let fast = driver.sim.speed_rpm();
+    assert!(fast > 1.5 * slow, "slow {slow}, fast {fast}");
+}
+
+#[test]
+fn speed_control_tracks_target_rpm() {
+    let mut driver = Driver::new(simulator());
+    assert_eq!(driver.run_until_started(1_000_000), FocState::ClosedLoop);
+
+    for target in [6000.0f32, 9000.0, 5000.0] {
+        driver.controller.speed.set_target_rpm(Some(target));
+        driver.run(1_000_000);
+        assert_eq!(driver.controller.foc.state(), FocState::ClosedLoop);
+        let rpm = driver.sim.speed_rpm() as f32;
+        assert!(
+            (rpm - target).abs() < 0.03 * target,
+            "target {target}, actual {rpm}"
+        );
+    }
+}
+
+#[test]
+fn desync_fault_when_rotor_blocked() {
+    let mut driver = Driver::new(simulator());
+    assert_eq!(driver.run_until_started(1_000_000), FocState::ClosedLoop);
+    driver.run(100_000);
+
+    // Block the rotor: the observer loses it, and the motor is
+    // shut down
+    driver.sim.set_load_torque(1.0);
+    driver.run(100_000);
+    let controller = &driver.controller;
+    assert_eq!(controller.fault(), Some(MotorFault::Desync));
+    assert_eq!(controller.pwm().duty, [0.0; 3]);
+    assert_eq!(driver.sim.phase_currents(), [0.0; 3]);
+}
@@ -0,0 +1,186 @@
+//! Tests of the motor parameter identification against the
+//! simulated motor
+
+use bldc::mock::{phase_states, MockDriver, MockPwm, MockSampler, MockTimer};
+use bldc::{
+    CommutationTimer, FaultConfig, IdentifyConfig, IdentifyError, MotorParameters, StartupConfig,
+    StartupState, ThreePhaseController,
+};
+use bldc_sim::{Inverter, Motor, Simulator};
+
+/// Time between ADC samples (one PWM period)
+const SAMPLE_US: u32 = 20;
+
+/// Runs a motor instance against the simulated motor as in the
+/// commutation tests, but with the motor current (the current in
+/// the driven windings) and supply voltage measured too
+struct Driver {
+    sim: Simulator,
+    motor: bldc::Motor<MockDriver, MockPwm, MockSampler, MockTimer>,
+}
+
+impl Driver {
+    fn new(sim: Simulator) -> Self {
+        let mut controller = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        );
+        // The open-loop ramp draws a few amps
+        controller.faults.set_config(FaultConfig {
+            max_current: 10.0,
+            ..Default::default()
+        });
+
+        let mut timer = MockTimer::default();
+        timer.start(1000).unwrap();
+        Self {
+            sim,
+            motor: bldc::Motor::new(controller, timer),
+        }
+    }
+
+    fn run(&mut self, duration_us: u32) {
+        for _ in 0..duration_us / SAMPLE_US {
+            self.sample();
+        }
+    }
+
+    /// Advance by one PWM period
+    fn sample(&mut self) {
+        let mut remaining_us = SAMPLE_US;
+        loop {
+            let timer = &self.motor.timer;
+            let expiry_us = timer.timeout_us.saturating_sub(timer.elapsed_us);
+            if expiry_us > remaining_us {
+                break;
+            }
+            self.advance(expiry_us);
+            remaining_us -= expiry_us;
+
+            self.motor.on_commutation_timer();
+            let controller = &self.motor.controller;
+            let (states, duty) = phase_states(controller.driver(), controller.pwm());
+            self.sim.set_phases(states, duty);
+        }
+        self.advance(remaining_us);
+
+        let current = self
+            .sim
+            .phase_currents()
+            .into_iter()
+            .fold(0.0, |max: f64, current| max.max(current.abs()));
+        let sampler = self.motor.controller.sampler_mut();
+        sampler.samples = self.sim.adc_samples();
+        sampler.current = Some(current as f32);
+        sampler.bus_voltage = Some(self.sim.inverter().supply_voltage as f32);
+        self.motor.on_samples();
+    }
+
+    fn advance(&mut self, us: u32) {
+        self.sim.run(us);
+        self.motor.timer.advance(us);
+    }
+
+    /// Run the identification until it finishes, or the timeout
+    /// expires
+    fn identify(&mut self, timeout_us: u32) -> Option<Result<MotorParameters, IdentifyError>> {
+        self.motor.controller.start_identification();
+        let mut elapsed = 0;
+        while elapsed < timeout_us && self.motor.controller.identify.is_active() {
+            self.run(1000);
+            elapsed += 1000;
+        }
+        self.motor.controller.identify.result()
+    }
+}
+
+fn simulator() -> Simulator {
+    let mut sim = Simulator::new(Motor::p1604(), Inverter::breadboard());
+    sim.set_load_torque(1e-4);
+    sim
+}
+
+/// A gentle start-up for the measurement of the back-EMF
+fn startup_config() -> StartupConfig {
+    StartupConfig {
+        align_duty: 0.05,
+        ramp_start_duty: 0.08,
+        ramp_end_duty: 0.08,
+        ramp_steps: 600,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn measures_p1604_parameters() {
+    let mut driver = Driver::new(simulator());
+    driver.motor.controller.startup.set_config(startup_config());
+    driver.motor.controller.identify.set_config(IdentifyConfig {
+        rated_kv: Some(3800.0),
+        ..Default::default()
+    });
+
+    let parameters = driver.identify(3_000_000).unwrap().unwrap();
+    let resistance = parameters.phase_resistance;
+    let inductance = parameters.phase_inductance;
+    let kv = parameters.kv(7);
+    assert!(
+        (resistance - 0.07).abs() < 0.0035,
+        "resistance {resistance}"
+    );
+    assert!((inductance - 10e-6).abs() < 1e-6, "inductance {inductance}");
+    assert!((kv - 3800.0).abs() < 190.0, "Kv {kv}");
+    assert_eq!(parameters.pole_pairs, Some(7));
+
+    // The motor is stopped afterwards, with the parameters applied
+    let controller = &driver.motor.controller;
+    assert_eq!(controller.fault(), None);
+    assert_eq!(controller.startup.state(), StartupState::Stopped);
+    assert_eq!(controller.speed.config().pole_pairs, 7);
+    assert_eq!(controller.foc.config().phase_resistance, resistance);
+}
+
+#[test]
+fn starts_with_measured_parameters() {
+    let mut driver = Driver::new(simulator());
+    driver.motor.controller.startup.set_config(startup_config());
+    driver.identify(3_000_000).unwrap().unwrap();
+
+    // Wait for the motor to stop, then start it with the derived
+    // start-up sequence (keeping the shorter ramp)
+    driver.run(500_000);
+    let controller = &mut driver.motor.controller;
+    let startup = controller.startup.config();
+    assert!(startup.ramp_end_period_us > 250 && startup.ramp_end_period_us < 380);
+    let duty = startup.ramp_end_duty;
+    controller.set_duty(duty).unwrap();
+    controller.startup.start();
+
+    let mut elapsed = 0;
+    while elapsed < 2_000_000 && !driver.motor.controller.startup.is_closed_loop() {
+        driver.run(1000);
+        elapsed += 1000;
+        assert_eq!(driver.motor.controller.fault(), None);
+    }
+    assert!(driver.motor.controller.startup.is_closed_loop());
+    assert_eq!(driver.motor.controller.startup.restarts(), 0);
+}
+
+#[test]
+fn fails_without_current_measurement() {
+    let mut driver = Driver::new(simulator());
+    driver.motor.controller.start_identification();
+    driver.motor.controller.sampler_mut().current = None;
+
+    // Without a current channel, the identification gives up at
+    // the first hold
+    for _ in 0..100 {
+        driver.motor.on_commutation_timer();
+    }
+    assert_eq!(
+        driver.motor.controller.identify.result(),
+        Some(Err(IdentifyError::NoCurrentMeasurement))
+    );
+    assert_eq!(driver.motor.controller.applied_duty(), 0.0);
+}
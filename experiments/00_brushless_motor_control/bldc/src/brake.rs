@@ -0,0 +1,198 @@
+//! Braking, and reversing the direction of rotation
+//!
+//! When the motor is stopped, the half bridges either let it
+//! coast, or brake it by shorting the windings through the
+//! low-side MOSFETs (see [`BrakeMode`]).
+//!
+//! A sensorless motor cannot be reversed while it is turning: the
+//! start-up sequence assumes that the rotor is at rest. To reverse
+//! it, the controller stops driving it and brakes it (or lets it
+//! coast) for long enough to come to a stop, and then starts it
+//! again in the other direction. [`Brake`] keeps track of how long
+//! the motor has been stopped, and of the pending reversal.
+
+use crate::step::Direction;
+
+/// What the half bridges do while the motor is stopped
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BrakeMode {
+    /// All half bridges high-Z, so the motor coasts to a stop
+    #[default]
+    Coast,
+    /// All low-side MOSFETs on, shorting the windings. The
+    /// back-EMF drives a current through the windings which
+    /// brakes the motor hard (the energy is dissipated in the
+    /// windings and MOSFETs).
+    Active,
+    /// The low-side MOSFETs are switched on for the brake duty
+    /// cycle, and off for the rest of the PWM period. The current
+    /// which builds up in the windings while they are shorted is
+    /// pushed back into the supply through the high-side diodes
+    /// when they are released.
+    Regenerative,
+}
+
+impl BrakeMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BrakeMode::Coast => "coast",
+            BrakeMode::Active => "active",
+            BrakeMode::Regenerative => "regenerative",
+        }
+    }
+}
+
+/// Parameters of the braking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrakeConfig {
+    pub mode: BrakeMode,
+
+    /// Duty cycle of the low-side MOSFETs in regenerative mode
+    /// (the braking strength)
+    pub regenerative_duty: f32,
+
+    /// How long the motor must be stopped (braking or coasting)
+    /// before it is assumed to be at rest, and can be started in
+    /// the other direction
+    pub stop_time_us: u32,
+}
+
+impl Default for BrakeConfig {
+    fn default() -> Self {
+        Self {
+            mode: BrakeMode::default(),
+            regenerative_duty: 0.5,
+            stop_time_us: 500_000,
+        }
+    }
+}
+
+/// Tracks how long the motor has been stopped, and the direction
+/// to start it in once it is at rest
+pub struct Brake {
+    config: BrakeConfig,
+
+    // The time since the motor was last driven (saturating)
+    stopped_us: u32,
+
+    // The direction to restart in once the motor is at rest
+    reversal: Option<Direction>,
+}
+
+impl Brake {
+    pub fn new(config: BrakeConfig) -> Self {
+        Self {
+            config,
+            // The motor is at rest at power-up
+            stopped_us: u32::MAX,
+            reversal: None,
+        }
+    }
+
+    pub fn config(&self) -> &BrakeConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: BrakeConfig) {
+        self.config = config;
+    }
+
+    /// The duty cycle of the low-side MOSFETs while the motor is
+    /// stopped (0.0 when coasting)
+    pub fn duty(&self) -> f32 {
+        match self.config.mode {
+            BrakeMode::Coast => 0.0,
+            BrakeMode::Active => 1.0,
+            BrakeMode::Regenerative => self.config.regenerative_duty,
+        }
+    }
+
+    /// The motor is being driven (call on every commutation or
+    /// current update)
+    pub fn running(&mut self) {
+        self.stopped_us = 0;
+    }
+
+    /// Whether the motor has been stopped for long enough to be
+    /// at rest
+    pub fn is_stopped(&self) -> bool {
+        self.stopped_us >= self.config.stop_time_us
+    }
+
+    /// Stop the motor, and start it again in `direction` once it is
+    /// at rest
+    pub fn reverse(&mut self, direction: Direction) {
+        self.reversal = Some(direction);
+    }
+
+    /// The direction the motor will be restarted in, if it is
+    /// being reversed
+    pub fn reversal(&self) -> Option<Direction> {
+        self.reversal
+    }
+
+    pub fn is_reversing(&self) -> bool {
+        self.reversal.is_some()
+    }
+
+    /// Give up the pending reversal, returning its direction
+    pub fn cancel(&mut self) -> Option<Direction> {
+        self.reversal.take()
+    }
+
+    /// Count `elapsed_us` more of the motor being stopped
+    ///
+    /// Returns the direction of the pending reversal once the
+    /// motor is at rest (after which it is no longer pending).
+    pub fn update(&mut self, elapsed_us: u32) -> Option<Direction> {
+        self.stopped_us = self.stopped_us.saturating_add(elapsed_us);
+        if self.is_stopped() {
+            self.reversal.take()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duty_follows_mode() {
+        let mut brake = Brake::new(BrakeConfig::default());
+        assert_eq!(brake.duty(), 0.0);
+        for (mode, duty) in [(BrakeMode::Active, 1.0), (BrakeMode::Regenerative, 0.3)] {
+            brake.set_config(BrakeConfig {
+                mode,
+                regenerative_duty: 0.3,
+                ..Default::default()
+            });
+            assert_eq!(brake.duty(), duty);
+        }
+    }
+
+    #[test]
+    fn reverses_once_stopped() {
+        let mut brake = Brake::new(BrakeConfig {
+            stop_time_us: 1000,
+            ..Default::default()
+        });
+        assert!(brake.is_stopped());
+
+        brake.running();
+        brake.reverse(Direction::Reverse);
+        assert_eq!(brake.update(600), None);
+        assert!(brake.is_reversing());
+        assert_eq!(brake.update(600), Some(Direction::Reverse));
+        assert!(!brake.is_reversing());
+        assert!(brake.is_stopped());
+
+        // A reversal can be given up before the motor is at rest
+        brake.running();
+        brake.reverse(Direction::Forward);
+        assert_eq!(brake.update(600), None);
+        assert_eq!(brake.cancel(), Some(Direction::Forward));
+        assert_eq!(brake.update(600), None);
+    }
+}
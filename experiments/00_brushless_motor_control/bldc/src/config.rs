@@ -0,0 +1,670 @@
+//! Description of the peripherals used by each motor
+//!
+//! When several motors are driven from one MCU, each motor needs
+//! its own PWM outputs, GPIO pins, ADC channels and commutation
+//! timer. A [`MotorConfig`] lists these for one motor, and
+//! [`check_configs`] checks that no peripheral is used twice.
+//!
+//! The ADC channels of a motor are converted together in one
+//! regular sequence ([`AdcSequence`]), which also gives the
+//! layout of the buffer the conversions are transferred into.
+//! The ADC itself is set up by the `stm32-adc` driver, from
+//! [`AdcSequence::to_sequence`].
+//!
+
+use crate::identify::MotorParameters;
+pub use stm32_adc::SampleTime;
+use stm32_adc::{AdcError, Sequence};
+
+/// The ADC reference voltage (V)
+pub const ADC_REFERENCE: f32 = 3.3;
+
+/// The number of ADC counts (12-bit conversions)
+pub const ADC_COUNTS: f32 = 4096.0;
+
+/// The most conversions in the sequence of one motor (three phase
+/// voltages, the current and the supply voltage)
+pub const MAX_CONVERSIONS: usize = 5;
+
+/// A GPIO pin (e.g. PB4 is `Pin { port: 'B', number: 4 }`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin {
+    pub port: char,
+    pub number: u8,
+}
+
+/// A timer output channel, and the pin it is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerChannel {
+    /// The timer number (e.g. 1 for TIM1)
+    pub timer: u8,
+    /// The channel number (1 to 4)
+    pub channel: u8,
+    pub pin: Pin,
+}
+
+/// An ADC input channel, and the pin it is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdcChannel {
+    /// The ADC number (e.g. 3 for ADC3)
+    pub adc: u8,
+    /// The input channel number (e.g. 0 for IN0)
+    pub channel: u8,
+    pub pin: Pin,
+    pub sample_time: SampleTime,
+}
+
+/// Converts the raw values of an ADC channel to amps or volts
+///
+/// The conversion is linear: `(raw - zero) * per_count`. Use
+/// [`AdcScale::divider`] or [`AdcScale::shunt`] for the nominal
+/// values of the components, or [`AdcScale::calibrate`] with two
+/// measurements to correct for their tolerances.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdcScale {
+    /// The raw value at zero amps or volts
+    pub zero: f32,
+    /// Amps or volts per ADC count
+    pub per_count: f32,
+}
+
+impl AdcScale {
+    /// A voltage measured through a resistor divider, where `ratio`
+    /// is the pin voltage over the measured voltage (e.g. 2.2 /
+    /// (10 + 2.2) for 10k over 2k2)
+    pub const fn divider(ratio: f32) -> Self {
+        Self {
+            zero: 0.0,
+            per_count: ADC_REFERENCE / ADC_COUNTS / ratio,
+        }
+    }
+
+    /// A current measured with a shunt resistor (`resistance` in
+    /// ohms) and an amplifier (`gain`), whose output is `offset`
+    /// volts at zero current (e.g. half the reference for an
+    /// amplifier measuring in both directions)
+    pub const fn shunt(resistance: f32, gain: f32, offset: f32) -> Self {
+        Self {
+            zero: offset / ADC_REFERENCE * ADC_COUNTS,
+            per_count: ADC_REFERENCE / ADC_COUNTS / (resistance * gain),
+        }
+    }
+
+    /// The scale through two points: the raw values, and the amps
+    /// or volts measured (e.g. with a multimeter) at each
+    pub fn calibrate(first: (u16, f32), second: (u16, f32)) -> Self {
+        let ((raw1, value1), (raw2, value2)) = (first, second);
+        let per_count = (value2 - value1) / (raw2 as f32 - raw1 as f32);
+        Self {
+            zero: raw1 as f32 - value1 / per_count,
+            per_count,
+        }
+    }
+
+    /// Convert a raw ADC value
+    pub fn convert(&self, raw: u16) -> f32 {
+        (raw as f32 - self.zero) * self.per_count
+    }
+}
+
+/// An ADC channel measuring a current or voltage, and the scale to
+/// convert its values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaledChannel {
+    pub channel: AdcChannel,
+    pub scale: AdcScale,
+}
+
+/// What a conversion in an [`AdcSequence`] measures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcInput {
+    /// The voltage of phase 0, 1 or 2
+    PhaseVoltage(u8),
+    /// The motor current
+    Current,
+    /// The supply voltage
+    BusVoltage,
+}
+
+/// The channels converted in one ADC regular sequence, in order
+///
+/// The conversions are transferred into a buffer in the same
+/// order, so [`AdcSequence::position`] gives where each input is
+/// in the buffer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AdcSequence {
+    conversions: [Option<(AdcInput, AdcChannel)>; MAX_CONVERSIONS],
+}
+
+impl AdcSequence {
+    /// Add a conversion to the end of the sequence
+    ///
+    /// Panics if the sequence already has [`MAX_CONVERSIONS`].
+    pub fn push(&mut self, input: AdcInput, channel: AdcChannel) {
+        let len = self.len();
+        self.conversions[len] = Some((input, channel));
+    }
+
+    /// The number of conversions
+    pub fn len(&self) -> usize {
+        self.conversions.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The inputs and their channels, in conversion order
+    pub fn iter(&self) -> impl Iterator<Item = (AdcInput, AdcChannel)> + Clone + '_ {
+        self.conversions.iter().flatten().copied()
+    }
+
+    /// The channels, in conversion order
+    pub fn channels(self) -> impl Iterator<Item = AdcChannel> + Clone {
+        self.conversions.into_iter().flatten().map(|(_, c)| c)
+    }
+
+    /// The position of `input` in the sequence (and the buffer)
+    pub fn position(&self, input: AdcInput) -> Option<usize> {
+        self.iter().position(|(i, _)| i == input)
+    }
+
+    /// The value of `input` in a buffer of conversions
+    pub fn read(&self, buffer: &[u16], input: AdcInput) -> Option<u16> {
+        buffer.get(self.position(input)?).copied()
+    }
+
+    /// The sequence to set up the ADC with (with the channel
+    /// numbers and sample times)
+    pub fn to_sequence(&self) -> Result<Sequence, AdcError> {
+        self.iter().try_fold(Sequence::new(), |sequence, (_, c)| {
+            sequence.with(c.channel, c.sample_time)
+        })
+    }
+}
+
+/// When the phase voltages are converted in each PWM period
+///
+/// The back-EMF is noisiest just after the half bridges switch,
+/// so the conversion is triggered part of the way through the
+/// on-time or the off-time of the line phase, which moves with
+/// the duty cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcTrigger {
+    /// A percentage of the way through the on-time (50 is the
+    /// centre)
+    OnTime(u8),
+    /// A percentage of the way through the off-time
+    OffTime(u8),
+}
+
+impl Default for AdcTrigger {
+    fn default() -> Self {
+        AdcTrigger::OnTime(50)
+    }
+}
+
+impl AdcTrigger {
+    /// The position of the trigger in the PWM period (between
+    /// 0.0 and 1.0) at the duty cycle `duty`
+    pub fn position(&self, duty: f32) -> f32 {
+        match *self {
+            AdcTrigger::OnTime(percent) => duty * percent as f32 / 100.0,
+            AdcTrigger::OffTime(percent) => duty + (1.0 - duty) * percent as f32 / 100.0,
+        }
+    }
+}
+
+/// The peripherals used to drive one motor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorConfig {
+    /// PWM outputs connected to the half bridge enable inputs
+    /// (phase 0, 1 and 2)
+    pub pwm: [TimerChannel; 3],
+
+    /// GPIO outputs connected to the half bridge signal inputs
+    /// (or the complementary timer outputs connected to the
+    /// low-side inputs, for drivers with separate high-side and
+    /// low-side inputs)
+    pub enable_pins: [Pin; 3],
+
+    /// ADC channels measuring the phase voltages. All three must
+    /// be on the same ADC, so that they are converted together
+    /// in one regular sequence.
+    pub phase_voltages: [AdcChannel; 3],
+
+    /// ADC channel measuring the motor current (optional, on the
+    /// same ADC as the phase voltages)
+    pub current: Option<ScaledChannel>,
+
+    /// ADC channel measuring the supply voltage (optional, on the
+    /// same ADC as the phase voltages)
+    pub bus_voltage: Option<ScaledChannel>,
+
+    /// When the phase voltages are converted in each PWM period
+    pub adc_trigger: AdcTrigger,
+
+    /// The timer which schedules the commutations (e.g. 3 for TIM3)
+    pub commutation_timer: u8,
+
+    /// The measured motor parameters (see [`crate::identify`]), from
+    /// which the start-up sequence and control settings are
+    /// derived (None to keep the defaults)
+    pub parameters: Option<MotorParameters>,
+}
+
+impl MotorConfig {
+    /// All the pins used by the motor
+    pub fn pins(&self) -> impl Iterator<Item = Pin> + Clone {
+        let adc = self.adc_sequence().channels().map(|c| c.pin);
+        self.pwm
+            .map(|c| c.pin)
+            .into_iter()
+            .chain(self.enable_pins)
+            .chain(adc)
+    }
+
+    /// The ADC used to measure the phase voltages
+    pub fn adc(&self) -> u8 {
+        self.phase_voltages[0].adc
+    }
+
+    /// The ADC conversions of the motor, in order
+    ///
+    /// The current is converted first, so that it is measured at
+    /// the trigger point (while the line phase is switched on),
+    /// then the phase voltages, then the supply voltage.
+    pub fn adc_sequence(&self) -> AdcSequence {
+        let mut sequence = AdcSequence::default();
+        if let Some(current) = self.current {
+            sequence.push(AdcInput::Current, current.channel);
+        }
+        for (n, channel) in self.phase_voltages.into_iter().enumerate() {
+            sequence.push(AdcInput::PhaseVoltage(n as u8), channel);
+        }
+        if let Some(bus_voltage) = self.bus_voltage {
+            sequence.push(AdcInput::BusVoltage, bus_voltage.channel);
+        }
+        sequence
+    }
+}
+
+/// A conflict between motor configurations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A pin is used for more than one purpose
+    PinUsedTwice(Pin),
+    /// A timer channel drives more than one phase
+    TimerChannelUsedTwice { timer: u8, channel: u8 },
+    /// An ADC channel measures more than one input
+    AdcChannelUsedTwice { adc: u8, channel: u8 },
+    /// An ADC samples more than one motor (each motor converts its
+    /// own sequence, on its own PWM period)
+    AdcUsedTwice(u8),
+    /// A commutation timer is shared with another motor, or
+    /// is also used for PWM
+    CommutationTimerUsedTwice(u8),
+    /// The ADC channels of a motor are not all on the same ADC
+    /// (the index of the motor in the list)
+    MixedAdcs(usize),
+    /// The ADC trigger percentage is over 100 (the index of the
+    /// motor in the list)
+    InvalidAdcTrigger(usize),
+    /// The ADC channels of a motor cannot be converted in one
+    /// sequence (e.g. a channel number which does not exist)
+    InvalidAdcSequence { motor: usize, error: AdcError },
+}
+
+/// Check that the motors do not share any pins, timers, ADCs or
+/// ADC channels
+pub fn check_configs(configs: &[MotorConfig]) -> Result<(), ConfigError> {
+    for (n, config) in configs.iter().enumerate() {
+        if config
+            .adc_sequence()
+            .channels()
+            .any(|c| c.adc != config.adc())
+        {
+            return Err(ConfigError::MixedAdcs(n));
+        }
+        if let Err(error) = config.adc_sequence().to_sequence() {
+            return Err(ConfigError::InvalidAdcSequence { motor: n, error });
+        }
+        let (AdcTrigger::OnTime(percent) | AdcTrigger::OffTime(percent)) = config.adc_trigger;
+        if percent > 100 {
+            return Err(ConfigError::InvalidAdcTrigger(n));
+        }
+    }
+
+    if let Some(pin) = first_duplicate(configs.iter().flat_map(MotorConfig::pins)) {
+        return Err(ConfigError::PinUsedTwice(pin));
+    }
+
+    let pwm = configs
+        .iter()
+        .flat_map(|c| c.pwm.map(|p| (p.timer, p.channel)));
+    if let Some((timer, channel)) = first_duplicate(pwm) {
+        return Err(ConfigError::TimerChannelUsedTwice { timer, channel });
+    }
+
+    let adc = configs
+        .iter()
+        .flat_map(|c| c.adc_sequence().channels().map(|p| (p.adc, p.channel)));
+    if let Some((adc, channel)) = first_duplicate(adc) {
+        return Err(ConfigError::AdcChannelUsedTwice { adc, channel });
+    }
+    if let Some(adc) = first_duplicate(configs.iter().map(MotorConfig::adc)) {
+        return Err(ConfigError::AdcUsedTwice(adc));
+    }
+
+    for config in configs {
+        let timer = config.commutation_timer;
+        let commutation = configs
+            .iter()
+            .filter(|c| c.commutation_timer == timer)
+            .count();
+        let pwm = configs
+            .iter()
+            .flat_map(|c| c.pwm.iter())
+            .any(|p| p.timer == timer);
+        if commutation > 1 || pwm {
+            return Err(ConfigError::CommutationTimerUsedTwice(timer));
+        }
+    }
+
+    Ok(())
+}
+
+// The first item which appears more than once
+fn first_duplicate<T, I>(items: I) -> Option<T>
+where
+    T: PartialEq,
+    I: Iterator<Item = T> + Clone,
+{
+    items
+        .clone()
+        .enumerate()
+        .find(|(n, item)| items.clone().skip(n + 1).any(|other| other == *item))
+        .map(|(_, item)| item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(port: char, number: u8) -> Pin {
+        Pin { port, number }
+    }
+
+    // The wiring of the motor on the STM32F746 DISCO board
+    fn disco() -> MotorConfig {
+        MotorConfig {
+            pwm: [
+                TimerChannel {
+                    timer: 1,
+                    channel: 1,
+                    pin: pin('A', 8),
+                },
+                TimerChannel {
+                    timer: 2,
+                    channel: 1,
+                    pin: pin('A', 15),
+                },
+                TimerChannel {
+                    timer: 5,
+                    channel: 4,
+                    pin: pin('I', 0),
+                },
+            ],
+            enable_pins: [pin('B', 4), pin('H', 6), pin('I', 2)],
+            phase_voltages: [
+                AdcChannel {
+                    adc: 3,
+                    channel: 0,
+                    pin: pin('A', 0),
+                    sample_time: SampleTime::Cycles3,
+                },
+                AdcChannel {
+                    adc: 3,
+                    channel: 8,
+                    pin: pin('F', 10),
+                    sample_time: SampleTime::Cycles3,
+                },
+                AdcChannel {
+                    adc: 3,
+                    channel: 7,
+                    pin: pin('F', 9),
+                    sample_time: SampleTime::Cycles3,
+                },
+            ],
+            current: None,
+            bus_voltage: None,
+            adc_trigger: AdcTrigger::default(),
+            commutation_timer: 3,
+            parameters: None,
+        }
+    }
+
+    // A second motor on different pins and peripherals
+    fn other() -> MotorConfig {
+        let mut config = disco();
+        for (n, p) in config.pwm.iter_mut().enumerate() {
+            *p = TimerChannel {
+                timer: 4,
+                channel: n as u8 + 1,
+                pin: pin('D', 12 + n as u8),
+            };
+        }
+        config.enable_pins = [pin('G', 0), pin('G', 1), pin('G', 2)];
+        for (n, p) in config.phase_voltages.iter_mut().enumerate() {
+            *p = AdcChannel {
+                adc: 1,
+                channel: 10 + n as u8,
+                pin: pin('C', n as u8),
+                sample_time: SampleTime::Cycles3,
+            };
+        }
+        config.commutation_timer = 9;
+        config
+    }
+
+    #[test]
+    fn accepts_separate_motors() {
+        assert_eq!(check_configs(&[disco(), other()]), Ok(()));
+        let channels = disco().adc_sequence().channels().map(|c| c.channel);
+        assert!(channels.eq([0, 8, 7]));
+    }
+
+    #[test]
+    fn rejects_shared_pin() {
+        let mut config = other();
+        config.enable_pins[1] = pin('H', 6);
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::PinUsedTwice(pin('H', 6)))
+        );
+    }
+
+    #[test]
+    fn rejects_shared_peripherals() {
+        let mut config = other();
+        config.pwm[2].timer = 5;
+        config.pwm[2].channel = 4;
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::TimerChannelUsedTwice {
+                timer: 5,
+                channel: 4
+            })
+        );
+
+        let mut config = other();
+        config.phase_voltages = config.phase_voltages.map(|c| AdcChannel { adc: 3, ..c });
+        config.phase_voltages[0].channel = 8;
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::AdcChannelUsedTwice { adc: 3, channel: 8 })
+        );
+
+        let mut config = other();
+        config.phase_voltages = config.phase_voltages.map(|c| AdcChannel { adc: 3, ..c });
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::AdcUsedTwice(3))
+        );
+
+        let mut config = other();
+        config.phase_voltages[1].adc = 2;
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::MixedAdcs(1))
+        );
+    }
+
+    #[test]
+    fn sample_time_registers() {
+        let registers = |config: MotorConfig| {
+            let sequence = config.adc_sequence().to_sequence().unwrap();
+            sequence.sample_time_registers()
+        };
+
+        let mut config = disco();
+        config.phase_voltages[0].sample_time = SampleTime::Cycles15;
+        config.phase_voltages[1].sample_time = SampleTime::Cycles480;
+        config.phase_voltages[2].sample_time = SampleTime::Cycles56;
+        assert_eq!(registers(config), (0, 0b001 | 0b111 << 24 | 0b011 << 21));
+
+        let mut config = other();
+        config.phase_voltages[2].sample_time = SampleTime::Cycles28;
+        assert_eq!(registers(config), (0b010 << 6, 0));
+
+        // There is no IN19
+        config.phase_voltages[2].channel = 19;
+        assert_eq!(
+            check_configs(&[config]),
+            Err(ConfigError::InvalidAdcSequence {
+                motor: 0,
+                error: AdcError::InvalidChannel(19)
+            })
+        );
+    }
+
+    // The disco motor with current and supply voltage channels
+    fn measured() -> MotorConfig {
+        let channel = |channel, number| AdcChannel {
+            adc: 3,
+            channel,
+            pin: pin('F', number),
+            sample_time: SampleTime::Cycles3,
+        };
+        MotorConfig {
+            current: Some(ScaledChannel {
+                channel: channel(6, 8),
+                scale: AdcScale::shunt(0.5, 1.0, 0.0),
+            }),
+            bus_voltage: Some(ScaledChannel {
+                channel: AdcChannel {
+                    sample_time: SampleTime::Cycles15,
+                    ..channel(5, 7)
+                },
+                scale: AdcScale::divider(2.2 / (10.0 + 2.2)),
+            }),
+            ..disco()
+        }
+    }
+
+    #[test]
+    fn adc_sequence_from_channel_list() {
+        let sequence = disco().adc_sequence();
+        assert_eq!(sequence.len(), 3);
+        assert_eq!(sequence.position(AdcInput::PhaseVoltage(2)), Some(2));
+        assert_eq!(sequence.position(AdcInput::Current), None);
+        assert_eq!(
+            sequence.to_sequence().unwrap().sequence_registers(),
+            [2 << 20, 0, 7 << 10 | 8 << 5]
+        );
+
+        // The current first, and the supply voltage last
+        let sequence = measured().adc_sequence();
+        assert_eq!(sequence.len(), 5);
+        assert_eq!(sequence.position(AdcInput::Current), Some(0));
+        assert_eq!(sequence.position(AdcInput::PhaseVoltage(0)), Some(1));
+        assert_eq!(sequence.position(AdcInput::BusVoltage), Some(4));
+        let registers = sequence.to_sequence().unwrap();
+        assert_eq!(
+            registers.sequence_registers(),
+            [4 << 20, 0, 6 | 8 << 10 | 7 << 15 | 5 << 20]
+        );
+        assert_eq!(registers.sample_time_registers(), (0, 0b001 << 15));
+
+        let buffer = [100, 2000, 2100, 2200, 3000];
+        assert_eq!(
+            sequence.read(&buffer, AdcInput::PhaseVoltage(2)),
+            Some(2200)
+        );
+        assert_eq!(sequence.read(&buffer, AdcInput::BusVoltage), Some(3000));
+        assert_eq!(sequence.read(&buffer[..3], AdcInput::BusVoltage), None);
+
+        // The extra pins and channels are checked too
+        assert_eq!(check_configs(&[measured(), other()]), Ok(()));
+        let mut config = other();
+        config.enable_pins[0] = pin('F', 8);
+        assert_eq!(
+            check_configs(&[measured(), config]),
+            Err(ConfigError::PinUsedTwice(pin('F', 8)))
+        );
+        let mut config = measured();
+        config.bus_voltage.as_mut().unwrap().channel.adc = 1;
+        assert_eq!(check_configs(&[config]), Err(ConfigError::MixedAdcs(0)));
+    }
+
+    #[test]
+    fn adc_scale_conversion() {
+        // 12.2 V through 10k over 2k2 is 2.2 V at the pin
+        let divider = AdcScale::divider(2.2 / (10.0 + 2.2));
+        assert!((divider.convert(2731) - 12.2).abs() < 0.01);
+        assert_eq!(divider.convert(0), 0.0);
+
+        // 1 A through 0.5 ohm is 0.5 V, amplified to 1.0 V above
+        // an offset of 1.65 V
+        let shunt = AdcScale::shunt(0.5, 2.0, 1.65);
+        assert_eq!(shunt.convert(2048), 0.0);
+        assert!((shunt.convert(2048 + 1241) - 1.0).abs() < 1e-3);
+        assert!((shunt.convert(2048 - 1241) + 1.0).abs() < 1e-3);
+
+        // Two measured points
+        let calibrated = AdcScale::calibrate((500, 2.0), (2500, 12.0));
+        assert!((calibrated.convert(1500) - 7.0).abs() < 1e-4);
+        assert!((calibrated.convert(100) - 0.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn adc_trigger_moves_with_duty_cycle() {
+        assert_eq!(AdcTrigger::OnTime(50).position(0.4), 0.2);
+        assert!((AdcTrigger::OffTime(50).position(0.4) - 0.7).abs() < 1e-6);
+        assert_eq!(AdcTrigger::OffTime(0).position(0.4), 0.4);
+
+        let mut config = other();
+        config.adc_trigger = AdcTrigger::OnTime(101);
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::InvalidAdcTrigger(1))
+        );
+    }
+
+    #[test]
+    fn rejects_shared_commutation_timer() {
+        let mut config = other();
+        config.commutation_timer = 3;
+        assert_eq!(
+            check_configs(&[disco(), config]),
+            Err(ConfigError::CommutationTimerUsedTwice(3))
+        );
+
+        // The PWM timers cannot also schedule commutations
+        config.commutation_timer = 4;
+        assert_eq!(
+            check_configs(&[config]),
+            Err(ConfigError::CommutationTimerUsedTwice(4))
+        );
+    }
+}
@@ -0,0 +1,1282 @@
+//! Three-phase motor controller (six-step commutation or FOC)
+//!
+
+use crate::brake::{Brake, BrakeConfig};
+use crate::config::AdcTrigger;
+use crate::fault::{FaultConfig, FaultMonitor, MotorFault};
+use crate::foc::{FocConfig, FocController, FocState};
+use crate::hal::{PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+use crate::identify::{Identification, IdentifyAction, IdentifyConfig, MotorParameters};
+use crate::pwm::{check_duty, PwmError, PwmTiming};
+use crate::samples::{PhaseSample, SampleHistory};
+use crate::speed::{SpeedConfig, SpeedController};
+use crate::startup::{Startup, StartupAction, StartupConfig, StartupState};
+use crate::step::{Direction, MotorStep, PhaseState};
+use crate::zero_crossing::{ZeroCrossingConfig, ZeroCrossingDetector};
+
+/// The number of phase voltage samples kept in the history (at
+/// 20 kHz, a whole commutation step of the P1604 down to about
+/// 900 RPM)
+pub const HISTORY_LEN: usize = 32;
+
+/// How the controller drives the motor
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMode {
+    /// Six-step (trapezoidal) commutation, timed from the back-EMF
+    /// zero crossings
+    #[default]
+    SixStep,
+    /// Field-oriented control (see [`crate::foc`]), run on the
+    /// phase current measurements
+    Foc,
+}
+
+impl ControlMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlMode::SixStep => "six-step",
+            ControlMode::Foc => "FOC",
+        }
+    }
+}
+
+/// Why the control mode cannot be changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeError {
+    /// FOC needs the phase currents, which the sampler does not
+    /// measure
+    NoPhaseCurrents,
+}
+
+impl ModeError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModeError::NoPhaseCurrents => "phase currents not measured",
+        }
+    }
+}
+
+/// Why the motor cannot be started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartError {
+    /// The controller is not armed (see
+    /// [`ThreePhaseController::arm`])
+    Disarmed,
+    /// A fault is latched (see [`ThreePhaseController::clear_fault`])
+    Fault(MotorFault),
+}
+
+impl StartError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StartError::Disarmed => "disarmed",
+            StartError::Fault(fault) => fault.name(),
+        }
+    }
+}
+
+/// The duty cycle the motor runs at once it has started (without
+/// speed control), until another is set (the end of the default
+/// start-up ramp)
+pub const DEFAULT_DUTY: f32 = 0.4;
+
+/// Three-phase motor controller supporting half bridge drivers
+///
+/// The struct controls three half-bridge drivers which have an
+/// enable and signal input (as opposed to high and low signal
+/// control for the high and low side MOSFETS). When enable is
+/// low, both transistors in the bridge are turned off, and the
+/// output is floating. When enable is on, the signal is used to
+/// turn on either the high side or low side MOSFET (the high
+/// side phase is in phase with signal).
+///
+/// The hardware is accessed through the [`PhaseDriver`] (the
+/// signal inputs), [`ThreePhasePwm`] (the enable inputs) and
+/// [`PhaseVoltageSampler`] (back-EMF measurement) traits.
+///
+/// The motor is driven by six-step commutation, or by
+/// field-oriented control (see [`ControlMode`]), in either
+/// direction (see [`ThreePhaseController::set_direction`]).
+pub struct ThreePhaseController<D, P, S> {
+    driver: D,
+    pwm: P,
+    sampler: S,
+
+    // Six-step commutation or FOC
+    mode: ControlMode,
+
+    // Whether the PWM outputs are on, so that the motor can be
+    // started
+    armed: bool,
+
+    // Duty cycle applied to the half bridges (sets motor power,
+    // or the q current in FOC mode)
+    applied_duty: f32,
+
+    // Duty cycle set by [`Self::set_duty`], applied once the motor
+    // is running without speed control. It is kept while the
+    // motor is stopped or starting (when the start-up sequence and
+    // the brake set the applied duty cycle).
+    commanded_duty: f32,
+
+    pub neutral_voltage: u16,
+
+    // The latest phase voltage samples (in six-step mode)
+    pub history: SampleHistory<PhaseSample, HISTORY_LEN>,
+
+    // The commutation step currently applied to the phases (and
+    // the direction of rotation)
+    step: MotorStep,
+
+    // When the phase voltages are converted in each PWM period
+    pub adc_trigger: AdcTrigger,
+
+    // Back-EMF zero-crossing detector for sensorless commutation
+    pub zero_crossing: ZeroCrossingDetector,
+
+    // Start-up sequence (align, open-loop ramp, closed loop)
+    pub startup: Startup,
+
+    // Closed-loop speed control (sets the duty cycle in
+    // closed-loop mode when a target speed is set)
+    pub speed: SpeedController,
+
+    // Field-oriented control (in FOC mode)
+    pub foc: FocController,
+
+    // Braking while stopped, and direction reversal
+    pub brake: Brake,
+
+    // Measuring the motor parameters (in six-step mode)
+    pub identify: Identification,
+
+    // Latches faults (the half bridges are kept in high-Z
+    // while there is a fault)
+    pub faults: FaultMonitor,
+
+    // The fixed commutation step period when running in
+    // open-loop mode (None in closed-loop mode, when the
+    // commutation is timed from the back-EMF zero crossings)
+    pub open_loop_step_us: Option<u32>,
+
+    // The last motor current (A) and supply voltage (V)
+    // measurements, and the charge drawn from the supply (mAh)
+    current: Option<f32>,
+    bus_voltage: Option<f32>,
+    consumed_mah: f32,
+}
+
+impl<D, P, S> ThreePhaseController<D, P, S>
+where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    S: PhaseVoltageSampler,
+{
+    pub fn new(driver: D, pwm: P, sampler: S) -> Self {
+        Self {
+            driver,
+            pwm,
+            sampler,
+            mode: ControlMode::default(),
+            armed: false,
+            applied_duty: 0.0,
+            commanded_duty: DEFAULT_DUTY,
+            neutral_voltage: 0,
+            history: SampleHistory::new(),
+            step: MotorStep::new(),
+            adc_trigger: AdcTrigger::default(),
+            zero_crossing: ZeroCrossingDetector::new(ZeroCrossingConfig::default()),
+            startup: Startup::new(StartupConfig::default()),
+            speed: SpeedController::new(SpeedConfig::default()),
+            foc: FocController::new(FocConfig::default()),
+            brake: Brake::new(BrakeConfig::default()),
+            identify: Identification::new(IdentifyConfig::default()),
+            faults: FaultMonitor::new(FaultConfig::default()),
+            open_loop_step_us: None,
+            current: None,
+            bus_voltage: None,
+            consumed_mah: 0.0,
+        }
+    }
+
+    pub fn driver(&self) -> &D {
+        &self.driver
+    }
+
+    pub fn pwm(&self) -> &P {
+        &self.pwm
+    }
+
+    pub fn sampler(&self) -> &S {
+        &self.sampler
+    }
+
+    pub fn sampler_mut(&mut self) -> &mut S {
+        &mut self.sampler
+    }
+
+    pub fn mode(&self) -> ControlMode {
+        self.mode
+    }
+
+    /// Switch between six-step commutation and FOC (which needs
+    /// a sampler measuring the phase currents)
+    ///
+    /// Changing the mode stops the motor (with the half bridges
+    /// high-Z), so start it again afterwards.
+    pub fn set_mode(&mut self, mode: ControlMode) -> Result<(), ModeError> {
+        if mode == ControlMode::Foc && self.sampler.phase_currents().is_none() {
+            return Err(ModeError::NoPhaseCurrents);
+        }
+        self.switch_mode(mode);
+        Ok(())
+    }
+
+    fn switch_mode(&mut self, mode: ControlMode) {
+        if mode != self.mode {
+            self.stop();
+            self.foc.stop();
+            self.zero_crossing.reset();
+            self.high_z();
+            self.mode = mode;
+        }
+    }
+
+    /// Set the PWM frequency (see [`ThreePhasePwm::set_frequency`])
+    pub fn set_pwm_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        self.pwm.set_frequency(frequency_hz)
+    }
+
+    /// Set the duty cycle (between 0.0 and 1.0). Once the motor is
+    /// running, it is applied to the line phase at the next
+    /// commutation (or sets the q current at the next current
+    /// samples in FOC mode).
+    pub fn set_duty(&mut self, duty: f32) -> Result<(), PwmError> {
+        self.commanded_duty = check_duty(duty)?;
+        Ok(())
+    }
+
+    /// The duty cycle set by [`Self::set_duty`]
+    pub fn duty(&self) -> f32 {
+        self.commanded_duty
+    }
+
+    /// The duty cycle applied to the half bridges (0.0 when the
+    /// motor is stopped)
+    pub fn applied_duty(&self) -> f32 {
+        self.applied_duty
+    }
+
+    /// Turn the PWM outputs on, so that the motor can be started
+    pub fn arm(&mut self) {
+        self.pwm.enable(true);
+        self.armed = true;
+    }
+
+    /// Stop the motor, and turn the PWM outputs off. The motor
+    /// cannot be started again until the controller is armed.
+    pub fn disarm(&mut self) {
+        self.stop();
+        self.foc.stop();
+        self.high_z();
+        self.pwm.enable(false);
+        self.armed = false;
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Start the motor (through the start-up sequence), unless it
+    /// is already running
+    ///
+    /// The controller must be armed, with no fault latched.
+    pub fn start(&mut self) -> Result<(), StartError> {
+        if !self.armed {
+            return Err(StartError::Disarmed);
+        }
+        if let Some(fault) = self.fault() {
+            return Err(StartError::Fault(fault));
+        }
+        if !self.is_started() {
+            self.startup.start();
+        }
+        Ok(())
+    }
+
+    /// Stop driving the motor (it is braked as set in the
+    /// [`BrakeConfig`] from the next commutation timer expiry)
+    pub fn stop(&mut self) {
+        self.startup.stop();
+        self.identify.abort();
+        self.cancel_reversal();
+    }
+
+    /// If the half bridge is enabled (i.e. not high-Z), set
+    /// it to pull-up (high-side is on and low-side is off),
+    /// or pull-down (high-side is off and low-side is on).
+    /// Note that calling this function and passing pull-up
+    /// as false pulls down instead. If the high-Z state is
+    /// enabled, then this function has no immediate effect
+    /// (but the state will persist if high-Z is removed).
+    fn pull_phase_up(&mut self, which: usize, pull_up: bool) {
+        self.driver.pull_phase_up(which, pull_up);
+    }
+
+    /// Set one of the phases as a power input
+    fn set_line_phase(&mut self, which: usize) {
+        // Now, en is tied to the high/low pin and pwm
+        // is tied to the high-Z (i.e. turn both MOSFETS off)
+        // To set a phase as the input, we want to alternate
+        // it between the high-side on and high-Z states (so
+        // it alternates driving and floating).
+        self.pwm.set_duty(which, self.applied_duty); // module high-Z
+        self.pull_phase_up(which, true);
+
+        // Keep the phase voltage conversions at the same point in
+        // the on-time or off-time as the duty cycle changes
+        let position = self.adc_trigger.position(self.applied_duty);
+        self.pwm.set_adc_trigger(position);
+    }
+
+    /// Set one of the phases as a neutral (return) path
+    fn set_neutral_phase(&mut self, which: usize) {
+        // Now, en is tied to the high/low pin and pwm
+        // is tied to the high-Z (i.e. turn both MOSFETS off)
+        // To set a phase as the neutral, we want it always
+        // enabled (not high-Z) and always pulled low
+        self.pwm.set_duty(which, 1.0); // never high-Z
+        self.pull_phase_up(which, false);
+    }
+
+    /// Set one of the phases as floating
+    fn set_floating_phase(&mut self, which: usize) {
+        // Now, en is tied to the high/low pin and pwm
+        // is tied to the high-Z (i.e. turn both MOSFETS off)
+        // To set a phase as floating, make it always high-Z
+        self.pwm.set_duty(which, 0.0); // always high-Z
+
+        // Pull down for definiteness (no effect, but ties to
+        // ground if subsequently high-Z is removed)
+        self.pull_phase_up(which, false);
+    }
+
+    pub fn set_step(&mut self, step: &MotorStep) {
+        // Float the phase first, so that the half bridge being
+        // released is never driven at the same time as the new one
+        let states = step.phase_states();
+        for state in [PhaseState::Floating, PhaseState::Neutral, PhaseState::Line] {
+            let which = states.iter().position(|s| *s == state).unwrap();
+            match state {
+                PhaseState::Line => self.set_line_phase(which),
+                PhaseState::Neutral => self.set_neutral_phase(which),
+                PhaseState::Floating => self.set_floating_phase(which),
+            }
+        }
+    }
+
+    /// The commutation step currently applied to the phases
+    pub fn step(&self) -> MotorStep {
+        self.step
+    }
+
+    /// The direction the motor turns in (or will turn in, once it
+    /// has been stopped to reverse it)
+    pub fn direction(&self) -> Direction {
+        self.brake.reversal().unwrap_or(self.step.direction())
+    }
+
+    /// Turn the motor in `direction`
+    ///
+    /// A motor at rest starts in the new direction the next time
+    /// it is started. A motor which is running (or has not been
+    /// stopped for long enough to be at rest) is stopped and
+    /// braked as set in the [`BrakeConfig`], and then started again
+    /// in the new direction if it was running.
+    pub fn set_direction(&mut self, direction: Direction) {
+        if direction == self.direction() {
+            return;
+        }
+        if self.brake.is_stopped() {
+            self.cancel_reversal();
+            self.step.set_direction(direction);
+        } else {
+            // The start-up sequence begins again once the motor is
+            // at rest (unless the motor is stopped in the meantime)
+            self.brake.reverse(direction);
+            if self.is_started() {
+                self.startup.start();
+            }
+        }
+    }
+
+    /// Process a new set of phase voltage samples
+    ///
+    /// Call whenever the sampler has a new measurement, with the
+    /// time since the last commutation. In closed-loop mode,
+    /// returns the time until the next commutation when the
+    /// back-EMF zero crossing is detected.
+    ///
+    /// The current and supply voltage are checked too, if the
+    /// sampler measures them. In FOC mode, the phase currents are
+    /// passed on to [`Self::on_current_samples`] (so call once per
+    /// PWM period).
+    pub fn on_samples(&mut self, since_commutation_us: u32) -> Option<u32> {
+        if self.fault().is_some() {
+            return None;
+        }
+        self.faults.on_samples();
+
+        if let Some(current) = self.sampler.current() {
+            self.check_current(current);
+        }
+        if let Some(voltage) = self.sampler.bus_voltage() {
+            self.check_bus_voltage(voltage);
+        }
+        if self.fault().is_some() {
+            return None;
+        }
+        if self.identify.drives_windings() {
+            // No back-EMF to detect while the windings are held
+            if let Some(current) = self.current {
+                let bus_voltage = self.nominal_bus_voltage();
+                self.identify
+                    .on_samples(since_commutation_us, current, bus_voltage);
+            }
+            return None;
+        }
+        if self.mode == ControlMode::Foc {
+            // The samples are taken once per PWM period
+            if let Some(currents) = self.sampler.phase_currents() {
+                let period_us = (1e6 / self.pwm.frequency_hz()) as u32;
+                self.on_current_samples(currents, period_us);
+            }
+            return None;
+        }
+
+        let phases = self.sampler.phase_voltages();
+        self.neutral_voltage = ZeroCrossingDetector::neutral_voltage(&phases);
+        self.history.push(PhaseSample {
+            phases,
+            since_commutation_us,
+        });
+
+        let zero_crossing = self
+            .zero_crossing
+            .update(&self.step, &phases, since_commutation_us);
+
+        // In closed-loop mode, commutate 30 electrical degrees
+        // after the zero crossing
+        match (zero_crossing, self.open_loop_step_us) {
+            (Some(zc), None) => Some(zc.commutation_delay_us),
+            _ => None,
+        }
+    }
+
+    /// Advance the commutation when the commutation timer expires
+    ///
+    /// The start-up sequence decides whether to hold the current
+    /// step or commutate, and how long until the timer should
+    /// expire next (returned in microseconds).
+    pub fn on_commutation_timer(&mut self) -> u32 {
+        if self.mode == ControlMode::Foc {
+            // The motor is driven from the current samples instead
+            let poll_us = self.startup.config().idle_poll_us;
+            if !self.foc.is_running() {
+                self.update_brake(poll_us);
+            }
+            return poll_us;
+        }
+
+        if self.identify.drives_windings() {
+            return self.on_identify_timer();
+        }
+
+        if self.brake.is_reversing() {
+            // Brake until the motor is at rest, then start again
+            // in the other direction
+            let poll_us = self.startup.config().idle_poll_us;
+            self.zero_crossing.reset();
+            self.apply_brake();
+            self.open_loop_step_us = Some(poll_us);
+            self.update_brake(poll_us);
+            return poll_us;
+        }
+
+        // Tell the zero-crossing detector how the step that
+        // has just ended was timed
+        match self.open_loop_step_us {
+            Some(step_us) => self.zero_crossing.commutated_after(step_us),
+            None => self.zero_crossing.commutated(),
+        }
+
+        // Losing the zero crossings in closed loop means the
+        // commutation no longer follows the rotor
+        let config = self.startup.config();
+        if self.startup.is_closed_loop()
+            && self.zero_crossing.missed_steps() >= config.max_missed_steps
+        {
+            self.set_fault(MotorFault::Desync);
+        }
+
+        let action = self.startup.update(&self.zero_crossing);
+        if self.startup.state() == StartupState::Failed {
+            self.set_fault(MotorFault::Stall);
+        }
+
+        if self.fault().is_some() {
+            // Keep the half bridges off until the fault is cleared
+            let poll_us = self.startup.config().idle_poll_us;
+            self.high_z();
+            self.brake.update(poll_us);
+            self.open_loop_step_us = Some(poll_us);
+            return poll_us;
+        }
+
+        match action {
+            StartupAction::Off { poll_us } => {
+                self.identify.abort();
+                self.zero_crossing.reset();
+                self.applied_duty = 0.0;
+                self.speed.reset(self.applied_duty);
+                self.apply_brake();
+                self.update_brake(poll_us);
+                self.open_loop_step_us = Some(poll_us);
+                poll_us
+            }
+            StartupAction::Align { duty, time_us } => {
+                self.zero_crossing.reset();
+                self.applied_duty = duty;
+                self.speed.reset(duty);
+                self.brake.running();
+                self.hold_step();
+                self.open_loop_step_us = Some(time_us);
+                time_us
+            }
+            StartupAction::OpenLoop { duty, step_us } => {
+                self.applied_duty = duty;
+                self.speed.reset(duty);
+                self.brake.running();
+                self.next_step();
+                self.open_loop_step_us = Some(step_us);
+                step_us
+            }
+            StartupAction::ClosedLoop => {
+                // Track the target speed (if there is one), once
+                // per commutation step
+                let step_period_us = self.zero_crossing.step_period_us();
+                let speed_duty =
+                    step_period_us.and_then(|period| self.speed.update(period, period));
+                self.update_duty(speed_duty);
+                if let Some(period) = step_period_us {
+                    self.on_identify_step(period);
+                }
+
+                self.brake.running();
+                self.next_step();
+                self.open_loop_step_us = None;
+
+                // The next commutation is scheduled when the zero
+                // crossing is detected. Until then, time out after
+                // one step period in case the zero crossing is missed.
+                self.zero_crossing
+                    .step_period_us()
+                    .unwrap_or(self.startup.config().ramp_end_period_us)
+            }
+        }
+    }
+
+    /// Run field-oriented control on a new set of phase current
+    /// measurements (A, positive into the motor), `period_us`
+    /// after the previous set
+    ///
+    /// Call once per PWM period in FOC mode (this does nothing in
+    /// six-step mode). The motor is started and stopped by
+    /// [`Startup::start`] and [`Startup::stop`] as in six-step
+    /// mode, and the duty cycle sets the q current (as a fraction
+    /// of [`FocConfig::max_current`]), unless speed control is on.
+    ///
+    /// In reverse, phases 1 and 2 are swapped, which turns the
+    /// field the other way.
+    pub fn on_current_samples(&mut self, currents: [f32; 3], period_us: u32) {
+        if self.mode != ControlMode::Foc || self.fault().is_some() {
+            return;
+        }
+        if self.startup.state() == StartupState::Stopped || self.brake.is_reversing() {
+            if self.foc.is_running() {
+                self.foc.stop();
+                self.apply_brake();
+            }
+            return;
+        }
+        if !self.foc.is_running() {
+            self.foc.start();
+        }
+        self.brake.running();
+
+        let phases = match self.step.direction() {
+            Direction::Forward => [0, 1, 2],
+            Direction::Reverse => [0, 2, 1],
+        };
+        let currents = phases.map(|phase| currents[phase]);
+
+        let speed_duty = self
+            .step_period_us()
+            .and_then(|period| self.speed.update(period, period_us));
+        self.update_duty(speed_duty);
+
+        let config = self.foc.config();
+        let bus_voltage = self.bus_voltage.unwrap_or(config.nominal_bus_voltage);
+        let target_current = self.applied_duty * config.max_current;
+        let was_closed_loop = self.foc.state() == FocState::ClosedLoop;
+        let dt = period_us as f32 * 1e-6;
+        match self.foc.update(currents, bus_voltage, target_current, dt) {
+            Some(duties) => {
+                // Every phase is driving, between the high side
+                // and low side
+                for (which, duty) in phases.into_iter().zip(duties) {
+                    self.pwm.set_duty(which, duty);
+                    self.pull_phase_up(which, true);
+                }
+            }
+            None if was_closed_loop => self.set_fault(MotorFault::Desync),
+            None => self.set_fault(MotorFault::Stall),
+        }
+    }
+
+    /// Measure the motor parameters (see [`crate::identify`])
+    ///
+    /// The motor must be at rest. The controller switches to
+    /// six-step mode, holds one commutation step to measure the
+    /// resistance and inductance, and then starts the motor to
+    /// measure the back-EMF. The motor is stopped afterwards, and
+    /// the parameters are applied if they were measured (see
+    /// [`ThreePhaseController::apply_parameters`]).
+    pub fn start_identification(&mut self) {
+        self.switch_mode(ControlMode::SixStep);
+        self.stop();
+        self.zero_crossing.reset();
+        self.identify.start();
+    }
+
+    /// Set up the start-up sequence, FOC and speed control for a
+    /// motor with `parameters`
+    pub fn apply_parameters(&mut self, parameters: &MotorParameters) {
+        let bus_voltage = self.nominal_bus_voltage();
+        let startup =
+            parameters.startup_config(self.identify.config(), bus_voltage, self.startup.config());
+        self.startup.set_config(startup);
+        self.foc
+            .set_config(parameters.foc_config(self.foc.config()));
+        self.speed
+            .set_config(parameters.speed_config(self.speed.config()));
+    }
+
+    /// The latched fault, if any
+    pub fn fault(&self) -> Option<MotorFault> {
+        self.faults.fault()
+    }
+
+    /// Latch a fault, and shut down the motor (all three half
+    /// bridges high-Z) until the fault is cleared
+    pub fn set_fault(&mut self, fault: MotorFault) {
+        if self.faults.latch(fault) {
+            self.stop();
+            self.foc.stop();
+            self.high_z();
+        }
+    }
+
+    /// Clear the latched fault, and disarm the controller. The
+    /// motor stays stopped until the controller is armed and the
+    /// motor started again.
+    pub fn clear_fault(&mut self) {
+        self.faults.clear();
+        self.disarm();
+    }
+
+    /// Count an ADC overrun (latches a fault if there are too many)
+    pub fn on_adc_overrun(&mut self) {
+        if let Some(fault) = self.faults.on_adc_overrun() {
+            self.set_fault(fault);
+        }
+    }
+
+    /// Check a motor current measurement (A)
+    pub fn check_current(&mut self, current: f32) {
+        self.current = Some(current);
+        if let Some(fault) = self.faults.check_current(current) {
+            self.set_fault(fault);
+        }
+    }
+
+    /// Check a supply voltage measurement (V)
+    pub fn check_bus_voltage(&mut self, voltage: f32) {
+        self.bus_voltage = Some(voltage);
+        if let Some(fault) = self.faults.check_bus_voltage(voltage) {
+            self.set_fault(fault);
+        }
+    }
+
+    /// The last motor current measurement (A), if measured
+    pub fn current(&self) -> Option<f32> {
+        self.current
+    }
+
+    /// The last supply voltage measurement (V), if measured
+    pub fn bus_voltage(&self) -> Option<f32> {
+        self.bus_voltage
+    }
+
+    /// Add the charge drawn at the last current measurement over
+    /// `dt_us` to the consumption
+    pub fn add_consumption(&mut self, dt_us: u32) {
+        if let Some(current) = self.current {
+            self.consumed_mah += current * dt_us as f32 / 3.6e6;
+        }
+    }
+
+    /// The charge drawn from the supply since start-up (mAh)
+    pub fn consumed_mah(&self) -> f32 {
+        self.consumed_mah
+    }
+
+    /// The measured commutation step period, when running in
+    /// closed loop (None otherwise)
+    ///
+    /// In FOC mode, this is the step period six-step commutation
+    /// would have at the measured speed.
+    pub fn step_period_us(&self) -> Option<u32> {
+        if self.mode == ControlMode::Foc {
+            self.foc.step_period_us()
+        } else if self.startup.is_closed_loop() {
+            self.zero_crossing.step_period_us()
+        } else {
+            None
+        }
+    }
+
+    /// The last supply voltage measurement, or the nominal supply
+    /// voltage if it is not measured (V)
+    fn nominal_bus_voltage(&self) -> f32 {
+        self.bus_voltage
+            .unwrap_or(self.foc.config().nominal_bus_voltage)
+    }
+
+    /// Hold or release the windings as the identification asks,
+    /// returning the time until the timer should expire next
+    fn on_identify_timer(&mut self) -> u32 {
+        let poll_us = self.startup.config().idle_poll_us;
+        self.zero_crossing.reset();
+        self.brake.running();
+        let time_us = match self.identify.on_timer() {
+            IdentifyAction::Hold { duty, time_us } => {
+                self.applied_duty = duty;
+                self.hold_step();
+                time_us
+            }
+            IdentifyAction::Float { time_us } => {
+                self.high_z();
+                time_us
+            }
+            IdentifyAction::Run => {
+                self.high_z();
+                self.startup.start();
+                poll_us
+            }
+            IdentifyAction::Done => {
+                self.high_z();
+                poll_us
+            }
+        };
+        self.open_loop_step_us = Some(time_us);
+        time_us
+    }
+
+    /// Measure the back-EMF at a commutation in closed loop while
+    /// identifying the motor, and stop the motor once finished
+    fn on_identify_step(&mut self, step_period_us: u32) {
+        if !self.identify.is_active() {
+            return;
+        }
+        let Some(current) = self.current else {
+            return;
+        };
+        let bus_voltage = self.nominal_bus_voltage();
+        self.identify
+            .on_step(step_period_us, self.applied_duty, current, bus_voltage);
+        if !self.identify.is_active() {
+            self.startup.stop();
+            if let Some(Ok(parameters)) = self.identify.result() {
+                self.apply_parameters(&parameters);
+            }
+        }
+    }
+
+    /// Apply the duty cycle from the speed controller, or the
+    /// commanded duty cycle if speed control is off
+    fn update_duty(&mut self, speed_duty: Option<f32>) {
+        match speed_duty {
+            Some(duty) => self.applied_duty = duty,
+            None => {
+                // With speed control on, hold the duty cycle until
+                // there is a speed measurement. The identification
+                // holds the duty cycle at the end of the ramp.
+                if self.speed.target_rpm().is_none() && !self.identify.is_active() {
+                    self.applied_duty = self.commanded_duty;
+                }
+                self.speed.reset(self.applied_duty);
+            }
+        }
+    }
+
+    /// Set all three half bridges to high-Z (both MOSFETs off)
+    fn high_z(&mut self) {
+        self.applied_duty = 0.0;
+        for which in 0..3 {
+            self.set_floating_phase(which);
+        }
+    }
+
+    /// Brake the stopped motor as set in the [`BrakeConfig`]
+    ///
+    /// All three phases are pulled down for the brake duty cycle
+    /// (shorting the windings through the low-side MOSFETs), and
+    /// high-Z for the rest of the period. With complementary
+    /// outputs, a phase which is pulled down keeps its low side on
+    /// for the whole period, so regenerative braking brakes as hard
+    /// as active braking.
+    fn apply_brake(&mut self) {
+        let duty = self.brake.duty();
+        self.applied_duty = 0.0;
+        for which in 0..3 {
+            self.pwm.set_duty(which, duty);
+            self.pull_phase_up(which, false);
+        }
+    }
+
+    /// Count `elapsed_us` of the motor being stopped, and start it
+    /// again once it is at rest, if it is being reversed
+    fn update_brake(&mut self, elapsed_us: u32) {
+        if let Some(direction) = self.brake.update(elapsed_us) {
+            self.step.set_direction(direction);
+            if self.is_started() {
+                self.startup.start();
+            }
+        }
+    }
+
+    /// Whether the motor has been started (and not stopped since)
+    fn is_started(&self) -> bool {
+        !matches!(
+            self.startup.state(),
+            StartupState::Stopped | StartupState::Failed
+        )
+    }
+
+    /// Give up a pending reversal (when the motor is shut down), but
+    /// start in the new direction next time
+    fn cancel_reversal(&mut self) {
+        if let Some(direction) = self.brake.cancel() {
+            self.step.set_direction(direction);
+        }
+    }
+
+    /// Re-apply the current step (e.g. after changing the duty cycle)
+    fn hold_step(&mut self) {
+        let step = self.step;
+        self.set_step(&step);
+    }
+
+    /// Move the motor on to the next commutation step (in the
+    /// direction of rotation)
+    fn next_step(&mut self) {
+        self.step.advance();
+        self.hold_step();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brake::BrakeMode;
+    use crate::mock::{phase_states, MockDriver, MockPwm, MockSampler};
+
+    type MockController = ThreePhaseController<MockDriver, MockPwm, MockSampler>;
+
+    fn controller() -> MockController {
+        ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        )
+    }
+
+    #[test]
+    fn set_step_drives_half_bridges() {
+        let mut c = controller();
+        c.applied_duty = 0.4;
+        let mut step = MotorStep::new();
+        for _ in 0..6 {
+            c.set_step(&step);
+            assert_eq!(
+                phase_states(c.driver(), c.pwm()),
+                (step.phase_states(), 0.4)
+            );
+            step.next();
+        }
+
+        // Step 0: phase 0 line, phase 1 neutral, phase 2 floating
+        c.set_step(&MotorStep::new());
+        assert_eq!(c.pwm().duty, [0.4, 1.0, 0.0]);
+
+        // The phase voltages are converted in the middle of the
+        // on-time
+        assert_eq!(c.pwm().adc_trigger, 0.2);
+        assert_eq!(c.driver().pulled_up, [true, false, false]);
+    }
+
+    #[test]
+    fn follows_startup_sequence() {
+        let mut c = controller();
+        c.startup.start();
+
+        // Align: hold step 0
+        assert_eq!(c.on_commutation_timer(), c.startup.config().align_time_us);
+        assert_eq!(c.step(), MotorStep::new());
+        assert_eq!(c.applied_duty(), c.startup.config().align_duty);
+
+        // Ramp: commutate at the open-loop period
+        assert_eq!(
+            c.on_commutation_timer(),
+            c.startup.config().ramp_start_period_us
+        );
+        assert_eq!(c.step().step(), 1);
+        assert!(c.open_loop_step_us.is_some());
+
+        c.startup.stop();
+        // Off: no phase is driven high
+        c.on_commutation_timer();
+        let (states, _) = phase_states(c.driver(), c.pwm());
+        assert!(!states.contains(&PhaseState::Line));
+        assert_eq!(c.applied_duty(), 0.0);
+    }
+
+    #[test]
+    fn keeps_duty_set_while_stopped() {
+        let mut c = controller();
+        assert_eq!(c.set_duty(1.5), Err(PwmError::InvalidDuty(1.5)));
+        c.set_duty(0.3).unwrap();
+        c.on_commutation_timer();
+        assert_eq!(c.duty(), 0.3);
+        assert_eq!(c.applied_duty(), 0.0);
+
+        // The start-up sequence sets its own duty cycle
+        c.arm();
+        c.start().unwrap();
+        c.on_commutation_timer();
+        assert_eq!(c.duty(), 0.3);
+        assert_eq!(c.applied_duty(), c.startup.config().align_duty);
+
+        // The commanded one is applied once the motor is controlled
+        // by the duty cycle (here by FOC)
+        c.sampler_mut().phase_currents = Some([0.0; 3]);
+        c.set_mode(ControlMode::Foc).unwrap();
+        assert_eq!(c.duty(), 0.3);
+        c.start().unwrap();
+        c.on_current_samples([0.0; 3], 20);
+        assert_eq!(c.applied_duty(), 0.3);
+
+        c.set_fault(MotorFault::OverCurrent);
+        assert_eq!(c.duty(), 0.3);
+        assert_eq!(c.applied_duty(), 0.0);
+    }
+
+    #[test]
+    fn no_reschedule_in_open_loop() {
+        let mut c = controller();
+        c.open_loop_step_us = Some(1000);
+        c.sampler_mut().samples = [1000, 3000, 1500];
+        assert_eq!(c.on_samples(100), None);
+        c.sampler_mut().samples = [1000, 3000, 2500];
+        assert_eq!(c.on_samples(200), None);
+        assert_eq!(c.on_samples(210), None);
+        assert_eq!(c.neutral_voltage, 2166);
+    }
+
+    #[test]
+    fn reschedules_in_closed_loop() {
+        let mut c = controller();
+        c.open_loop_step_us = None;
+        c.sampler_mut().samples = [1000, 3000, 1500];
+        assert_eq!(c.on_samples(100), None);
+        c.sampler_mut().samples = [1000, 3000, 2500];
+        assert_eq!(c.on_samples(200), None);
+        assert!(c.on_samples(210).is_some());
+    }
+
+    #[test]
+    fn keeps_sample_history() {
+        let mut c = controller();
+        c.sampler_mut().samples = [1000, 3000, 1500];
+        c.on_samples(100);
+        c.sampler_mut().samples = [1000, 3000, 2500];
+        c.on_samples(150);
+        assert_eq!(c.history.len(), 2);
+        assert_eq!(
+            c.history.latest(),
+            Some(PhaseSample {
+                phases: [1000, 3000, 2500],
+                since_commutation_us: 150,
+            })
+        );
+
+        // The floating phase crosses the neutral voltage about half
+        // way between the samples
+        let crossing = ZeroCrossingDetector::interpolated_crossing_us(&c.step(), &c.history);
+        assert!(matches!(crossing, Some(124..=126)), "{crossing:?}");
+    }
+
+    #[test]
+    fn changes_direction_at_rest() {
+        let mut c = controller();
+        c.set_direction(Direction::Reverse);
+        assert_eq!(c.step().direction(), Direction::Reverse);
+        assert!(!c.brake.is_reversing());
+
+        // Align, then the ramp goes back through the steps
+        c.startup.start();
+        c.on_commutation_timer();
+        c.on_commutation_timer();
+        assert_eq!(c.step().step(), 5);
+    }
+
+    #[test]
+    fn brakes_before_reversing() {
+        let mut c = controller();
+        c.brake.set_config(BrakeConfig {
+            mode: BrakeMode::Active,
+            ..Default::default()
+        });
+        c.startup.start();
+        for _ in 0..4 {
+            c.on_commutation_timer();
+        }
+        assert_eq!(c.step().step(), 3);
+
+        c.set_direction(Direction::Reverse);
+        assert_eq!(c.direction(), Direction::Reverse);
+        assert_eq!(c.step().direction(), Direction::Forward);
+        assert_eq!(c.startup.state(), StartupState::Starting);
+
+        // All the windings are shorted through the low sides
+        let poll_us = c.startup.config().idle_poll_us;
+        assert_eq!(c.on_commutation_timer(), poll_us);
+        assert_eq!(c.pwm().duty, [1.0; 3]);
+        assert_eq!(c.driver().pulled_up, [false; 3]);
+
+        // Once the motor is at rest, it starts again in reverse
+        let polls = c.brake.config().stop_time_us / poll_us;
+        for _ in 1..polls {
+            c.on_commutation_timer();
+        }
+        assert_eq!(c.step().direction(), Direction::Reverse);
+        assert_eq!(c.startup.state(), StartupState::Starting);
+        assert_eq!(c.on_commutation_timer(), c.startup.config().align_time_us);
+        c.on_commutation_timer();
+        assert_eq!(c.step().step(), 2);
+    }
+
+    #[test]
+    fn fault_sets_high_z_until_cleared() {
+        let mut c = controller();
+        c.startup.start();
+        c.on_commutation_timer();
+        c.on_commutation_timer();
+
+        c.set_fault(MotorFault::DmaTransferError);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+        assert_eq!(c.on_commutation_timer(), c.startup.config().idle_poll_us);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+
+        // The motor cannot be started until the fault is cleared
+        c.startup.start();
+        c.on_commutation_timer();
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+        assert_eq!(c.fault(), Some(MotorFault::DmaTransferError));
+
+        c.clear_fault();
+        c.startup.start();
+        c.on_commutation_timer();
+        assert_ne!(c.pwm().duty, [0.0; 3]);
+    }
+
+    #[test]
+    fn starts_only_when_armed() {
+        let mut c = controller();
+        assert_eq!(c.start(), Err(StartError::Disarmed));
+        assert_eq!(c.startup.state(), StartupState::Stopped);
+
+        c.arm();
+        assert!(c.pwm().enabled);
+        assert_eq!(c.start(), Ok(()));
+        c.on_commutation_timer();
+        assert_eq!(c.startup.state(), StartupState::Align);
+
+        // Starting again does not restart the sequence
+        assert_eq!(c.start(), Ok(()));
+        assert_eq!(c.startup.state(), StartupState::Align);
+
+        c.set_fault(MotorFault::OverCurrent);
+        assert_eq!(c.start(), Err(StartError::Fault(MotorFault::OverCurrent)));
+        c.clear_fault();
+        c.arm();
+
+        c.disarm();
+        assert!(!c.pwm().enabled);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+        assert_eq!(c.startup.state(), StartupState::Stopped);
+        assert_eq!(c.start(), Err(StartError::Disarmed));
+    }
+
+    #[test]
+    fn stays_stopped_after_clearing_a_fault() {
+        let mut c = controller();
+        c.arm();
+        c.start().unwrap();
+        c.on_commutation_timer();
+        c.set_fault(MotorFault::OverCurrent);
+
+        c.clear_fault();
+        assert_eq!(c.fault(), None);
+        assert!(!c.is_armed());
+        assert!(!c.pwm().enabled);
+        c.on_commutation_timer();
+        assert_eq!(c.startup.state(), StartupState::Stopped);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+
+        // Only an explicit arm and start runs the motor again
+        assert_eq!(c.start(), Err(StartError::Disarmed));
+        c.arm();
+        assert_eq!(c.start(), Ok(()));
+        c.on_commutation_timer();
+        assert_eq!(c.startup.state(), StartupState::Align);
+    }
+
+    #[test]
+    fn switches_to_foc_mode() {
+        let mut c = controller();
+        c.startup.start();
+        c.on_commutation_timer();
+
+        // FOC needs the phase currents
+        assert_eq!(
+            c.set_mode(ControlMode::Foc),
+            Err(ModeError::NoPhaseCurrents)
+        );
+        assert_eq!(c.mode(), ControlMode::SixStep);
+        assert_ne!(c.startup.state(), StartupState::Stopped);
+
+        c.sampler_mut().phase_currents = Some([0.0; 3]);
+        c.set_mode(ControlMode::Foc).unwrap();
+        assert_eq!(c.mode(), ControlMode::Foc);
+        assert_eq!(c.startup.state(), StartupState::Stopped);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+
+        // All three half bridges are driven from the current samples
+        c.startup.start();
+        c.set_duty(0.2).unwrap();
+        c.on_current_samples([0.0; 3], 20);
+        assert_eq!(c.foc.state(), FocState::OpenLoop);
+        assert_eq!(c.driver().pulled_up, [true; 3]);
+        assert!(c.pwm().duty.iter().all(|&d| d > 0.0 && d < 1.0));
+
+        // The commutation timer is not used
+        let duty = c.pwm().duty;
+        assert_eq!(c.on_commutation_timer(), c.startup.config().idle_poll_us);
+        assert_eq!(c.pwm().duty, duty);
+
+        c.startup.stop();
+        c.on_current_samples([0.0; 3], 20);
+        assert!(!c.foc.is_running());
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+
+        // Current samples are ignored in six-step mode
+        c.set_mode(ControlMode::SixStep).unwrap();
+        c.startup.start();
+        c.on_current_samples([0.0; 3], 20);
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+    }
+
+    #[test]
+    fn passes_phase_currents_to_foc() {
+        let mut c = controller();
+        c.set_pwm_frequency(20_000).unwrap();
+        c.sampler_mut().phase_currents = Some([0.0; 3]);
+        c.set_mode(ControlMode::Foc).unwrap();
+        c.startup.start();
+        c.set_duty(0.2).unwrap();
+
+        // The phase voltages are not used for commutation, but the
+        // currents drive the FOC
+        assert_eq!(c.on_samples(100), None);
+        assert_eq!(c.foc.state(), FocState::OpenLoop);
+        assert_eq!(c.driver().pulled_up, [true; 3]);
+        assert!(c.pwm().duty.iter().all(|&d| d > 0.0 && d < 1.0));
+    }
+
+    #[test]
+    fn records_measurements() {
+        let mut c = controller();
+        assert_eq!(c.current(), None);
+        c.add_consumption(1_000_000);
+        assert_eq!(c.consumed_mah(), 0.0);
+
+        c.check_current(3.6);
+        c.check_bus_voltage(12.0);
+        assert_eq!(c.current(), Some(3.6));
+        assert_eq!(c.bus_voltage(), Some(12.0));
+
+        // 3.6 A for 10 s is 10 mAh
+        for _ in 0..10 {
+            c.add_consumption(1_000_000);
+        }
+        assert!((c.consumed_mah() - 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn checks_sampled_measurements() {
+        let mut c = controller();
+        c.startup.start();
+        c.on_commutation_timer();
+
+        c.sampler_mut().current = Some(1.5);
+        c.sampler_mut().bus_voltage = Some(12.1);
+        c.on_samples(100);
+        assert_eq!(c.current(), Some(1.5));
+        assert_eq!(c.bus_voltage(), Some(12.1));
+        assert_eq!(c.fault(), None);
+
+        // Over the limit: the motor is shut down
+        c.sampler_mut().current = Some(c.faults.config().max_current + 0.1);
+        assert_eq!(c.on_samples(200), None);
+        assert_eq!(c.fault(), Some(MotorFault::OverCurrent));
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+    }
+
+    #[test]
+    fn stall_fault_when_start_up_fails() {
+        let mut c = controller();
+        c.startup.start();
+
+        // No back-EMF: every start-up attempt fails
+        for _ in 0..100_000 {
+            c.on_commutation_timer();
+            if c.fault().is_some() {
+                break;
+            }
+        }
+        assert_eq!(c.fault(), Some(MotorFault::Stall));
+        assert_eq!(c.pwm().duty, [0.0; 3]);
+    }
+}
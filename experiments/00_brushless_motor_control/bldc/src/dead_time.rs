@@ -0,0 +1,104 @@
+//! Dead-time for complementary PWM outputs
+//!
+//! When a half bridge is driven by complementary signals (the
+//! high-side and low-side inputs of a driver such as the FD6288T
+//! or IR2109), both MOSFETs must be off for a short time at every
+//! switching edge, so that they are never on at the same time
+//! (shoot-through). The advanced timers (TIM1 and TIM8) insert
+//! this dead-time in hardware. It is set by the DTG[7:0] field of
+//! the BDTR register, which is encoded in four ranges with
+//! increasing step sizes (RM0385 rev 8, p. 700):
+//!
+//! | DTG[7:5] | Dead-time (timer ticks) | Range       |
+//! |----------|-------------------------|-------------|
+//! | 0xx      | DTG[7:0]                | 0 to 127    |
+//! | 10x      | (64 + DTG[5:0]) * 2     | 128 to 254  |
+//! | 110      | (32 + DTG[4:0]) * 8     | 256 to 504  |
+//! | 111      | (32 + DTG[4:0]) * 16    | 512 to 1008 |
+//!
+
+/// The longest dead-time which can be set (in timer ticks)
+pub const MAX_DEAD_TIME_TICKS: u32 = 1008;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadTimeError {
+    /// The dead-time is longer than the timer can insert (at
+    /// this timer clock frequency)
+    TooLong { max_ns: u32 },
+}
+
+/// Work out the DTG[7:0] bits for a dead-time of at least
+/// `dead_time_ns`, for a timer clocked at `timer_clock_hz`
+///
+/// The dead-time is rounded up to the next value which can be
+/// encoded, so that it is never shorter than requested.
+pub fn dead_time_bits(dead_time_ns: u32, timer_clock_hz: u32) -> Result<u8, DeadTimeError> {
+    let ticks = (dead_time_ns as u64 * timer_clock_hz as u64).div_ceil(1_000_000_000) as u32;
+    let bits = match ticks {
+        0..=127 => ticks,
+        128..=254 => 0b1000_0000 | (ticks.div_ceil(2) - 64),
+        255..=504 => 0b1100_0000 | (ticks.div_ceil(8) - 32),
+        505..=MAX_DEAD_TIME_TICKS => 0b1110_0000 | (ticks.div_ceil(16) - 32),
+        _ => {
+            return Err(DeadTimeError::TooLong {
+                max_ns: ticks_to_ns(MAX_DEAD_TIME_TICKS, timer_clock_hz),
+            })
+        }
+    };
+    Ok(bits as u8)
+}
+
+/// The dead-time (in nanoseconds) set by the DTG[7:0] bits
+/// `bits`, for a timer clocked at `timer_clock_hz`
+pub fn dead_time_ns(bits: u8, timer_clock_hz: u32) -> u32 {
+    let bits = bits as u32;
+    let ticks = match bits >> 5 {
+        0b000..=0b011 => bits,
+        0b100 | 0b101 => (64 + (bits & 0b11_1111)) * 2,
+        0b110 => (32 + (bits & 0b1_1111)) * 8,
+        _ => (32 + (bits & 0b1_1111)) * 16,
+    };
+    ticks_to_ns(ticks, timer_clock_hz)
+}
+
+fn ticks_to_ns(ticks: u32, timer_clock_hz: u32) -> u32 {
+    (ticks as u64 * 1_000_000_000 / timer_clock_hz as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_each_range() {
+        // 100 MHz timer clock (10 ns per tick)
+        let clock = 100_000_000;
+        assert_eq!(dead_time_bits(0, clock), Ok(0));
+        assert_eq!(dead_time_bits(1270, clock), Ok(127));
+        assert_eq!(dead_time_bits(1280, clock), Ok(0b1000_0000));
+        assert_eq!(dead_time_bits(2540, clock), Ok(0b1011_1111));
+        assert_eq!(dead_time_bits(2560, clock), Ok(0b1100_0000));
+        assert_eq!(dead_time_bits(5040, clock), Ok(0b1101_1111));
+        assert_eq!(dead_time_bits(5120, clock), Ok(0b1110_0000));
+        assert_eq!(dead_time_bits(10_080, clock), Ok(0b1111_1111));
+    }
+
+    #[test]
+    fn never_shorter_than_requested() {
+        let clock = 216_000_000;
+        for ns in (0..4000).step_by(7) {
+            let bits = dead_time_bits(ns, clock).unwrap();
+            let actual = dead_time_ns(bits, clock);
+            // The decoded time is rounded down to the nanosecond
+            assert!(actual + 1 >= ns, "{ns} ns gave {actual} ns");
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        assert_eq!(
+            dead_time_bits(10_090, 100_000_000),
+            Err(DeadTimeError::TooLong { max_ns: 10_080 })
+        );
+    }
+}
@@ -0,0 +1,523 @@
+//! DShot throttle input
+//!
+//! Flight controllers send the throttle to an ESC as DShot frames
+//! (DShot150, 300 and 600 differ only in the bit rate). A frame is
+//! 16 bits, most significant first:
+//!
+//! | Bits  | Field                                            |
+//! |-------|--------------------------------------------------|
+//! | 15..5 | Value (0 stop, 1-47 commands, 48-2047 throttle)  |
+//! | 4     | Telemetry request                                |
+//! | 3..0  | CRC (XOR of the three nibbles above)             |
+//!
+//! Every bit starts with a rising edge. A 1 is high for 75% of
+//! the bit period, and a 0 for 37.5%. Frames are separated by an
+//! idle (low) gap.
+//!
+//! The firmware captures the time of every edge with a timer, so
+//! a frame is 32 edge times (a rising and a falling edge for each
+//! bit). [`decode_edges`] turns these into a [`DshotFrame`] without
+//! knowing the bit rate, because each bit is decoded from the ratio
+//! of its high time to its period. [`DshotInput`] then handles the
+//! special commands and maps the throttle.
+//!
+//! In bidirectional DShot, the signal is inverted (idle high, and
+//! every bit starts with a falling edge) and so is the CRC. After
+//! each frame, the ESC replies on the same line with its eRPM (see
+//! [`crate::dshot_telemetry`]).
+//!
+
+/// The number of edges captured for one frame
+pub const EDGES_PER_FRAME: usize = 32;
+
+/// The smallest throttle value (values below this are commands)
+pub const MIN_THROTTLE: u16 = 48;
+
+/// The largest throttle value
+pub const MAX_THROTTLE: u16 = 2047;
+
+// In 3D mode, values up to this are reverse, and above it forward
+const THREE_D_REVERSE_MAX: u16 = 1047;
+
+// Commands which change a setting only take effect if they are
+// sent this many times in a row (with the telemetry bit set)
+const SETTING_REPEATS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DshotError {
+    /// A bit is not a valid pulse (the high time is not shorter
+    /// than the bit period, or the bit periods are uneven)
+    BadPulse,
+    /// The CRC does not match the rest of the frame
+    Crc,
+    /// The capture did not start at the beginning of a frame. The
+    /// idle gap between frames is `skip` edges in, so skip that
+    /// many edges to line up with the next frame.
+    Misaligned { skip: usize },
+}
+
+/// A DShot frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DshotFrame {
+    /// The 11-bit value (stop, command or throttle)
+    pub value: u16,
+    /// Whether telemetry is requested
+    pub telemetry: bool,
+}
+
+impl DshotFrame {
+    pub fn new(value: u16, telemetry: bool) -> Self {
+        Self {
+            value: value & 0x7ff,
+            telemetry,
+        }
+    }
+
+    /// Encode the frame as 16 bits (including the CRC)
+    pub fn encode(&self) -> u16 {
+        let data = self.value << 1 | self.telemetry as u16;
+        data << 4 | crc(data)
+    }
+
+    /// Decode a frame from 16 bits, checking the CRC
+    pub fn decode(bits: u16) -> Result<Self, DshotError> {
+        let data = bits >> 4;
+        if crc(data) != bits & 0xf {
+            return Err(DshotError::Crc);
+        }
+        Ok(Self::new(data >> 1, data & 1 == 1))
+    }
+
+    /// Encode the frame as 16 bits for bidirectional DShot (the
+    /// CRC is inverted)
+    pub fn encode_bidirectional(&self) -> u16 {
+        self.encode() ^ 0xf
+    }
+
+    /// Decode a bidirectional DShot frame from 16 bits, checking
+    /// the (inverted) CRC
+    pub fn decode_bidirectional(bits: u16) -> Result<Self, DshotError> {
+        Self::decode(bits ^ 0xf)
+    }
+
+    /// The special command in this frame, if it is one
+    pub fn command(&self) -> Option<DshotCommand> {
+        match self.value {
+            1..MIN_THROTTLE => Some(DshotCommand::from_value(self.value as u8)),
+            _ => None,
+        }
+    }
+}
+
+// XOR of the three nibbles of the 12-bit data
+fn crc(data: u16) -> u16 {
+    (data ^ data >> 4 ^ data >> 8) & 0xf
+}
+
+/// Decode a frame from the times of its 32 edges (in timer ticks,
+/// starting with the first edge of a bit)
+///
+/// The timer may wrap around during the frame. For bidirectional
+/// DShot (`bidirectional` is true), the bits start with a falling
+/// edge and the CRC is inverted.
+pub fn decode_edges(
+    edges: &[u16; EDGES_PER_FRAME],
+    bidirectional: bool,
+) -> Result<DshotFrame, DshotError> {
+    let interval = |n: usize| edges[n].wrapping_sub(edges[n - 1]);
+
+    // Within a frame, the edges are less than a bit period apart.
+    // A much longer interval is the idle gap before a frame.
+    let shortest = (1..EDGES_PER_FRAME).map(interval).min().unwrap_or(0);
+    if let Some(skip) = (1..EDGES_PER_FRAME).find(|&n| interval(n) > 8 * shortest.max(1)) {
+        return Err(DshotError::Misaligned { skip });
+    }
+
+    // The average bit period (the last bit has no following
+    // rising edge, so use the other 15)
+    let bits = EDGES_PER_FRAME / 2;
+    let average = edges[EDGES_PER_FRAME - 2].wrapping_sub(edges[0]) as u32 / (bits as u32 - 1);
+
+    let mut value = 0;
+    for bit in 0..bits {
+        let high = interval(2 * bit + 1) as u32;
+        let period = match bit {
+            15 => average,
+            _ => edges[2 * bit + 2].wrapping_sub(edges[2 * bit]) as u32,
+        };
+
+        // Allow the bit period to vary by 25%
+        if high >= period || 4 * period < 3 * average || 4 * period > 5 * average {
+            return Err(DshotError::BadPulse);
+        }
+
+        // Half way between 37.5% (a 0) and 75% (a 1)
+        let one = 16 * high > 9 * period;
+        value = value << 1 | one as u16;
+    }
+
+    if bidirectional {
+        DshotFrame::decode_bidirectional(value)
+    } else {
+        DshotFrame::decode(value)
+    }
+}
+
+/// DShot special commands (values 1 to 47)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DshotCommand {
+    /// Beep (1 to 5, in increasing pitch)
+    Beep(u8),
+    /// Send the ESC information
+    EscInfo,
+    /// Spin in the normal direction
+    SpinDirectionNormal,
+    /// Spin in the reversed direction
+    SpinDirectionReversed,
+    /// Turn off 3D (bidirectional) mode
+    ThreeDModeOff,
+    /// Turn on 3D (bidirectional) mode
+    ThreeDModeOn,
+    /// Send the current settings
+    SettingsRequest,
+    /// Save the current settings
+    SaveSettings,
+    /// Turn on extended telemetry (temperature, voltage and
+    /// current, between the eRPM replies of bidirectional DShot)
+    ExtendedTelemetryEnable,
+    /// Turn off extended telemetry
+    ExtendedTelemetryDisable,
+    /// Any other command (e.g. LEDs), which is ignored
+    Other(u8),
+}
+
+impl DshotCommand {
+    fn from_value(value: u8) -> Self {
+        match value {
+            1..=5 => DshotCommand::Beep(value),
+            6 => DshotCommand::EscInfo,
+            // 7 and 8 are the old spin direction commands, 20
+            // and 21 the newer ones
+            7 | 20 => DshotCommand::SpinDirectionNormal,
+            8 | 21 => DshotCommand::SpinDirectionReversed,
+            9 => DshotCommand::ThreeDModeOff,
+            10 => DshotCommand::ThreeDModeOn,
+            11 => DshotCommand::SettingsRequest,
+            12 => DshotCommand::SaveSettings,
+            13 => DshotCommand::ExtendedTelemetryEnable,
+            14 => DshotCommand::ExtendedTelemetryDisable,
+            _ => DshotCommand::Other(value),
+        }
+    }
+
+    /// Whether the command changes a setting (and must be repeated
+    /// before it takes effect)
+    fn changes_setting(&self) -> bool {
+        matches!(
+            self,
+            DshotCommand::SpinDirectionNormal
+                | DshotCommand::SpinDirectionReversed
+                | DshotCommand::ThreeDModeOff
+                | DshotCommand::ThreeDModeOn
+                | DshotCommand::SettingsRequest
+                | DshotCommand::SaveSettings
+                | DshotCommand::ExtendedTelemetryEnable
+                | DshotCommand::ExtendedTelemetryDisable
+        )
+    }
+}
+
+/// What the motor should do in response to a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DshotAction {
+    /// Stop the motor (a value of 0)
+    Stop,
+    /// Run at `throttle` (between 0.0 and 1.0), in the reversed
+    /// direction if `reverse` is true
+    Throttle { throttle: f32, reverse: bool },
+    /// A command has been received (settings commands only once
+    /// they have been repeated enough times)
+    Command(DshotCommand),
+    /// Nothing to do (a settings command which has not been
+    /// repeated enough times yet)
+    None,
+}
+
+/// Handles the commands and throttle in a stream of DShot frames
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DshotInput {
+    reversed: bool,
+    three_d: bool,
+    extended_telemetry: bool,
+
+    // The last settings command, and how many times in a row it
+    // has been received
+    repeated: Option<(DshotCommand, u8)>,
+}
+
+impl DshotInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the spin direction is reversed
+    pub fn reversed(&self) -> bool {
+        self.reversed
+    }
+
+    /// Whether 3D (bidirectional) mode is on
+    pub fn three_d(&self) -> bool {
+        self.three_d
+    }
+
+    /// Whether extended telemetry is on
+    pub fn extended_telemetry(&self) -> bool {
+        self.extended_telemetry
+    }
+
+    /// Process a frame
+    pub fn update(&mut self, frame: DshotFrame) -> DshotAction {
+        let Some(command) = frame.command() else {
+            self.repeated = None;
+            return self.throttle(frame.value);
+        };
+
+        if !command.changes_setting() {
+            self.repeated = None;
+            return DshotAction::Command(command);
+        }
+
+        // Settings commands must be sent with the telemetry bit
+        // set, several times in a row
+        let count = match self.repeated {
+            Some((previous, count)) if previous == command && frame.telemetry => count + 1,
+            _ if frame.telemetry => 1,
+            _ => 0,
+        };
+        self.repeated = Some((command, count));
+        if count != SETTING_REPEATS {
+            return DshotAction::None;
+        }
+
+        match command {
+            DshotCommand::SpinDirectionNormal => self.reversed = false,
+            DshotCommand::SpinDirectionReversed => self.reversed = true,
+            DshotCommand::ThreeDModeOff => self.three_d = false,
+            DshotCommand::ThreeDModeOn => self.three_d = true,
+            DshotCommand::ExtendedTelemetryEnable => self.extended_telemetry = true,
+            DshotCommand::ExtendedTelemetryDisable => self.extended_telemetry = false,
+            _ => {}
+        }
+        DshotAction::Command(command)
+    }
+
+    fn throttle(&self, value: u16) -> DshotAction {
+        if value < MIN_THROTTLE {
+            return DshotAction::Stop;
+        }
+
+        let (throttle, reverse) = if !self.three_d {
+            let range = MAX_THROTTLE - MIN_THROTTLE;
+            ((value - MIN_THROTTLE) as f32 / range as f32, false)
+        } else if value <= THREE_D_REVERSE_MAX {
+            let range = THREE_D_REVERSE_MAX - MIN_THROTTLE;
+            ((value - MIN_THROTTLE) as f32 / range as f32, true)
+        } else {
+            let range = MAX_THROTTLE - THREE_D_REVERSE_MAX - 1;
+            (
+                (value - THREE_D_REVERSE_MAX - 1) as f32 / range as f32,
+                false,
+            )
+        };
+
+        DshotAction::Throttle {
+            throttle,
+            reverse: reverse != self.reversed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Capture the edges of an encoded frame, as a timer with
+    // `bit_ticks` ticks per bit would, starting at `start` (with
+    // some jitter)
+    fn edges(bits: u16, bit_ticks: u16, start: u16) -> [u16; EDGES_PER_FRAME] {
+        let mut edges = [0; EDGES_PER_FRAME];
+        for bit in 0..16 {
+            let rising = start.wrapping_add(bit as u16 * bit_ticks);
+            let one = bits & (0x8000 >> bit) != 0;
+            let high = if one {
+                bit_ticks * 3 / 4
+            } else {
+                bit_ticks * 3 / 8
+            };
+            let jitter = (bit % 3) as u16;
+            edges[2 * bit] = rising.wrapping_add(jitter);
+            edges[2 * bit + 1] = rising.wrapping_add(high);
+        }
+        edges
+    }
+
+    #[test]
+    fn encodes_with_crc() {
+        // Throttle 1046 without telemetry (the example in the
+        // DShot description)
+        let frame = DshotFrame::new(1046, false);
+        assert_eq!(frame.encode(), 0b1000_0010_1100_0110);
+        assert_eq!(DshotFrame::decode(frame.encode()), Ok(frame));
+        assert_eq!(
+            DshotFrame::decode(frame.encode() ^ 0x100),
+            Err(DshotError::Crc)
+        );
+    }
+
+    #[test]
+    fn decodes_captured_edges() {
+        // DShot600 (67 ticks per bit at 40 MHz) and DShot150 (267
+        // ticks), with the timer wrapping during the frame
+        for (bit_ticks, start) in [(67, 100), (267, 65_000)] {
+            for value in [0, 5, 48, 1046, 2047] {
+                let frame = DshotFrame::new(value, value % 2 == 0);
+                assert_eq!(
+                    decode_edges(&edges(frame.encode(), bit_ticks, start), false),
+                    Ok(frame)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_bidirectional_frames() {
+        let frame = DshotFrame::new(1046, false);
+        assert_eq!(frame.encode_bidirectional(), 0b1000_0010_1100_1001);
+
+        // The edges are timed the same (only the polarity of the
+        // signal differs), but the CRC is inverted
+        let captured = edges(frame.encode_bidirectional(), 67, 0);
+        assert_eq!(decode_edges(&captured, true), Ok(frame));
+        assert_eq!(decode_edges(&captured, false), Err(DshotError::Crc));
+    }
+
+    #[test]
+    fn rejects_bad_captures() {
+        let frame = DshotFrame::new(1000, false);
+        let mut captured = edges(frame.encode(), 67, 0);
+
+        // Corrupt one bit (a 0 becomes a 1, or a 1 a 0)
+        let high = captured[9] - captured[8];
+        captured[9] = captured[8] + if high > 33 { 25 } else { 50 };
+        assert_eq!(decode_edges(&captured, false), Err(DshotError::Crc));
+
+        // One bit much longer than the others
+        for edge in &mut captured[10..] {
+            *edge += 40;
+        }
+        assert_eq!(decode_edges(&captured, false), Err(DshotError::BadPulse));
+    }
+
+    #[test]
+    fn finds_the_gap_between_frames() {
+        // Start capturing 10 edges into a frame: the last 22 edges
+        // of this frame and the first 10 of the next
+        let first = edges(DshotFrame::new(300, false).encode(), 67, 0);
+        let second = edges(DshotFrame::new(300, false).encode(), 67, 5000);
+        let mut captured = [0; EDGES_PER_FRAME];
+        captured[..22].copy_from_slice(&first[10..]);
+        captured[22..].copy_from_slice(&second[..10]);
+        assert_eq!(
+            decode_edges(&captured, false),
+            Err(DshotError::Misaligned { skip: 22 })
+        );
+    }
+
+    #[test]
+    fn maps_throttle() {
+        let mut input = DshotInput::new();
+        assert_eq!(input.update(DshotFrame::new(0, false)), DshotAction::Stop);
+        assert_eq!(
+            input.update(DshotFrame::new(MIN_THROTTLE, false)),
+            DshotAction::Throttle {
+                throttle: 0.0,
+                reverse: false
+            }
+        );
+        assert_eq!(
+            input.update(DshotFrame::new(MAX_THROTTLE, false)),
+            DshotAction::Throttle {
+                throttle: 1.0,
+                reverse: false
+            }
+        );
+    }
+
+    #[test]
+    fn settings_commands_must_be_repeated() {
+        let mut input = DshotInput::new();
+        let on = DshotFrame::new(10, true);
+        for _ in 0..5 {
+            assert_eq!(input.update(on), DshotAction::None);
+        }
+        assert_eq!(
+            input.update(on),
+            DshotAction::Command(DshotCommand::ThreeDModeOn)
+        );
+        assert!(input.three_d());
+
+        // Without the telemetry bit, the command is ignored
+        for _ in 0..10 {
+            assert_eq!(input.update(DshotFrame::new(21, false)), DshotAction::None);
+        }
+        assert!(!input.reversed());
+
+        // Extended telemetry is a setting too
+        for _ in 0..6 {
+            input.update(DshotFrame::new(13, true));
+        }
+        assert!(input.extended_telemetry());
+
+        // Beeps do not need to be repeated
+        assert_eq!(
+            input.update(DshotFrame::new(3, false)),
+            DshotAction::Command(DshotCommand::Beep(3))
+        );
+    }
+
+    #[test]
+    fn three_d_mode_and_reversal() {
+        let mut input = DshotInput::new();
+        for _ in 0..6 {
+            input.update(DshotFrame::new(10, true));
+        }
+
+        // Low half reverse, high half forward
+        assert_eq!(
+            input.update(DshotFrame::new(THREE_D_REVERSE_MAX, false)),
+            DshotAction::Throttle {
+                throttle: 1.0,
+                reverse: true
+            }
+        );
+        assert_eq!(
+            input.update(DshotFrame::new(THREE_D_REVERSE_MAX + 1, false)),
+            DshotAction::Throttle {
+                throttle: 0.0,
+                reverse: false
+            }
+        );
+
+        // Reversing the spin direction swaps them
+        for _ in 0..6 {
+            input.update(DshotFrame::new(21, true));
+        }
+        assert_eq!(
+            input.update(DshotFrame::new(MAX_THROTTLE, false)),
+            DshotAction::Throttle {
+                throttle: 1.0,
+                reverse: true
+            }
+        );
+    }
+}
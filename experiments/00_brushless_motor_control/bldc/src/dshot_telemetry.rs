@@ -0,0 +1,334 @@
+//! Bidirectional DShot telemetry
+//!
+//! In bidirectional DShot, the ESC replies to every frame on the
+//! same line, about 30 us after the end of the frame, at 5/4 of
+//! the DShot bit rate. The reply is 16 bits, most significant
+//! first:
+//!
+//! | Bits  | Field                                             |
+//! |-------|---------------------------------------------------|
+//! | 15..4 | Value (eRPM period, or extended telemetry)        |
+//! | 3..0  | CRC (inverted XOR of the three nibbles above)     |
+//!
+//! The value is normally the period of one electrical revolution
+//! in microseconds, as a 3-bit exponent and a 9-bit mantissa
+//! (`eeem mmmm mmmm`, period = mantissa << exponent). The mantissa
+//! is always normalised (its top bit set, unless the exponent is
+//! 0), which leaves the values `pppp 0000 0000` to `pppp 1111 1111`
+//! with an even `pppp` for extended telemetry (temperature,
+//! voltage and current). The flight controller turns extended
+//! telemetry on with a DShot command.
+//!
+//! Each nibble of the 16 bits is sent as a 5-bit GCR code, so that
+//! there are never more than two 0 bits in a row. The 20 bits are
+//! sent after a start bit, with a 1 bit as a change of the line
+//! level and a 0 bit as no change, which makes 21 bits on the
+//! line (see [`gcr_encode`]).
+//!
+
+/// The number of bits on the line in a reply (including the start
+/// bit)
+pub const GCR_BITS: usize = 21;
+
+/// Every so many replies, extended telemetry is sent instead of
+/// the eRPM (when it is on)
+pub const EXTENDED_INTERVAL: u8 = 8;
+
+// The longest period which can be encoded (the mantissa and
+// exponent all ones). Anything longer is sent as this, which
+// means that the motor is stopped.
+const MAX_PERIOD_US: u32 = 0x1ff << 7;
+
+// The 5-bit GCR code of each nibble
+const GCR: [u8; 16] = [
+    0x19, 0x1b, 0x12, 0x13, 0x1d, 0x15, 0x16, 0x17, 0x1a, 0x09, 0x0a, 0x0b, 0x1e, 0x0d, 0x0e, 0x0f,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryError {
+    /// A 5-bit group on the line is not a GCR code
+    Gcr,
+    /// The CRC does not match the rest of the reply
+    Crc,
+}
+
+/// The contents of a telemetry reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Telemetry {
+    /// The period of one electrical revolution in microseconds
+    /// (from which the flight controller works out the eRPM)
+    ErpmPeriod(u32),
+    /// Temperature in degrees C
+    Temperature(u8),
+    /// Voltage in steps of 0.25 V
+    Voltage(u8),
+    /// Current in A
+    Current(u8),
+    /// Another type of extended telemetry (debug values, stress
+    /// level or status), which is not sent by this firmware
+    Other { frame_type: u8, value: u8 },
+}
+
+impl Telemetry {
+    /// The eRPM reply for a commutation step period (there are six
+    /// steps per electrical revolution), or None when the motor is
+    /// stopped
+    pub fn from_step_period(step_period_us: Option<u32>) -> Self {
+        Telemetry::ErpmPeriod(step_period_us.map_or(MAX_PERIOD_US, |period| 6 * period))
+    }
+
+    /// The voltage reply, rounded to the nearest 0.25 V
+    pub fn voltage(volts: f32) -> Self {
+        Telemetry::Voltage((volts * 4.0 + 0.5) as u8)
+    }
+
+    /// The current reply, rounded to the nearest A
+    pub fn current(amps: f32) -> Self {
+        Telemetry::Current((amps + 0.5) as u8)
+    }
+
+    /// The electrical RPM, or None when the motor is stopped
+    pub fn erpm(&self) -> Option<u32> {
+        match *self {
+            Telemetry::ErpmPeriod(period) if (1..MAX_PERIOD_US).contains(&period) => {
+                Some(60_000_000 / period)
+            }
+            _ => None,
+        }
+    }
+
+    /// Encode the reply as 16 bits (including the CRC)
+    pub fn encode(&self) -> u16 {
+        let value = match *self {
+            Telemetry::ErpmPeriod(period) => {
+                // Keep the top nine bits of the period
+                let period = period.min(MAX_PERIOD_US);
+                let exponent = (u32::BITS - period.leading_zeros()).saturating_sub(9);
+                (exponent << 9 | period >> exponent) as u16
+            }
+            Telemetry::Temperature(value) => 0x200 | value as u16,
+            Telemetry::Voltage(value) => 0x400 | value as u16,
+            Telemetry::Current(value) => 0x600 | value as u16,
+            Telemetry::Other { frame_type, value } => (frame_type as u16 & 0xf) << 8 | value as u16,
+        };
+        value << 4 | crc(value)
+    }
+
+    /// Decode a reply from 16 bits, checking the CRC
+    pub fn decode(bits: u16) -> Result<Self, TelemetryError> {
+        let value = bits >> 4;
+        if crc(value) != bits & 0xf {
+            return Err(TelemetryError::Crc);
+        }
+
+        let frame_type = (value >> 8) as u8;
+        let low = value as u8;
+        Ok(match frame_type {
+            // An odd type (or 0) is an eRPM period
+            _ if frame_type & 1 == 1 || frame_type == 0 => {
+                Telemetry::ErpmPeriod(((value & 0x1ff) as u32) << (value >> 9))
+            }
+            0x2 => Telemetry::Temperature(low),
+            0x4 => Telemetry::Voltage(low),
+            0x6 => Telemetry::Current(low),
+            _ => Telemetry::Other {
+                frame_type,
+                value: low,
+            },
+        })
+    }
+}
+
+// Inverted XOR of the three nibbles of the 12-bit value
+fn crc(value: u16) -> u16 {
+    !(value ^ value >> 4 ^ value >> 8) & 0xf
+}
+
+/// Encode a 16-bit reply as the 21 bits sent on the line, first
+/// bit sent in bit 20 (a 0 bit is the line low)
+///
+/// The line is high before the reply, so the start bit is low.
+pub fn gcr_encode(bits: u16) -> u32 {
+    let gcr = (0..4).fold(0, |gcr, nibble| {
+        gcr << 5 | GCR[(bits >> (12 - 4 * nibble) & 0xf) as usize] as u32
+    });
+
+    // Change the line level for every 1 bit
+    let mut level = 0;
+    let mut line = 0;
+    for bit in (0..GCR_BITS - 1).rev() {
+        level ^= gcr >> bit & 1;
+        line |= level << bit;
+    }
+    line
+}
+
+/// Decode the 21 bits received on the line (first bit received in
+/// bit 20) into a 16-bit reply
+pub fn gcr_decode(line: u32) -> Result<u16, TelemetryError> {
+    // A 1 bit wherever the line level changed
+    let gcr = (line ^ line >> 1) & 0xf_ffff;
+
+    (0..4).try_fold(0, |bits, nibble| {
+        let code = (gcr >> (15 - 5 * nibble) & 0x1f) as u8;
+        let value = GCR
+            .iter()
+            .position(|&c| c == code)
+            .ok_or(TelemetryError::Gcr)?;
+        Ok(bits << 4 | value as u16)
+    })
+}
+
+/// Readings sent as extended telemetry (None if not measured)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ExtendedReadings {
+    /// Temperature in degrees C
+    pub temperature_c: Option<u8>,
+    /// Supply voltage in V
+    pub voltage: Option<f32>,
+    /// Motor current in A
+    pub current: Option<f32>,
+}
+
+/// Chooses the reply to each bidirectional DShot frame
+///
+/// Most replies are the eRPM. When extended telemetry is on, every
+/// [`EXTENDED_INTERVAL`] replies one of the readings is sent
+/// instead, taking turns between those which are measured.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TelemetryScheduler {
+    // Replies since the last extended telemetry
+    count: u8,
+
+    // The reading to try next (0 temperature, 1 voltage, 2 current)
+    next_reading: usize,
+}
+
+impl TelemetryScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The next reply, given the eRPM reply and the readings (None
+    /// when extended telemetry is off)
+    pub fn next(&mut self, erpm: Telemetry, readings: Option<&ExtendedReadings>) -> Telemetry {
+        self.count = self.count.saturating_add(1);
+        let Some(readings) = readings.filter(|_| self.count >= EXTENDED_INTERVAL) else {
+            return erpm;
+        };
+
+        for _ in 0..3 {
+            let reading = self.next_reading;
+            self.next_reading = (reading + 1) % 3;
+            let telemetry = match reading {
+                0 => readings.temperature_c.map(Telemetry::Temperature),
+                1 => readings.voltage.map(Telemetry::voltage),
+                _ => readings.current.map(Telemetry::current),
+            };
+            if let Some(telemetry) = telemetry {
+                self.count = 0;
+                return telemetry;
+            }
+        }
+        erpm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_erpm_period() {
+        // Stopped: all ones (CRC 0)
+        let stopped = Telemetry::from_step_period(None);
+        assert_eq!(stopped.encode(), 0xfff0);
+        assert_eq!(stopped.erpm(), None);
+
+        // 1000 us is 60,000 eRPM: exponent 1, mantissa 500
+        let running = Telemetry::ErpmPeriod(1000);
+        assert_eq!(running.encode(), 0x3f47);
+        assert_eq!(running.erpm(), Some(60_000));
+
+        // Short periods are not shifted, long ones lose their low
+        // bits
+        assert_eq!(Telemetry::ErpmPeriod(300).encode() >> 4, 300);
+        assert_eq!(
+            Telemetry::decode(Telemetry::ErpmPeriod(20_001).encode()),
+            Ok(Telemetry::ErpmPeriod(19_968))
+        );
+        assert_eq!(
+            Telemetry::from_step_period(Some(2500)),
+            Telemetry::ErpmPeriod(15_000)
+        );
+    }
+
+    #[test]
+    fn encodes_extended_telemetry() {
+        assert_eq!(Telemetry::Temperature(40).encode(), 0x2287);
+        assert_eq!(Telemetry::voltage(12.6), Telemetry::Voltage(50));
+        assert_eq!(Telemetry::current(3.4), Telemetry::Current(3));
+        for telemetry in [
+            Telemetry::Temperature(40),
+            Telemetry::Voltage(50),
+            Telemetry::Current(3),
+            Telemetry::Other {
+                frame_type: 0xe,
+                value: 1,
+            },
+        ] {
+            assert_eq!(Telemetry::decode(telemetry.encode()), Ok(telemetry));
+            assert_eq!(telemetry.erpm(), None);
+        }
+        assert_eq!(Telemetry::decode(0x2287 ^ 0x10), Err(TelemetryError::Crc));
+    }
+
+    #[test]
+    fn gcr_line_bits() {
+        // Worked out by hand from the GCR table
+        assert_eq!(gcr_encode(0xfff0), 0x05_2951);
+        assert_eq!(gcr_encode(0x3f47), 0x0e_d525);
+        assert_eq!(gcr_decode(0x05_2951), Ok(0xfff0));
+        assert_eq!(gcr_decode(0x0e_d525), Ok(0x3f47));
+
+        // The start bit is always low, and the decoding does not
+        // depend on the polarity of the line
+        for bits in [0, 0x1234, 0xfff0, 0xffff] {
+            let line = gcr_encode(bits);
+            assert_eq!(line >> 20, 0);
+            assert_eq!(gcr_decode(line), Ok(bits));
+            assert_eq!(gcr_decode(!line & 0x1f_ffff), Ok(bits));
+        }
+
+        // Three 0 bits in a row is not a GCR code
+        assert_eq!(gcr_decode(0x0f_ffff), Err(TelemetryError::Gcr));
+    }
+
+    #[test]
+    fn schedules_extended_telemetry() {
+        let erpm = Telemetry::ErpmPeriod(1000);
+        let readings = ExtendedReadings {
+            temperature_c: None,
+            voltage: Some(12.0),
+            current: Some(2.0),
+        };
+
+        // Off: always the eRPM
+        let mut scheduler = TelemetryScheduler::new();
+        assert!((0..20).all(|_| scheduler.next(erpm, None) == erpm));
+
+        // On: every eighth reply, taking turns between the readings
+        let mut scheduler = TelemetryScheduler::new();
+        let replies: [Telemetry; 16] =
+            core::array::from_fn(|_| scheduler.next(erpm, Some(&readings)));
+        assert!(replies[..7].iter().all(|&reply| reply == erpm));
+        assert_eq!(replies[7], Telemetry::Voltage(48));
+        assert!(replies[8..15].iter().all(|&reply| reply == erpm));
+        assert_eq!(replies[15], Telemetry::Current(2));
+
+        // Nothing measured: always the eRPM
+        let mut scheduler = TelemetryScheduler::new();
+        let none = ExtendedReadings::default();
+        assert!((0..20).all(|_| scheduler.next(erpm, Some(&none)) == erpm));
+    }
+}
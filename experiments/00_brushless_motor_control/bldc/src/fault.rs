@@ -0,0 +1,206 @@
+//! Motor fault detection
+//!
+//! A fault is latched when the motor cannot be driven safely. The
+//! controller then puts all three half bridges into high-Z, and
+//! keeps them there until the fault is cleared. Only the first
+//! fault is kept (later faults are usually caused by the first).
+//!
+
+/// The reason the motor was shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorFault {
+    /// No zero crossings were found after all start-up attempts
+    /// (the rotor is not turning)
+    Stall,
+    /// The zero crossings were lost in closed-loop mode
+    Desync,
+    /// Too many ADC overruns (the phase voltages cannot be
+    /// sampled reliably)
+    AdcOverrun,
+    /// The DMA transfer of the ADC samples failed
+    DmaTransferError,
+    /// The motor current exceeded the limit
+    OverCurrent,
+    /// The supply voltage exceeded the limit
+    OverVoltage,
+    /// The timer break input turned the PWM outputs off (e.g. an
+    /// external over-current comparator)
+    BreakInput,
+    /// The commutation timer could not count the time until the
+    /// next commutation (see [`crate::TimeoutTooLong`])
+    TimerOverflow,
+}
+
+impl MotorFault {
+    /// Short name of the fault (for display)
+    pub fn name(&self) -> &'static str {
+        match self {
+            MotorFault::Stall => "stall",
+            MotorFault::Desync => "desync",
+            MotorFault::AdcOverrun => "ADC overrun",
+            MotorFault::DmaTransferError => "DMA transfer error",
+            MotorFault::OverCurrent => "over-current",
+            MotorFault::OverVoltage => "over-voltage",
+            MotorFault::BreakInput => "break input",
+            MotorFault::TimerOverflow => "timer overflow",
+        }
+    }
+}
+
+/// Fault detection limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// Number of sample sets over which ADC overruns are counted
+    pub adc_overrun_window: u32,
+
+    /// Number of ADC overruns allowed in one window (occasional
+    /// overruns are harmless, a burst of them is not)
+    pub max_adc_overruns: u32,
+
+    /// Maximum motor current (A)
+    pub max_current: f32,
+
+    /// Maximum supply voltage (V)
+    pub max_bus_voltage: f32,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            adc_overrun_window: 1000,
+            max_adc_overruns: 10,
+            // The bench supply is limited to 2 A
+            max_current: 2.0,
+            // Maximum voltage of the P1604
+            max_bus_voltage: 16.5,
+        }
+    }
+}
+
+/// Latches the first fault detected
+pub struct FaultMonitor {
+    config: FaultConfig,
+    fault: Option<MotorFault>,
+
+    // Sample sets and ADC overruns in the current window
+    window_samples: u32,
+    window_overruns: u32,
+}
+
+impl FaultMonitor {
+    pub fn new(config: FaultConfig) -> Self {
+        Self {
+            config,
+            fault: None,
+            window_samples: 0,
+            window_overruns: 0,
+        }
+    }
+
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: FaultConfig) {
+        self.config = config;
+    }
+
+    /// The latched fault, if any
+    pub fn fault(&self) -> Option<MotorFault> {
+        self.fault
+    }
+
+    /// Latch a fault (ignored if there is already a fault).
+    /// Returns true if this is a new fault.
+    pub fn latch(&mut self, fault: MotorFault) -> bool {
+        if self.fault.is_some() {
+            return false;
+        }
+        self.fault = Some(fault);
+        true
+    }
+
+    /// Clear the latched fault
+    pub fn clear(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Count a new set of phase voltage samples
+    pub fn on_samples(&mut self) {
+        self.window_samples += 1;
+        if self.window_samples >= self.config.adc_overrun_window {
+            self.window_samples = 0;
+            self.window_overruns = 0;
+        }
+    }
+
+    /// Count an ADC overrun, and return the fault if there have
+    /// been too many in this window
+    pub fn on_adc_overrun(&mut self) -> Option<MotorFault> {
+        self.window_overruns += 1;
+        (self.window_overruns > self.config.max_adc_overruns).then_some(MotorFault::AdcOverrun)
+    }
+
+    /// Check a motor current measurement (A)
+    pub fn check_current(&self, current: f32) -> Option<MotorFault> {
+        (current.abs() > self.config.max_current).then_some(MotorFault::OverCurrent)
+    }
+
+    /// Check a supply voltage measurement (V)
+    pub fn check_bus_voltage(&self, voltage: f32) -> Option<MotorFault> {
+        (voltage > self.config.max_bus_voltage).then_some(MotorFault::OverVoltage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_first_fault_until_cleared() {
+        let mut monitor = FaultMonitor::new(FaultConfig::default());
+        assert_eq!(monitor.fault(), None);
+        assert!(monitor.latch(MotorFault::Desync));
+        assert!(!monitor.latch(MotorFault::Stall));
+        assert_eq!(monitor.fault(), Some(MotorFault::Desync));
+
+        monitor.clear();
+        assert_eq!(monitor.fault(), None);
+    }
+
+    #[test]
+    fn adc_overrun_storm() {
+        let mut monitor = FaultMonitor::new(FaultConfig {
+            adc_overrun_window: 100,
+            max_adc_overruns: 3,
+            ..Default::default()
+        });
+
+        // Occasional overruns are allowed
+        for _ in 0..10 {
+            for _ in 0..99 {
+                monitor.on_samples();
+            }
+            assert_eq!(monitor.on_adc_overrun(), None);
+            monitor.on_samples();
+        }
+
+        // A burst is not
+        for _ in 0..3 {
+            assert_eq!(monitor.on_adc_overrun(), None);
+        }
+        assert_eq!(monitor.on_adc_overrun(), Some(MotorFault::AdcOverrun));
+    }
+
+    #[test]
+    fn current_and_voltage_limits() {
+        let monitor = FaultMonitor::new(FaultConfig::default());
+        assert_eq!(monitor.check_current(-1.5), None);
+        assert_eq!(monitor.check_bus_voltage(12.0), None);
+        assert_eq!(monitor.check_current(-2.5), Some(MotorFault::OverCurrent));
+        assert_eq!(
+            monitor.check_bus_voltage(17.0),
+            Some(MotorFault::OverVoltage)
+        );
+    }
+}
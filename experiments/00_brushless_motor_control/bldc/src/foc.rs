@@ -0,0 +1,707 @@
+//! Field-oriented control (FOC)
+//!
+//! Six-step commutation drives two phases at a time, so the
+//! current jumps between pairs of windings every 60 electrical
+//! degrees. Field-oriented control drives all three half bridges
+//! all the time, so that the stator current is a vector which
+//! turns with the rotor, at 90 electrical degrees to the rotor
+//! flux (where it gives the most torque per amp):
+//!
+//! - The phase currents are transformed to two stationary axes
+//!   (alpha and beta, the [`clarke`] transform), and then to the
+//!   axes of the rotor (d along the rotor flux, and q at 90
+//!   degrees to it, the [`park`] transform).
+//! - A PI controller for each axis sets the voltage which keeps
+//!   the d current at zero, and the q current (the torque) at
+//!   the target.
+//! - The voltage is transformed back to the stationary axes
+//!   ([`inverse_park`]), and converted to the duty cycles of the
+//!   three half bridges by space-vector PWM ([`space_vector`]).
+//!
+//! There is no position sensor: a [`FluxObserver`] estimates the
+//! rotor angle from the voltages and currents, and a [`Pll`]
+//! follows the angle to measure the speed. The observer cannot
+//! see the rotor while it is still, so [`FocController`] starts
+//! the motor in open loop, by turning the current vector at an
+//! increasing speed until the observer can take over.
+//!
+//! All angles are electrical angles in radians, and speeds are in
+//! electrical radians per second.
+//!
+//! The half bridges must connect each phase to ground for the
+//! off-time of the PWM (complementary outputs). The L298 cannot
+//! be used, because its half bridges are high-Z in the off-time.
+//!
+
+use core::f32::consts::{PI, TAU};
+use libm::{atan2f, cosf, remainderf, sinf, sqrtf};
+
+const SQRT_3: f32 = 1.732_050_8;
+
+/// A vector in the stationary frame (alpha along phase A)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AlphaBeta {
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+impl AlphaBeta {
+    pub fn magnitude(&self) -> f32 {
+        sqrtf(self.alpha * self.alpha + self.beta * self.beta)
+    }
+
+    /// The angle from the alpha axis (between -pi and pi)
+    pub fn angle(&self) -> f32 {
+        atan2f(self.beta, self.alpha)
+    }
+}
+
+/// A vector in the rotor frame (d along the rotor flux)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Dq {
+    pub d: f32,
+    pub q: f32,
+}
+
+/// Wrap an angle to between -pi and pi
+pub fn wrap_angle(angle: f32) -> f32 {
+    remainderf(angle, TAU)
+}
+
+/// Transform the phase quantities (in the order A, B, C, with B
+/// lagging A by 120 degrees) to the stationary frame
+///
+/// The transform keeps the amplitude: balanced phase currents of
+/// amplitude I give a vector of magnitude I. Any part common to
+/// all three phases (e.g. the same offset in each measurement)
+/// is removed.
+pub fn clarke(phases: [f32; 3]) -> AlphaBeta {
+    let [a, b, c] = phases;
+    AlphaBeta {
+        alpha: (2.0 * a - b - c) / 3.0,
+        beta: (b - c) / SQRT_3,
+    }
+}
+
+/// Transform a vector in the stationary frame to the phase
+/// quantities (A, B, C)
+pub fn inverse_clarke(v: AlphaBeta) -> [f32; 3] {
+    let beta = 0.5 * SQRT_3 * v.beta;
+    [v.alpha, -0.5 * v.alpha + beta, -0.5 * v.alpha - beta]
+}
+
+/// Transform a vector in the stationary frame to the frame of a
+/// rotor at `angle`
+pub fn park(v: AlphaBeta, angle: f32) -> Dq {
+    let (sin, cos) = (sinf(angle), cosf(angle));
+    Dq {
+        d: v.alpha * cos + v.beta * sin,
+        q: -v.alpha * sin + v.beta * cos,
+    }
+}
+
+/// Transform a vector in the frame of a rotor at `angle` to the
+/// stationary frame
+pub fn inverse_park(v: Dq, angle: f32) -> AlphaBeta {
+    let (sin, cos) = (sinf(angle), cosf(angle));
+    AlphaBeta {
+        alpha: v.d * cos - v.q * sin,
+        beta: v.d * sin + v.q * cos,
+    }
+}
+
+/// The largest voltage vector which space-vector PWM can apply
+/// from a supply of `bus_voltage` (15% more than sinusoidal PWM)
+pub fn max_voltage(bus_voltage: f32) -> f32 {
+    bus_voltage / SQRT_3
+}
+
+/// The duty cycles of the half bridges (A, B, C) which apply the
+/// voltage vector `v` to the motor from a supply of `bus_voltage`
+///
+/// All three phase voltages are shifted by the same amount, so
+/// that they are centred between the supply rails (min-max
+/// injection, which switches in the same way as the usual sector
+/// by sector space-vector modulation). Vectors larger than
+/// [`max_voltage`] are distorted, because the duty cycles are
+/// limited to between 0.0 and 1.0.
+pub fn space_vector(v: AlphaBeta, bus_voltage: f32) -> [f32; 3] {
+    let phases = inverse_clarke(v);
+    let max = phases.iter().fold(f32::MIN, |max, &p| max.max(p));
+    let min = phases.iter().fold(f32::MAX, |min, &p| min.min(p));
+    let offset = (max + min) / 2.0;
+    phases.map(|p| (0.5 + (p - offset) / bus_voltage).clamp(0.0, 1.0))
+}
+
+/// The voltage vector applied by the half bridges (A, B, C) at
+/// duty cycles `duties` from a supply of `bus_voltage`
+pub fn applied_voltage(duties: [f32; 3], bus_voltage: f32) -> AlphaBeta {
+    clarke(duties.map(|duty| duty * bus_voltage))
+}
+
+/// Parameters of the FOC controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FocConfig {
+    /// Resistance of one phase (ohm)
+    pub phase_resistance: f32,
+
+    /// Inductance of one phase (H)
+    pub phase_inductance: f32,
+
+    /// Peak flux linkage of one phase with the rotor magnets
+    /// (V s, or peak phase back-EMF per electrical rad/s)
+    pub flux_linkage: f32,
+
+    /// Bandwidth of the d and q current loops (rad/s)
+    pub current_bandwidth: f32,
+
+    /// Rate at which the observed flux converges onto the flux
+    /// linkage (rad/s)
+    pub observer_bandwidth: f32,
+
+    /// Bandwidth of the speed measurement (rad/s)
+    pub pll_bandwidth: f32,
+
+    /// The q current at a duty cycle of 1.0 (A)
+    pub max_current: f32,
+
+    /// The q current while starting in open loop (A)
+    pub startup_current: f32,
+
+    /// The acceleration of the current vector while starting in
+    /// open loop (rad/s^2)
+    pub startup_acceleration: f32,
+
+    /// The speed at which the observer takes over (rad/s). The
+    /// back-EMF must be large enough to observe.
+    pub handover_speed: f32,
+
+    /// The lowest speed in closed loop (rad/s). Below this, the
+    /// rotor angle cannot be observed reliably, and the motor is
+    /// stopped.
+    pub min_speed: f32,
+
+    /// The supply voltage (V) to use until it is measured
+    pub nominal_bus_voltage: f32,
+}
+
+impl Default for FocConfig {
+    /// For the P1604 (3800 Kv, 7 pole pairs) on a 12 V supply
+    fn default() -> Self {
+        Self {
+            phase_resistance: 0.07,
+            phase_inductance: 10e-6,
+            // 60 / (sqrt(3) * 2 pi * Kv * pole pairs)
+            flux_linkage: 2.07e-4,
+            current_bandwidth: 5000.0,
+            observer_bandwidth: 2000.0,
+            pll_bandwidth: 1000.0,
+            max_current: 4.0,
+            startup_current: 2.0,
+            startup_acceleration: 20_000.0,
+            handover_speed: 2000.0,
+            min_speed: 1000.0,
+            nominal_bus_voltage: 12.0,
+        }
+    }
+}
+
+/// PI controller with a limited output
+///
+/// The integral is kept within the output limit, so that it does
+/// not wind up while the output is limited.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PiController {
+    pub kp: f32,
+    pub ki: f32,
+    integral: f32,
+}
+
+impl PiController {
+    pub fn new(kp: f32, ki: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            integral: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+    }
+
+    /// Update with a new error, `dt` seconds after the previous
+    /// update, and return the output (between -`limit` and
+    /// `limit`)
+    pub fn update(&mut self, error: f32, dt: f32, limit: f32) -> f32 {
+        self.integral = (self.integral + self.ki * error * dt).clamp(-limit, limit);
+        (self.kp * error + self.integral).clamp(-limit, limit)
+    }
+}
+
+/// Estimates the rotor angle from the phase voltages and currents
+///
+/// The flux of the rotor magnets through the windings is the
+/// integral of the voltage across the windings less the
+/// resistive drop, minus the flux of the winding current itself:
+///
+/// flux = integral(v - R i) dt - L i
+///
+/// A plain integrator drifts with any error in the measurements,
+/// so the estimate is also pulled onto a circle with the radius
+/// of the flux linkage (the nonlinear observer of Ortega et al.).
+/// The angle of the flux is the angle of the rotor d axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FluxObserver {
+    resistance: f32,
+    inductance: f32,
+    flux_linkage: f32,
+    gain: f32,
+
+    // The integral of v - R i
+    integral: AlphaBeta,
+    flux: AlphaBeta,
+}
+
+impl FluxObserver {
+    pub fn new(config: &FocConfig) -> Self {
+        let flux_linkage = config.flux_linkage;
+        let mut observer = Self {
+            resistance: config.phase_resistance,
+            inductance: config.phase_inductance,
+            flux_linkage,
+            // The radial error decays at 2 * gain * flux_linkage^2
+            gain: config.observer_bandwidth / (2.0 * flux_linkage * flux_linkage),
+            integral: AlphaBeta::default(),
+            flux: AlphaBeta::default(),
+        };
+        observer.reset();
+        observer
+    }
+
+    /// Start again from a rotor at angle 0
+    pub fn reset(&mut self) {
+        self.integral = AlphaBeta {
+            alpha: self.flux_linkage,
+            beta: 0.0,
+        };
+        self.flux = self.integral;
+    }
+
+    /// Update with the voltage applied over the last `dt` seconds
+    /// and the current measured at the end, and return the rotor
+    /// angle
+    pub fn update(&mut self, voltage: AlphaBeta, current: AlphaBeta, dt: f32) -> f32 {
+        let flux = self.flux;
+        let error =
+            self.flux_linkage * self.flux_linkage - flux.alpha * flux.alpha - flux.beta * flux.beta;
+        self.integral.alpha +=
+            (voltage.alpha - self.resistance * current.alpha + self.gain * flux.alpha * error) * dt;
+        self.integral.beta +=
+            (voltage.beta - self.resistance * current.beta + self.gain * flux.beta * error) * dt;
+
+        self.flux = AlphaBeta {
+            alpha: self.integral.alpha - self.inductance * current.alpha,
+            beta: self.integral.beta - self.inductance * current.beta,
+        };
+        self.angle()
+    }
+
+    /// The observed rotor flux
+    pub fn flux(&self) -> AlphaBeta {
+        self.flux
+    }
+
+    /// The observed rotor angle
+    pub fn angle(&self) -> f32 {
+        self.flux.angle()
+    }
+}
+
+/// Phase-locked loop which follows an angle, to measure its speed
+/// (without differentiating the noisy angle)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Pll {
+    kp: f32,
+    ki: f32,
+    angle: f32,
+    speed: f32,
+}
+
+impl Pll {
+    /// A critically damped loop with the given bandwidth (rad/s)
+    pub fn new(bandwidth: f32) -> Self {
+        Self {
+            kp: 2.0 * bandwidth,
+            ki: bandwidth * bandwidth,
+            angle: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self, angle: f32, speed: f32) {
+        self.angle = angle;
+        self.speed = speed;
+    }
+
+    /// Update with the angle measured `dt` seconds after the
+    /// previous one
+    pub fn update(&mut self, angle: f32, dt: f32) {
+        let error = wrap_angle(angle - self.angle);
+        self.speed += self.ki * error * dt;
+        self.angle = wrap_angle(self.angle + (self.speed + self.kp * error) * dt);
+    }
+
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+/// State of the FOC controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocState {
+    /// Not driving the motor
+    Stopped,
+    /// Turning the current vector at an increasing speed, until
+    /// the observer can take over
+    OpenLoop,
+    /// Following the observed rotor angle
+    ClosedLoop,
+    /// The observer did not follow the rotor at the handover, or
+    /// the rotor slowed down too much in closed loop
+    Failed,
+}
+
+/// Sensorless field-oriented control of one motor
+///
+/// Call [`update`](Self::update) with the phase currents at the
+/// start of each PWM period. It returns the duty cycles to load
+/// for the next period, assuming that they are applied from the
+/// start of the next period (as with preloaded timer compare
+/// registers).
+///
+/// The phases are in the order of the half bridges in the six-step
+/// commutation sequence of [`crate::MotorStep`], which turns the
+/// motor forwards: phase 1 leads phase 0 by 120 degrees, and phase
+/// 2 lags it (A, C, B in the usual naming). A positive q current
+/// turns the motor in the same direction as six-step commutation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FocController {
+    config: FocConfig,
+    state: FocState,
+
+    d_current: PiController,
+    q_current: PiController,
+    observer: FluxObserver,
+    pll: Pll,
+
+    // The angle and speed of the current vector in open loop
+    open_loop_angle: f32,
+    open_loop_speed: f32,
+
+    // The voltages applied in the current PWM period, and loaded
+    // for the next one
+    applied: AlphaBeta,
+    loaded: AlphaBeta,
+
+    // The last measured current
+    current: Dq,
+}
+
+impl FocController {
+    pub fn new(config: FocConfig) -> Self {
+        let current_pi = || {
+            PiController::new(
+                config.phase_inductance * config.current_bandwidth,
+                config.phase_resistance * config.current_bandwidth,
+            )
+        };
+        Self {
+            config,
+            state: FocState::Stopped,
+            d_current: current_pi(),
+            q_current: current_pi(),
+            observer: FluxObserver::new(&config),
+            pll: Pll::new(config.pll_bandwidth),
+            open_loop_angle: 0.0,
+            open_loop_speed: 0.0,
+            applied: AlphaBeta::default(),
+            loaded: AlphaBeta::default(),
+            current: Dq::default(),
+        }
+    }
+
+    pub fn config(&self) -> &FocConfig {
+        &self.config
+    }
+
+    /// Change the parameters (this stops the motor)
+    pub fn set_config(&mut self, config: FocConfig) {
+        *self = Self::new(config);
+    }
+
+    pub fn state(&self) -> FocState {
+        self.state
+    }
+
+    /// Whether the motor is being driven (in open or closed loop)
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, FocState::OpenLoop | FocState::ClosedLoop)
+    }
+
+    /// Start the motor in open loop
+    pub fn start(&mut self) {
+        *self = Self::new(self.config);
+        self.state = FocState::OpenLoop;
+    }
+
+    pub fn stop(&mut self) {
+        self.state = FocState::Stopped;
+    }
+
+    /// The observed rotor angle
+    pub fn angle(&self) -> f32 {
+        self.observer.angle()
+    }
+
+    /// The measured rotor speed
+    pub fn speed(&self) -> f32 {
+        self.pll.speed()
+    }
+
+    /// The last measured d and q currents
+    pub fn current(&self) -> Dq {
+        self.current
+    }
+
+    /// The six-step commutation step period at the measured
+    /// speed (in closed loop only)
+    pub fn step_period_us(&self) -> Option<u32> {
+        let speed = self.speed();
+        (self.state == FocState::ClosedLoop && speed > 0.0)
+            .then(|| (PI / 3.0 / speed * 1e6 + 0.5) as u32)
+    }
+
+    /// Run the current loop on the phase currents (A, positive
+    /// into the motor) measured `dt` seconds after the previous
+    /// update, with a target q current of `target_current` (A)
+    ///
+    /// Returns the duty cycles of the half bridges (which must all
+    /// be driving), or None when the motor is stopped or has
+    /// failed (when the half bridges should be high-Z).
+    pub fn update(
+        &mut self,
+        currents: [f32; 3],
+        bus_voltage: f32,
+        target_current: f32,
+        dt: f32,
+    ) -> Option<[f32; 3]> {
+        let [a, c, b] = currents;
+        let current = clarke([a, b, c]);
+
+        // The observer runs in open loop too, so that it has found
+        // the rotor by the handover
+        let observed = self.observer.update(self.applied, current, dt);
+        self.pll.update(observed, dt);
+
+        let config = &self.config;
+        let (angle, speed, target_current) = match self.state {
+            FocState::Stopped | FocState::Failed => return None,
+            FocState::OpenLoop => {
+                self.open_loop_speed += config.startup_acceleration * dt;
+                self.open_loop_angle = wrap_angle(self.open_loop_angle + self.open_loop_speed * dt);
+                if self.open_loop_speed >= config.handover_speed {
+                    // The rotor lags the current vector, but turns
+                    // at the same speed
+                    let error = self.pll.speed() - self.open_loop_speed;
+                    if error.abs() > 0.25 * self.open_loop_speed {
+                        self.state = FocState::Failed;
+                        return None;
+                    }
+                    self.state = FocState::ClosedLoop;
+                }
+                (
+                    self.open_loop_angle,
+                    self.open_loop_speed,
+                    config.startup_current,
+                )
+            }
+            FocState::ClosedLoop => {
+                if self.pll.speed() < config.min_speed {
+                    self.state = FocState::Failed;
+                    return None;
+                }
+                (observed, self.pll.speed(), target_current)
+            }
+        };
+
+        self.current = park(current, angle);
+        let max = max_voltage(bus_voltage);
+        let d = self.d_current.update(-self.current.d, dt, max);
+        let q_limit = sqrtf((max * max - d * d).max(0.0));
+        let q = self
+            .q_current
+            .update(target_current - self.current.q, dt, q_limit);
+
+        // The new voltage is applied from the start of the next
+        // period until the end of it, so on average 1.5 periods
+        // after the currents were measured
+        let voltage = inverse_park(Dq { d, q }, angle + 1.5 * speed * dt);
+        let [a, b, c] = space_vector(voltage, bus_voltage);
+        self.applied = self.loaded;
+        self.loaded = applied_voltage([a, b, c], bus_voltage);
+        Some([a, c, b])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "{actual} != {expected}"
+        );
+    }
+
+    // Balanced phase quantities (A, B, C) of amplitude 1.0 at angle
+    // `theta`
+    fn balanced(theta: f32) -> [f32; 3] {
+        [0.0, -TAU / 3.0, TAU / 3.0].map(|shift| cosf(theta + shift))
+    }
+
+    #[test]
+    fn transforms_balanced_phases() {
+        for n in 0..12 {
+            let theta = n as f32 * 0.5;
+            let v = clarke(balanced(theta));
+            assert_close(v.magnitude(), 1.0, 1e-5);
+            assert_close(wrap_angle(v.angle() - theta), 0.0, 1e-5);
+
+            // Constant in the frame turning with the phases
+            let dq = park(v, theta + 0.3);
+            assert_close(dq.d, cosf(0.3), 1e-5);
+            assert_close(dq.q, -sinf(0.3), 1e-5);
+
+            let back = inverse_clarke(inverse_park(dq, theta + 0.3));
+            for (x, y) in back.iter().zip(balanced(theta)) {
+                assert_close(*x, y, 1e-5);
+            }
+        }
+
+        // Common-mode offsets are removed
+        let offset = clarke(balanced(1.0).map(|x| x + 0.2));
+        assert_eq!(offset, clarke(balanced(1.0)));
+    }
+
+    #[test]
+    fn space_vector_duty_cycles() {
+        // Zero voltage: all phases at half duty cycle
+        assert_eq!(space_vector(AlphaBeta::default(), 12.0), [0.5; 3]);
+
+        for n in 0..12 {
+            let theta = n as f32 * 0.5;
+            let v = inverse_park(Dq { d: 0.0, q: 5.0 }, theta);
+            let duties = space_vector(v, 12.0);
+            assert!(duties.iter().all(|d| (0.0..=1.0).contains(d)));
+            let applied = applied_voltage(duties, 12.0);
+            assert_close(applied.alpha, v.alpha, 1e-4);
+            assert_close(applied.beta, v.beta, 1e-4);
+
+            // The largest vector is applied without distortion
+            // (using the whole duty cycle range when a line-to-line
+            // voltage peaks, at 30 degrees from a phase)
+            let max = inverse_park(
+                Dq {
+                    d: max_voltage(12.0),
+                    q: 0.0,
+                },
+                theta,
+            );
+            let duties = space_vector(max, 12.0);
+            let highest = duties.iter().fold(0.0f32, |a, &b| a.max(b));
+            let lowest = duties.iter().fold(1.0f32, |a, &b| a.min(b));
+            assert!(highest - lowest <= 1.0 + 1e-5);
+            let applied = applied_voltage(duties, 12.0);
+            assert_close(applied.alpha, max.alpha, 1e-4);
+            assert_close(applied.beta, max.beta, 1e-4);
+        }
+
+        let peak = inverse_park(
+            Dq {
+                d: max_voltage(12.0),
+                q: 0.0,
+            },
+            PI / 6.0,
+        );
+        let duties = space_vector(peak, 12.0);
+        assert_close(duties[0], 1.0, 1e-5);
+        assert_close(duties[1], 0.5, 1e-5);
+        assert_close(duties[2], 0.0, 1e-5);
+    }
+
+    #[test]
+    fn pi_integral_does_not_wind_up() {
+        let mut pi = PiController::new(0.5, 100.0);
+        for _ in 0..1000 {
+            assert_eq!(pi.update(10.0, 1e-3, 2.0), 2.0);
+        }
+
+        // When the error reverses, the output follows straight away
+        assert!(pi.update(-1.0, 1e-3, 2.0) < 2.0 - 0.5);
+    }
+
+    #[test]
+    fn observer_and_pll_follow_rotor() {
+        // No current: the voltage is the back-EMF of the rotor
+        // turning at 3000 rad/s, starting at 1 rad (which the
+        // observer does not know)
+        let config = FocConfig::default();
+        let mut observer = FluxObserver::new(&config);
+        let mut pll = Pll::new(config.pll_bandwidth);
+        let (speed, dt) = (3000.0, 20e-6);
+        let mut theta: f32 = 1.0;
+        for _ in 0..5000 {
+            theta = wrap_angle(theta + speed * dt);
+            // The voltage is the derivative of the flux
+            let back_emf = inverse_park(
+                Dq {
+                    d: 0.0,
+                    q: config.flux_linkage * speed,
+                },
+                theta,
+            );
+            let angle = observer.update(back_emf, AlphaBeta::default(), dt);
+            pll.update(angle, dt);
+        }
+        assert_close(observer.flux().magnitude(), config.flux_linkage, 1e-5);
+        assert_close(wrap_angle(observer.angle() - theta), 0.0, 0.05);
+        assert_close(pll.speed(), speed, 10.0);
+    }
+
+    #[test]
+    fn drives_only_when_started() {
+        let mut foc = FocController::new(FocConfig::default());
+        assert_eq!(foc.update([0.0; 3], 12.0, 1.0, 20e-6), None);
+
+        // No current flows yet: the q voltage rises to drive the
+        // start-up current
+        foc.start();
+        assert_eq!(foc.state(), FocState::OpenLoop);
+        let mut duties = [0.5; 3];
+        for _ in 0..10 {
+            duties = foc.update([0.0; 3], 12.0, 1.0, 20e-6).unwrap();
+        }
+        assert!(duties.iter().any(|d| (d - 0.5).abs() > 0.01));
+        assert_eq!(foc.step_period_us(), None);
+
+        foc.stop();
+        assert_eq!(foc.update([0.0; 3], 12.0, 1.0, 20e-6), None);
+        assert!(!foc.is_running());
+    }
+}
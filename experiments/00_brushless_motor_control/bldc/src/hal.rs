@@ -0,0 +1,93 @@
+//! Hardware abstraction traits for the three-phase controller
+//!
+//! The controller drives three half bridges, each with two
+//! inputs: a PWM signal which switches the half bridge between
+//! driving and high-Z (both MOSFETs off), and a signal which
+//! selects whether the high-side or low-side MOSFET is on
+//! when the half bridge is driving. The phase voltages are
+//! measured by an ADC for the back-EMF zero-crossing detection
+//! (optionally with the motor current and supply voltage).
+
+use crate::pwm::{PwmError, PwmTiming};
+
+/// Selects the high-side or low-side MOSFET of each half bridge
+pub trait PhaseDriver {
+    /// Set the half bridge for phase `which` (0, 1 or 2) to
+    /// pull up (high-side on, low-side off), or pull down
+    /// (high-side off, low-side on), when it is not high-Z
+    fn pull_phase_up(&mut self, which: usize, pull_up: bool);
+}
+
+/// Three synchronised PWM outputs (one per half bridge)
+///
+/// A PWM output which is high enables its half bridge, and a
+/// PWM output which is low sets it to high-Z.
+pub trait ThreePhasePwm {
+    /// Turn the PWM outputs on or off
+    fn enable(&mut self, enable: bool);
+
+    /// Set the PWM frequency, and return the timer settings used
+    /// (which give the achieved frequency and duty cycle
+    /// resolution). The frequency is not changed if it cannot be
+    /// set.
+    fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError>;
+
+    /// The PWM frequency achieved, in Hz
+    fn frequency_hz(&self) -> f32;
+
+    /// Set the duty cycle (between 0.0 and 1.0) of the PWM
+    /// output for phase `which` (0, 1 or 2). A duty cycle of
+    /// 1.0 must hold the output high for the whole period.
+    fn set_duty(&mut self, which: usize, duty: f32);
+
+    /// Trigger the phase voltage conversion at `position` (between
+    /// 0.0 and 1.0) through each PWM period
+    fn set_adc_trigger(&mut self, position: f32);
+}
+
+/// Source of phase voltage measurements
+pub trait PhaseVoltageSampler {
+    /// The most recent measurement of the three phase voltages
+    /// (raw ADC values)
+    fn phase_voltages(&self) -> [u16; 3];
+
+    /// The motor current (A) measured with the phase voltages, if
+    /// it is measured
+    fn current(&self) -> Option<f32> {
+        None
+    }
+
+    /// The supply voltage (V) measured with the phase voltages, if
+    /// it is measured
+    fn bus_voltage(&self) -> Option<f32> {
+        None
+    }
+
+    /// The three phase currents (A) measured with the phase
+    /// voltages, if they are measured (FOC needs them)
+    fn phase_currents(&self) -> Option<[f32; 3]> {
+        None
+    }
+}
+
+/// Timer which expires when the next commutation is due
+///
+/// The timer also measures the time since the last commutation,
+/// because it is restarted every time the motor commutates.
+pub trait CommutationTimer {
+    /// Time since the timer was last started (in microseconds)
+    fn elapsed_us(&self) -> u32;
+
+    /// Restart the timer, so that it expires after `timeout_us`
+    ///
+    /// Fails (leaving the timer stopped) if the timer cannot count
+    /// that long.
+    fn start(&mut self, timeout_us: u32) -> Result<(), TimeoutTooLong>;
+}
+
+/// The commutation timer cannot count the time asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutTooLong {
+    /// The longest time the timer can count (in microseconds)
+    pub max_us: u32,
+}
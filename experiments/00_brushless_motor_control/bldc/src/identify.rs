@@ -0,0 +1,583 @@
+//! Measuring the motor parameters
+//!
+//! The identification runs the motor through three measurements,
+//! using the motor current and supply voltage channels:
+//!
+//! 1. Resistance: hold one commutation step, raising the duty
+//!    cycle until the test current flows. Two phases are in
+//!    series, so the phase resistance is the applied voltage
+//!    (duty cycle times supply voltage) over twice the current.
+//! 2. Inductance: after letting the current decay, apply the same
+//!    duty cycle again, and time how long the current takes to
+//!    reach 63% of its final value. This is the time constant
+//!    L / R of the windings.
+//! 3. Back-EMF: start the motor, and run it in closed loop. The
+//!    applied voltage is the resistive drop plus the back-EMF
+//!    of the two driven phases, which is proportional to the
+//!    speed measured from the commutation step period.
+//!
+//! The number of pole pairs cannot be seen from the electrical
+//! measurements alone. It is the ratio of the measured
+//! (electrical) back-EMF constant to the rated (mechanical) Kv of
+//! the motor, if the rated Kv is given.
+//!
+//! The state machine is advanced by the controller (see
+//! [`ThreePhaseController::identify`](crate::ThreePhaseController::identify)):
+//! [`Identification::on_timer`] whenever the commutation timer
+//! expires, [`Identification::on_samples`] with every current
+//! measurement, and [`Identification::on_step`] at every
+//! commutation in closed loop.
+
+use crate::foc::FocConfig;
+use crate::speed::SpeedConfig;
+use crate::startup::{StartupConfig, MAX_STARTUP_US};
+use core::f32::consts::PI;
+
+/// Parameters of the identification
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdentifyConfig {
+    /// The current at which the resistance and inductance are
+    /// measured (A). The derived start-up sequence aligns the rotor
+    /// with this current too.
+    pub test_current: f32,
+
+    /// The increase in duty cycle from one hold to the next, while
+    /// looking for the test current
+    pub duty_step: f32,
+
+    /// The highest duty cycle to try
+    pub max_duty: f32,
+
+    /// How long each duty cycle is held (and how long the current
+    /// is left to decay)
+    pub hold_us: u32,
+
+    /// Time for the current to settle at the start of each hold,
+    /// before it is measured
+    pub settle_us: u32,
+
+    /// Commutation steps in closed loop before the back-EMF is
+    /// measured (for the speed to settle)
+    pub settle_steps: u32,
+
+    /// Commutation steps over which the back-EMF is measured
+    pub measure_steps: u32,
+
+    /// The line-to-line back-EMF, as a fraction of the supply
+    /// voltage, at which the derived start-up ramp ends
+    pub handover_back_emf: f32,
+
+    /// The rated Kv of the motor (mechanical RPM per volt), to
+    /// find the number of pole pairs
+    pub rated_kv: Option<f32>,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        Self {
+            test_current: 1.0,
+            duty_step: 0.002,
+            max_duty: 0.2,
+            hold_us: 10_000,
+            settle_us: 2000,
+            settle_steps: 300,
+            measure_steps: 600,
+            handover_back_emf: 0.1,
+            rated_kv: None,
+        }
+    }
+}
+
+/// The measured motor parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorParameters {
+    /// Resistance of one phase (ohm)
+    pub phase_resistance: f32,
+
+    /// Inductance of one phase (H)
+    pub phase_inductance: f32,
+
+    /// Peak line-to-line back-EMF per electrical rad/s (V s)
+    pub back_emf_constant: f32,
+
+    /// Number of pole pairs, if known
+    pub pole_pairs: Option<u8>,
+}
+
+impl MotorParameters {
+    /// The velocity constant (mechanical RPM per volt) for
+    /// `pole_pairs` pairs of poles
+    pub fn kv(&self, pole_pairs: u8) -> f32 {
+        60.0 / (2.0 * PI * self.back_emf_constant * pole_pairs as f32)
+    }
+
+    /// The line-to-line back-EMF (V) at a commutation step period
+    /// of `step_period_us`
+    pub fn back_emf(&self, step_period_us: f32) -> f32 {
+        self.back_emf_constant * PI / 3.0 / (step_period_us * 1e-6)
+    }
+
+    /// The commutation step period (µs) at which the line-to-line
+    /// back-EMF is `back_emf` (V)
+    pub fn step_period_us(&self, back_emf: f32) -> f32 {
+        self.back_emf_constant * PI / 3.0 / back_emf * 1e6
+    }
+
+    /// The start-up sequence for this motor on a supply of
+    /// `bus_voltage` (the counts and times not derived from the
+    /// parameters are taken from `base`)
+    ///
+    /// The rotor is aligned with the test current, and the ramp
+    /// ends when the back-EMF reaches the handover fraction of the
+    /// supply voltage (it starts five times slower). The ramp duty
+    /// cycle at the start drives the test current against the
+    /// back-EMF. By the end of the ramp, it only covers three
+    /// quarters of the back-EMF, so that the rotor lags the
+    /// commutation, and the zero crossings fall inside the steps
+    /// where they are detected (a rotor which runs ahead of the
+    /// commutation is never handed over to the closed loop).
+    ///
+    /// The ramp periods are limited to [`MAX_STARTUP_US`] (a motor
+    /// with a large back-EMF constant starts its ramp faster than
+    /// five times the end period).
+    pub fn startup_config(
+        &self,
+        config: &IdentifyConfig,
+        bus_voltage: f32,
+        base: &StartupConfig,
+    ) -> StartupConfig {
+        let resistive = 2.0 * self.phase_resistance * config.test_current / bus_voltage;
+        let end_period_us = self.step_period_us(config.handover_back_emf * bus_voltage);
+        StartupConfig {
+            align_duty: resistive.min(1.0),
+            ramp_start_period_us: startup_period_us(5.0 * end_period_us),
+            ramp_end_period_us: startup_period_us(end_period_us),
+            ramp_start_duty: (config.handover_back_emf / 5.0 + resistive).min(1.0),
+            ramp_end_duty: (0.75 * config.handover_back_emf + resistive).min(1.0),
+            ..*base
+        }
+    }
+
+    /// The FOC settings for this motor (assuming the back-EMF is
+    /// sinusoidal)
+    pub fn foc_config(&self, base: &FocConfig) -> FocConfig {
+        FocConfig {
+            phase_resistance: self.phase_resistance,
+            phase_inductance: self.phase_inductance,
+            flux_linkage: self.back_emf_constant / libm::sqrtf(3.0),
+            ..*base
+        }
+    }
+
+    /// The speed controller settings for this motor
+    pub fn speed_config(&self, base: &SpeedConfig) -> SpeedConfig {
+        SpeedConfig {
+            pole_pairs: self.pole_pairs.unwrap_or(base.pole_pairs),
+            ..*base
+        }
+    }
+}
+
+/// Why the identification failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentifyError {
+    /// The motor current is not measured
+    NoCurrentMeasurement,
+    /// The test current was not reached at the highest duty cycle
+    CurrentTooLow,
+    /// The current did not rise to 63% of its final value
+    NoCurrentRise,
+    /// The motor was stopped (or a fault latched) before the
+    /// back-EMF was measured
+    Stopped,
+}
+
+impl IdentifyError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdentifyError::NoCurrentMeasurement => "no current measurement",
+            IdentifyError::CurrentTooLow => "current too low",
+            IdentifyError::NoCurrentRise => "no current rise",
+            IdentifyError::Stopped => "stopped",
+        }
+    }
+}
+
+/// What to do when the commutation timer expires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentifyAction {
+    /// Hold the current commutation step at `duty` for `time_us`
+    Hold { duty: f32, time_us: u32 },
+    /// Set all the half bridges to high-Z for `time_us`
+    Float { time_us: u32 },
+    /// Start the motor (the back-EMF is measured in closed loop)
+    Run,
+    /// Finished (see [`Identification::result`])
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Resistance,
+    Decay,
+    Inductance,
+    BackEmf,
+    Done,
+}
+
+/// Identification state machine
+pub struct Identification {
+    config: IdentifyConfig,
+    state: State,
+    result: Option<Result<MotorParameters, IdentifyError>>,
+
+    // The duty cycle being held, and the latest supply voltage
+    duty: f32,
+    bus_voltage: f32,
+
+    // The settled current in this hold
+    current_sum: f32,
+    current_samples: u32,
+
+    // The resistance, and the current it was measured at
+    resistance: f32,
+    final_current: f32,
+
+    // The last current sample while the current is rising (time
+    // since the start of the hold, and current), and the time
+    // constant once it has risen far enough
+    previous: (u32, f32),
+    time_constant_us: Option<f32>,
+    inductance: f32,
+
+    // Commutation steps in closed loop, and the sums of the step
+    // periods and back-EMF while measuring
+    steps: u32,
+    period_sum: f32,
+    back_emf_sum: f32,
+}
+
+impl Identification {
+    pub fn new(config: IdentifyConfig) -> Self {
+        Self {
+            config,
+            state: State::Idle,
+            result: None,
+            duty: 0.0,
+            bus_voltage: 0.0,
+            current_sum: 0.0,
+            current_samples: 0,
+            resistance: 0.0,
+            final_current: 0.0,
+            previous: (0, 0.0),
+            time_constant_us: None,
+            inductance: 0.0,
+            steps: 0,
+            period_sum: 0.0,
+            back_emf_sum: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &IdentifyConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: IdentifyConfig) {
+        self.config = config;
+    }
+
+    /// Start measuring (the motor must be stopped)
+    pub fn start(&mut self) {
+        *self = Self::new(self.config);
+        self.state = State::Resistance;
+    }
+
+    /// Whether the identification is in progress
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, State::Idle | State::Done)
+    }
+
+    /// Whether the identification is driving the windings directly
+    /// (rather than through the start-up sequence)
+    pub fn drives_windings(&self) -> bool {
+        matches!(
+            self.state,
+            State::Resistance | State::Decay | State::Inductance
+        )
+    }
+
+    /// The result of the last identification, once finished
+    pub fn result(&self) -> Option<Result<MotorParameters, IdentifyError>> {
+        self.result
+    }
+
+    /// Stop measuring (the motor has been stopped, or a fault
+    /// latched)
+    pub fn abort(&mut self) {
+        if self.is_active() {
+            self.finish(Err(IdentifyError::Stopped));
+        }
+    }
+
+    /// Process a current measurement (A), with the supply voltage
+    /// (V), `since_us` after the timer was last started
+    pub fn on_samples(&mut self, since_us: u32, current: f32, bus_voltage: f32) {
+        self.bus_voltage = bus_voltage;
+        match self.state {
+            State::Resistance if since_us >= self.config.settle_us => {
+                self.current_sum += current;
+                self.current_samples += 1;
+            }
+            State::Inductance if self.time_constant_us.is_none() => {
+                let target = (1.0 - libm::expf(-1.0)) * self.final_current;
+                let (t0, i0) = self.previous;
+                if current >= target && current > i0 {
+                    let fraction = (target - i0) / (current - i0);
+                    self.time_constant_us = Some(t0 as f32 + fraction * (since_us - t0) as f32);
+                }
+                self.previous = (since_us, current);
+            }
+            _ => {}
+        }
+    }
+
+    /// Advance the measurement of the windings when the commutation
+    /// timer expires
+    pub fn on_timer(&mut self) -> IdentifyAction {
+        let hold_us = self.config.hold_us;
+        match self.state {
+            State::Resistance => {
+                if self.duty > 0.0 && self.current_samples == 0 {
+                    return self.finish(Err(IdentifyError::NoCurrentMeasurement));
+                }
+                let current = match self.current_samples {
+                    0 => 0.0,
+                    n => self.current_sum / n as f32,
+                };
+                self.current_sum = 0.0;
+                self.current_samples = 0;
+
+                if current >= self.config.test_current {
+                    self.resistance = self.duty * self.bus_voltage / (2.0 * current);
+                    self.final_current = current;
+                    self.state = State::Decay;
+                    return IdentifyAction::Float { time_us: hold_us };
+                }
+
+                self.duty += self.config.duty_step;
+                if self.duty > self.config.max_duty {
+                    return self.finish(Err(IdentifyError::CurrentTooLow));
+                }
+                IdentifyAction::Hold {
+                    duty: self.duty,
+                    time_us: hold_us,
+                }
+            }
+            State::Decay => {
+                self.state = State::Inductance;
+                self.previous = (0, 0.0);
+                self.time_constant_us = None;
+                IdentifyAction::Hold {
+                    duty: self.duty,
+                    time_us: hold_us,
+                }
+            }
+            State::Inductance => match self.time_constant_us {
+                Some(time_constant_us) => {
+                    self.inductance = time_constant_us * 1e-6 * self.resistance;
+                    self.state = State::BackEmf;
+                    IdentifyAction::Run
+                }
+                None => self.finish(Err(IdentifyError::NoCurrentRise)),
+            },
+            State::BackEmf => IdentifyAction::Run,
+            State::Idle | State::Done => IdentifyAction::Done,
+        }
+    }
+
+    /// Process a commutation in closed loop, with the measured step
+    /// period, and the duty cycle, current (A) and supply voltage
+    /// (V) over the step
+    pub fn on_step(&mut self, step_period_us: u32, duty: f32, current: f32, bus_voltage: f32) {
+        if self.state != State::BackEmf {
+            return;
+        }
+        self.steps += 1;
+        if self.steps <= self.config.settle_steps {
+            return;
+        }
+
+        self.period_sum += step_period_us as f32;
+        self.back_emf_sum += duty * bus_voltage - 2.0 * self.resistance * current;
+        if self.steps < self.config.settle_steps + self.config.measure_steps {
+            return;
+        }
+
+        let n = self.config.measure_steps as f32;
+        let step_period_us = self.period_sum / n;
+        let speed = PI / 3.0 / (step_period_us * 1e-6);
+        let back_emf_constant = self.back_emf_sum / n / speed;
+
+        // Kv = 60 / (2 pi * Ke * pole pairs), with Ke per electrical
+        // rad/s
+        let pole_pairs = self.config.rated_kv.map(|kv| {
+            let pole_pairs = 60.0 / (2.0 * PI * back_emf_constant * kv);
+            libm::roundf(pole_pairs).max(1.0) as u8
+        });
+        self.finish(Ok(MotorParameters {
+            phase_resistance: self.resistance,
+            phase_inductance: self.inductance,
+            back_emf_constant,
+            pole_pairs,
+        }));
+    }
+
+    fn finish(&mut self, result: Result<MotorParameters, IdentifyError>) -> IdentifyAction {
+        self.state = State::Done;
+        self.result = Some(result);
+        IdentifyAction::Done
+    }
+}
+
+// A ramp step period (µs) which the commutation timer can time
+fn startup_period_us(period_us: f32) -> u32 {
+    (period_us as u32).clamp(1, MAX_STARTUP_US)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p1604() -> MotorParameters {
+        MotorParameters {
+            phase_resistance: 0.07,
+            phase_inductance: 10e-6,
+            // 60 / (2 pi * 3800 Kv * 7 pole pairs)
+            back_emf_constant: 3.59e-4,
+            pole_pairs: Some(7),
+        }
+    }
+
+    // Run the resistance and inductance measurements on an R-L
+    // circuit (two phases in series) sampled every 20 us
+    fn measure_windings(identification: &mut Identification, bus_voltage: f32) -> IdentifyAction {
+        let (r, l) = (0.07, 10e-6);
+        let mut action = identification.on_timer();
+        for _ in 0..1000 {
+            match action {
+                IdentifyAction::Hold { duty, time_us } => {
+                    let final_current = duty * bus_voltage / (2.0 * r);
+                    for t in (20..=time_us).step_by(20) {
+                        let rise = 1.0 - libm::expf(-(t as f32 * 1e-6) * r / l);
+                        identification.on_samples(t, final_current * rise, bus_voltage);
+                    }
+                }
+                IdentifyAction::Float { .. } => {}
+                _ => return action,
+            }
+            action = identification.on_timer();
+        }
+        action
+    }
+
+    #[test]
+    fn measures_resistance_and_inductance() {
+        let mut identification = Identification::new(IdentifyConfig::default());
+        identification.start();
+        assert!(identification.drives_windings());
+        assert_eq!(
+            measure_windings(&mut identification, 12.0),
+            IdentifyAction::Run
+        );
+        assert!(identification.is_active());
+        assert!(!identification.drives_windings());
+
+        // 60 back-EMF measurements at 12000 eRPM, after 10 to settle
+        identification.set_config(IdentifyConfig {
+            settle_steps: 10,
+            measure_steps: 60,
+            rated_kv: Some(3800.0),
+            ..*identification.config()
+        });
+        let expected = p1604();
+        let step_period_us = 833;
+        let back_emf = expected.back_emf(step_period_us as f32);
+        for _ in 0..70 {
+            let duty = (back_emf + 2.0 * 0.07 * 0.5) / 12.0;
+            identification.on_step(step_period_us, duty, 0.5, 12.0);
+        }
+
+        let parameters = identification.result().unwrap().unwrap();
+        assert!((parameters.phase_resistance - 0.07).abs() < 0.002);
+        assert!((parameters.phase_inductance - 10e-6).abs() < 0.5e-6);
+        assert!((parameters.back_emf_constant - expected.back_emf_constant).abs() < 1e-6);
+        assert_eq!(parameters.pole_pairs, Some(7));
+        assert!((parameters.kv(7) - 3800.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn fails_without_current() {
+        let mut identification = Identification::new(IdentifyConfig::default());
+        identification.start();
+        identification.on_timer();
+        assert_eq!(identification.on_timer(), IdentifyAction::Done);
+        assert_eq!(
+            identification.result(),
+            Some(Err(IdentifyError::NoCurrentMeasurement))
+        );
+
+        // Too little current at the highest duty cycle
+        identification.start();
+        let mut action = identification.on_timer();
+        while let IdentifyAction::Hold { time_us, .. } = action {
+            identification.on_samples(time_us, 0.1, 12.0);
+            action = identification.on_timer();
+        }
+        assert_eq!(
+            identification.result(),
+            Some(Err(IdentifyError::CurrentTooLow))
+        );
+    }
+
+    #[test]
+    fn derives_startup_config() {
+        let parameters = p1604();
+        let config = IdentifyConfig::default();
+        let startup = parameters.startup_config(&config, 12.0, &StartupConfig::default());
+
+        // 1.2 V of back-EMF at the end of the ramp
+        assert!((parameters.back_emf(startup.ramp_end_period_us as f32) - 1.2).abs() < 0.01);
+        assert_eq!(startup.ramp_start_period_us / 5, startup.ramp_end_period_us);
+        assert!((startup.align_duty - 0.14 / 12.0).abs() < 1e-4);
+        assert!((startup.ramp_start_duty - 0.02 - 0.14 / 12.0).abs() < 1e-4);
+        assert!((startup.ramp_end_duty - 0.075 - 0.14 / 12.0).abs() < 1e-4);
+        assert_eq!(startup.ramp_steps, StartupConfig::default().ramp_steps);
+
+        let foc = parameters.foc_config(&FocConfig::default());
+        assert!((foc.flux_linkage - FocConfig::default().flux_linkage).abs() < 1e-6);
+    }
+
+    #[test]
+    fn limits_startup_periods() {
+        // A motor with 100 times the back-EMF constant reaches the
+        // handover back-EMF with a step period of about 30 ms, and
+        // the ramp would start five times slower than that
+        let parameters = MotorParameters {
+            back_emf_constant: 3.59e-2,
+            ..p1604()
+        };
+        let config = IdentifyConfig::default();
+        let startup = parameters.startup_config(&config, 12.0, &StartupConfig::default());
+        assert!(startup.ramp_end_period_us > 20_000);
+        assert_eq!(startup.ramp_start_period_us, MAX_STARTUP_US);
+
+        let parameters = MotorParameters {
+            back_emf_constant: 3.59e-1,
+            ..parameters
+        };
+        let startup = parameters.startup_config(&config, 12.0, &StartupConfig::default());
+        assert_eq!(startup.ramp_end_period_us, MAX_STARTUP_US);
+        assert_eq!(startup.ramp_start_period_us, MAX_STARTUP_US);
+    }
+}
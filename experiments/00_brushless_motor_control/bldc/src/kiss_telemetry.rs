@@ -0,0 +1,246 @@
+//! KISS ESC serial telemetry
+//!
+//! Besides the DShot line, an ESC can report its state to the
+//! flight controller over a one-wire (half-duplex) UART at
+//! 115200 baud, in the frame format of the KISS ESCs (which
+//! BLHeli_32 uses too). A frame is 10 bytes, with the 16-bit
+//! values big-endian:
+//!
+//! | Bytes | Field                                |
+//! |-------|--------------------------------------|
+//! | 0     | Temperature (degrees C)              |
+//! | 1, 2  | Voltage (0.01 V)                     |
+//! | 3, 4  | Current (0.01 A)                     |
+//! | 5, 6  | Consumption (mAh)                    |
+//! | 7, 8  | Electrical RPM (100 eRPM)            |
+//! | 9     | CRC8 of bytes 0 to 8                 |
+//!
+//! The CRC is the CRC-8 with polynomial 0x07 (no reflection, and
+//! starting from 0).
+//!
+
+use crate::controller::ThreePhaseController;
+use crate::hal::{PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+use crate::speed::electrical_rpm;
+
+/// The length of a frame (including the CRC)
+pub const FRAME_LEN: usize = 10;
+
+/// The UART baud rate
+pub const BAUD_RATE: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KissError {
+    /// The CRC does not match the rest of the frame
+    Crc,
+}
+
+/// The contents of a telemetry frame
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct KissTelemetry {
+    /// Temperature in degrees C
+    pub temperature_c: u8,
+    /// Supply voltage in V
+    pub voltage: f32,
+    /// Motor current in A
+    pub current: f32,
+    /// Charge drawn from the supply in mAh
+    pub consumption_mah: u16,
+    /// Electrical RPM
+    pub erpm: u32,
+}
+
+impl KissTelemetry {
+    /// The telemetry from the state of a motor controller
+    ///
+    /// Quantities which have not been measured are sent as 0. The
+    /// controller does not measure the temperature, so set
+    /// `temperature_c` afterwards if it is measured elsewhere.
+    pub fn from_controller<D, P, S>(controller: &ThreePhaseController<D, P, S>) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        Self {
+            temperature_c: 0,
+            voltage: controller.bus_voltage().unwrap_or(0.0),
+            current: controller.current().unwrap_or(0.0),
+            consumption_mah: (controller.consumed_mah() + 0.5) as u16,
+            erpm: controller
+                .step_period_us()
+                .map_or(0, |period| (electrical_rpm(period) + 0.5) as u32),
+        }
+    }
+
+    /// Encode the telemetry as a frame (including the CRC)
+    ///
+    /// Values which do not fit are sent as the largest value which
+    /// does.
+    pub fn encode(&self) -> [u8; FRAME_LEN] {
+        let voltage = (self.voltage * 100.0 + 0.5) as u16;
+        let current = (self.current * 100.0 + 0.5) as u16;
+        let erpm = ((self.erpm + 50) / 100).min(u16::MAX as u32) as u16;
+
+        let mut frame = [0; FRAME_LEN];
+        frame[0] = self.temperature_c;
+        frame[1..3].copy_from_slice(&voltage.to_be_bytes());
+        frame[3..5].copy_from_slice(&current.to_be_bytes());
+        frame[5..7].copy_from_slice(&self.consumption_mah.to_be_bytes());
+        frame[7..9].copy_from_slice(&erpm.to_be_bytes());
+        frame[9] = crc8(&frame[..9]);
+        frame
+    }
+
+    /// Decode a frame, checking the CRC
+    pub fn decode(frame: &[u8; FRAME_LEN]) -> Result<Self, KissError> {
+        if crc8(&frame[..9]) != frame[9] {
+            return Err(KissError::Crc);
+        }
+
+        let value = |n: usize| u16::from_be_bytes([frame[n], frame[n + 1]]);
+        Ok(Self {
+            temperature_c: frame[0],
+            voltage: value(1) as f32 / 100.0,
+            current: value(3) as f32 / 100.0,
+            consumption_mah: value(5),
+            erpm: value(7) as u32 * 100,
+        })
+    }
+}
+
+/// Finds telemetry frames in a stream of received bytes (e.g. on
+/// the host, from a USB serial adapter on the telemetry line)
+///
+/// Nothing marks the start of a frame, so the decoder keeps the
+/// last [`FRAME_LEN`] bytes, and takes them as a frame when their
+/// CRC matches.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KissDecoder {
+    buffer: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl KissDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a received byte, returning the telemetry if it completes
+    /// a frame
+    pub fn push(&mut self, byte: u8) -> Option<KissTelemetry> {
+        if self.len == FRAME_LEN {
+            self.buffer.copy_within(1.., 0);
+            self.len -= 1;
+        }
+        self.buffer[self.len] = byte;
+        self.len += 1;
+
+        if self.len < FRAME_LEN {
+            return None;
+        }
+        let telemetry = KissTelemetry::decode(&self.buffer).ok()?;
+        self.len = 0;
+        Some(telemetry)
+    }
+}
+
+/// The CRC-8 (polynomial 0x07) of `bytes`
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDriver, MockPwm, MockSampler};
+
+    #[test]
+    fn crc8_check_value() {
+        // The standard check value of CRC-8 (polynomial 0x07)
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn encodes_frame() {
+        let telemetry = KissTelemetry {
+            temperature_c: 35,
+            voltage: 12.6,
+            current: 3.25,
+            consumption_mah: 420,
+            erpm: 60_000,
+        };
+        let frame = telemetry.encode();
+        assert_eq!(
+            frame[..9],
+            [35, 0x04, 0xec, 0x01, 0x45, 0x01, 0xa4, 0x02, 0x58]
+        );
+        assert_eq!(frame[9], crc8(&frame[..9]));
+
+        let decoded = KissTelemetry::decode(&frame).unwrap();
+        assert_eq!(decoded.temperature_c, 35);
+        assert!((decoded.voltage - 12.6).abs() < 0.005);
+        assert!((decoded.current - 3.25).abs() < 0.005);
+        assert_eq!(decoded.consumption_mah, 420);
+        assert_eq!(decoded.erpm, 60_000);
+
+        let mut corrupted = frame;
+        corrupted[4] ^= 0x10;
+        assert_eq!(KissTelemetry::decode(&corrupted), Err(KissError::Crc));
+    }
+
+    #[test]
+    fn finds_frames_in_a_stream() {
+        let first = KissTelemetry {
+            temperature_c: 40,
+            voltage: 16.8,
+            ..KissTelemetry::default()
+        };
+        let second = KissTelemetry {
+            erpm: 12_300,
+            ..first
+        };
+
+        // Start part way through a frame
+        let mut stream = [0; 3 + 2 * FRAME_LEN];
+        stream[..3].copy_from_slice(&[0x12, 0x00, 0x5a]);
+        stream[3..13].copy_from_slice(&first.encode());
+        stream[13..].copy_from_slice(&second.encode());
+
+        let mut decoder = KissDecoder::new();
+        let mut decoded = stream.iter().filter_map(|&byte| decoder.push(byte));
+        assert_eq!(decoded.next().map(|t| t.temperature_c), Some(40));
+        assert_eq!(decoded.next().map(|t| t.erpm), Some(12_300));
+        assert_eq!(decoded.next(), None);
+    }
+
+    #[test]
+    fn from_controller_state() {
+        let mut c = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        );
+
+        // Nothing measured, and not running in closed loop
+        assert_eq!(KissTelemetry::from_controller(&c), KissTelemetry::default());
+
+        c.check_bus_voltage(11.1);
+        c.check_current(7.2);
+        c.add_consumption(1_000_000);
+        let telemetry = KissTelemetry::from_controller(&c);
+        assert_eq!(telemetry.voltage, 11.1);
+        assert_eq!(telemetry.current, 7.2);
+        assert_eq!(telemetry.consumption_mah, 2);
+        assert_eq!(telemetry.erpm, 0);
+    }
+}
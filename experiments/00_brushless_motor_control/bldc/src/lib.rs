@@ -0,0 +1,61 @@
+//! Hardware-independent BLDC motor control logic
+//!
+//! Everything in this crate is independent of the STM32F746
+//! peripherals, so that it can be shared by the motor control
+//! firmware and tested on the host with `cargo test`.
+
+#![no_std]
+
+pub mod brake;
+pub mod config;
+pub mod controller;
+pub mod dead_time;
+pub mod dshot;
+pub mod dshot_telemetry;
+pub mod fault;
+pub mod foc;
+pub mod hal;
+pub mod identify;
+pub mod kiss_telemetry;
+pub mod mock;
+pub mod motor;
+pub mod parameter;
+pub mod protocol;
+pub mod pulse_input;
+pub mod pwm;
+pub mod samples;
+pub mod speed;
+pub mod startup;
+pub mod step;
+pub mod stream;
+pub mod zero_crossing;
+
+pub use brake::{Brake, BrakeConfig, BrakeMode};
+pub use config::{
+    check_configs, AdcInput, AdcScale, AdcSequence, AdcTrigger, ConfigError, MotorConfig,
+    SampleTime,
+};
+pub use controller::{ControlMode, ModeError, StartError, ThreePhaseController, DEFAULT_DUTY};
+pub use dead_time::{dead_time_bits, DeadTimeError};
+pub use dshot::{DshotAction, DshotCommand, DshotError, DshotFrame, DshotInput};
+pub use dshot_telemetry::{ExtendedReadings, Telemetry, TelemetryError, TelemetryScheduler};
+pub use fault::{FaultConfig, FaultMonitor, MotorFault};
+pub use foc::{FocConfig, FocController, FocState};
+pub use hal::{CommutationTimer, PhaseDriver, PhaseVoltageSampler, ThreePhasePwm, TimeoutTooLong};
+pub use identify::{
+    Identification, IdentifyAction, IdentifyConfig, IdentifyError, MotorParameters,
+};
+pub use kiss_telemetry::{KissDecoder, KissError, KissTelemetry};
+pub use motor::Motor;
+pub use parameter::{Parameter, ParameterError, ParameterInfo, ParameterType};
+pub use protocol::{
+    ErrorCode, Frame, FrameDecoder, ProtocolError, Received, Request, Response, StatusReport,
+};
+pub use pulse_input::{PulseAction, PulseCalibration, PulseInput, PulseInputConfig, PulseProtocol};
+pub use pwm::{PwmError, PwmTiming};
+pub use samples::{AdcSnapshot, PhaseSample, SampleHistory, SnapshotCell, Timestamper};
+pub use speed::{SpeedConfig, SpeedController};
+pub use startup::{Startup, StartupAction, StartupConfig, StartupState};
+pub use step::{Direction, MotorStep, PhaseState};
+pub use stream::{Sample, StreamField, StreamFields};
+pub use zero_crossing::{ZeroCrossing, ZeroCrossingConfig, ZeroCrossingDetector};
@@ -0,0 +1,151 @@
+//! Mock implementations of the hardware abstraction traits
+//!
+//! These record the state the controller has set, so that
+//! the controller can be run on the host (in tests, or
+//! connected to a simulated motor).
+
+use crate::hal::{
+    CommutationTimer, PhaseDriver, PhaseVoltageSampler, ThreePhasePwm, TimeoutTooLong,
+};
+use crate::pwm::{PwmError, PwmTiming};
+use crate::step::PhaseState;
+
+/// Records which side of each half bridge is selected
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MockDriver {
+    pub pulled_up: [bool; 3],
+}
+
+impl PhaseDriver for MockDriver {
+    fn pull_phase_up(&mut self, which: usize, pull_up: bool) {
+        self.pulled_up[which] = pull_up;
+    }
+}
+
+/// Records the PWM settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockPwm {
+    pub enabled: bool,
+    pub timer_clock_hz: u32,
+    pub timing: Option<PwmTiming>,
+    pub duty: [f32; 3],
+    pub adc_trigger: f32,
+}
+
+impl Default for MockPwm {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            // The TIM1 clock in the firmware
+            timer_clock_hz: 40_000_000,
+            timing: None,
+            duty: [0.0; 3],
+            adc_trigger: 0.0,
+        }
+    }
+}
+
+impl ThreePhasePwm for MockPwm {
+    fn enable(&mut self, enable: bool) {
+        self.enabled = enable;
+    }
+
+    fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        let timing = PwmTiming::new(frequency_hz, self.timer_clock_hz)?;
+        self.timing = Some(timing);
+        Ok(timing)
+    }
+
+    fn frequency_hz(&self) -> f32 {
+        self.timing
+            .map_or(0.0, |timing| timing.frequency_hz(self.timer_clock_hz))
+    }
+
+    fn set_duty(&mut self, which: usize, duty: f32) {
+        self.duty[which] = duty;
+    }
+
+    fn set_adc_trigger(&mut self, position: f32) {
+        self.adc_trigger = position;
+    }
+}
+
+/// Returns whatever measurements it is given
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MockSampler {
+    pub samples: [u16; 3],
+    pub current: Option<f32>,
+    pub bus_voltage: Option<f32>,
+    pub phase_currents: Option<[f32; 3]>,
+}
+
+impl PhaseVoltageSampler for MockSampler {
+    fn phase_voltages(&self) -> [u16; 3] {
+        self.samples
+    }
+
+    fn current(&self) -> Option<f32> {
+        self.current
+    }
+
+    fn bus_voltage(&self) -> Option<f32> {
+        self.bus_voltage
+    }
+
+    fn phase_currents(&self) -> Option<[f32; 3]> {
+        self.phase_currents
+    }
+}
+
+/// Timer which is advanced by hand (16-bit at 1 MHz, like the
+/// firmware's commutation timer)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MockTimer {
+    pub elapsed_us: u32,
+    pub timeout_us: u32,
+}
+
+impl MockTimer {
+    /// Advance the time by `us` microseconds, and return whether
+    /// the timer has expired
+    pub fn advance(&mut self, us: u32) -> bool {
+        self.elapsed_us += us;
+        self.elapsed_us >= self.timeout_us
+    }
+}
+
+impl CommutationTimer for MockTimer {
+    fn elapsed_us(&self) -> u32 {
+        self.elapsed_us
+    }
+
+    fn start(&mut self, timeout_us: u32) -> Result<(), TimeoutTooLong> {
+        const MAX_US: u32 = u16::MAX as u32;
+        if timeout_us > MAX_US {
+            return Err(TimeoutTooLong { max_us: MAX_US });
+        }
+        self.elapsed_us = 0;
+        self.timeout_us = timeout_us;
+        Ok(())
+    }
+}
+
+/// Work out the state of each phase (and the duty cycle of the
+/// line phase) from the mock driver and PWM settings
+///
+/// A phase with PWM duty 0.0 is floating, a phase which is pulled
+/// down is neutral, and a phase which is pulled up is the line.
+pub fn phase_states(driver: &MockDriver, pwm: &MockPwm) -> ([PhaseState; 3], f32) {
+    let mut duty = 0.0;
+    let states = core::array::from_fn(|n| {
+        if pwm.duty[n] == 0.0 {
+            PhaseState::Floating
+        } else if driver.pulled_up[n] {
+            duty = pwm.duty[n];
+            PhaseState::Line
+        } else {
+            PhaseState::Neutral
+        }
+    });
+    (states, duty)
+}
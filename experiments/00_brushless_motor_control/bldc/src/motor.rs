@@ -0,0 +1,119 @@
+//! One motor instance: a controller and its commutation timer
+//!
+//! Each motor driven by the firmware has its own controller
+//! (with its own PWM outputs, phase driver and phase voltage
+//! sampler) and its own commutation timer, so that several
+//! motors can run independently on one MCU. The interrupt
+//! handlers for each motor call [`Motor::on_samples`] and
+//! [`Motor::on_commutation_timer`].
+
+use crate::controller::ThreePhaseController;
+use crate::fault::MotorFault;
+use crate::hal::{CommutationTimer, PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+
+pub struct Motor<D, P, S, T> {
+    pub controller: ThreePhaseController<D, P, S>,
+    pub timer: T,
+}
+
+impl<D, P, S, T> Motor<D, P, S, T>
+where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    S: PhaseVoltageSampler,
+    T: CommutationTimer,
+{
+    pub fn new(controller: ThreePhaseController<D, P, S>, timer: T) -> Self {
+        Self { controller, timer }
+    }
+
+    /// Process a new set of phase voltage samples (call when the
+    /// sampler has a new measurement)
+    ///
+    /// In closed-loop mode, the commutation timer is restarted
+    /// when the back-EMF zero crossing is detected, so that it
+    /// expires when the next commutation is due.
+    pub fn on_samples(&mut self) {
+        let since_commutation_us = self.timer.elapsed_us();
+        if let Some(delay_us) = self.controller.on_samples(since_commutation_us) {
+            self.restart_timer(delay_us);
+        }
+    }
+
+    /// Commutate (call when the commutation timer expires)
+    pub fn on_commutation_timer(&mut self) {
+        let timeout_us = self.controller.on_commutation_timer();
+
+        // Restarting the timer also resets it, so that it
+        // measures the time since this commutation
+        self.restart_timer(timeout_us);
+    }
+
+    /// Restart the commutation timer, or shut the motor down if
+    /// the timer cannot count `timeout_us`
+    fn restart_timer(&mut self, timeout_us: u32) {
+        if self.timer.start(timeout_us.max(1)).is_err() {
+            self.controller.set_fault(MotorFault::TimerOverflow);
+
+            // Keep polling, as the controller does while stopped
+            let poll_us = self.controller.startup.config().idle_poll_us;
+            self.timer.start(poll_us).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDriver, MockPwm, MockSampler, MockTimer};
+
+    fn motor() -> Motor<MockDriver, MockPwm, MockSampler, MockTimer> {
+        let controller = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        );
+        Motor::new(controller, MockTimer::default())
+    }
+
+    #[test]
+    fn commutation_restarts_timer() {
+        let mut motor = motor();
+        motor.controller.startup.start();
+        motor.timer.elapsed_us = 123;
+        motor.on_commutation_timer();
+        assert_eq!(motor.timer.elapsed_us, 0);
+        assert_eq!(
+            motor.timer.timeout_us,
+            motor.controller.startup.config().align_time_us
+        );
+    }
+
+    #[test]
+    fn zero_crossing_reschedules_timer_in_closed_loop() {
+        let mut motor = motor();
+        motor.timer.start(1000).unwrap();
+        for (t, v) in [(100, 1500), (200, 2500), (210, 2500)] {
+            motor.timer.elapsed_us = t;
+            motor.controller.sampler_mut().samples = [1000, 3000, v];
+            motor.on_samples();
+        }
+        assert_eq!(motor.timer.elapsed_us, 0);
+        assert!(motor.timer.timeout_us < 1000);
+    }
+
+    #[test]
+    fn shuts_down_when_the_timer_overflows() {
+        let mut motor = motor();
+        let mut config = *motor.controller.startup.config();
+        config.align_time_us = 100_000;
+        motor.controller.startup.set_config(config);
+        motor.controller.startup.start();
+        motor.on_commutation_timer();
+        assert_eq!(motor.controller.fault(), Some(MotorFault::TimerOverflow));
+        assert_eq!(
+            motor.timer.timeout_us,
+            motor.controller.startup.config().idle_poll_us
+        );
+    }
+}
@@ -0,0 +1,627 @@
+//! The registry of tunable parameters
+//!
+//! Every setting which can be changed at run time (from the CLI
+//! `get`, `set`, `list` and `reset` commands, or the binary
+//! protocol) is a [`Parameter`], described by a [`ParameterInfo`]:
+//! its name, type, default, range, units and description. The
+//! firmware starts from the defaults ([`Parameter::reset_all`]), so
+//! that they are all in one place, and the read-only parameters are
+//! the build-time constants of the firmware.
+//!
+//! Every value is an `f32`. The integers are whole numbers, and the
+//! choices are the index of the option (e.g. 0 for forward and 1
+//! for reverse).
+
+use crate::brake::BrakeMode;
+use crate::config::AdcTrigger;
+use crate::controller::{ControlMode, ThreePhaseController, DEFAULT_DUTY};
+use crate::hal::{PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+use crate::startup::MAX_STARTUP_US;
+use crate::step::Direction;
+
+/// The type of the values of a parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterType {
+    Float,
+    Integer,
+    /// One of the options, by index
+    Choice(&'static [&'static str]),
+}
+
+impl ParameterType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParameterType::Float => "float",
+            ParameterType::Integer => "integer",
+            ParameterType::Choice(_) => "choice",
+        }
+    }
+}
+
+/// The description of a parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterInfo {
+    /// The name of the parameter (as the CLI and host tool take it)
+    pub name: &'static str,
+    pub kind: ParameterType,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+    /// The number of decimals to show (for floats)
+    pub decimals: u8,
+    pub units: &'static str,
+    pub description: &'static str,
+    /// Fixed when the firmware is built
+    pub read_only: bool,
+}
+
+impl ParameterInfo {
+    const fn float(name: &'static str, default: f32, min: f32, max: f32) -> Self {
+        Self {
+            name,
+            kind: ParameterType::Float,
+            default,
+            min,
+            max,
+            decimals: 3,
+            units: "",
+            description: "",
+            read_only: false,
+        }
+    }
+
+    const fn integer(name: &'static str, default: u32, min: u32, max: u32) -> Self {
+        Self {
+            kind: ParameterType::Integer,
+            decimals: 0,
+            ..Self::float(name, default as f32, min as f32, max as f32)
+        }
+    }
+
+    const fn choice(name: &'static str, options: &'static [&'static str], default: u32) -> Self {
+        Self {
+            kind: ParameterType::Choice(options),
+            ..Self::integer(name, default, 0, options.len() as u32 - 1)
+        }
+    }
+
+    /// A build-time constant
+    const fn constant(name: &'static str, value: u32) -> Self {
+        Self {
+            read_only: true,
+            ..Self::integer(name, value, value, value)
+        }
+    }
+
+    const fn decimals(self, decimals: u8) -> Self {
+        Self { decimals, ..self }
+    }
+
+    const fn units(self, units: &'static str) -> Self {
+        Self { units, ..self }
+    }
+
+    const fn describe(self, description: &'static str) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+
+    /// Whether the value has the type of the parameter, and is in
+    /// range
+    pub fn is_valid(&self, value: f32) -> bool {
+        let whole = match self.kind {
+            ParameterType::Float => true,
+            ParameterType::Integer | ParameterType::Choice(_) => value == value as u32 as f32,
+        };
+        value.is_finite() && whole && value >= self.min && value <= self.max
+    }
+
+    /// Parse a value (a number, or the name of an option for a
+    /// choice)
+    pub fn parse(&self, text: &str) -> Option<f32> {
+        if let ParameterType::Choice(options) = self.kind {
+            if let Some(index) = options.iter().position(|option| *option == text) {
+                return Some(index as f32);
+            }
+        }
+        text.parse().ok()
+    }
+
+    /// The name of the option, for a choice
+    pub fn option(&self, value: f32) -> Option<&'static str> {
+        match self.kind {
+            ParameterType::Choice(options) => options.get(value as usize).copied(),
+            _ => None,
+        }
+    }
+}
+
+/// Why a parameter could not be set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterError {
+    ReadOnly,
+    /// Out of range, or not of the type of the parameter
+    OutOfRange,
+    /// The hardware cannot do it (e.g. FOC without phase current
+    /// measurements)
+    Unsupported,
+}
+
+impl ParameterError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParameterError::ReadOnly => "read-only",
+            ParameterError::OutOfRange => "out of range",
+            ParameterError::Unsupported => "not supported",
+        }
+    }
+}
+
+/// A tunable setting
+///
+/// The values are the parameter IDs of the binary protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parameter {
+    Duty = 1,
+    PwmFrequency = 2,
+    TargetRpm = 3,
+    Direction = 4,
+    BrakeMode = 5,
+    RegenerativeDuty = 6,
+    MaxCurrent = 7,
+    MaxBusVoltage = 8,
+    SpeedKp = 9,
+    SpeedKi = 10,
+    AlignDuty = 11,
+    AlignTime = 12,
+    RampStartPeriod = 13,
+    RampEndPeriod = 14,
+    RampStartDuty = 15,
+    RampEndDuty = 16,
+    RampSteps = 17,
+    AdcTrigger = 18,
+    AdcTriggerPercent = 19,
+    ClockFrequency = 20,
+    SystickRate = 21,
+    HeapSize = 22,
+    CommandBufferLen = 23,
+    DeadTime = 24,
+    OpenLoopStep = 25,
+    Mode = 26,
+}
+
+/// The descriptions of the parameters, in the order of their IDs
+const PARAMETERS: [ParameterInfo; 26] = [
+    ParameterInfo::float("duty", DEFAULT_DUTY, 0.0, 1.0)
+        .describe("PWM duty cycle (setting it turns off speed control)"),
+    ParameterInfo::integer("pwm-frequency", 20_000, 1_000, 100_000)
+        .units("Hz")
+        .describe("PWM frequency"),
+    ParameterInfo::float("rpm", 0.0, 0.0, 100_000.0)
+        .decimals(0)
+        .units("RPM")
+        .describe("Target mechanical speed (0 turns off speed control)"),
+    ParameterInfo::choice("direction", &["forward", "reverse"], 0)
+        .describe("Direction of rotation"),
+    ParameterInfo::choice("brake", &["coast", "active", "regenerative"], 0)
+        .describe("What the half bridges do while the motor is stopped"),
+    ParameterInfo::float("brake-duty", 0.5, 0.0, 1.0)
+        .describe("Low-side duty cycle for regenerative braking"),
+    ParameterInfo::float("max-current", 2.0, 0.1, 100.0)
+        .decimals(2)
+        .units("A")
+        .describe("Over-current fault limit"),
+    ParameterInfo::float("max-voltage", 16.5, 0.1, 100.0)
+        .decimals(2)
+        .units("V")
+        .describe("Over-voltage fault limit"),
+    ParameterInfo::float("speed-kp", 3e-5, 0.0, 1.0)
+        .decimals(6)
+        .describe("Proportional gain of the speed controller (duty per RPM)"),
+    ParameterInfo::float("speed-ki", 1e-3, 0.0, 1.0)
+        .decimals(6)
+        .describe("Integral gain of the speed controller (duty per RPM s)"),
+    ParameterInfo::float("align-duty", 0.2, 0.0, 1.0)
+        .describe("Duty cycle while aligning the rotor at start-up"),
+    ParameterInfo::integer("align-time", 50_000, 0, MAX_STARTUP_US)
+        .units("us")
+        .describe("Time to hold the alignment step"),
+    ParameterInfo::integer("ramp-start-period", 1500, 1, MAX_STARTUP_US)
+        .units("us")
+        .describe("Commutation step period at the start of the open-loop ramp"),
+    ParameterInfo::integer("ramp-end-period", 300, 1, MAX_STARTUP_US)
+        .units("us")
+        .describe("Commutation step period at the end of the open-loop ramp"),
+    ParameterInfo::float("ramp-start-duty", 0.4, 0.0, 1.0)
+        .describe("Duty cycle at the start of the open-loop ramp"),
+    ParameterInfo::float("ramp-end-duty", 0.4, 0.0, 1.0)
+        .describe("Duty cycle at the end of the open-loop ramp"),
+    ParameterInfo::integer("ramp-steps", 2000, 1, 100_000)
+        .describe("Number of commutation steps in the open-loop ramp"),
+    ParameterInfo::choice("adc-trigger", &["on-time", "off-time"], 0)
+        .describe("Part of the PWM period in which the phase voltages are converted"),
+    ParameterInfo::integer("adc-trigger-percent", 50, 0, 100)
+        .units("%")
+        .describe("How far through the on-time or off-time the conversion is triggered"),
+    ParameterInfo::constant("clock-frequency", 216_000_000)
+        .units("Hz")
+        .describe("System clock frequency"),
+    ParameterInfo::constant("systick-rate", 1000)
+        .units("Hz")
+        .describe("Tick rate of the task scheduler"),
+    ParameterInfo::constant("heap-size", 1024)
+        .units("bytes")
+        .describe("Size of the heap"),
+    ParameterInfo::constant("command-buffer", 40)
+        .units("bytes")
+        .describe("Longest CLI command"),
+    ParameterInfo::constant("dead-time", 500)
+        .units("ns")
+        .describe("Dead-time between the high-side and low-side outputs (complementary PWM)"),
+    ParameterInfo::constant("open-loop-step", 3000)
+        .units("us")
+        .describe("Commutation timer period at power-up"),
+    ParameterInfo::choice("mode", &["six-step", "foc"], 0)
+        .describe("Six-step commutation or FOC (changing it stops the motor)"),
+];
+
+impl Parameter {
+    pub const ALL: [Parameter; 26] = [
+        Parameter::Duty,
+        Parameter::PwmFrequency,
+        Parameter::TargetRpm,
+        Parameter::Direction,
+        Parameter::BrakeMode,
+        Parameter::RegenerativeDuty,
+        Parameter::MaxCurrent,
+        Parameter::MaxBusVoltage,
+        Parameter::SpeedKp,
+        Parameter::SpeedKi,
+        Parameter::AlignDuty,
+        Parameter::AlignTime,
+        Parameter::RampStartPeriod,
+        Parameter::RampEndPeriod,
+        Parameter::RampStartDuty,
+        Parameter::RampEndDuty,
+        Parameter::RampSteps,
+        Parameter::AdcTrigger,
+        Parameter::AdcTriggerPercent,
+        Parameter::ClockFrequency,
+        Parameter::SystickRate,
+        Parameter::HeapSize,
+        Parameter::CommandBufferLen,
+        Parameter::DeadTime,
+        Parameter::OpenLoopStep,
+        Parameter::Mode,
+    ];
+
+    pub const fn info(&self) -> ParameterInfo {
+        PARAMETERS[*self as usize - 1]
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.info().name
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// The parameter with the protocol ID `code`
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| *p as u8 == code)
+    }
+
+    /// The current value of the parameter
+    pub fn get<D, P, S>(&self, controller: &ThreePhaseController<D, P, S>) -> f32
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        let startup = controller.startup.config();
+        match self {
+            Parameter::Duty => controller.duty(),
+            Parameter::PwmFrequency => controller.pwm().frequency_hz(),
+            Parameter::TargetRpm => controller.speed.target_rpm().unwrap_or(0.0),
+            Parameter::Direction => controller.direction() as u8 as f32,
+            Parameter::BrakeMode => controller.brake.config().mode as u8 as f32,
+            Parameter::RegenerativeDuty => controller.brake.config().regenerative_duty,
+            Parameter::MaxCurrent => controller.faults.config().max_current,
+            Parameter::MaxBusVoltage => controller.faults.config().max_bus_voltage,
+            Parameter::SpeedKp => controller.speed.config().kp,
+            Parameter::SpeedKi => controller.speed.config().ki,
+            Parameter::AlignDuty => startup.align_duty,
+            Parameter::AlignTime => startup.align_time_us as f32,
+            Parameter::RampStartPeriod => startup.ramp_start_period_us as f32,
+            Parameter::RampEndPeriod => startup.ramp_end_period_us as f32,
+            Parameter::RampStartDuty => startup.ramp_start_duty,
+            Parameter::RampEndDuty => startup.ramp_end_duty,
+            Parameter::RampSteps => startup.ramp_steps as f32,
+            Parameter::AdcTrigger => match controller.adc_trigger {
+                AdcTrigger::OnTime(_) => 0.0,
+                AdcTrigger::OffTime(_) => 1.0,
+            },
+            Parameter::AdcTriggerPercent => {
+                let (AdcTrigger::OnTime(percent) | AdcTrigger::OffTime(percent)) =
+                    controller.adc_trigger;
+                percent as f32
+            }
+            Parameter::ClockFrequency
+            | Parameter::SystickRate
+            | Parameter::HeapSize
+            | Parameter::CommandBufferLen
+            | Parameter::DeadTime
+            | Parameter::OpenLoopStep => self.info().default,
+            Parameter::Mode => controller.mode() as u8 as f32,
+        }
+    }
+
+    /// Set the parameter, if the value is valid
+    pub fn set<D, P, S>(
+        &self,
+        controller: &mut ThreePhaseController<D, P, S>,
+        value: f32,
+    ) -> Result<(), ParameterError>
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        let info = self.info();
+        if info.read_only {
+            return Err(ParameterError::ReadOnly);
+        }
+        if !info.is_valid(value) {
+            return Err(ParameterError::OutOfRange);
+        }
+        // Integers and choices are whole numbers in range
+        let index = value as u32;
+        match self {
+            Parameter::Duty => {
+                controller.speed.set_target_rpm(None);
+                controller
+                    .set_duty(value)
+                    .map_err(|_| ParameterError::OutOfRange)?;
+            }
+            Parameter::PwmFrequency => {
+                controller
+                    .set_pwm_frequency(index)
+                    .map_err(|_| ParameterError::OutOfRange)?;
+            }
+            Parameter::TargetRpm => {
+                controller
+                    .speed
+                    .set_target_rpm(if value > 0.0 { Some(value) } else { None });
+            }
+            Parameter::Direction => controller.set_direction(match index {
+                0 => Direction::Forward,
+                _ => Direction::Reverse,
+            }),
+            Parameter::BrakeMode | Parameter::RegenerativeDuty => {
+                let mut config = *controller.brake.config();
+                match self {
+                    Parameter::BrakeMode => {
+                        config.mode = match index {
+                            0 => BrakeMode::Coast,
+                            1 => BrakeMode::Active,
+                            _ => BrakeMode::Regenerative,
+                        }
+                    }
+                    _ => config.regenerative_duty = value,
+                }
+                controller.brake.set_config(config);
+            }
+            Parameter::MaxCurrent | Parameter::MaxBusVoltage => {
+                let mut config = *controller.faults.config();
+                match self {
+                    Parameter::MaxCurrent => config.max_current = value,
+                    _ => config.max_bus_voltage = value,
+                }
+                controller.faults.set_config(config);
+            }
+            Parameter::SpeedKp | Parameter::SpeedKi => {
+                let mut config = *controller.speed.config();
+                match self {
+                    Parameter::SpeedKp => config.kp = value,
+                    _ => config.ki = value,
+                }
+                controller.speed.set_config(config);
+            }
+            Parameter::AlignDuty
+            | Parameter::AlignTime
+            | Parameter::RampStartPeriod
+            | Parameter::RampEndPeriod
+            | Parameter::RampStartDuty
+            | Parameter::RampEndDuty
+            | Parameter::RampSteps => {
+                let mut config = *controller.startup.config();
+                match self {
+                    Parameter::AlignDuty => config.align_duty = value,
+                    Parameter::AlignTime => config.align_time_us = index,
+                    Parameter::RampStartPeriod => config.ramp_start_period_us = index,
+                    Parameter::RampEndPeriod => config.ramp_end_period_us = index,
+                    Parameter::RampStartDuty => config.ramp_start_duty = value,
+                    Parameter::RampEndDuty => config.ramp_end_duty = value,
+                    _ => config.ramp_steps = index,
+                }
+                controller.startup.set_config(config);
+            }
+            Parameter::AdcTrigger | Parameter::AdcTriggerPercent => {
+                let (AdcTrigger::OnTime(percent) | AdcTrigger::OffTime(percent)) =
+                    controller.adc_trigger;
+                let on_time = matches!(controller.adc_trigger, AdcTrigger::OnTime(_));
+                let (on_time, percent) = match self {
+                    Parameter::AdcTrigger => (index == 0, percent),
+                    _ => (on_time, index as u8),
+                };
+                controller.adc_trigger = if on_time {
+                    AdcTrigger::OnTime(percent)
+                } else {
+                    AdcTrigger::OffTime(percent)
+                };
+            }
+            Parameter::ClockFrequency
+            | Parameter::SystickRate
+            | Parameter::HeapSize
+            | Parameter::CommandBufferLen
+            | Parameter::DeadTime
+            | Parameter::OpenLoopStep => return Err(ParameterError::ReadOnly),
+            Parameter::Mode => controller
+                .set_mode(match index {
+                    0 => ControlMode::SixStep,
+                    _ => ControlMode::Foc,
+                })
+                .map_err(|_| ParameterError::Unsupported)?,
+        }
+        Ok(())
+    }
+
+    /// Set the parameter back to its default (read-only parameters
+    /// are left alone)
+    pub fn reset<D, P, S>(
+        &self,
+        controller: &mut ThreePhaseController<D, P, S>,
+    ) -> Result<(), ParameterError>
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        let info = self.info();
+        if info.read_only {
+            return Ok(());
+        }
+        self.set(controller, info.default)
+    }
+
+    /// Set all the parameters to their defaults
+    pub fn reset_all<D, P, S>(
+        controller: &mut ThreePhaseController<D, P, S>,
+    ) -> Result<(), ParameterError>
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        Self::ALL
+            .into_iter()
+            .try_for_each(|parameter| parameter.reset(controller))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brake::BrakeConfig;
+    use crate::fault::FaultConfig;
+    use crate::mock::{MockDriver, MockPwm, MockSampler};
+    use crate::speed::SpeedConfig;
+    use crate::startup::StartupConfig;
+
+    fn controller() -> ThreePhaseController<MockDriver, MockPwm, MockSampler> {
+        ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        )
+    }
+
+    #[test]
+    fn defaults_match_configs() {
+        let mut c = controller();
+        Parameter::reset_all(&mut c).unwrap();
+        assert_eq!(*c.startup.config(), StartupConfig::default());
+        assert_eq!(*c.brake.config(), BrakeConfig::default());
+        assert_eq!(*c.faults.config(), FaultConfig::default());
+        assert_eq!(*c.speed.config(), SpeedConfig::default());
+        assert_eq!(c.adc_trigger, AdcTrigger::default());
+        for parameter in Parameter::ALL {
+            let info = parameter.info();
+            assert!(info.is_valid(info.default), "{}", info.name);
+            assert_eq!(parameter.get(&c), info.default, "{}", info.name);
+        }
+    }
+
+    #[test]
+    fn checks_values() {
+        let mut c = controller();
+        assert_eq!(
+            Parameter::Duty.set(&mut c, 1.5),
+            Err(ParameterError::OutOfRange)
+        );
+        assert_eq!(
+            Parameter::MaxCurrent.set(&mut c, f32::NAN),
+            Err(ParameterError::OutOfRange)
+        );
+        assert_eq!(
+            Parameter::RampSteps.set(&mut c, 10.5),
+            Err(ParameterError::OutOfRange)
+        );
+        assert_eq!(
+            Parameter::BrakeMode.set(&mut c, 3.0),
+            Err(ParameterError::OutOfRange)
+        );
+        assert_eq!(
+            Parameter::HeapSize.set(&mut c, 1024.0),
+            Err(ParameterError::ReadOnly)
+        );
+
+        Parameter::RampSteps.set(&mut c, 500.0).unwrap();
+        assert_eq!(c.startup.config().ramp_steps, 500);
+        Parameter::RampSteps.reset(&mut c).unwrap();
+        assert_eq!(c.startup.config().ramp_steps, 2000);
+    }
+
+    #[test]
+    fn keeps_duty_while_stopped() {
+        let mut c = controller();
+        Parameter::Duty.set(&mut c, 0.3).unwrap();
+        c.on_commutation_timer();
+        assert_eq!(Parameter::Duty.get(&c), 0.3);
+    }
+
+    #[test]
+    fn sets_adc_trigger() {
+        let mut c = controller();
+        Parameter::AdcTriggerPercent.set(&mut c, 30.0).unwrap();
+        assert_eq!(c.adc_trigger, AdcTrigger::OnTime(30));
+        Parameter::AdcTrigger.set(&mut c, 1.0).unwrap();
+        assert_eq!(c.adc_trigger, AdcTrigger::OffTime(30));
+        assert_eq!(Parameter::AdcTrigger.get(&c), 1.0);
+    }
+
+    #[test]
+    fn sets_mode() {
+        let mut c = controller();
+        assert_eq!(
+            Parameter::Mode.set(&mut c, 1.0),
+            Err(ParameterError::Unsupported)
+        );
+        assert_eq!(Parameter::Mode.get(&c), 0.0);
+
+        c.sampler_mut().phase_currents = Some([0.0; 3]);
+        Parameter::Mode.set(&mut c, 1.0).unwrap();
+        assert_eq!(c.mode(), ControlMode::Foc);
+        Parameter::Mode.reset(&mut c).unwrap();
+        assert_eq!(c.mode(), ControlMode::SixStep);
+    }
+
+    #[test]
+    fn parses_values() {
+        let info = Parameter::BrakeMode.info();
+        assert_eq!(info.parse("regenerative"), Some(2.0));
+        assert_eq!(info.parse("1"), Some(1.0));
+        assert_eq!(info.parse("nonsense"), None);
+        assert_eq!(info.option(2.0), Some("regenerative"));
+        assert_eq!(Parameter::Duty.info().option(0.5), None);
+
+        for parameter in Parameter::ALL {
+            assert_eq!(Parameter::from_name(parameter.name()), Some(parameter));
+            assert_eq!(Parameter::from_code(parameter as u8), Some(parameter));
+        }
+        assert_eq!(Parameter::from_name("nonsense"), None);
+    }
+}
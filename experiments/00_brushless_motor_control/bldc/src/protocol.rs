@@ -0,0 +1,1240 @@
+//! Binary command and telemetry protocol
+//!
+//! The serial CLI is meant for people. Scripts and host tools use
+//! this protocol on the same port instead: each message is a
+//! payload framed by COBS (consistent overhead byte stuffing), so
+//! that a frame contains no zero bytes, and sent between two zero
+//! bytes:
+//!
+//! ```text
+//! 0x00, COBS(payload, CRC16), 0x00
+//! ```
+//!
+//! The payload is:
+//!
+//! | Bytes | Field                                    |
+//! |-------|------------------------------------------|
+//! | 0     | Protocol version ([`PROTOCOL_VERSION`])  |
+//! | 1     | Message ID                               |
+//! | 2..   | Message fields (little-endian)           |
+//!
+//! followed by the CRC-16 of the payload (little-endian). The CRC
+//! is the CRC-16/CCITT-FALSE (polynomial 0x1021, starting from
+//! 0xffff, no reflection).
+//!
+//! The CLI never receives zero bytes from a terminal, so a zero
+//! byte switches the port from text to binary mode for one frame,
+//! and the port goes back to text mode after it (see
+//! [`FrameDecoder`]). The host sends [`Request`]s, and the device
+//! answers each with one [`Response`]. Once telemetry is streamed
+//! ([`Request::Stream`]), the device sends [`Response::Sample`]
+//! messages without being asked too.
+//!
+//! Version 2 added the field selection to the stream (which sent
+//! [`Response::Status`] in version 1).
+
+use crate::controller::{ControlMode, StartError, ThreePhaseController};
+use crate::fault::MotorFault;
+use crate::hal::{PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+use crate::parameter::{Parameter, ParameterError};
+use crate::startup::StartupState;
+use crate::step::Direction;
+use crate::stream::{Sample, StreamField, StreamFields};
+
+/// The version of the message format, sent in every payload
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// The longest payload (without the CRC)
+pub const MAX_PAYLOAD_LEN: usize = 64;
+
+/// The longest frame, including the CRC, the COBS overhead byte
+/// and both zero bytes
+pub const MAX_FRAME_LEN: usize = MAX_PAYLOAD_LEN + 2 + 1 + 2;
+
+// Message IDs. The responses have the top bit set.
+const PING: u8 = 0x01;
+const GET_STATUS: u8 = 0x02;
+const ARM: u8 = 0x03;
+const DISARM: u8 = 0x04;
+const START: u8 = 0x05;
+const STOP: u8 = 0x06;
+const GET_PARAMETER: u8 = 0x07;
+const SET_PARAMETER: u8 = 0x08;
+const STREAM: u8 = 0x09;
+const PONG: u8 = 0x81;
+const OK: u8 = 0x82;
+const ERROR: u8 = 0x83;
+const PARAMETER: u8 = 0x84;
+const STATUS: u8 = 0x85;
+const SAMPLE: u8 = 0x86;
+
+/// Why a received frame or payload was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The COBS encoding is invalid
+    Encoding,
+    /// The CRC does not match the payload
+    Crc,
+    /// The frame is longer than [`MAX_FRAME_LEN`]
+    TooLong,
+    /// The payload is from a different protocol version
+    Version(u8),
+    /// The message ID is not known
+    UnknownMessage(u8),
+    /// The payload is too short or too long for the message
+    Length,
+    /// A field has a value which is not defined
+    InvalidValue,
+    /// The parameter ID is not known
+    UnknownParameter(u8),
+}
+
+impl ProtocolError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtocolError::Encoding => "invalid encoding",
+            ProtocolError::Crc => "CRC mismatch",
+            ProtocolError::TooLong => "frame too long",
+            ProtocolError::Version(_) => "unsupported version",
+            ProtocolError::UnknownMessage(_) => "unknown message",
+            ProtocolError::Length => "wrong length",
+            ProtocolError::InvalidValue => "invalid value",
+            ProtocolError::UnknownParameter(_) => "unknown parameter",
+        }
+    }
+}
+
+/// Why the device rejected a request (sent in
+/// [`Response::Error`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame was corrupted (COBS, CRC or length)
+    Frame = 1,
+    /// The request is from a different protocol version
+    Version = 2,
+    /// The message ID is not a request
+    UnknownMessage = 3,
+    /// The request fields are malformed
+    Malformed = 4,
+    /// The parameter ID is not known
+    UnknownParameter = 5,
+    /// The value is out of range for the parameter
+    OutOfRange = 6,
+    /// The motor cannot be started while disarmed
+    Disarmed = 7,
+    /// The motor cannot be started while a fault is latched
+    Fault = 8,
+    /// The parameter cannot be set
+    ReadOnly = 9,
+    /// The hardware cannot do it
+    Unsupported = 10,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 10] = [
+        ErrorCode::Frame,
+        ErrorCode::Version,
+        ErrorCode::UnknownMessage,
+        ErrorCode::Malformed,
+        ErrorCode::UnknownParameter,
+        ErrorCode::OutOfRange,
+        ErrorCode::Disarmed,
+        ErrorCode::Fault,
+        ErrorCode::ReadOnly,
+        ErrorCode::Unsupported,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::Frame => "corrupted frame",
+            ErrorCode::Version => "unsupported version",
+            ErrorCode::UnknownMessage => "unknown message",
+            ErrorCode::Malformed => "malformed request",
+            ErrorCode::UnknownParameter => "unknown parameter",
+            ErrorCode::OutOfRange => "out of range",
+            ErrorCode::Disarmed => "disarmed",
+            ErrorCode::Fault => "fault latched",
+            ErrorCode::ReadOnly => "read-only",
+            ErrorCode::Unsupported => "not supported",
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, ProtocolError> {
+        Self::ALL
+            .into_iter()
+            .find(|error| *error as u8 == code)
+            .ok_or(ProtocolError::InvalidValue)
+    }
+}
+
+impl From<ProtocolError> for ErrorCode {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            ProtocolError::Encoding | ProtocolError::Crc | ProtocolError::TooLong => {
+                ErrorCode::Frame
+            }
+            ProtocolError::Version(_) => ErrorCode::Version,
+            ProtocolError::UnknownMessage(_) => ErrorCode::UnknownMessage,
+            ProtocolError::Length | ProtocolError::InvalidValue => ErrorCode::Malformed,
+            ProtocolError::UnknownParameter(_) => ErrorCode::UnknownParameter,
+        }
+    }
+}
+
+impl From<ParameterError> for ErrorCode {
+    fn from(error: ParameterError) -> Self {
+        match error {
+            ParameterError::ReadOnly => ErrorCode::ReadOnly,
+            ParameterError::OutOfRange => ErrorCode::OutOfRange,
+            ParameterError::Unsupported => ErrorCode::Unsupported,
+        }
+    }
+}
+
+impl From<StartError> for ErrorCode {
+    fn from(error: StartError) -> Self {
+        match error {
+            StartError::Disarmed => ErrorCode::Disarmed,
+            StartError::Fault(_) => ErrorCode::Fault,
+        }
+    }
+}
+
+/// The state of the motor, as sent in [`Response::Status`]
+///
+/// The measurements which are not available are sent as NaN (or
+/// 0 for the step period).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusReport {
+    pub armed: bool,
+    pub mode: ControlMode,
+    pub state: StartupState,
+    pub direction: Direction,
+    pub fault: Option<MotorFault>,
+    /// The commutation step (0 to 5)
+    pub step: u8,
+    pub duty: f32,
+    pub target_rpm: Option<f32>,
+    /// The measured step period in closed loop
+    pub step_period_us: Option<u32>,
+    /// The raw neutral (star point) voltage
+    pub neutral_voltage: u16,
+    pub current: Option<f32>,
+    pub bus_voltage: Option<f32>,
+}
+
+impl StatusReport {
+    pub fn from_controller<D, P, S>(controller: &ThreePhaseController<D, P, S>) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        Self {
+            armed: controller.is_armed(),
+            mode: controller.mode(),
+            state: controller.startup.state(),
+            direction: controller.direction(),
+            fault: controller.fault(),
+            step: controller.step().step(),
+            duty: controller.applied_duty(),
+            target_rpm: controller.speed.target_rpm(),
+            step_period_us: controller.step_period_us(),
+            neutral_voltage: controller.neutral_voltage,
+            current: controller.current(),
+            bus_voltage: controller.bus_voltage(),
+        }
+    }
+
+    fn encode(&self, w: &mut PayloadWriter) {
+        w.u8(self.armed as u8);
+        w.u8(self.mode as u8);
+        encode_state(w, self.state);
+        w.u8(self.direction as u8);
+        encode_fault(w, self.fault);
+        w.u8(self.step);
+        w.f32(self.duty);
+        w.f32(self.target_rpm.unwrap_or(f32::NAN));
+        w.u32(self.step_period_us.unwrap_or(0));
+        w.u16(self.neutral_voltage);
+        w.f32(self.current.unwrap_or(f32::NAN));
+        w.f32(self.bus_voltage.unwrap_or(f32::NAN));
+    }
+
+    fn decode(r: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        let armed = match r.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(ProtocolError::InvalidValue),
+        };
+        let mode = match r.u8()? {
+            0 => ControlMode::SixStep,
+            1 => ControlMode::Foc,
+            _ => return Err(ProtocolError::InvalidValue),
+        };
+        let state = decode_state(r)?;
+        let direction = match r.u8()? {
+            0 => Direction::Forward,
+            1 => Direction::Reverse,
+            _ => return Err(ProtocolError::InvalidValue),
+        };
+        let fault = decode_fault(r)?;
+        Ok(Self {
+            armed,
+            mode,
+            state,
+            direction,
+            fault,
+            step: r.u8()?,
+            duty: r.f32()?,
+            target_rpm: r.optional_f32()?,
+            step_period_us: r.optional_u32()?,
+            neutral_voltage: r.u16()?,
+            current: r.optional_f32()?,
+            bus_voltage: r.optional_f32()?,
+        })
+    }
+}
+
+/// Encode a sample, with only the selected fields
+fn encode_sample(w: &mut PayloadWriter, sample: &Sample) {
+    w.u16(sample.fields.bits());
+    for field in sample.fields.iter() {
+        match field {
+            StreamField::Time => w.u32(sample.time_ms),
+            StreamField::PhaseVoltages => sample.phase_voltages.iter().for_each(|&v| w.u16(v)),
+            StreamField::Neutral => w.u16(sample.neutral_voltage),
+            StreamField::Step => w.u8(sample.step),
+            StreamField::Duty => w.f32(sample.duty),
+            StreamField::Period => w.u32(sample.step_period_us.unwrap_or(0)),
+            StreamField::Rpm => w.f32(sample.rpm.unwrap_or(f32::NAN)),
+            StreamField::Current => w.f32(sample.current.unwrap_or(f32::NAN)),
+            StreamField::BusVoltage => w.f32(sample.bus_voltage.unwrap_or(f32::NAN)),
+            StreamField::State => encode_state(w, sample.state),
+            StreamField::Fault => encode_fault(w, sample.fault),
+        }
+    }
+}
+
+/// Decode a sample (the fields which were not sent are left at
+/// their defaults)
+fn decode_sample(r: &mut PayloadReader) -> Result<Sample, ProtocolError> {
+    let fields = StreamFields::from_bits(r.u16()?).ok_or(ProtocolError::InvalidValue)?;
+    let mut sample = Sample {
+        fields,
+        ..Sample::default()
+    };
+    for field in fields.iter() {
+        match field {
+            StreamField::Time => sample.time_ms = r.u32()?,
+            StreamField::PhaseVoltages => {
+                for voltage in &mut sample.phase_voltages {
+                    *voltage = r.u16()?;
+                }
+            }
+            StreamField::Neutral => sample.neutral_voltage = r.u16()?,
+            StreamField::Step => sample.step = r.u8()?,
+            StreamField::Duty => sample.duty = r.f32()?,
+            StreamField::Period => sample.step_period_us = r.optional_u32()?,
+            StreamField::Rpm => sample.rpm = r.optional_f32()?,
+            StreamField::Current => sample.current = r.optional_f32()?,
+            StreamField::BusVoltage => sample.bus_voltage = r.optional_f32()?,
+            StreamField::State => sample.state = decode_state(r)?,
+            StreamField::Fault => sample.fault = decode_fault(r)?,
+        }
+    }
+    Ok(sample)
+}
+
+/// The start-up state as a code and the number of ramp steps
+fn encode_state(w: &mut PayloadWriter, state: StartupState) {
+    let (code, ramp_steps) = match state {
+        StartupState::Stopped => (0, 0),
+        StartupState::Starting => (1, 0),
+        StartupState::Align => (2, 0),
+        StartupState::Ramp(steps) => (3, steps),
+        StartupState::ClosedLoop => (4, 0),
+        StartupState::Failed => (5, 0),
+    };
+    w.u8(code);
+    w.u32(ramp_steps);
+}
+
+fn decode_state(r: &mut PayloadReader) -> Result<StartupState, ProtocolError> {
+    let (code, ramp_steps) = (r.u8()?, r.u32()?);
+    Ok(match code {
+        0 => StartupState::Stopped,
+        1 => StartupState::Starting,
+        2 => StartupState::Align,
+        3 => StartupState::Ramp(ramp_steps),
+        4 => StartupState::ClosedLoop,
+        5 => StartupState::Failed,
+        _ => return Err(ProtocolError::InvalidValue),
+    })
+}
+
+/// The fault as its code (0 for none)
+fn encode_fault(w: &mut PayloadWriter, fault: Option<MotorFault>) {
+    w.u8(fault.map_or(0, |fault| {
+        1 + FAULTS.iter().position(|f| *f == fault).unwrap_or(0) as u8
+    }));
+}
+
+fn decode_fault(r: &mut PayloadReader) -> Result<Option<MotorFault>, ProtocolError> {
+    match r.u8()? {
+        0 => Ok(None),
+        code => FAULTS
+            .get(code as usize - 1)
+            .map(|fault| Some(*fault))
+            .ok_or(ProtocolError::InvalidValue),
+    }
+}
+
+// The faults in the order of their codes (from 1)
+const FAULTS: [MotorFault; 8] = [
+    MotorFault::Stall,
+    MotorFault::Desync,
+    MotorFault::AdcOverrun,
+    MotorFault::DmaTransferError,
+    MotorFault::OverCurrent,
+    MotorFault::OverVoltage,
+    MotorFault::BreakInput,
+    MotorFault::TimerOverflow,
+];
+
+/// A message from the host to the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    /// Check that the device is there (answered by
+    /// [`Response::Pong`])
+    Ping,
+    /// Read the motor state (answered by [`Response::Status`])
+    GetStatus,
+    /// See [`ThreePhaseController::arm`]
+    Arm,
+    /// See [`ThreePhaseController::disarm`]
+    Disarm,
+    /// See [`ThreePhaseController::start`]
+    Start,
+    /// See [`ThreePhaseController::stop`]
+    Stop,
+    /// Read a parameter (answered by [`Response::Parameter`])
+    GetParameter(Parameter),
+    /// Set a parameter
+    SetParameter(Parameter, f32),
+    /// Send a [`Response::Sample`] with the selected fields every
+    /// `period_ms` (0 stops)
+    Stream {
+        period_ms: u16,
+        fields: StreamFields,
+    },
+}
+
+impl Request {
+    /// Encode the request as a frame
+    pub fn encode(&self) -> Frame {
+        let mut w = PayloadWriter::new();
+        match *self {
+            Request::Ping => w.u8(PING),
+            Request::GetStatus => w.u8(GET_STATUS),
+            Request::Arm => w.u8(ARM),
+            Request::Disarm => w.u8(DISARM),
+            Request::Start => w.u8(START),
+            Request::Stop => w.u8(STOP),
+            Request::GetParameter(parameter) => {
+                w.u8(GET_PARAMETER);
+                w.u8(parameter as u8);
+            }
+            Request::SetParameter(parameter, value) => {
+                w.u8(SET_PARAMETER);
+                w.u8(parameter as u8);
+                w.f32(value);
+            }
+            Request::Stream { period_ms, fields } => {
+                w.u8(STREAM);
+                w.u16(period_ms);
+                w.u16(fields.bits());
+            }
+        }
+        Frame::new(w.payload())
+    }
+
+    /// Decode a payload (as found by the [`FrameDecoder`])
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = PayloadReader::new(payload)?;
+        let request = match r.u8()? {
+            PING => Request::Ping,
+            GET_STATUS => Request::GetStatus,
+            ARM => Request::Arm,
+            DISARM => Request::Disarm,
+            START => Request::Start,
+            STOP => Request::Stop,
+            GET_PARAMETER => Request::GetParameter(r.parameter()?),
+            SET_PARAMETER => Request::SetParameter(r.parameter()?, r.f32()?),
+            STREAM => Request::Stream {
+                period_ms: r.u16()?,
+                fields: StreamFields::from_bits(r.u16()?).ok_or(ProtocolError::InvalidValue)?,
+            },
+            id => return Err(ProtocolError::UnknownMessage(id)),
+        };
+        r.finish()?;
+        Ok(request)
+    }
+
+    /// Carry out the request, and return the response
+    ///
+    /// [`Request::Stream`] is only acknowledged: the caller sends
+    /// the status messages.
+    pub fn respond<D, P, S>(&self, controller: &mut ThreePhaseController<D, P, S>) -> Response
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        let result = match *self {
+            Request::Ping => return Response::Pong,
+            Request::GetStatus => {
+                return Response::Status(StatusReport::from_controller(controller))
+            }
+            Request::GetParameter(parameter) => {
+                return Response::Parameter(parameter, parameter.get(controller))
+            }
+            Request::Arm => {
+                controller.arm();
+                Ok(())
+            }
+            Request::Disarm => {
+                controller.disarm();
+                Ok(())
+            }
+            Request::Start => controller.start().map_err(ErrorCode::from),
+            Request::Stop => {
+                controller.stop();
+                Ok(())
+            }
+            Request::SetParameter(parameter, value) => {
+                parameter.set(controller, value).map_err(ErrorCode::from)
+            }
+            Request::Stream { .. } => Ok(()),
+        };
+        match result {
+            Ok(()) => Response::Ok,
+            Err(error) => Response::Error(error),
+        }
+    }
+}
+
+/// A message from the device to the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Response {
+    /// Answer to [`Request::Ping`]
+    Pong,
+    /// The request was carried out
+    Ok,
+    /// The request was rejected
+    Error(ErrorCode),
+    /// The value of a parameter
+    Parameter(Parameter, f32),
+    /// The motor state (answer to [`Request::GetStatus`])
+    Status(StatusReport),
+    /// The streamed variables (see [`Request::Stream`])
+    Sample(Sample),
+}
+
+impl Response {
+    /// Encode the response as a frame
+    pub fn encode(&self) -> Frame {
+        let mut w = PayloadWriter::new();
+        match self {
+            Response::Pong => w.u8(PONG),
+            Response::Ok => w.u8(OK),
+            Response::Error(error) => {
+                w.u8(ERROR);
+                w.u8(*error as u8);
+            }
+            Response::Parameter(parameter, value) => {
+                w.u8(PARAMETER);
+                w.u8(*parameter as u8);
+                w.f32(*value);
+            }
+            Response::Status(status) => {
+                w.u8(STATUS);
+                status.encode(&mut w);
+            }
+            Response::Sample(sample) => {
+                w.u8(SAMPLE);
+                encode_sample(&mut w, sample);
+            }
+        }
+        Frame::new(w.payload())
+    }
+
+    /// Decode a payload (as found by the [`FrameDecoder`])
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = PayloadReader::new(payload)?;
+        let response = match r.u8()? {
+            PONG => Response::Pong,
+            OK => Response::Ok,
+            ERROR => Response::Error(ErrorCode::from_code(r.u8()?)?),
+            PARAMETER => Response::Parameter(r.parameter()?, r.f32()?),
+            STATUS => Response::Status(StatusReport::decode(&mut r)?),
+            SAMPLE => Response::Sample(decode_sample(&mut r)?),
+            id => return Err(ProtocolError::UnknownMessage(id)),
+        };
+        r.finish()?;
+        Ok(response)
+    }
+}
+
+/// Builds a payload (the version is written first)
+struct PayloadWriter {
+    buffer: [u8; MAX_PAYLOAD_LEN],
+    len: usize,
+}
+
+impl PayloadWriter {
+    fn new() -> Self {
+        let mut writer = Self {
+            buffer: [0; MAX_PAYLOAD_LEN],
+            len: 0,
+        };
+        writer.u8(PROTOCOL_VERSION);
+        writer
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// Reads the fields of a payload (after checking the version)
+struct PayloadReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(payload: &'a [u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Self { bytes: payload };
+        match reader.u8()? {
+            PROTOCOL_VERSION => Ok(reader),
+            version => Err(ProtocolError::Version(version)),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let (bytes, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or(ProtocolError::Length)?;
+        self.bytes = rest;
+        Ok(*bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn parameter(&mut self) -> Result<Parameter, ProtocolError> {
+        let code = self.u8()?;
+        Parameter::from_code(code).ok_or(ProtocolError::UnknownParameter(code))
+    }
+
+    fn f32(&mut self) -> Result<f32, ProtocolError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    /// A measurement which is sent as NaN when there is none
+    fn optional_f32(&mut self) -> Result<Option<f32>, ProtocolError> {
+        Ok(Some(self.f32()?).filter(|value| !value.is_nan()))
+    }
+
+    /// A period which is sent as 0 when there is none
+    fn optional_u32(&mut self) -> Result<Option<u32>, ProtocolError> {
+        Ok(Some(self.u32()?).filter(|&value| value != 0))
+    }
+
+    /// Check that every byte was read
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtocolError::Length)
+        }
+    }
+}
+
+/// An encoded message, ready to send (including both zero bytes)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    bytes: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Frame {
+    /// Frame a payload of up to [`MAX_PAYLOAD_LEN`] bytes
+    pub fn new(payload: &[u8]) -> Self {
+        let mut data = [0; MAX_PAYLOAD_LEN + 2];
+        let len = payload.len();
+        data[..len].copy_from_slice(payload);
+        data[len..len + 2].copy_from_slice(&crc16(payload).to_le_bytes());
+
+        let mut frame = Self {
+            bytes: [0; MAX_FRAME_LEN],
+            len: 0,
+        };
+        let encoded_len = cobs_encode(&data[..len + 2], &mut frame.bytes[1..]);
+        frame.len = encoded_len + 2;
+        frame
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// What a received byte completed (see [`FrameDecoder::push`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received<'a> {
+    /// A byte outside of a frame (for the CLI)
+    Text(u8),
+    /// The payload of a frame, with the CRC checked
+    Payload(&'a [u8]),
+    /// A frame which was rejected
+    Error(ProtocolError),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    #[default]
+    Text,
+    Frame,
+    // Discarding the rest of a frame which was too long
+    Overflow,
+}
+
+/// Separates the frames from the text in a stream of received
+/// bytes (on the device, or on the host)
+///
+/// A zero byte starts a frame, and the next zero byte ends it.
+/// After a frame which is rejected, the zero byte which ended it
+/// starts another one instead, so that a decoder which started
+/// part way through a frame is back in step at the next frame.
+/// Empty frames (two zero bytes in a row) are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDecoder {
+    buffer: [u8; MAX_FRAME_LEN],
+    len: usize,
+    state: DecoderState,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self {
+            buffer: [0; MAX_FRAME_LEN],
+            len: 0,
+            state: DecoderState::Text,
+        }
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a frame has been started (and not yet ended)
+    pub fn in_frame(&self) -> bool {
+        self.state != DecoderState::Text
+    }
+
+    /// Add a received byte
+    pub fn push(&mut self, byte: u8) -> Option<Received<'_>> {
+        match (self.state, byte) {
+            (DecoderState::Text, 0) => {
+                self.state = DecoderState::Frame;
+                self.len = 0;
+                None
+            }
+            (DecoderState::Text, byte) => Some(Received::Text(byte)),
+            (DecoderState::Frame, 0) if self.len == 0 => None,
+            (DecoderState::Frame, 0) => {
+                let len = self.len;
+                self.len = 0;
+                match decode_frame(&mut self.buffer[..len]) {
+                    Ok(payload_len) => {
+                        self.state = DecoderState::Text;
+                        Some(Received::Payload(&self.buffer[..payload_len]))
+                    }
+                    Err(error) => Some(Received::Error(error)),
+                }
+            }
+            (DecoderState::Frame, byte) => {
+                // Room for the frame without its zero bytes
+                if self.len == MAX_FRAME_LEN - 2 {
+                    self.state = DecoderState::Overflow;
+                    return Some(Received::Error(ProtocolError::TooLong));
+                }
+                self.buffer[self.len] = byte;
+                self.len += 1;
+                None
+            }
+            (DecoderState::Overflow, 0) => {
+                self.state = DecoderState::Frame;
+                self.len = 0;
+                None
+            }
+            (DecoderState::Overflow, _) => None,
+        }
+    }
+}
+
+/// Decode a frame (without its zero bytes) in place, and check the
+/// CRC, returning the length of the payload
+fn decode_frame(frame: &mut [u8]) -> Result<usize, ProtocolError> {
+    let len = cobs_decode(frame)?;
+    if len < 2 {
+        return Err(ProtocolError::Encoding);
+    }
+    let payload_len = len - 2;
+    let crc = u16::from_le_bytes([frame[payload_len], frame[payload_len + 1]]);
+    if crc16(&frame[..payload_len]) != crc {
+        return Err(ProtocolError::Crc);
+    }
+    Ok(payload_len)
+}
+
+/// COBS-encode `data` into `out` (which must have room for one
+/// more byte per 254 bytes of data), returning the encoded length
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    // Each block starts with its length (code), and ends where the
+    // data has a zero byte, or after 254 non-zero bytes
+    let mut code_index = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for &byte in data {
+        if byte != 0 {
+            out[len] = byte;
+            len += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xff {
+            out[code_index] = code;
+            code_index = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    out[code_index] = code;
+    len
+}
+
+/// COBS-decode `data` in place, returning the decoded length
+fn cobs_decode(data: &mut [u8]) -> Result<usize, ProtocolError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < data.len() {
+        let code = data[read] as usize;
+        if code == 0 || read + code > data.len() {
+            return Err(ProtocolError::Encoding);
+        }
+        data.copy_within(read + 1..read + code, write);
+        read += code;
+        write += code - 1;
+        // The zero byte at the end of a block (except for the last
+        // block, and the blocks of 254 bytes)
+        if code != 0xff && read < data.len() {
+            data[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// The CRC-16/CCITT-FALSE of `bytes`
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brake::BrakeMode;
+    use crate::mock::{MockDriver, MockPwm, MockSampler};
+
+    fn controller() -> ThreePhaseController<MockDriver, MockPwm, MockSampler> {
+        ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        )
+    }
+
+    /// Decode every frame in `bytes`
+    fn payloads(decoder: &mut FrameDecoder, bytes: &[u8]) -> [Option<Response>; 4] {
+        let mut responses = [None; 4];
+        let mut count = 0;
+        for &byte in bytes {
+            if let Some(Received::Payload(payload)) = decoder.push(byte) {
+                responses[count] = Some(Response::decode(payload).unwrap());
+                count += 1;
+            }
+        }
+        responses
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        // The standard check value of CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(&[]), 0xffff);
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        let cases: [&[u8]; 5] = [
+            &[],
+            &[0],
+            &[0x11, 0x22, 0x00, 0x33],
+            &[0x11, 0x00, 0x00, 0x00],
+            &[0xaa; 300],
+        ];
+        for data in cases {
+            let mut encoded = [0; 310];
+            let len = cobs_encode(data, &mut encoded);
+            assert!(!encoded[..len].contains(&0), "{data:?}");
+            assert_eq!(len, data.len() + 1 + data.len() / 254);
+            let decoded_len = cobs_decode(&mut encoded[..len]).unwrap();
+            assert_eq!(&encoded[..decoded_len], data);
+        }
+
+        // The example from the COBS paper
+        let mut encoded = [0; 6];
+        let len = cobs_encode(&[0x11, 0x22, 0x00, 0x33], &mut encoded);
+        assert_eq!(encoded[..len], [0x03, 0x11, 0x22, 0x02, 0x33]);
+        assert_eq!(
+            cobs_decode(&mut [0x05, 0x11, 0x22]),
+            Err(ProtocolError::Encoding)
+        );
+    }
+
+    #[test]
+    fn encodes_requests() {
+        let frame = Request::SetParameter(Parameter::Duty, 0.25).encode();
+        let bytes = frame.as_bytes();
+        assert_eq!(bytes[0], 0);
+        assert_eq!(bytes[bytes.len() - 1], 0);
+        assert!(!bytes[1..bytes.len() - 1].contains(&0));
+
+        let mut decoder = FrameDecoder::new();
+        let (last, rest) = bytes.split_last().unwrap();
+        assert!(rest.iter().all(|&byte| decoder.push(byte).is_none()));
+        let Some(Received::Payload(payload)) = decoder.push(*last) else {
+            panic!("no payload");
+        };
+        assert_eq!(
+            payload,
+            [PROTOCOL_VERSION, SET_PARAMETER, 1, 0x00, 0x00, 0x80, 0x3e]
+        );
+        assert_eq!(
+            Request::decode(payload),
+            Ok(Request::SetParameter(Parameter::Duty, 0.25))
+        );
+
+        for request in [
+            Request::Ping,
+            Request::Start,
+            Request::GetParameter(Parameter::SpeedKi),
+            Request::Stream {
+                period_ms: 100,
+                fields: StreamFields::all(),
+            },
+        ] {
+            let frame = request.encode();
+            let bytes = frame.as_bytes();
+            let mut decoder = FrameDecoder::new();
+            let payload = bytes.iter().find_map(|&byte| match decoder.push(byte) {
+                Some(Received::Payload(payload)) => Some(Request::decode(payload)),
+                _ => None,
+            });
+            assert_eq!(payload, Some(Ok(request)));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let v = PROTOCOL_VERSION;
+        assert_eq!(Request::decode(&[]), Err(ProtocolError::Length));
+        assert_eq!(Request::decode(&[9, PING]), Err(ProtocolError::Version(9)));
+        assert_eq!(
+            Request::decode(&[v, 0x7f]),
+            Err(ProtocolError::UnknownMessage(0x7f))
+        );
+        assert_eq!(Request::decode(&[v, PING, 0]), Err(ProtocolError::Length));
+        assert_eq!(
+            Request::decode(&[v, GET_PARAMETER]),
+            Err(ProtocolError::Length)
+        );
+        assert_eq!(
+            Request::decode(&[v, GET_PARAMETER, 99]),
+            Err(ProtocolError::UnknownParameter(99))
+        );
+        assert_eq!(
+            ErrorCode::from(ProtocolError::UnknownParameter(99)),
+            ErrorCode::UnknownParameter
+        );
+    }
+
+    #[test]
+    fn separates_frames_from_text() {
+        let mut stream = [0; 64];
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            stream[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        push(b"help\r");
+        push(Response::Pong.encode().as_bytes());
+        push(b"ok");
+        push(Response::Error(ErrorCode::Disarmed).encode().as_bytes());
+        push(Response::Ok.encode().as_bytes());
+
+        let mut decoder = FrameDecoder::new();
+        let mut text = [0; 8];
+        let mut text_len = 0;
+        let mut responses = [None; 3];
+        let mut count = 0;
+        for &byte in &stream[..len] {
+            match decoder.push(byte) {
+                Some(Received::Text(byte)) => {
+                    text[text_len] = byte;
+                    text_len += 1;
+                }
+                Some(Received::Payload(payload)) => {
+                    responses[count] = Some(Response::decode(payload).unwrap());
+                    count += 1;
+                }
+                Some(Received::Error(error)) => panic!("{error:?}"),
+                None => {}
+            }
+        }
+        assert_eq!(&text[..text_len], b"help\rok");
+        assert_eq!(
+            responses,
+            [
+                Some(Response::Pong),
+                Some(Response::Error(ErrorCode::Disarmed)),
+                Some(Response::Ok)
+            ]
+        );
+    }
+
+    #[test]
+    fn recovers_from_corrupted_frames() {
+        let pong = Response::Pong.encode();
+        let ok = Response::Ok.encode();
+
+        // Start part way through a frame: its end is taken as the
+        // start of a frame, which is rejected, and the decoder is
+        // back in step at the next frame
+        let mut decoder = FrameDecoder::new();
+        let mut stream = [0; 2 * MAX_FRAME_LEN];
+        let tail = &pong.as_bytes()[2..];
+        stream[..tail.len()].copy_from_slice(tail);
+        stream[tail.len()..tail.len() + ok.as_bytes().len()].copy_from_slice(ok.as_bytes());
+        let responses = payloads(&mut decoder, &stream[..tail.len() + ok.as_bytes().len()]);
+        assert_eq!(responses, [Some(Response::Ok), None, None, None]);
+
+        // A corrupted CRC
+        let mut corrupted = pong.clone();
+        corrupted.bytes[3] ^= 0x01;
+        let mut decoder = FrameDecoder::new();
+        let errors = corrupted
+            .as_bytes()
+            .iter()
+            .filter_map(|&byte| match decoder.push(byte) {
+                Some(Received::Error(error)) => Some(error),
+                _ => None,
+            })
+            .next();
+        assert_eq!(errors, Some(ProtocolError::Crc));
+        // The zero byte which ended it started another frame
+        assert!(decoder.in_frame());
+        assert_eq!(
+            payloads(&mut decoder, ok.as_bytes()),
+            [Some(Response::Ok), None, None, None]
+        );
+
+        // A frame which is too long is discarded
+        let mut decoder = FrameDecoder::new();
+        decoder.push(0);
+        let errors = (0..MAX_FRAME_LEN + 10)
+            .filter_map(|_| match decoder.push(0x55) {
+                Some(Received::Error(error)) => Some(error),
+                _ => None,
+            })
+            .count();
+        assert_eq!(errors, 1);
+        assert_eq!(
+            payloads(&mut decoder, ok.as_bytes()),
+            [Some(Response::Ok), None, None, None]
+        );
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let status = StatusReport {
+            armed: true,
+            mode: ControlMode::SixStep,
+            state: StartupState::Ramp(42),
+            direction: Direction::Reverse,
+            fault: Some(MotorFault::OverVoltage),
+            step: 3,
+            duty: 0.35,
+            target_rpm: None,
+            step_period_us: Some(1234),
+            neutral_voltage: 2048,
+            current: Some(1.5),
+            bus_voltage: None,
+        };
+        let frame = Response::Status(status).encode();
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(
+            payloads(&mut decoder, frame.as_bytes()),
+            [Some(Response::Status(status)), None, None, None]
+        );
+        assert!(frame.as_bytes().len() <= MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn sample_round_trip() {
+        let sample = Sample {
+            fields: StreamFields::parse("time,phases,rpm,state,fault").unwrap(),
+            time_ms: 123_456,
+            phase_voltages: [1000, 2000, 3000],
+            rpm: Some(5400.0),
+            state: StartupState::ClosedLoop,
+            fault: Some(MotorFault::Desync),
+            // Not selected, so not sent
+            duty: 0.5,
+            ..Sample::default()
+        };
+        let frame = Response::Sample(sample).encode();
+        let mut decoder = FrameDecoder::new();
+        let [Some(Response::Sample(decoded)), None, None, None] =
+            payloads(&mut decoder, frame.as_bytes())
+        else {
+            panic!("no sample");
+        };
+        assert_eq!(
+            decoded,
+            Sample {
+                duty: 0.0,
+                ..sample
+            }
+        );
+
+        // Every field fits in a frame
+        let sample = Sample {
+            fields: StreamFields::all(),
+            ..sample
+        };
+        let frame = Response::Sample(sample).encode();
+        assert_eq!(
+            payloads(&mut decoder, frame.as_bytes()),
+            [Some(Response::Sample(sample)), None, None, None]
+        );
+    }
+
+    #[test]
+    fn responds_to_requests() {
+        let mut c = controller();
+        c.disarm();
+        assert_eq!(Request::Ping.respond(&mut c), Response::Pong);
+        assert_eq!(
+            Request::Start.respond(&mut c),
+            Response::Error(ErrorCode::Disarmed)
+        );
+        assert_eq!(Request::Arm.respond(&mut c), Response::Ok);
+        assert_eq!(Request::Start.respond(&mut c), Response::Ok);
+        assert_eq!(c.startup.state(), StartupState::Starting);
+
+        let Response::Status(status) = Request::GetStatus.respond(&mut c) else {
+            panic!("no status");
+        };
+        assert!(status.armed);
+        assert_eq!(status.state, c.startup.state());
+
+        assert_eq!(
+            Request::SetParameter(Parameter::Duty, 0.3).respond(&mut c),
+            Response::Ok
+        );
+        assert_eq!(
+            Request::GetParameter(Parameter::Duty).respond(&mut c),
+            Response::Parameter(Parameter::Duty, 0.3)
+        );
+        assert_eq!(
+            Request::SetParameter(Parameter::Duty, 1.5).respond(&mut c),
+            Response::Error(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            Request::SetParameter(Parameter::BrakeMode, 2.0).respond(&mut c),
+            Response::Ok
+        );
+        assert_eq!(c.brake.config().mode, BrakeMode::Regenerative);
+        assert_eq!(
+            Request::SetParameter(Parameter::BrakeMode, 0.5).respond(&mut c),
+            Response::Error(ErrorCode::OutOfRange)
+        );
+        assert_eq!(
+            Request::SetParameter(Parameter::TargetRpm, 1200.0).respond(&mut c),
+            Response::Ok
+        );
+        assert_eq!(c.speed.target_rpm(), Some(1200.0));
+        assert_eq!(
+            Request::SetParameter(Parameter::TargetRpm, 0.0).respond(&mut c),
+            Response::Ok
+        );
+        assert_eq!(c.speed.target_rpm(), None);
+        assert_eq!(
+            Request::SetParameter(Parameter::MaxCurrent, f32::NAN).respond(&mut c),
+            Response::Error(ErrorCode::OutOfRange)
+        );
+    }
+}
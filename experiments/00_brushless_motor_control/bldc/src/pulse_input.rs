@@ -0,0 +1,424 @@
+//! PWM, OneShot125 and Multishot throttle input
+//!
+//! These protocols send the throttle as the width of a pulse,
+//! repeated at the flight controller's (or receiver's) loop rate:
+//!
+//! | Protocol   | Pulse width (throttle 0 to 1) |
+//! |------------|-------------------------------|
+//! | PWM        | 1000 to 2000 us               |
+//! | OneShot125 | 125 to 250 us                 |
+//! | Multishot  | 5 to 25 us                    |
+//!
+//! The ranges do not overlap, so [`PulseInput`] detects the
+//! protocol from the first few pulses. The endpoints of the range
+//! vary between transmitters, so they can be calibrated: if the
+//! throttle is at full when the input is first detected, the
+//! widths at full throttle and then at zero throttle are recorded
+//! as the new endpoints.
+//!
+//! The motor only starts once the throttle has been seen at zero
+//! (armed), so that it does not start if the input is first
+//! detected with the throttle part way up.
+//!
+
+/// The number of consecutive pulses of the same protocol needed to
+/// detect it
+pub const DETECT_PULSES: u8 = 10;
+
+/// The number of consecutive invalid pulses after which the input
+/// is lost (and the protocol must be detected again)
+pub const LOST_PULSES: u8 = 10;
+
+/// The number of pulses at zero throttle after which the
+/// calibration is finished
+pub const CALIBRATION_PULSES: u8 = 50;
+
+/// Throttles below this stop the motor
+pub const STOP_THROTTLE: f32 = 0.02;
+
+/// A throttle protocol which sends the throttle as a pulse width
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseProtocol {
+    /// Servo PWM (1000 to 2000 us)
+    Pwm,
+    /// OneShot125 (125 to 250 us)
+    OneShot125,
+    /// Multishot (5 to 25 us)
+    Multishot,
+}
+
+impl PulseProtocol {
+    /// The protocol with a pulse of `width_ns`, allowing 20% either
+    /// side of the nominal range
+    pub fn detect(width_ns: u32) -> Option<Self> {
+        [
+            PulseProtocol::Pwm,
+            PulseProtocol::OneShot125,
+            PulseProtocol::Multishot,
+        ]
+        .into_iter()
+        .find(|protocol| protocol.accepts(width_ns))
+    }
+
+    /// The nominal pulse widths at zero and full throttle
+    pub const fn nominal(&self) -> PulseCalibration {
+        match self {
+            PulseProtocol::Pwm => PulseCalibration {
+                min_ns: 1_000_000,
+                max_ns: 2_000_000,
+            },
+            PulseProtocol::OneShot125 => PulseCalibration {
+                min_ns: 125_000,
+                max_ns: 250_000,
+            },
+            PulseProtocol::Multishot => PulseCalibration {
+                min_ns: 5_000,
+                max_ns: 25_000,
+            },
+        }
+    }
+
+    fn accepts(&self, width_ns: u32) -> bool {
+        let nominal = self.nominal();
+        let min = nominal.min_ns / 5 * 4;
+        let max = nominal.max_ns / 5 * 6;
+        (min..=max).contains(&width_ns)
+    }
+}
+
+/// The pulse widths at zero and full throttle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PulseCalibration {
+    pub min_ns: u32,
+    pub max_ns: u32,
+}
+
+impl PulseCalibration {
+    /// The throttle (between 0.0 and 1.0) for a pulse of
+    /// `width_ns`
+    pub fn throttle(&self, width_ns: u32) -> f32 {
+        let span = self.max_ns.saturating_sub(self.min_ns).max(1);
+        let throttle = width_ns.saturating_sub(self.min_ns) as f32 / span as f32;
+        throttle.min(1.0)
+    }
+
+    // Half way between the endpoints
+    fn middle_ns(&self) -> u32 {
+        self.min_ns + (self.max_ns - self.min_ns) / 2
+    }
+}
+
+/// The calibrated endpoints of each protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PulseInputConfig {
+    pub pwm: PulseCalibration,
+    pub oneshot125: PulseCalibration,
+    pub multishot: PulseCalibration,
+}
+
+impl PulseInputConfig {
+    /// The nominal endpoints for every protocol
+    pub const NOMINAL: Self = Self {
+        pwm: PulseProtocol::Pwm.nominal(),
+        oneshot125: PulseProtocol::OneShot125.nominal(),
+        multishot: PulseProtocol::Multishot.nominal(),
+    };
+
+    pub fn calibration(&self, protocol: PulseProtocol) -> PulseCalibration {
+        match protocol {
+            PulseProtocol::Pwm => self.pwm,
+            PulseProtocol::OneShot125 => self.oneshot125,
+            PulseProtocol::Multishot => self.multishot,
+        }
+    }
+
+    fn calibration_mut(&mut self, protocol: PulseProtocol) -> &mut PulseCalibration {
+        match protocol {
+            PulseProtocol::Pwm => &mut self.pwm,
+            PulseProtocol::OneShot125 => &mut self.oneshot125,
+            PulseProtocol::Multishot => &mut self.multishot,
+        }
+    }
+}
+
+impl Default for PulseInputConfig {
+    fn default() -> Self {
+        Self::NOMINAL
+    }
+}
+
+/// What the motor should do in response to a pulse
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PulseAction {
+    /// Stop the motor (zero throttle, or the input has been lost)
+    Stop,
+    /// Run at `throttle` (between 0.0 and 1.0)
+    Throttle(f32),
+    /// The endpoints of `protocol` have been calibrated
+    Calibrated {
+        protocol: PulseProtocol,
+        calibration: PulseCalibration,
+    },
+    /// Nothing to do (still detecting the protocol, calibrating or
+    /// waiting to be armed)
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Waiting for enough pulses of one protocol
+    Detecting { protocol: PulseProtocol, count: u8 },
+    // Recording the width at full throttle
+    CalibratingMax { max_ns: u32 },
+    // Waiting for the width at zero throttle to settle
+    CalibratingMin { max_ns: u32, count: u8 },
+    // Running (once armed)
+    Running { armed: bool },
+}
+
+/// Detects the protocol of a pulse throttle input, and turns the
+/// pulse widths into throttles
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseInput {
+    config: PulseInputConfig,
+    protocol: Option<PulseProtocol>,
+    state: State,
+
+    // Consecutive pulses not valid for the protocol
+    invalid: u8,
+}
+
+impl PulseInput {
+    pub fn new(config: PulseInputConfig) -> Self {
+        Self {
+            config,
+            protocol: None,
+            state: State::Detecting {
+                protocol: PulseProtocol::Pwm,
+                count: 0,
+            },
+            invalid: 0,
+        }
+    }
+
+    /// The endpoints in use (including any calibration)
+    pub fn config(&self) -> &PulseInputConfig {
+        &self.config
+    }
+
+    /// The detected protocol
+    pub fn protocol(&self) -> Option<PulseProtocol> {
+        self.protocol
+    }
+
+    /// Process a pulse of `width_ns`
+    pub fn update(&mut self, width_ns: u32) -> PulseAction {
+        let Some(protocol) = self.protocol else {
+            self.detect(width_ns);
+            return PulseAction::None;
+        };
+
+        // Ignore glitches, but start again if the pulses stay out
+        // of range
+        if !protocol.accepts(width_ns) {
+            self.invalid += 1;
+            if self.invalid >= LOST_PULSES {
+                return self.signal_lost();
+            }
+            return PulseAction::None;
+        }
+        self.invalid = 0;
+
+        let calibration = self.config.calibration(protocol);
+        match self.state {
+            State::CalibratingMax { max_ns } if width_ns > calibration.middle_ns() => {
+                self.state = State::CalibratingMax {
+                    max_ns: max_ns.max(width_ns),
+                };
+                PulseAction::None
+            }
+            State::CalibratingMax { max_ns } => {
+                self.state = State::CalibratingMin { max_ns, count: 0 };
+                PulseAction::None
+            }
+            State::CalibratingMin { max_ns, count } if count + 1 < CALIBRATION_PULSES => {
+                self.state = State::CalibratingMin {
+                    max_ns,
+                    count: count + 1,
+                };
+                PulseAction::None
+            }
+            State::CalibratingMin { max_ns, .. } => {
+                self.finish_calibration(protocol, width_ns, max_ns)
+            }
+            State::Running { armed } => {
+                let throttle = calibration.throttle(width_ns);
+                if throttle < STOP_THROTTLE {
+                    self.state = State::Running { armed: true };
+                    PulseAction::Stop
+                } else if armed {
+                    PulseAction::Throttle(throttle)
+                } else {
+                    PulseAction::None
+                }
+            }
+            State::Detecting { .. } => unreachable!("protocol detected"),
+        }
+    }
+
+    /// No pulses have been received for a while: stop the motor,
+    /// and detect the protocol again
+    pub fn signal_lost(&mut self) -> PulseAction {
+        *self = Self::new(self.config);
+        PulseAction::Stop
+    }
+
+    fn detect(&mut self, width_ns: u32) {
+        let Some(detected) = PulseProtocol::detect(width_ns) else {
+            self.state = State::Detecting {
+                protocol: PulseProtocol::Pwm,
+                count: 0,
+            };
+            return;
+        };
+
+        let count = match self.state {
+            State::Detecting { protocol, count } if protocol == detected => count + 1,
+            _ => 1,
+        };
+        if count < DETECT_PULSES {
+            self.state = State::Detecting {
+                protocol: detected,
+                count,
+            };
+            return;
+        }
+
+        // Full throttle when the input is detected starts the
+        // calibration
+        self.protocol = Some(detected);
+        self.state = if width_ns > self.config.calibration(detected).middle_ns() {
+            State::CalibratingMax { max_ns: width_ns }
+        } else {
+            State::Running { armed: false }
+        };
+    }
+
+    fn finish_calibration(
+        &mut self,
+        protocol: PulseProtocol,
+        min_ns: u32,
+        max_ns: u32,
+    ) -> PulseAction {
+        // Already at zero throttle, so armed
+        self.state = State::Running { armed: true };
+
+        // Keep the previous endpoints if the range is much smaller
+        // than it should be
+        let nominal = protocol.nominal();
+        if max_ns.saturating_sub(min_ns) < (nominal.max_ns - nominal.min_ns) / 2 {
+            return PulseAction::Stop;
+        }
+
+        let calibration = PulseCalibration { min_ns, max_ns };
+        *self.config.calibration_mut(protocol) = calibration;
+        PulseAction::Calibrated {
+            protocol,
+            calibration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `count` pulses of `width_ns`, returning the last action
+    fn pulses(input: &mut PulseInput, width_ns: u32, count: usize) -> PulseAction {
+        (0..count)
+            .map(|_| input.update(width_ns))
+            .last()
+            .unwrap_or(PulseAction::None)
+    }
+
+    #[test]
+    fn detects_each_protocol() {
+        for (protocol, width_ns) in [
+            (PulseProtocol::Pwm, 1_000_000),
+            (PulseProtocol::OneShot125, 125_000),
+            (PulseProtocol::Multishot, 5_000),
+        ] {
+            let mut input = PulseInput::new(PulseInputConfig::NOMINAL);
+            pulses(&mut input, width_ns, DETECT_PULSES as usize - 1);
+            assert_eq!(input.protocol(), None);
+            pulses(&mut input, width_ns, 1);
+            assert_eq!(input.protocol(), Some(protocol));
+        }
+
+        // Between the ranges
+        assert_eq!(PulseProtocol::detect(50_000), None);
+        assert_eq!(PulseProtocol::detect(500_000), None);
+    }
+
+    #[test]
+    fn arms_at_zero_throttle() {
+        let mut input = PulseInput::new(PulseInputConfig::NOMINAL);
+
+        // Detected part way up: the motor does not start
+        pulses(&mut input, 1_500_000, DETECT_PULSES as usize);
+        assert_eq!(input.protocol(), Some(PulseProtocol::Pwm));
+        assert_eq!(input.update(1_400_000), PulseAction::None);
+
+        assert_eq!(input.update(1_000_000), PulseAction::Stop);
+        assert_eq!(input.update(1_250_000), PulseAction::Throttle(0.25));
+        assert_eq!(input.update(2_100_000), PulseAction::Throttle(1.0));
+    }
+
+    #[test]
+    fn loses_the_signal() {
+        let mut input = PulseInput::new(PulseInputConfig::NOMINAL);
+        pulses(&mut input, 125_000, DETECT_PULSES as usize + 1);
+        assert_eq!(input.update(187_500), PulseAction::Throttle(0.5));
+
+        // A glitch is ignored, but not a change of protocol
+        assert_eq!(input.update(10_000), PulseAction::None);
+        assert_eq!(input.update(187_500), PulseAction::Throttle(0.5));
+        assert_eq!(
+            pulses(&mut input, 10_000, LOST_PULSES as usize),
+            PulseAction::Stop
+        );
+        assert_eq!(input.protocol(), None);
+
+        pulses(&mut input, 10_000, DETECT_PULSES as usize);
+        assert_eq!(input.protocol(), Some(PulseProtocol::Multishot));
+    }
+
+    #[test]
+    fn calibrates_endpoints() {
+        let mut input = PulseInput::new(PulseInputConfig::NOMINAL);
+
+        // Full throttle at power on, then zero throttle
+        pulses(&mut input, 1_950_000, DETECT_PULSES as usize);
+        pulses(&mut input, 1_960_000, 20);
+        assert_eq!(
+            pulses(&mut input, 1_050_000, CALIBRATION_PULSES as usize),
+            PulseAction::None
+        );
+        let calibration = PulseCalibration {
+            min_ns: 1_050_000,
+            max_ns: 1_960_000,
+        };
+        assert_eq!(
+            input.update(1_050_000),
+            PulseAction::Calibrated {
+                protocol: PulseProtocol::Pwm,
+                calibration,
+            }
+        );
+        assert_eq!(input.config().pwm, calibration);
+
+        // Armed, with the new endpoints
+        assert_eq!(input.update(1_960_000), PulseAction::Throttle(1.0));
+        assert_eq!(input.update(1_051_000), PulseAction::Stop);
+    }
+}
@@ -0,0 +1,197 @@
+//! PWM frequency and duty cycle settings
+//!
+//! A timer counts at the timer clock frequency divided by
+//! (PSC + 1), and the PWM period is (ARR + 1) counts. Both
+//! registers are 16 bits. To get the finest duty cycle
+//! resolution, the smallest prescaler which keeps ARR in range
+//! is used.
+//!
+//! The timer clocks are not all the same: TIM1 is on APB2, and
+//! TIM2 and TIM5 are on APB1. The timers driving the three phases
+//! must end up with the same PWM frequency, so check that the
+//! [`PwmTiming`] for each timer gives the same frequency.
+//!
+
+/// The coarsest duty cycle resolution allowed (the number of
+/// steps between 0.0 and 1.0)
+pub const MIN_RESOLUTION: u32 = 100;
+
+// ARR is at most 65534, so that a compare value of ARR + 1 (which
+// holds the output high for the whole period) fits in 16 bits
+const MAX_PERIOD: u32 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PwmError {
+    /// The frequency cannot be set with at least
+    /// [`MIN_RESOLUTION`] steps of duty cycle
+    FrequencyTooHigh { max_hz: u32 },
+    /// The frequency is lower than the timer can count with the
+    /// largest prescaler
+    FrequencyTooLow { min_hz: u32 },
+    /// The timers driving the three phases cannot be set to the
+    /// same frequency (their clocks differ)
+    MismatchedTimers,
+    /// The duty cycle is not between 0.0 and 1.0
+    InvalidDuty(f32),
+}
+
+/// Prescaler and auto-reload register values for a PWM frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmTiming {
+    /// The PSC register value (the timer clock is divided by
+    /// PSC + 1)
+    pub prescaler: u16,
+    /// The ARR register value (the period is ARR + 1 counts)
+    pub arr: u16,
+}
+
+impl PwmTiming {
+    /// Work out the prescaler and ARR for a PWM frequency of
+    /// `frequency_hz`, on a timer clocked at `timer_clock_hz`
+    pub fn new(frequency_hz: u32, timer_clock_hz: u32) -> Result<Self, PwmError> {
+        if frequency_hz == 0 {
+            return Err(PwmError::FrequencyTooLow {
+                min_hz: Self::min_frequency_hz(timer_clock_hz),
+            });
+        }
+
+        let max_hz = timer_clock_hz / MIN_RESOLUTION;
+        if frequency_hz > max_hz {
+            return Err(PwmError::FrequencyTooHigh { max_hz });
+        }
+
+        let prescaler = (timer_clock_hz as u64).div_ceil(frequency_hz as u64 * MAX_PERIOD as u64);
+        if prescaler > 1 << 16 {
+            return Err(PwmError::FrequencyTooLow {
+                min_hz: Self::min_frequency_hz(timer_clock_hz),
+            });
+        }
+
+        // Round to the nearest period (which may round up to just
+        // over the maximum)
+        let divisor = prescaler * frequency_hz as u64;
+        let period = ((timer_clock_hz as u64 + divisor / 2) / divisor)
+            .clamp(MIN_RESOLUTION as u64, MAX_PERIOD as u64);
+
+        Ok(Self {
+            prescaler: (prescaler - 1) as u16,
+            arr: (period - 1) as u16,
+        })
+    }
+
+    fn min_frequency_hz(timer_clock_hz: u32) -> u32 {
+        (timer_clock_hz as u64).div_ceil((1 << 16) * MAX_PERIOD as u64) as u32
+    }
+
+    /// The PWM frequency achieved on a timer clocked at
+    /// `timer_clock_hz` (which differs slightly from the frequency
+    /// requested, because the period is a whole number of counts)
+    pub fn frequency_hz(&self, timer_clock_hz: u32) -> f32 {
+        timer_clock_hz as f32 / ((self.prescaler as f32 + 1.0) * self.resolution() as f32)
+    }
+
+    /// The number of steps of duty cycle between 0.0 and 1.0
+    pub fn resolution(&self) -> u32 {
+        self.arr as u32 + 1
+    }
+
+    /// The compare register value for a duty cycle between 0.0
+    /// and 1.0 (rounded to the nearest step). A duty cycle of 1.0
+    /// gives ARR + 1, which holds the output high for the whole
+    /// period.
+    pub fn compare(&self, duty: f32) -> Result<u16, PwmError> {
+        let duty = check_duty(duty)?;
+        Ok((duty * self.resolution() as f32 + 0.5) as u16)
+    }
+
+    /// The compare register value which triggers the ADC at
+    /// `position` (between 0.0 and 1.0) through the PWM period.
+    /// The trigger is on the rising edge of a compare output in
+    /// PWM mode 2, so it is kept between 1 and ARR (a compare
+    /// value of 0 or above ARR never has a rising edge).
+    pub fn trigger_compare(&self, position: f32) -> u16 {
+        let compare = (position * self.resolution() as f32 + 0.5) as u16;
+        compare.clamp(1, self.arr.max(1))
+    }
+}
+
+/// Check that `duty` is a valid duty cycle (between 0.0 and 1.0)
+pub fn check_duty(duty: f32) -> Result<f32, PwmError> {
+    if (0.0..=1.0).contains(&duty) {
+        Ok(duty)
+    } else {
+        Err(PwmError::InvalidDuty(duty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_prescaler_when_period_fits() {
+        // 20 kHz from a 40 MHz timer clock (TIM1 with pclk2 at
+        // 20 MHz)
+        let timing = PwmTiming::new(20_000, 40_000_000).unwrap();
+        assert_eq!(
+            timing,
+            PwmTiming {
+                prescaler: 0,
+                arr: 1999
+            }
+        );
+        assert_eq!(timing.resolution(), 2000);
+        assert_eq!(timing.frequency_hz(40_000_000), 20_000.0);
+    }
+
+    #[test]
+    fn prescaler_for_low_frequency() {
+        let clock = 216_000_000;
+        let timing = PwmTiming::new(50, clock).unwrap();
+        assert!(timing.prescaler > 0);
+        assert!(timing.resolution() > 30_000);
+        assert!((timing.frequency_hz(clock) - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn achieved_frequency_is_close() {
+        let clock = 108_000_000;
+        for frequency in [7, 333, 8_000, 24_000, 47_000, 300_000] {
+            let timing = PwmTiming::new(frequency, clock).unwrap();
+            let achieved = timing.frequency_hz(clock);
+            let error = (achieved - frequency as f32).abs() / frequency as f32;
+            assert!(error < 0.01, "{frequency} Hz gave {achieved} Hz");
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_frequencies() {
+        let clock = 40_000_000;
+        assert_eq!(
+            PwmTiming::new(500_000, clock),
+            Err(PwmError::FrequencyTooHigh { max_hz: 400_000 })
+        );
+        assert_eq!(
+            PwmTiming::new(0, clock),
+            Err(PwmError::FrequencyTooLow { min_hz: 1 })
+        );
+    }
+
+    #[test]
+    fn duty_cycle_compare_values() {
+        let timing = PwmTiming::new(20_000, 40_000_000).unwrap();
+        assert_eq!(timing.compare(0.0), Ok(0));
+        assert_eq!(timing.compare(0.25), Ok(500));
+        assert_eq!(timing.compare(1.0), Ok(2000));
+        assert_eq!(timing.compare(1.5), Err(PwmError::InvalidDuty(1.5)));
+        assert_eq!(timing.compare(-0.1), Err(PwmError::InvalidDuty(-0.1)));
+    }
+
+    #[test]
+    fn adc_trigger_compare_values() {
+        let timing = PwmTiming::new(20_000, 40_000_000).unwrap();
+        assert_eq!(timing.trigger_compare(0.2), 400);
+        assert_eq!(timing.trigger_compare(0.0), 1);
+        assert_eq!(timing.trigger_compare(1.0), 1999);
+    }
+}
@@ -0,0 +1,298 @@
+//! Handing ADC samples from the DMA interrupt to other contexts
+//!
+//! The ADC conversions of each PWM period are transferred by DMA
+//! into one of two buffers, alternately (double-buffer mode), so
+//! the buffer which has just been completed is not written again
+//! until the next PWM period. The DMA interrupt copies it into a
+//! [`SnapshotCell`], with a timestamp, from which any other
+//! context can read a consistent copy without taking a lock.
+//!
+//! The timestamps are microseconds from a free-running cycle
+//! counter (see [`Timestamper`]), and recent samples can be kept
+//! in a [`SampleHistory`] (a fixed-size ring buffer, so that no
+//! heap is needed).
+
+use crate::config::MAX_CONVERSIONS;
+use core::sync::atomic::{fence, AtomicU16, AtomicU32, Ordering};
+
+/// The conversions of one ADC sequence, and when they were taken
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AdcSnapshot {
+    /// The raw conversions, in sequence order (see
+    /// [`AdcSequence::read`](crate::config::AdcSequence::read))
+    pub raw: [u16; MAX_CONVERSIONS],
+
+    /// When the conversions were transferred (microseconds, see
+    /// [`Timestamper`])
+    pub timestamp_us: u32,
+
+    /// The number of snapshots published so far, including this
+    /// one (a reader which sees a jump has missed some)
+    pub count: u32,
+}
+
+/// One set of phase voltages, as kept in the controller's
+/// [`SampleHistory`] for the zero-crossing detection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseSample {
+    /// The raw phase voltages
+    pub phases: [u16; 3],
+
+    /// When the samples were taken, since the last commutation
+    pub since_commutation_us: u32,
+}
+
+/// The latest [`AdcSnapshot`], shared without a lock
+///
+/// One context (the DMA interrupt) publishes snapshots, and any
+/// number of others read them. The cell is a sequence lock: the
+/// sequence number is odd while a snapshot is being written, and
+/// a reader which sees it change during its read (because the
+/// writer has interrupted it) reads again. A reader at a higher
+/// priority than the writer may interrupt a write instead, and
+/// then gets nothing rather than waiting for it.
+///
+/// The snapshot is held in atomics, so the cell can be a `static`
+/// (e.g. `static SAMPLES: SnapshotCell = SnapshotCell::new()`).
+pub struct SnapshotCell {
+    sequence: AtomicU32,
+    raw: [AtomicU16; MAX_CONVERSIONS],
+    timestamp_us: AtomicU32,
+}
+
+impl SnapshotCell {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            raw: [const { AtomicU16::new(0) }; MAX_CONVERSIONS],
+            timestamp_us: AtomicU32::new(0),
+        }
+    }
+
+    /// Publish a new snapshot
+    ///
+    /// There must only be one writer (the writer is never
+    /// interrupted by another write).
+    pub fn publish(&self, raw: &[u16; MAX_CONVERSIONS], timestamp_us: u32) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        for (cell, value) in self.raw.iter().zip(raw) {
+            cell.store(*value, Ordering::Relaxed);
+        }
+        self.timestamp_us.store(timestamp_us, Ordering::Relaxed);
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// The latest snapshot, or None if nothing has been published
+    /// (or if the caller has interrupted a write)
+    pub fn read(&self) -> Option<AdcSnapshot> {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                // The write cannot complete until the caller
+                // returns
+                return None;
+            }
+
+            let raw = self.raw.each_ref().map(|cell| cell.load(Ordering::Relaxed));
+            let timestamp_us = self.timestamp_us.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return (before != 0).then_some(AdcSnapshot {
+                    raw,
+                    timestamp_us,
+                    count: before / 2,
+                });
+            }
+        }
+    }
+
+    /// The number of snapshots published so far
+    pub fn count(&self) -> u32 {
+        self.sequence.load(Ordering::Acquire) / 2
+    }
+}
+
+impl Default for SnapshotCell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts a 32-bit cycle counter (e.g. the Cortex-M DWT
+/// `CYCCNT`) into a microsecond timestamp
+///
+/// The cycle counter wraps much sooner than a microsecond count
+/// (after 20 s at 216 MHz), so the elapsed cycles are accumulated
+/// on every update. Update at least once per counter period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamper {
+    cycles_per_us: u32,
+    last_cycles: u32,
+
+    // Cycles not yet counted as a whole microsecond
+    remainder: u32,
+    now_us: u32,
+}
+
+impl Timestamper {
+    /// A timestamper for a counter at `clock_hz` (at least 1 MHz)
+    pub const fn new(clock_hz: u32) -> Self {
+        Self {
+            cycles_per_us: clock_hz / 1_000_000,
+            last_cycles: 0,
+            remainder: 0,
+            now_us: 0,
+        }
+    }
+
+    /// Change the counter frequency (e.g. once the clocks are
+    /// configured), starting from a counter value of `cycles`
+    pub fn set_clock(&mut self, clock_hz: u32, cycles: u32) {
+        self.cycles_per_us = clock_hz / 1_000_000;
+        self.last_cycles = cycles;
+        self.remainder = 0;
+    }
+
+    /// The time in microseconds at counter value `cycles` (the
+    /// time wraps after about 71 minutes)
+    pub fn update(&mut self, cycles: u32) -> u32 {
+        let elapsed = cycles.wrapping_sub(self.last_cycles) as u64 + self.remainder as u64;
+        let cycles_per_us = self.cycles_per_us.max(1) as u64;
+        self.last_cycles = cycles;
+        self.remainder = (elapsed % cycles_per_us) as u32;
+        self.now_us = self.now_us.wrapping_add((elapsed / cycles_per_us) as u32);
+        self.now_us
+    }
+}
+
+/// The last `N` samples, in a ring buffer
+///
+/// The oldest sample is overwritten when the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleHistory<T, const N: usize> {
+    samples: [T; N],
+
+    // Where the next sample goes, and the number of samples
+    next: usize,
+    len: usize,
+}
+
+impl<T: Copy + Default, const N: usize> SampleHistory<T, N> {
+    pub fn new() -> Self {
+        Self {
+            samples: [T::default(); N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a sample, overwriting the oldest if the buffer is full
+    pub fn push(&mut self, sample: T) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The sample `age` samples before the latest (0 for the latest)
+    pub fn get(&self, age: usize) -> Option<T> {
+        (age < self.len).then(|| self.samples[(self.next + N - 1 - age) % N])
+    }
+
+    pub fn latest(&self) -> Option<T> {
+        self.get(0)
+    }
+
+    /// The samples from the oldest to the latest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = T> + '_ {
+        (0..self.len).rev().filter_map(|age| self.get(age))
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Default for SampleHistory<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_cell_publishes_latest() {
+        let cell = SnapshotCell::new();
+        assert_eq!(cell.read(), None);
+        assert_eq!(cell.count(), 0);
+
+        cell.publish(&[1, 2, 3, 4, 5], 100);
+        cell.publish(&[6, 7, 8, 9, 10], 150);
+        assert_eq!(
+            cell.read(),
+            Some(AdcSnapshot {
+                raw: [6, 7, 8, 9, 10],
+                timestamp_us: 150,
+                count: 2,
+            })
+        );
+        assert_eq!(cell.count(), 2);
+
+        // A read which interrupts a write gets nothing
+        cell.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(cell.read(), None);
+    }
+
+    #[test]
+    fn timestamps_across_counter_wrap() {
+        // 216 MHz, starting just before the counter wraps
+        let mut timestamps = Timestamper::new(1_000_000);
+        timestamps.set_clock(216_000_000, u32::MAX - 215);
+        assert_eq!(timestamps.update(0), 1);
+
+        // Fractions of a microsecond are carried over
+        assert_eq!(timestamps.update(108), 1);
+        assert_eq!(timestamps.update(216), 2);
+        assert_eq!(timestamps.update(216 + 21_600_000), 100_002);
+    }
+
+    #[test]
+    fn history_keeps_latest_samples() {
+        let mut history = SampleHistory::<u32, 4>::new();
+        assert!(history.is_empty());
+        assert_eq!(history.latest(), None);
+
+        for n in 1..=6 {
+            history.push(n);
+        }
+        assert_eq!(history.len(), 4);
+        assert_eq!(history.latest(), Some(6));
+        assert_eq!(history.get(3), Some(3));
+        assert_eq!(history.get(4), None);
+
+        let mut oldest_first = history.iter();
+        assert_eq!(oldest_first.next(), Some(3));
+        assert_eq!(oldest_first.next_back(), Some(6));
+        assert_eq!(oldest_first.count(), 2);
+
+        history.clear();
+        assert_eq!(history.iter().next(), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Closed-loop speed control
+//!
+//! The motor speed is measured from the commutation step period
+//! (the time between back-EMF zero crossings), and a PI controller
+//! adjusts the duty cycle to track a target speed. The duty cycle
+//! is limited to a range, and its rate of change is limited so that
+//! the commutation can follow the rotor as it accelerates. The
+//! integral is not accumulated while the output is limited
+//! (anti-windup).
+//!
+
+/// Electrical RPM from the commutation step period (there are
+/// six steps per electrical revolution)
+pub fn electrical_rpm(step_period_us: u32) -> f32 {
+    60e6 / (6.0 * step_period_us as f32)
+}
+
+/// Mechanical RPM from the commutation step period
+///
+/// For a motor with `pole_pairs` pairs of poles, there are
+/// `pole_pairs` electrical revolutions per mechanical revolution
+/// (e.g. 7 for the 14-pole P1604).
+pub fn mechanical_rpm(step_period_us: u32, pole_pairs: u8) -> f32 {
+    electrical_rpm(step_period_us) / pole_pairs as f32
+}
+
+/// Parameters of the speed controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeedConfig {
+    /// Number of pole pairs of the motor
+    pub pole_pairs: u8,
+
+    /// Proportional gain (duty cycle per mechanical RPM)
+    pub kp: f32,
+
+    /// Integral gain (duty cycle per mechanical RPM per second)
+    pub ki: f32,
+
+    /// Lowest duty cycle (keeps enough back-EMF to stay
+    /// synchronised)
+    pub min_duty: f32,
+
+    /// Highest duty cycle
+    pub max_duty: f32,
+
+    /// Maximum change in the duty cycle per second
+    pub max_duty_rate: f32,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        Self {
+            pole_pairs: 7,
+            kp: 3e-5,
+            ki: 1e-3,
+            min_duty: 0.05,
+            max_duty: 0.95,
+            max_duty_rate: 0.5,
+        }
+    }
+}
+
+/// PI speed controller
+pub struct SpeedController {
+    config: SpeedConfig,
+
+    // Target mechanical RPM (None when speed control is off)
+    target_rpm: Option<f32>,
+
+    // Integral term (duty cycle)
+    integral: f32,
+
+    // The last duty cycle output
+    duty: f32,
+}
+
+impl SpeedController {
+    pub fn new(config: SpeedConfig) -> Self {
+        Self {
+            config,
+            target_rpm: None,
+            integral: 0.0,
+            duty: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &SpeedConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: SpeedConfig) {
+        self.config = config;
+    }
+
+    /// Set the target mechanical RPM, or None to turn off speed
+    /// control (so the duty cycle is set directly)
+    pub fn set_target_rpm(&mut self, target_rpm: Option<f32>) {
+        self.target_rpm = target_rpm;
+    }
+
+    pub fn target_rpm(&self) -> Option<f32> {
+        self.target_rpm
+    }
+
+    /// Start from the duty cycle `duty` (call while the motor is
+    /// controlled by something else, so that the duty cycle does
+    /// not jump when speed control takes over)
+    pub fn reset(&mut self, duty: f32) {
+        self.integral = duty;
+        self.duty = duty;
+    }
+
+    /// Update the controller with a new measurement of the
+    /// commutation step period, `dt_us` after the previous one
+    ///
+    /// Returns the new duty cycle, or None if speed control is off.
+    pub fn update(&mut self, step_period_us: u32, dt_us: u32) -> Option<f32> {
+        let target_rpm = self.target_rpm?;
+        let config = &self.config;
+        let dt = dt_us as f32 * 1e-6;
+
+        let error = target_rpm - mechanical_rpm(step_period_us, config.pole_pairs);
+        let proportional = config.kp * error;
+        let integral = self.integral + config.ki * error * dt;
+        let unlimited = proportional + integral;
+
+        let max_step = config.max_duty_rate * dt;
+        let duty = unlimited
+            .clamp(config.min_duty, config.max_duty)
+            .clamp(self.duty - max_step, self.duty + max_step);
+
+        // Only integrate if the output is not limited, or if the
+        // error is reducing the integral
+        if duty == unlimited || (unlimited > duty) != (error > 0.0) {
+            self.integral = integral;
+        }
+
+        // Keep the integral in the output range
+        self.integral = self.integral.clamp(config.min_duty, config.max_duty);
+
+        self.duty = duty;
+        Some(duty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Step period (us) of a P1604 at the given mechanical RPM
+    fn step_period_us(rpm: f32) -> u32 {
+        (60e6 / (6.0 * 7.0 * rpm) + 0.5) as u32
+    }
+
+    // First-order model of a motor: the speed settles at a
+    // value proportional to the duty cycle (minus a load)
+    struct Model {
+        rpm: f32,
+    }
+
+    impl Model {
+        fn run(&mut self, duty: f32, dt_us: u32) {
+            let steady_rpm = 40_000.0 * (duty - 0.03);
+            let tau = 0.05;
+            self.rpm += (steady_rpm - self.rpm) * (dt_us as f32 * 1e-6 / tau);
+        }
+    }
+
+    #[test]
+    fn rpm_from_step_period() {
+        // 1000 us per step is 10,000 electrical RPM
+        assert_eq!(electrical_rpm(1000), 10_000.0);
+        assert!((mechanical_rpm(step_period_us(5000.0), 7) - 5000.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn off_without_target() {
+        let mut speed = SpeedController::new(SpeedConfig::default());
+        assert_eq!(speed.update(1000, 1000), None);
+    }
+
+    #[test]
+    fn tracks_target_speed() {
+        let mut speed = SpeedController::new(SpeedConfig::default());
+        let mut model = Model { rpm: 3000.0 };
+        speed.reset(0.105);
+        speed.set_target_rpm(Some(8000.0));
+
+        let mut t = 0;
+        while t < 2_000_000 {
+            let period = step_period_us(model.rpm);
+            let duty = speed.update(period, period).unwrap();
+            model.run(duty, period);
+            t += period;
+        }
+        assert!((model.rpm - 8000.0).abs() < 50.0, "rpm {}", model.rpm);
+    }
+
+    #[test]
+    fn limits_duty_rate() {
+        let mut speed = SpeedController::new(SpeedConfig::default());
+        speed.reset(0.1);
+        speed.set_target_rpm(Some(30_000.0));
+
+        // 0.5 per second, for 100 ms
+        let mut duty = 0.1;
+        for _ in 0..100 {
+            let next = speed.update(step_period_us(3000.0), 1000).unwrap();
+            assert!(next - duty <= 0.0005 + 1e-6);
+            duty = next;
+        }
+        assert!((duty - 0.15).abs() < 1e-3);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut speed = SpeedController::new(SpeedConfig::default());
+        speed.reset(0.5);
+        speed.set_target_rpm(Some(60_000.0));
+
+        // Unreachable target: the duty cycle saturates
+        for _ in 0..10_000 {
+            speed.update(step_period_us(20_000.0), 1000);
+        }
+        assert_eq!(speed.update(step_period_us(20_000.0), 1000), Some(0.95));
+
+        // When the target is reduced, the duty cycle starts to fall
+        // straight away
+        speed.set_target_rpm(Some(10_000.0));
+        let duty = speed.update(step_period_us(20_000.0), 1000).unwrap();
+        assert!(duty < 0.95);
+    }
+}
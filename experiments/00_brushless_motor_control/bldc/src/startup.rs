@@ -0,0 +1,418 @@
+//! Start-up sequence for sensorless commutation
+//!
+//! The back-EMF is proportional to the motor speed, so it
+//! cannot be measured while the motor is stationary or
+//! turning slowly. The motor is started in open loop instead:
+//!
+//! 1. Align: hold one commutation step for a while, so that
+//!    the rotor moves to a known position.
+//! 2. Ramp: commutate at a fixed (decreasing) period, without
+//!    any feedback, to accelerate the motor.
+//! 3. Closed loop: once enough consecutive back-EMF zero
+//!    crossings have been seen, commutate from the zero
+//!    crossings instead.
+//!
+//! If the ramp ends without the zero crossings being found,
+//! or if the zero crossings are lost in closed loop, the
+//! sequence restarts from the alignment.
+//!
+//! The state machine is advanced by calling [`Startup::update`]
+//! every time the commutation timer expires. It returns the
+//! [`StartupAction`] to take, which includes when the timer
+//! should expire next.
+
+use crate::zero_crossing::ZeroCrossingDetector;
+
+/// The longest start-up time (the align time and the ramp step
+/// periods, in microseconds). The firmware times them with a
+/// 16-bit commutation timer at 1 MHz.
+pub const MAX_STARTUP_US: u32 = 65_000;
+
+/// Parameters of the start-up sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartupConfig {
+    /// Duty cycle while aligning the rotor
+    pub align_duty: f32,
+
+    /// Time to hold the alignment step
+    pub align_time_us: u32,
+
+    /// Commutation step period at the start of the ramp
+    pub ramp_start_period_us: u32,
+
+    /// Commutation step period at the end of the ramp
+    pub ramp_end_period_us: u32,
+
+    /// Duty cycle at the start of the ramp
+    pub ramp_start_duty: f32,
+
+    /// Duty cycle at the end of the ramp (which is kept when
+    /// switching to closed loop)
+    pub ramp_end_duty: f32,
+
+    /// Number of commutation steps in the ramp. The speed
+    /// (inverse of the step period) increases linearly from
+    /// one step to the next.
+    pub ramp_steps: u32,
+
+    /// Number of consecutive steps with a zero crossing
+    /// required to switch to closed loop
+    pub handover_steps: u32,
+
+    /// Number of consecutive steps without a zero crossing
+    /// after which synchronisation is considered lost
+    pub max_missed_steps: u32,
+
+    /// Number of times the sequence may restart before giving up
+    pub max_restarts: u32,
+
+    /// How often to check for a start request when stopped
+    pub idle_poll_us: u32,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            align_duty: 0.2,
+            align_time_us: 50_000,
+            ramp_start_period_us: 1500,
+            ramp_end_period_us: 300,
+            ramp_start_duty: 0.4,
+            ramp_end_duty: 0.4,
+            ramp_steps: 2000,
+            handover_steps: 12,
+            max_missed_steps: 6,
+            max_restarts: 3,
+            idle_poll_us: 10_000,
+        }
+    }
+}
+
+/// The state of the start-up sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupState {
+    /// Motor off
+    Stopped,
+    /// Start requested, alignment not yet begun
+    Starting,
+    /// Holding a step to align the rotor
+    Align,
+    /// Open-loop acceleration (the number of ramp steps done)
+    Ramp(u32),
+    /// Commutation is timed from the back-EMF zero crossings
+    ClosedLoop,
+    /// The motor could not be started (restarts exhausted)
+    Failed,
+}
+
+impl StartupState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StartupState::Stopped => "stopped",
+            StartupState::Starting => "starting",
+            StartupState::Align => "align",
+            StartupState::Ramp(_) => "ramp",
+            StartupState::ClosedLoop => "closed loop",
+            StartupState::Failed => "failed",
+        }
+    }
+}
+
+/// What to do when the commutation timer expires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartupAction {
+    /// Turn off the PWM and check again after `poll_us`
+    Off { poll_us: u32 },
+
+    /// Apply the current step (without commutating) for
+    /// `time_us`, at the given duty cycle
+    Align { duty: f32, time_us: u32 },
+
+    /// Commutate, and commutate again after `step_us` (at
+    /// the given duty cycle)
+    OpenLoop { duty: f32, step_us: u32 },
+
+    /// Commutate; the next commutation will be scheduled from
+    /// the back-EMF zero crossing
+    ClosedLoop,
+}
+
+/// Start-up state machine
+pub struct Startup {
+    config: StartupConfig,
+    state: StartupState,
+    restarts: u32,
+}
+
+impl Startup {
+    pub fn new(config: StartupConfig) -> Self {
+        Self {
+            config,
+            state: StartupState::Stopped,
+            restarts: 0,
+        }
+    }
+
+    pub fn config(&self) -> &StartupConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: StartupConfig) {
+        self.config = config;
+    }
+
+    pub fn state(&self) -> StartupState {
+        self.state
+    }
+
+    /// Number of times the sequence has restarted since the
+    /// last call to [`Startup::start`]
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Request the motor to start (takes effect on the next update)
+    pub fn start(&mut self) {
+        self.restarts = 0;
+        self.state = StartupState::Starting;
+    }
+
+    /// Stop the motor (takes effect on the next update)
+    pub fn stop(&mut self) {
+        self.state = StartupState::Stopped;
+    }
+
+    /// Whether the motor is running in closed loop
+    pub fn is_closed_loop(&self) -> bool {
+        self.state == StartupState::ClosedLoop
+    }
+
+    /// Advance the state machine (call when the commutation timer
+    /// expires)
+    ///
+    /// The `detector` is the zero-crossing detector, which must
+    /// already have been told that the step has ended (so that
+    /// it includes the zero crossing in that step, if any).
+    pub fn update(&mut self, detector: &ZeroCrossingDetector) -> StartupAction {
+        match self.state {
+            StartupState::Stopped | StartupState::Failed => self.off(),
+            StartupState::Starting => self.align(),
+            StartupState::Align => self.ramp(0),
+            StartupState::Ramp(done) => {
+                if detector.synchronised_steps() >= self.config.handover_steps {
+                    self.state = StartupState::ClosedLoop;
+                    StartupAction::ClosedLoop
+                } else if done + 1 >= self.config.ramp_steps {
+                    self.restart()
+                } else {
+                    self.ramp(done + 1)
+                }
+            }
+            StartupState::ClosedLoop => {
+                if detector.missed_steps() >= self.config.max_missed_steps {
+                    self.restart()
+                } else {
+                    StartupAction::ClosedLoop
+                }
+            }
+        }
+    }
+
+    fn off(&self) -> StartupAction {
+        StartupAction::Off {
+            poll_us: self.config.idle_poll_us,
+        }
+    }
+
+    fn align(&mut self) -> StartupAction {
+        self.state = StartupState::Align;
+        StartupAction::Align {
+            duty: self.config.align_duty,
+            time_us: self.config.align_time_us,
+        }
+    }
+
+    fn restart(&mut self) -> StartupAction {
+        if self.restarts >= self.config.max_restarts {
+            self.state = StartupState::Failed;
+            self.off()
+        } else {
+            self.restarts += 1;
+            self.align()
+        }
+    }
+
+    fn ramp(&mut self, done: u32) -> StartupAction {
+        self.state = StartupState::Ramp(done);
+
+        let config = &self.config;
+        let fraction = if config.ramp_steps > 1 {
+            done as f32 / (config.ramp_steps - 1) as f32
+        } else {
+            1.0
+        };
+
+        // Interpolate the speed, not the period
+        let start_speed = 1.0 / config.ramp_start_period_us as f32;
+        let end_speed = 1.0 / config.ramp_end_period_us as f32;
+        let speed = start_speed + (end_speed - start_speed) * fraction;
+        let duty =
+            config.ramp_start_duty + (config.ramp_end_duty - config.ramp_start_duty) * fraction;
+
+        StartupAction::OpenLoop {
+            duty,
+            step_us: (1.0 / speed + 0.5) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step::MotorStep;
+    use crate::zero_crossing::ZeroCrossingConfig;
+
+    fn config() -> StartupConfig {
+        StartupConfig {
+            ramp_start_period_us: 1000,
+            ramp_end_period_us: 250,
+            ramp_start_duty: 0.2,
+            ramp_end_duty: 0.5,
+            ramp_steps: 4,
+            handover_steps: 2,
+            max_missed_steps: 2,
+            max_restarts: 1,
+            ..Default::default()
+        }
+    }
+
+    // Step the detector through one commutation step, with
+    // (or without) a clean zero crossing half way through
+    fn run_step(detector: &mut ZeroCrossingDetector, step: &mut MotorStep, zero_crossing: bool) {
+        let before = if step.back_emf_rising() { 1000 } else { 3000 };
+        let after = if zero_crossing { 4000 - before } else { before };
+        let mut phases = [2000; 3];
+        for (t, v) in [(100, before), (300, after), (310, after)] {
+            phases[step.floating_phase()] = v;
+            detector.update(step, &phases, t);
+        }
+        detector.commutated_after(600);
+        step.next();
+    }
+
+    #[test]
+    fn stopped_until_started() {
+        let detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        let mut startup = Startup::new(config());
+        assert_eq!(
+            startup.update(&detector),
+            StartupAction::Off { poll_us: 10_000 }
+        );
+        assert_eq!(startup.state(), StartupState::Stopped);
+
+        startup.start();
+        assert_eq!(
+            startup.update(&detector),
+            StartupAction::Align {
+                duty: 0.2,
+                time_us: 50_000
+            }
+        );
+    }
+
+    #[test]
+    fn ramp_follows_profile() {
+        let detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        let mut startup = Startup::new(config());
+        startup.start();
+        startup.update(&detector);
+
+        let mut periods = [0; 4];
+        for (n, period) in periods.iter_mut().enumerate() {
+            match startup.update(&detector) {
+                StartupAction::OpenLoop { duty, step_us } => {
+                    *period = step_us;
+                    assert!((duty - (0.2 + 0.1 * n as f32)).abs() < 1e-6);
+                }
+                action => panic!("unexpected action {action:?}"),
+            }
+        }
+        // Speed increases linearly from 1/1000 to 1/250 per us
+        assert_eq!(periods, [1000, 500, 333, 250]);
+    }
+
+    #[test]
+    fn hands_over_to_closed_loop() {
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        let mut step = MotorStep::new();
+        let mut startup = Startup::new(StartupConfig {
+            ramp_steps: 100,
+            ..config()
+        });
+        startup.start();
+        startup.update(&detector);
+        startup.update(&detector);
+
+        run_step(&mut detector, &mut step, true);
+        assert!(matches!(
+            startup.update(&detector),
+            StartupAction::OpenLoop { .. }
+        ));
+        run_step(&mut detector, &mut step, true);
+        assert_eq!(startup.update(&detector), StartupAction::ClosedLoop);
+        assert!(startup.is_closed_loop());
+    }
+
+    #[test]
+    fn restarts_when_ramp_fails_then_gives_up() {
+        let detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        let mut startup = Startup::new(config());
+        startup.start();
+
+        for attempt in 0..2 {
+            assert!(matches!(
+                startup.update(&detector),
+                StartupAction::Align { .. }
+            ));
+            for _ in 0..4 {
+                assert!(matches!(
+                    startup.update(&detector),
+                    StartupAction::OpenLoop { .. }
+                ));
+            }
+            assert_eq!(startup.restarts(), attempt);
+        }
+        assert!(matches!(
+            startup.update(&detector),
+            StartupAction::Off { .. }
+        ));
+        assert_eq!(startup.state(), StartupState::Failed);
+    }
+
+    #[test]
+    fn restarts_when_synchronisation_lost() {
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        let mut step = MotorStep::new();
+        let mut startup = Startup::new(StartupConfig {
+            ramp_steps: 100,
+            ..config()
+        });
+        startup.start();
+        startup.update(&detector);
+        startup.update(&detector);
+        for _ in 0..2 {
+            run_step(&mut detector, &mut step, true);
+            startup.update(&detector);
+        }
+        assert!(startup.is_closed_loop());
+
+        run_step(&mut detector, &mut step, false);
+        assert_eq!(startup.update(&detector), StartupAction::ClosedLoop);
+        run_step(&mut detector, &mut step, false);
+        assert!(matches!(
+            startup.update(&detector),
+            StartupAction::Align { .. }
+        ));
+        assert_eq!(startup.restarts(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Six-step (trapezoidal) commutation sequence
+//!
+//! The motor turns forwards when the steps are applied in
+//! increasing order, and in reverse when they are applied in
+//! decreasing order (see [`Direction`]).
+
+/// The state of one phase during a commutation step
+///
+/// In each of the six commutation steps, one phase is driven
+/// with the PWM signal (the line phase, sourcing current), one
+/// phase is held low (the neutral phase, sinking current), and
+/// the remaining phase is left floating so that the back-EMF
+/// can be measured on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseState {
+    /// Driven high with the PWM duty cycle (sources current)
+    Line,
+    /// Always driven low (sinks current)
+    Neutral,
+    /// Both MOSFETs off (high-Z)
+    Floating,
+}
+
+/// The direction the commutation sequence turns the motor
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Through the steps in increasing order
+    #[default]
+    Forward,
+    /// Through the steps in decreasing order
+    Reverse,
+}
+
+impl Direction {
+    /// The other direction
+    pub fn reversed(self) -> Self {
+        match self {
+            Direction::Forward => Direction::Reverse,
+            Direction::Reverse => Direction::Forward,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Forward => "forward",
+            Direction::Reverse => "reverse",
+        }
+    }
+}
+
+/// The numbers 0 to 5, and the direction the steps are taken in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorStep {
+    step: u8,
+    direction: Direction,
+}
+
+impl Default for MotorStep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MotorStep {
+    pub fn new() -> Self {
+        Self {
+            step: 0,
+            direction: Direction::Forward,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.step = (self.step + 1) % 6;
+    }
+
+    pub fn prev(&mut self) {
+        self.step = (self.step + 5) % 6;
+    }
+
+    /// Move on to the following step in the direction of rotation
+    pub fn advance(&mut self) {
+        match self.direction {
+            Direction::Forward => self.next(),
+            Direction::Reverse => self.prev(),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Change the direction (only while the motor is stopped, since
+    /// the step does not change)
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    /// The step number, between 0 and 5
+    pub fn step(&self) -> u8 {
+        self.step
+    }
+
+    /// The state of each of the three phases in this step
+    ///
+    /// Phase 0, 1 and 2 are the phases driven by the half
+    /// bridges with enable 1, 2 and 3 respectively.
+    pub fn phase_states(&self) -> [PhaseState; 3] {
+        use PhaseState::*;
+        match self.step {
+            0 => [Line, Neutral, Floating],
+            1 => [Floating, Neutral, Line],
+            2 => [Neutral, Floating, Line],
+            3 => [Neutral, Line, Floating],
+            4 => [Floating, Line, Neutral],
+            5 => [Line, Floating, Neutral],
+            _ => panic!("Invalid value for MotorStep"),
+        }
+    }
+
+    /// The phase which is left floating in this step (used to
+    /// measure the back-EMF)
+    pub fn floating_phase(&self) -> usize {
+        self.phase_states()
+            .iter()
+            .position(|state| *state == PhaseState::Floating)
+            .unwrap()
+    }
+
+    /// Whether the back-EMF on the floating phase is expected
+    /// to rise through the neutral voltage in this step
+    ///
+    /// The floating phase moves from the state it had in the
+    /// previous step to the state it will have in the next step.
+    /// Going forwards, in even steps it moves from neutral to line
+    /// (rising), and in odd steps from line to neutral (falling).
+    /// In reverse, the slopes are the other way round.
+    pub fn back_emf_rising(&self) -> bool {
+        self.step.is_multiple_of(2) == (self.direction == Direction::Forward)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_step_has_one_phase_of_each_state() {
+        let mut step = MotorStep::new();
+        for _ in 0..6 {
+            let states = step.phase_states();
+            for state in [PhaseState::Line, PhaseState::Neutral, PhaseState::Floating] {
+                assert_eq!(states.iter().filter(|s| **s == state).count(), 1);
+            }
+            step.next();
+        }
+    }
+
+    #[test]
+    fn back_emf_slope_matches_neighbouring_steps() {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let mut step = MotorStep::new();
+            step.set_direction(direction);
+            for _ in 0..6 {
+                let phase = step.floating_phase();
+                let mut before = step;
+                let mut after = step;
+                for _ in 0..5 {
+                    before.advance();
+                }
+                after.advance();
+
+                let (from, to) = if step.back_emf_rising() {
+                    (PhaseState::Neutral, PhaseState::Line)
+                } else {
+                    (PhaseState::Line, PhaseState::Neutral)
+                };
+                assert_eq!(before.phase_states()[phase], from);
+                assert_eq!(after.phase_states()[phase], to);
+                step.advance();
+            }
+        }
+    }
+
+    #[test]
+    fn reverse_goes_back_through_steps() {
+        let mut step = MotorStep::new();
+        step.prev();
+        assert_eq!(step.step(), 5);
+
+        step.set_direction(Direction::Reverse);
+        let steps: [u8; 6] = core::array::from_fn(|_| {
+            step.advance();
+            step.step()
+        });
+        assert_eq!(steps, [4, 3, 2, 1, 0, 5]);
+        assert_eq!(Direction::Reverse.reversed(), Direction::Forward);
+    }
+}
@@ -0,0 +1,267 @@
+//! Live telemetry streaming
+//!
+//! To debug the commutation, the firmware can send a [`Sample`] of
+//! the motor state at a fixed rate (the `stream` command, or
+//! [`Request::Stream`](crate::protocol::Request::Stream) in the
+//! binary protocol), with only the [`StreamField`]s which are
+//! selected, as CSV lines or as binary protocol frames.
+
+use crate::controller::ThreePhaseController;
+use crate::fault::MotorFault;
+use crate::hal::{PhaseDriver, PhaseVoltageSampler, ThreePhasePwm};
+use crate::speed::mechanical_rpm;
+use crate::startup::StartupState;
+
+/// A variable which can be streamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamField {
+    /// Milliseconds since start-up
+    Time,
+    /// The raw ADC conversions of the three phase voltages
+    PhaseVoltages,
+    /// The raw neutral (star point) voltage
+    Neutral,
+    /// The commutation step (0 to 5)
+    Step,
+    /// The PWM duty cycle applied
+    Duty,
+    /// The measured commutation step period in closed loop (µs)
+    Period,
+    /// The mechanical RPM (from the step period)
+    Rpm,
+    /// The motor current (A)
+    Current,
+    /// The supply voltage (V)
+    BusVoltage,
+    /// The start-up state
+    State,
+    /// The latched fault
+    Fault,
+}
+
+impl StreamField {
+    /// The fields in the order they are sent
+    pub const ALL: [StreamField; 11] = [
+        StreamField::Time,
+        StreamField::PhaseVoltages,
+        StreamField::Neutral,
+        StreamField::Step,
+        StreamField::Duty,
+        StreamField::Period,
+        StreamField::Rpm,
+        StreamField::Current,
+        StreamField::BusVoltage,
+        StreamField::State,
+        StreamField::Fault,
+    ];
+
+    /// The name of the field (as the `stream` command takes it)
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamField::Time => "time",
+            StreamField::PhaseVoltages => "phases",
+            StreamField::Neutral => "neutral",
+            StreamField::Step => "step",
+            StreamField::Duty => "duty",
+            StreamField::Period => "period",
+            StreamField::Rpm => "rpm",
+            StreamField::Current => "current",
+            StreamField::BusVoltage => "voltage",
+            StreamField::State => "state",
+            StreamField::Fault => "fault",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+
+    /// The CSV column headings of the field
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            StreamField::Time => &["time_ms"],
+            StreamField::PhaseVoltages => &["phase_a", "phase_b", "phase_c"],
+            StreamField::Neutral => &["neutral"],
+            StreamField::Step => &["step"],
+            StreamField::Duty => &["duty"],
+            StreamField::Period => &["period_us"],
+            StreamField::Rpm => &["rpm"],
+            StreamField::Current => &["current_a"],
+            StreamField::BusVoltage => &["voltage_v"],
+            StreamField::State => &["state"],
+            StreamField::Fault => &["fault"],
+        }
+    }
+
+    fn bit(&self) -> u16 {
+        1 << *self as u16
+    }
+}
+
+/// A set of [`StreamField`]s
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFields(u16);
+
+impl StreamFields {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self((1 << StreamField::ALL.len()) - 1)
+    }
+
+    pub fn with(self, field: StreamField) -> Self {
+        Self(self.0 | field.bit())
+    }
+
+    pub fn contains(&self, field: StreamField) -> bool {
+        self.0 & field.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The selected fields, in the order they are sent
+    pub fn iter(&self) -> impl Iterator<Item = StreamField> + '_ {
+        StreamField::ALL
+            .into_iter()
+            .filter(|field| self.contains(*field))
+    }
+
+    /// The fields as sent in the binary protocol (one bit per field,
+    /// in the order of [`StreamField::ALL`])
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// The fields from their bits, unless a bit is not a field
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        (bits & !Self::all().0 == 0).then_some(Self(bits))
+    }
+
+    /// Parse a comma-separated list of field names (or `all`)
+    pub fn parse(list: &str) -> Option<Self> {
+        if list == "all" {
+            return Some(Self::all());
+        }
+        list.split(',').try_fold(Self::empty(), |fields, name| {
+            StreamField::from_name(name).map(|field| fields.with(field))
+        })
+    }
+}
+
+/// The streamed state of a motor
+///
+/// All the variables are read, but only the selected `fields`
+/// are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub fields: StreamFields,
+    pub time_ms: u32,
+    pub phase_voltages: [u16; 3],
+    pub neutral_voltage: u16,
+    pub step: u8,
+    pub duty: f32,
+    pub step_period_us: Option<u32>,
+    pub rpm: Option<f32>,
+    pub current: Option<f32>,
+    pub bus_voltage: Option<f32>,
+    pub state: StartupState,
+    pub fault: Option<MotorFault>,
+}
+
+impl Sample {
+    pub fn from_controller<D, P, S>(
+        controller: &ThreePhaseController<D, P, S>,
+        fields: StreamFields,
+        time_ms: u32,
+    ) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        let step_period_us = controller.step_period_us();
+        let pole_pairs = controller.speed.config().pole_pairs;
+        Self {
+            fields,
+            time_ms,
+            phase_voltages: controller.sampler().phase_voltages(),
+            neutral_voltage: controller.neutral_voltage,
+            step: controller.step().step(),
+            duty: controller.applied_duty(),
+            step_period_us,
+            rpm: step_period_us.map(|period| mechanical_rpm(period, pole_pairs)),
+            current: controller.current(),
+            bus_voltage: controller.bus_voltage(),
+            state: controller.startup.state(),
+            fault: controller.fault(),
+        }
+    }
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+        Self {
+            fields: StreamFields::empty(),
+            time_ms: 0,
+            phase_voltages: [0; 3],
+            neutral_voltage: 0,
+            step: 0,
+            duty: 0.0,
+            step_period_us: None,
+            rpm: None,
+            current: None,
+            bus_voltage: None,
+            state: StartupState::Stopped,
+            fault: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDriver, MockPwm, MockSampler};
+
+    #[test]
+    fn parses_field_lists() {
+        let fields = StreamFields::parse("duty,phases,rpm").unwrap();
+        assert!(fields.contains(StreamField::Duty));
+        assert!(!fields.contains(StreamField::Time));
+        assert!(fields.iter().eq([
+            StreamField::PhaseVoltages,
+            StreamField::Duty,
+            StreamField::Rpm
+        ]));
+        assert_eq!(StreamFields::parse("all"), Some(StreamFields::all()));
+        assert_eq!(StreamFields::parse("duty,nonsense"), None);
+        assert_eq!(StreamFields::parse(""), None);
+
+        assert_eq!(StreamFields::from_bits(fields.bits()), Some(fields));
+        assert_eq!(StreamFields::from_bits(0x8000), None);
+        for field in StreamField::ALL {
+            assert_eq!(StreamField::from_name(field.name()), Some(field));
+        }
+    }
+
+    #[test]
+    fn samples_controller() {
+        let mut c = ThreePhaseController::new(
+            MockDriver::default(),
+            MockPwm::default(),
+            MockSampler::default(),
+        );
+        c.sampler_mut().samples = [100, 200, 300];
+        c.check_current(2.5);
+
+        let sample = Sample::from_controller(&c, StreamFields::all(), 1234);
+        assert_eq!(sample.time_ms, 1234);
+        assert_eq!(sample.phase_voltages, [100, 200, 300]);
+        assert_eq!(sample.current, Some(2.5));
+        assert_eq!(sample.rpm, None);
+        assert_eq!(sample.state, StartupState::Stopped);
+    }
+}
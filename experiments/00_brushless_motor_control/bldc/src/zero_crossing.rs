@@ -0,0 +1,467 @@
+//! Back-EMF zero-crossing detection for sensorless commutation
+//!
+//! During each commutation step, the back-EMF on the floating
+//! phase crosses the motor neutral voltage half way through
+//! the step (30 electrical degrees after the previous
+//! commutation). The next commutation should therefore happen
+//! 30 electrical degrees (half a step) after the zero crossing.
+//!
+//! The detector is fed with the three phase voltages (raw ADC
+//! values) and the time since the last commutation, and reports
+//! when the floating phase has crossed the neutral voltage
+//! (in the direction expected for the current step). Times
+//! are all in microseconds.
+
+use crate::samples::{PhaseSample, SampleHistory};
+use crate::step::MotorStep;
+
+/// Tuning parameters for the zero-crossing detector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroCrossingConfig {
+    /// Time after a commutation during which samples are
+    /// ignored. Directly after commutation, the current in the
+    /// newly floating phase decays through the freewheeling
+    /// diodes, which clamps the phase voltage to one of the
+    /// rails and can look like an early zero crossing.
+    pub blanking_us: u32,
+
+    /// Number of consecutive samples that must be past the
+    /// neutral voltage before the zero crossing is accepted
+    /// (filters out switching noise).
+    pub confirm_samples: u8,
+}
+
+impl Default for ZeroCrossingConfig {
+    fn default() -> Self {
+        Self {
+            blanking_us: 50,
+            confirm_samples: 2,
+        }
+    }
+}
+
+/// A detected back-EMF zero crossing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroCrossing {
+    /// Time of the zero crossing, since the last commutation
+    pub since_commutation_us: u32,
+
+    /// Estimated duration of one commutation step (60
+    /// electrical degrees)
+    pub step_period_us: u32,
+
+    /// Time to wait, from when the zero crossing was reported,
+    /// before the next commutation
+    pub commutation_delay_us: u32,
+}
+
+/// Back-EMF zero-crossing detector
+///
+/// Call [`ZeroCrossingDetector::update`] for every new set of
+/// phase voltage samples, and one of the `commutated` functions
+/// every time the motor step changes.
+pub struct ZeroCrossingDetector {
+    config: ZeroCrossingConfig,
+
+    // Whether a sample before the crossing (on the expected
+    // side of the neutral voltage) has been seen in this step
+    approaching: bool,
+
+    // Number of consecutive samples past the neutral voltage
+    consecutive: u8,
+
+    // Time (since commutation) of the first sample past neutral
+    first_past_us: u32,
+
+    // The zero crossing detected in the current step, if any,
+    // and the time (since commutation) when it was reported
+    detected: Option<ZeroCrossing>,
+    reported_us: u32,
+
+    // Time from the zero crossing in the previous step to the
+    // commutation at the start of the current step, if known
+    carry_us: Option<u32>,
+
+    // Filtered step period estimate
+    step_period_us: Option<u32>,
+
+    // Number of steps in a row with (or without) a zero crossing
+    synchronised_steps: u32,
+    missed_steps: u32,
+}
+
+impl ZeroCrossingDetector {
+    pub fn new(config: ZeroCrossingConfig) -> Self {
+        Self {
+            config,
+            approaching: false,
+            consecutive: 0,
+            first_past_us: 0,
+            detected: None,
+            reported_us: 0,
+            carry_us: None,
+            step_period_us: None,
+            synchronised_steps: 0,
+            missed_steps: 0,
+        }
+    }
+
+    /// Forget all timing history (e.g. when the motor is stopped)
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// The estimated neutral (star point) voltage
+    ///
+    /// The motor star point is not accessible, so it is
+    /// estimated as the average of the three phase voltages.
+    pub fn neutral_voltage(phases: &[u16; 3]) -> u16 {
+        (phases.iter().map(|v| *v as u32).sum::<u32>() / 3) as u16
+    }
+
+    /// Process a new set of phase voltage samples
+    ///
+    /// The `step` is the commutation step currently applied to
+    /// the motor, and `since_commutation_us` is the time the
+    /// samples were taken, relative to the start of that step.
+    /// Returns the zero crossing the first time it is detected
+    /// in a step, after which the caller should commutate after
+    /// `commutation_delay_us` and then call
+    /// [`ZeroCrossingDetector::commutated`].
+    pub fn update(
+        &mut self,
+        step: &MotorStep,
+        phases: &[u16; 3],
+        since_commutation_us: u32,
+    ) -> Option<ZeroCrossing> {
+        if self.detected.is_some() {
+            return None;
+        }
+
+        let neutral = Self::neutral_voltage(phases);
+        let floating = phases[step.floating_phase()];
+        let past_neutral = if step.back_emf_rising() {
+            floating > neutral
+        } else {
+            floating < neutral
+        };
+
+        // Only accept a crossing after the back-EMF has been seen
+        // on the other side of the neutral voltage. This rejects
+        // a floating phase which is at the neutral voltage because
+        // the rotor is not turning, or which has already crossed
+        // before the start of the step (commutation too late).
+        //
+        // The freewheeling diodes clamp the floating phase past the
+        // neutral voltage after commutation, so samples before the
+        // crossing can be trusted during the blanking time, but
+        // samples past it cannot.
+        if !past_neutral {
+            self.approaching = true;
+            self.consecutive = 0;
+            return None;
+        } else if !self.approaching || since_commutation_us < self.config.blanking_us {
+            return None;
+        }
+
+        if self.consecutive == 0 {
+            self.first_past_us = since_commutation_us;
+        }
+        self.consecutive = self.consecutive.saturating_add(1);
+        if self.consecutive < self.config.confirm_samples {
+            return None;
+        }
+
+        // If the time of the previous zero crossing is not
+        // known, assume this one happened in the middle of
+        // the step.
+        let measured = match self.carry_us {
+            Some(carry_us) => carry_us + self.first_past_us,
+            None => 2 * self.first_past_us,
+        };
+        let step_period_us = match self.step_period_us {
+            Some(previous) => (previous + measured) / 2,
+            None => measured,
+        };
+        self.step_period_us = Some(step_period_us);
+
+        // Commutate 30 electrical degrees after the zero crossing,
+        // less the time already spent confirming it.
+        let confirm_us = since_commutation_us - self.first_past_us;
+        let zero_crossing = ZeroCrossing {
+            since_commutation_us: self.first_past_us,
+            step_period_us,
+            commutation_delay_us: (step_period_us / 2).saturating_sub(confirm_us),
+        };
+        self.detected = Some(zero_crossing);
+        self.reported_us = since_commutation_us;
+        Some(zero_crossing)
+    }
+
+    /// Notify the detector that the motor was commutated after the
+    /// delay returned by [`ZeroCrossingDetector::update`] (closed-loop
+    /// operation)
+    pub fn commutated(&mut self) {
+        let carry_us = self
+            .detected
+            .map(|zc| self.reported_us - zc.since_commutation_us + zc.commutation_delay_us);
+        self.end_step(carry_us);
+    }
+
+    /// Notify the detector that the motor was commutated after a
+    /// fixed step period chosen by the caller (open-loop operation)
+    pub fn commutated_after(&mut self, step_us: u32) {
+        let carry_us = self
+            .detected
+            .map(|zc| step_us.saturating_sub(zc.since_commutation_us));
+        self.end_step(carry_us);
+    }
+
+    fn end_step(&mut self, carry_us: Option<u32>) {
+        if self.detected.is_some() {
+            self.synchronised_steps += 1;
+            self.missed_steps = 0;
+        } else {
+            self.synchronised_steps = 0;
+            self.missed_steps += 1;
+        }
+
+        self.carry_us = carry_us;
+        self.detected = None;
+        self.approaching = false;
+        self.consecutive = 0;
+    }
+
+    /// The time of the latest zero crossing in a history of phase
+    /// voltage samples, interpolated between the samples on either
+    /// side of it
+    ///
+    /// Only the samples since the last commutation (the latest
+    /// samples, with increasing times) are used, and `step` is the
+    /// step applied during them. This is finer than the sample
+    /// period, but uses the neutral voltage of each sample as it is.
+    pub fn interpolated_crossing_us<const N: usize>(
+        step: &MotorStep,
+        history: &SampleHistory<PhaseSample, N>,
+    ) -> Option<u32> {
+        // The floating phase voltage relative to neutral, positive
+        // past the crossing
+        let past = |sample: &PhaseSample| {
+            let neutral = Self::neutral_voltage(&sample.phases) as i32;
+            let floating = sample.phases[step.floating_phase()] as i32 - neutral;
+            if step.back_emf_rising() {
+                floating
+            } else {
+                -floating
+            }
+        };
+
+        let mut after: Option<PhaseSample> = None;
+        for before in history.iter().rev() {
+            if let Some(after) = after {
+                if before.since_commutation_us > after.since_commutation_us {
+                    // Before the last commutation
+                    return None;
+                }
+                let (v0, v1) = (past(&before), past(&after));
+                if v0 <= 0 && v1 > 0 {
+                    let dt = after.since_commutation_us - before.since_commutation_us;
+                    let fraction = (-v0) as u32 * dt / (v1 - v0) as u32;
+                    return Some(before.since_commutation_us + fraction);
+                }
+            }
+            after = Some(before);
+        }
+        None
+    }
+
+    /// The current estimate of the commutation step period
+    pub fn step_period_us(&self) -> Option<u32> {
+        self.step_period_us
+    }
+
+    /// Number of consecutive steps in which a zero crossing was seen
+    pub fn synchronised_steps(&self) -> u32 {
+        self.synchronised_steps
+    }
+
+    /// Number of consecutive steps in which no zero crossing was seen
+    pub fn missed_steps(&self) -> u32 {
+        self.missed_steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Synthetic back-EMF on the floating phase: a ramp from
+    // 1000 to 3000 over the step (crossing 2000 at mid step),
+    // with the other two phases at 1000 and 3000 so that the
+    // neutral estimate is constant.
+    fn samples(step: &MotorStep, t_us: u32, period_us: u32) -> [u16; 3] {
+        let ramp = 1000 + (2000 * t_us / period_us) as u16;
+        let back_emf = if step.back_emf_rising() {
+            ramp
+        } else {
+            4000 - ramp
+        };
+        let mut phases = [1000, 3000, 0];
+        let floating = step.floating_phase();
+        let mut others = (0..3).filter(|p| *p != floating);
+        phases[others.next().unwrap()] = 1000;
+        phases[others.next().unwrap()] = 3000;
+        phases[floating] = back_emf;
+        phases
+    }
+
+    #[test]
+    fn neutral_voltage_is_average() {
+        assert_eq!(
+            ZeroCrossingDetector::neutral_voltage(&[0, 3000, 3003]),
+            2001
+        );
+        assert_eq!(ZeroCrossingDetector::neutral_voltage(&[4095; 3]), 4095);
+    }
+
+    #[test]
+    fn detects_crossing_in_every_step() {
+        let period_us = 600;
+        let mut step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+
+        for _ in 0..12 {
+            let zc = (0..period_us)
+                .step_by(10)
+                .find_map(|t| detector.update(&step, &samples(&step, t, period_us), t))
+                .expect("no zero crossing detected");
+            assert!((300..=310).contains(&zc.since_commutation_us));
+            detector.commutated_after(period_us);
+            step.next();
+        }
+
+        assert_eq!(detector.synchronised_steps(), 12);
+        assert_eq!(detector.missed_steps(), 0);
+        let period = detector.step_period_us().unwrap();
+        assert!((590..=610).contains(&period));
+    }
+
+    #[test]
+    fn ignores_samples_during_blanking() {
+        let step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig {
+            blanking_us: 100,
+            confirm_samples: 1,
+        });
+        let below = [1000, 3000, 1500];
+        let above = [1000, 3000, 2500];
+
+        // Samples before the crossing are used during the blanking
+        // time, but samples past it are not (they may be caused by
+        // the freewheeling diodes)
+        assert_eq!(detector.update(&step, &below, 10), None);
+        assert_eq!(detector.update(&step, &above, 20), None);
+        assert_eq!(detector.update(&step, &above, 90), None);
+        assert!(detector.update(&step, &above, 100).is_some());
+    }
+
+    #[test]
+    fn interpolates_crossing_from_history() {
+        let period_us = 600;
+        let mut step = MotorStep::new();
+        let mut history = SampleHistory::<PhaseSample, 8>::new();
+        let sample = |step: &MotorStep, t| PhaseSample {
+            phases: samples(step, t, period_us),
+            since_commutation_us: t,
+        };
+
+        // The end of the previous step, then samples every 50 us
+        // (the crossing is at 300 us, between samples)
+        history.push(sample(&step, 590));
+        step.next();
+        for t in (20..=170).step_by(50) {
+            history.push(sample(&step, t));
+        }
+        let interpolate = ZeroCrossingDetector::interpolated_crossing_us;
+        assert_eq!(interpolate(&step, &history), None);
+
+        for t in (220..=370).step_by(50) {
+            history.push(sample(&step, t));
+        }
+        let crossing = interpolate(&step, &history).unwrap();
+        assert!((299..=301).contains(&crossing), "crossing at {crossing}");
+    }
+
+    #[test]
+    fn requires_back_emf_before_crossing() {
+        let step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig {
+            blanking_us: 0,
+            confirm_samples: 1,
+        });
+
+        // Floating phase past neutral for the whole step (e.g.
+        // still clamped to the supply by the freewheeling diodes)
+        let clamped = [1000, 3000, 4000];
+        for t in (0..500).step_by(10) {
+            assert_eq!(detector.update(&step, &clamped, t), None);
+        }
+    }
+
+    #[test]
+    fn requires_consecutive_samples() {
+        let step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig {
+            blanking_us: 0,
+            confirm_samples: 3,
+        });
+        let below = [1000, 3000, 1500];
+        let above = [1000, 3000, 2500];
+
+        assert_eq!(detector.update(&step, &above, 100), None);
+        assert_eq!(detector.update(&step, &below, 110), None);
+        assert_eq!(detector.update(&step, &above, 120), None);
+        assert_eq!(detector.update(&step, &above, 130), None);
+        let zc = detector.update(&step, &above, 140).unwrap();
+        assert_eq!(zc.since_commutation_us, 120);
+    }
+
+    #[test]
+    fn counts_missed_steps() {
+        let mut step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig::default());
+        for _ in 0..3 {
+            let phases = [2000; 3];
+            assert_eq!(detector.update(&step, &phases, 200), None);
+            detector.commutated();
+            step.next();
+        }
+        assert_eq!(detector.missed_steps(), 3);
+        assert_eq!(detector.synchronised_steps(), 0);
+        assert_eq!(detector.step_period_us(), None);
+    }
+
+    #[test]
+    fn closed_loop_delay_tracks_period() {
+        let period_us = 400;
+        let mut step = MotorStep::new();
+        let mut detector = ZeroCrossingDetector::new(ZeroCrossingConfig {
+            blanking_us: 20,
+            confirm_samples: 1,
+        });
+
+        for _ in 0..12 {
+            // Samples arrive every 5 us; commutate after the
+            // reported delay, so the next step starts at that time.
+            let zc = (0..period_us)
+                .step_by(5)
+                .find_map(|t| detector.update(&step, &samples(&step, t, period_us), t))
+                .unwrap();
+            assert!((200..=205).contains(&zc.commutation_delay_us));
+            detector.commutated();
+            step.next();
+        }
+        let period = detector.step_period_us().unwrap();
+        assert!((400..=410).contains(&period));
+    }
+}
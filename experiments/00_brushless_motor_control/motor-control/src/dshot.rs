@@ -0,0 +1,400 @@
+//! DShot throttle input (so the firmware can act as an ESC)
+//!
+//! The DShot signal from the flight controller is connected to
+//! PB8 (D15, CN7 pin 10), which is TIM4 channel 3. The timer
+//! captures the time of every edge, and DMA1 (stream 7, channel
+//! 2) transfers the captures into a buffer. When all 32 edges of a
+//! frame have been captured, the DMA interrupt decodes the frame
+//! (see [`bldc::dshot`]) and applies it to motor 0.
+//!
+//! If the line idles high, the flight controller is sending
+//! bidirectional DShot, and each frame is answered with the eRPM
+//! (or extended telemetry, see [`bldc::dshot_telemetry`]). The
+//! pin is switched to an output, and DMA1 (stream 6, channel 2)
+//! writes one bit of the reply to the GPIOB BSRR register at every
+//! TIM4 update. Once the reply has been sent, the pin is switched
+//! back and the capture of the next frame starts.
+//!
+
+use crate::app::{dshot_reply_task, dshot_task};
+use crate::motor::pwm::HSI_HZ;
+use crate::motor::set_throttle;
+use bldc::dshot::{
+    decode_edges, DshotAction, DshotCommand, DshotError, DshotFrame, EDGES_PER_FRAME,
+};
+use bldc::dshot_telemetry::{gcr_encode, ExtendedReadings, Telemetry, GCR_BITS};
+use bldc::Direction;
+use cortex_m::asm::nop;
+use rtic::Mutex;
+use stm32f7xx_hal::{
+    gpio::PB8,
+    pac::{gpiob, DMA1, GPIOB, RCC, TIM4},
+    rcc::Clocks,
+};
+
+/// The delay between the end of a frame and the reply
+const REPLY_DELAY_US: u32 = 30;
+
+// The longest delay before the reply (in bits of the reply), which
+// limits the size of the reply buffer
+const MAX_DELAY_BITS: usize = 48;
+
+/// The length of the reply buffer: the delay, the bits of the
+/// reply, and the return to idle
+pub const REPLY_WORDS: usize = MAX_DELAY_BITS + GCR_BITS + 1;
+
+/// The buffer DMA1 transfers the edge times of a frame into
+pub type EdgeBuffer = [u16; EDGES_PER_FRAME];
+
+/// The GPIOB BSRR values DMA1 transfers to send a reply
+pub type ReplyBuffer = [u32; REPLY_WORDS];
+
+// BSRR values setting PB8 high and low
+const BSRR_HIGH: u32 = 1 << 8;
+const BSRR_LOW: u32 = 1 << (8 + 16);
+
+/// Captures DShot frames using TIM4 and DMA1, and sends the
+/// bidirectional DShot replies
+pub struct DshotReceiver {
+    tim: TIM4,
+    timer_clock_hz: u32,
+
+    // The DMA peripheral transferring the edge times to memory
+    dma: DMA1,
+
+    // The buffer into which the edge times are transferred
+    edges: &'static mut EdgeBuffer,
+
+    // Set while skipping the end of a frame to line up with the
+    // start of the next one
+    resync: bool,
+
+    // Whether the last frame was bidirectional DShot, and its bit
+    // period (in timer ticks)
+    bidirectional: bool,
+    bit_ticks: u16,
+
+    // The GPIOB BSRR values transferred by DMA to send a reply
+    reply: &'static mut ReplyBuffer,
+}
+
+// SAFETY: GPIOB is split in init, and the other GPIOB pins are only
+// set up there. At run time, only PB8 is changed here: its mode
+// (read-modify-write of MODER, in the DShot interrupts only) and
+// its output level (BSRR writes, which are atomic).
+fn gpiob() -> &'static gpiob::RegisterBlock {
+    unsafe { &*GPIOB::ptr() }
+}
+
+impl DshotReceiver {
+    /// Set up the capture of the frames. The buffers are static,
+    /// so that DMA1 can keep using them.
+    pub fn new(
+        rcc: &RCC,
+        tim: TIM4,
+        pin: PB8,
+        dma: DMA1,
+        edges: &'static mut EdgeBuffer,
+        reply: &'static mut ReplyBuffer,
+    ) -> Self {
+        const TIM4_CH3_AF: u8 = 2;
+        let _ = pin.into_alternate::<TIM4_CH3_AF>();
+
+        // Enable the timer and DMA clocks (delay after two clock
+        // cycles before accessing peripheral registers)
+        rcc.apb1enr.modify(|_, w| w.tim4en().bit(true));
+        rcc.ahb1enr.modify(|_, w| w.dma1en().set_bit());
+        nop();
+        nop();
+
+        // Count at the full timer clock, over the whole 16-bit range
+        // (the decoder handles the counter wrapping)
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.arr.write(|w| w.arr().bits(0xffff));
+
+        // Capture channel 3 from TI3, with a short filter (four
+        // timer clocks) to reject glitches
+        tim.ccmr2_input().write(|w| unsafe {
+            w.cc3s().bits(0b01);
+            w.ic3f().bits(0b0010)
+        });
+
+        // Capture on both edges, and request a DMA transfer on
+        // every capture
+        tim.ccer.write(|w| {
+            w.cc3p().set_bit();
+            w.cc3np().set_bit();
+            w.cc3e().set_bit()
+        });
+        tim.dier.write(|w| w.cc3de().set_bit());
+
+        // Transfer from the CCR3 register of TIM4
+        let ccr3_addr = TIM4::ptr() as u32 + 0x3c;
+        dma.st[7].par.write(|w| unsafe { w.bits(ccr3_addr) });
+
+        dma.st[7]
+            .m0ar
+            .write(|w| unsafe { w.bits(edges.as_ptr() as u32) });
+
+        dma.st[7].cr.write(|w| {
+            // Peripheral to memory, on channel 2 (TIM4_CH3, see
+            // table 27 p. 225)
+            unsafe { w.dir().bits(0b00) };
+            w.chsel().bits(2);
+
+            // Half word transfers, incrementing the memory address
+            unsafe {
+                w.psize().bits(0b01);
+                w.msize().bits(0b01);
+            }
+            w.minc().set_bit();
+
+            // Interrupts on transfer complete and error
+            w.tcie().set_bit();
+            w.teie().set_bit()
+        });
+
+        // Transfer the replies to the BSRR register of GPIOB
+        let bsrr_addr = GPIOB::ptr() as u32 + 0x18;
+        dma.st[6].par.write(|w| unsafe { w.bits(bsrr_addr) });
+
+        reply.fill(BSRR_HIGH);
+        dma.st[6]
+            .m0ar
+            .write(|w| unsafe { w.bits(reply.as_ptr() as u32) });
+
+        dma.st[6].cr.write(|w| {
+            // Memory to peripheral, on channel 2 (TIM4_UP)
+            unsafe { w.dir().bits(0b01) };
+            w.chsel().bits(2);
+
+            // Word transfers, incrementing the memory address
+            unsafe {
+                w.psize().bits(0b10);
+                w.msize().bits(0b10);
+            }
+            w.minc().set_bit();
+
+            w.tcie().set_bit();
+            w.teie().set_bit()
+        });
+
+        tim.cr1.write(|w| w.cen().set_bit());
+
+        let mut receiver = Self {
+            tim,
+            timer_clock_hz: HSI_HZ,
+            dma,
+            edges,
+            resync: false,
+            bidirectional: false,
+            bit_ticks: 0,
+            reply,
+        };
+        receiver.capture(EDGES_PER_FRAME);
+        receiver
+    }
+
+    /// Use the TIM4 clock frequency from the configured clocks
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.timer_clock_hz = clocks.timclk1().raw();
+    }
+
+    /// Whether the flight controller is sending bidirectional
+    /// DShot (and so expects a reply to every frame)
+    pub fn bidirectional(&self) -> bool {
+        self.bidirectional
+    }
+
+    /// Start the DMA transfer of the next `count` edges
+    fn capture(&mut self, count: usize) {
+        let stream = &self.dma.st[7];
+        stream.cr.modify(|_, w| w.en().clear_bit());
+        while stream.cr.read().en().bit() {}
+
+        // Discard any capture from before the transfer started
+        let _ = self.tim.ccr3().read();
+
+        stream.ndtr.write(|w| w.ndt().bits(count as u16));
+        stream.cr.modify(|_, w| w.en().set_bit());
+    }
+
+    /// Handle the DMA1 stream 7 interrupt
+    ///
+    /// Returns the decoded frame (or the reason it could not be
+    /// decoded) when a whole frame has been captured. For a
+    /// bidirectional frame, the capture starts again once the reply
+    /// has been sent, so [`send_reply`](Self::send_reply) must be
+    /// called.
+    pub fn on_dma_interrupt(&mut self) -> Option<Result<DshotFrame, DshotError>> {
+        if self.dma.hisr.read().teif7().bit() {
+            defmt::info!("DShot DMA transfer error");
+            self.dma.hifcr.write(|w| w.cteif7().set_bit());
+            self.capture(EDGES_PER_FRAME);
+            return None;
+        }
+
+        if !self.dma.hisr.read().tcif7().bit() {
+            return None;
+        }
+        self.dma.hifcr.write(|w| w.ctcif7().set_bit());
+
+        // The end of a frame has been skipped, so the next capture
+        // starts with the first edge of a frame
+        if self.resync {
+            self.resync = false;
+            self.capture(EDGES_PER_FRAME);
+            return None;
+        }
+
+        // The line has just gone back to idle: low for DShot, and
+        // high for bidirectional DShot
+        self.bidirectional = gpiob().idr.read().idr8().bit_is_set();
+        let bits = (EDGES_PER_FRAME / 2 - 1) as u16;
+        self.bit_ticks = self.edges[EDGES_PER_FRAME - 2].wrapping_sub(self.edges[0]) / bits;
+
+        let result = decode_edges(self.edges, self.bidirectional);
+        match result {
+            Err(DshotError::Misaligned { skip }) => {
+                self.resync = true;
+                self.capture(skip);
+            }
+            Ok(_) if self.bidirectional => {}
+            _ => self.capture(EDGES_PER_FRAME),
+        }
+        Some(result)
+    }
+
+    /// Send a bidirectional DShot reply (after the frame which has
+    /// just been captured)
+    pub fn send_reply(&mut self, telemetry: Telemetry) {
+        let line = gcr_encode(telemetry.encode());
+
+        // The reply is at 5/4 of the bit rate of the frame
+        let bit_ticks = (self.bit_ticks as u32 * 4 / 5).max(1);
+        let delay_ticks = REPLY_DELAY_US * (self.timer_clock_hz / 1_000_000);
+        let delay_bits = ((delay_ticks / bit_ticks) as usize).min(MAX_DELAY_BITS);
+
+        let reply = &mut self.reply;
+        reply[..delay_bits].fill(BSRR_HIGH);
+        for bit in 0..GCR_BITS {
+            let high = line >> (GCR_BITS - 1 - bit) & 1 == 1;
+            reply[delay_bits + bit] = if high { BSRR_HIGH } else { BSRR_LOW };
+        }
+        reply[delay_bits + GCR_BITS] = BSRR_HIGH;
+        let words = delay_bits + GCR_BITS + 1;
+
+        // Stop capturing (the reply would be captured as well), and
+        // drive the pin, starting at the idle level
+        self.tim.ccer.modify(|_, w| w.cc3e().clear_bit());
+        let gpiob = gpiob();
+        gpiob.bsrr.write(|w| w.bs8().set_bit());
+        gpiob.moder.modify(|_, w| w.moder8().output());
+
+        // Transfer one word at every update event
+        self.tim.arr.write(|w| w.arr().bits(bit_ticks as u16 - 1));
+        self.tim.cnt.write(|w| w.cnt().bits(0));
+        let stream = &self.dma.st[6];
+        stream.ndtr.write(|w| w.ndt().bits(words as u16));
+        stream.cr.modify(|_, w| w.en().set_bit());
+        self.tim.dier.modify(|_, w| w.ude().set_bit());
+    }
+
+    /// Handle the DMA1 stream 6 interrupt (the reply has been
+    /// sent), and start capturing the next frame
+    pub fn on_reply_dma_interrupt(&mut self) {
+        if self.dma.hisr.read().teif6().bit() {
+            defmt::info!("DShot reply DMA transfer error");
+        }
+        self.dma.hifcr.write(|w| {
+            w.ctcif6().set_bit();
+            w.cteif6().set_bit()
+        });
+
+        self.tim.dier.modify(|_, w| w.ude().clear_bit());
+        self.dma.st[6].cr.modify(|_, w| w.en().clear_bit());
+        self.tim.arr.write(|w| w.arr().bits(0xffff));
+        gpiob().moder.modify(|_, w| w.moder8().alternate());
+        self.tim.ccer.modify(|_, w| w.cc3e().set_bit());
+        self.capture(EDGES_PER_FRAME);
+    }
+}
+
+/// DShot DMA interrupt (a frame has been captured)
+pub fn dshot_task(mut cx: dshot_task::Context<'_>) {
+    let input = cx.local.dshot_input;
+    let frame = match cx
+        .shared
+        .dshot_receiver
+        .lock(|receiver| receiver.on_dma_interrupt())
+    {
+        Some(Ok(frame)) => frame,
+        Some(Err(error)) => {
+            defmt::debug!("DShot: {}", defmt::Debug2Format(&error));
+            return;
+        }
+        None => return,
+    };
+
+    // Reply first, so that the reply is not delayed by the motor
+    // control
+    if cx
+        .shared
+        .dshot_receiver
+        .lock(|receiver| receiver.bidirectional())
+    {
+        let (erpm, readings) = cx.shared.motor0.lock(|motor| {
+            let controller = &motor.controller;
+            let erpm = Telemetry::from_step_period(controller.step_period_us());
+
+            // The temperature is not measured
+            let readings = ExtendedReadings {
+                temperature_c: None,
+                voltage: controller.bus_voltage(),
+                current: controller.current(),
+            };
+            (erpm, readings)
+        });
+        let extended = input.extended_telemetry().then_some(&readings);
+        let reply = cx.local.dshot_telemetry.next(erpm, extended);
+        cx.shared
+            .dshot_receiver
+            .lock(|receiver| receiver.send_reply(reply));
+    }
+
+    match input.update(frame) {
+        DshotAction::Stop => cx.shared.motor0.lock(|motor| motor.controller.stop()),
+        DshotAction::Throttle { throttle, reverse } => cx.shared.motor0.lock(|motor| {
+            // A change of direction brakes the motor to a stop
+            // first (see set_direction)
+            let direction = match reverse {
+                true => Direction::Reverse,
+                false => Direction::Forward,
+            };
+            motor.controller.set_direction(direction);
+            set_throttle(&mut motor.controller, throttle);
+        }),
+        DshotAction::Command(DshotCommand::Beep(n)) => defmt::info!("DShot beep {}", n),
+        DshotAction::Command(DshotCommand::SaveSettings) => {
+            // There is no storage for settings yet, so they only last
+            // until the next reset
+            defmt::info!(
+                "DShot settings: reversed {}, 3D {}",
+                input.reversed(),
+                input.three_d()
+            );
+        }
+        DshotAction::Command(command) => {
+            defmt::info!("DShot command: {}", defmt::Debug2Format(&command))
+        }
+        DshotAction::None => {}
+    }
+}
+
+/// DShot reply DMA interrupt (a bidirectional DShot reply has been
+/// sent)
+pub fn dshot_reply_task(mut cx: dshot_reply_task::Context<'_>) {
+    cx.shared
+        .dshot_receiver
+        .lock(|receiver| receiver.on_reply_dma_interrupt());
+}
@@ -1,4 +1,5 @@
 // Linked-List First Fit Heap allocator (feature = "llff")
+use bldc::Parameter;
 use embedded_alloc::LlffHeap as Heap;
 
 #[global_allocator]
@@ -7,7 +8,7 @@ static HEAP: Heap = Heap::empty();
 pub fn init_heap() {
     // Initialize the allocator BEFORE you use it
     use core::mem::MaybeUninit;
-    const HEAP_SIZE: usize = 1024;
+    const HEAP_SIZE: usize = Parameter::HeapSize.info().default as usize;
     static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
     unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
 }
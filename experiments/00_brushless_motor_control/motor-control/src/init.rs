@@ -1,19 +1,52 @@
 use crate::app::Mono;
 use crate::app::{init, Local, Shared};
+use crate::dshot::{DshotReceiver, EdgeBuffer, ReplyBuffer, REPLY_WORDS};
 use crate::heap::init_heap;
-use crate::motor::{MotorStep, ThreePhaseController};
-use crate::uart_serial::init_uart_serial;
+use crate::kiss_telemetry::KissTelemetryUart;
+use crate::motor::adc::{share_dma2, AdcSampler, DmaBuffers, SAMPLES};
+#[cfg(feature = "complementary-pwm")]
+use crate::motor::complementary_pwm::{ComplementaryPins, ComplementaryPwm};
+use crate::motor::config::{MOTORS, PULSE_INPUT};
+#[cfg(not(feature = "complementary-pwm"))]
+use crate::motor::pwm::ThreeChannelPwm;
+use crate::motor::tim8_pwm::Tim8Pwm;
+use crate::motor::{CommutationCounter, EnablePins, ThreePhaseController};
+use crate::pulse_input::PulseReceiver;
+use crate::serial_port::{RxQueue, SerialPort, TxQueue};
+use bldc::config::MAX_CONVERSIONS;
+use bldc::dshot::EDGES_PER_FRAME;
+use bldc::{
+    check_configs, CommutationTimer, DshotInput, Parameter, PhaseDriver, PulseInput,
+    TelemetryScheduler, ThreePhasePwm,
+};
+use heapless::spsc::Queue;
 use stm32f7xx_hal::prelude::*;
 use stm32f7xx_hal::rcc::{self, HSEClock};
-use stm32f7xx_hal::timer::Event;
+use stm32f7xx_hal::timer::{self, CounterUs, Event};
 
 use crate::CLOCK_FREQ_HZ;
 
+/// Dead-time between the high-side and low-side outputs of the
+/// complementary PWM (the FD6288T and IR2109 add their own, so
+/// this only needs to cover the MOSFET switching times)
+#[cfg(feature = "complementary-pwm")]
+const DEAD_TIME_NS: u32 = Parameter::DeadTime.info().default as u32;
+
+/// The commutation timer period until the first commutation timer
+/// interrupt (when the start-up sequence takes over)
+const OPEN_LOOP_STEP_US: u32 = Parameter::OpenLoopStep.info().default as u32;
+
 pub fn init(cx: init::Context) -> (Shared, Local) {
     defmt::info!("Starting RTIC init task");
 
     Mono::start(cx.core.SYST, CLOCK_FREQ_HZ);
 
+    // The cycle counter timestamps the ADC samples
+    let mut dcb = cx.core.DCB;
+    let mut dwt = cx.core.DWT;
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+
     // Initialise the heap
     init_heap();
 
@@ -25,40 +58,135 @@ pub fn init(cx: init::Context) -> (Shared, Local) {
     // peripheral.
     let gpioa = device.GPIOA.split();
     let gpiob = device.GPIOB.split();
+    let gpioc = device.GPIOC.split();
+    let gpiog = device.GPIOG.split();
+    #[cfg(not(feature = "complementary-pwm"))]
     let gpioh = device.GPIOH.split();
     let gpioi = device.GPIOI.split();
     let gpiof = device.GPIOF.split();
 
+    // Check that the motors do not share any timers, pins,
+    // ADCs or ADC channels
+    if let Err(error) = check_configs(&MOTORS) {
+        defmt::panic!(
+            "Invalid motor configuration: {}",
+            defmt::Debug2Format(&error)
+        );
+    }
+
     // Do all the PAC-level setup here before any HAL
     // setup which eats the resources.
 
     //let adc = init_adc3(&device.RCC, device.ADC3, gpioa.pa0);
 
-    let en1 = gpiob.pb4.into_push_pull_output();
-    let en2 = gpioh.ph6.into_push_pull_output();
-    let en3 = gpioi.pi2.into_push_pull_output();
+    #[cfg(not(feature = "complementary-pwm"))]
+    let (enable_pins, mut pwm) = {
+        let enable_pins = EnablePins {
+            en1: gpiob.pb4.into_push_pull_output().erase(),
+            en2: gpioh.ph6.into_push_pull_output().erase(),
+            en3: gpioi.pi2.into_push_pull_output().erase(),
+        };
+
+        let pwm = ThreeChannelPwm::new(
+            &device.RCC,
+            device.TIM1,
+            gpioa.pa8,
+            device.TIM2,
+            gpioa.pa15.into(),
+            device.TIM5,
+            gpioi.pi0,
+        );
+
+        (enable_pins, pwm)
+    };
+
+    #[cfg(feature = "complementary-pwm")]
+    let (mut pwm, enable_pins) = {
+        let gpioe = device.GPIOE.split();
+        let pins = ComplementaryPins {
+            high: (gpioe.pe9, gpioe.pe11, gpioe.pe13),
+            low: (gpioe.pe8, gpioe.pe10, gpioe.pe12),
+            break_input: gpioe.pe15,
+        };
+        ComplementaryPwm::new(&device.RCC, device.TIM1, pins, DEAD_TIME_NS)
+    };
+
+    // Motor 1 (TIM8 after TIM1, whose set-up overwrites the APB2
+    // clock enables)
+    let enable_pins1 = EnablePins {
+        en1: gpiog.pg6.into_push_pull_output().erase(),
+        en2: gpiog.pg7.into_push_pull_output().erase(),
+        en3: gpioi.pi3.into_push_pull_output().erase(),
+    };
+    let mut pwm1 = Tim8Pwm::new(&device.RCC, device.TIM8, (gpioi.pi5, gpioi.pi6, gpioi.pi7));
+
+    // DShot throttle input on PB8 (TIM4 channel 3), with static
+    // DMA buffers for the edge times and the replies
+    let dshot_edges = cortex_m::singleton!(: EdgeBuffer = [0; EDGES_PER_FRAME]).unwrap();
+    let dshot_reply = cortex_m::singleton!(: ReplyBuffer = [0; REPLY_WORDS]).unwrap();
+    let mut dshot_receiver = DshotReceiver::new(
+        &device.RCC,
+        device.TIM4,
+        gpiob.pb8,
+        device.DMA1,
+        dshot_edges,
+        dshot_reply,
+    );
+
+    // PWM, OneShot125 or Multishot throttle input on PB14 (TIM12
+    // channel 1)
+    let mut pulse_receiver = PulseReceiver::new(&device.RCC, device.TIM12, gpiob.pb14);
 
-    let mut three_phase_controller = ThreePhaseController::new(
-        en1,
-        en2,
-        en3,
+    // KISS ESC telemetry output on PC6 (USART6 TX)
+    let mut kiss_uart = KissTelemetryUart::new(&device.RCC, device.USART6, gpioc.pc6);
+
+    // Serial CLI on USART1 (ST-LINK virtual COM port), with static
+    // ring buffers shared with the USART1 interrupt
+    let rx_queue = cortex_m::singleton!(: RxQueue = Queue::new()).unwrap();
+    let tx_queue = cortex_m::singleton!(: TxQueue = Queue::new()).unwrap();
+    let (mut serial_port, serial_rx, serial_tx) = SerialPort::new(
+        &device.RCC,
+        device.USART1,
+        gpiob.pb7,
+        gpioa.pa9,
+        rx_queue,
+        tx_queue,
+    );
+
+    // The samplers of both motors use DMA2, with static buffers
+    // (so that DMA2 can keep writing to them, and not on the heap)
+    let dma2 = share_dma2(&device.RCC, device.DMA2);
+
+    // Motor 0 on ADC3 (A0 to A4 on the Arduino header)
+    gpioa.pa0.into_analog();
+    gpiof.pf10.into_analog();
+    gpiof.pf9.into_analog();
+    gpiof.pf8.into_analog();
+    gpiof.pf7.into_analog();
+    let adc_buffers = cortex_m::singleton!(: DmaBuffers = [[0; MAX_CONVERSIONS]; 2]).unwrap();
+    let mut sampler = AdcSampler::new(
         &device.RCC,
-        device.TIM1,
-        gpioa.pa8,
-        device.TIM2,
-        gpioa.pa15.into(),
-        device.TIM5,
-        gpioi.pi0,
         device.ADC3,
-        gpioa.pa0,
-        gpiof.pf10,
-        gpiof.pf9,
-        device.DMA2,
+        dma2,
+        adc_buffers,
+        &SAMPLES[0],
+        &MOTORS[0],
     );
 
-    three_phase_controller.enable(true);
-    three_phase_controller.set_period(2000);
-    three_phase_controller.set_duty(0.4);
+    // Motor 1 on ADC1
+    gpioa.pa4.into_analog();
+    gpioa.pa6.into_analog();
+    gpioc.pc2.into_analog();
+    gpioa.pa5.into_analog();
+    let adc_buffers = cortex_m::singleton!(: DmaBuffers = [[0; MAX_CONVERSIONS]; 2]).unwrap();
+    let mut sampler1 = AdcSampler::new(
+        &device.RCC,
+        device.ADC1,
+        dma2,
+        adc_buffers,
+        &SAMPLES[1],
+        &MOTORS[1],
+    );
 
     // The DISCO board has a 25 MHz oscillator connected to
     // the HSE input. Configure the MCU to use this external
@@ -74,51 +202,105 @@ pub fn init(cx: init::Context) -> (Shared, Local) {
         .pclk2(20_000_000.Hz())
         .freeze();
 
-    // Set up the usart1 (stlink v2 serial)
-    let (serial_rx, serial_tx) = init_uart_serial(device.USART1, gpiob.pb7, gpioa.pa9, &clocks);
-
-    //let pwm_freq = 20.kHz();
-
-    // let pin = gpioi.pi0.into_alternate();
-    // let sig1 = device.TIM5.pwm_hz(pin, pwm_freq, &clocks).split();
-    // let pin = gpioa.pa15.into_alternate();
-    // let sig2 = device.TIM2.pwm_hz(pin, pwm_freq, &clocks).split();
-    // let pin = gpioa.pa8.into_alternate();
-    // let sig3 = device.TIM1.pwm_hz(pin, pwm_freq, &clocks).split();
-
-    // let mut bldc = BldcCtrl::new(en1, en2, en3, sig1, sig2, sig3);
-
-    // // Set motor PWM duty cycle
-    // bldc.set_duty(0.5);
+    // The PWM frequency depends on the timer clocks, so it is set
+    // once the clocks are configured
+    pwm.set_clocks(&clocks);
+    pwm1.set_clocks(&clocks);
+    dshot_receiver.set_clocks(&clocks);
+    pulse_receiver.set_clocks(&clocks);
+    kiss_uart.set_clocks(&clocks);
+    serial_port.set_clocks(&clocks);
+    sampler.set_clocks(&clocks);
+    sampler1.set_clocks(&clocks);
 
-    // // Turn on the PWM
-    // bldc.enable();
-
-    // Set up the motor commutation timer
-    let mut counter = device.TIM3.counter_us(&clocks);
-    counter.start(3.millis()).unwrap();
-    counter.listen(Event::Update);
+    // The controllers start disarmed and stopped (see the `arm`
+    // and `start` commands)
+    let motor0 = new_motor(
+        0,
+        ThreePhaseController::new(enable_pins, pwm, sampler),
+        device.TIM3.counter_us(&clocks),
+    );
+    let motor1 = new_motor(
+        1,
+        bldc::ThreePhaseController::new(enable_pins1, pwm1, sampler1),
+        device.TIM7.counter_us(&clocks),
+    );
 
     // Set up the green output LED
     let green_led = gpioi.pi1.into_push_pull_output();
 
     crate::app::hello_loop::spawn().ok();
     crate::app::serial_task::spawn().ok();
+    crate::app::kiss_telemetry_task::spawn().ok();
     //crate::app::adc_task::spawn().ok();
 
     defmt::info!("Ending init task");
 
     (
         Shared {
-            three_phase_controller,
-            commutator_counter: counter,
+            motor0,
+            motor1,
+            dshot_receiver,
         },
         Local {
             serial_rx,
             serial_tx,
+            serial_port,
             green_led,
-            motor_step: MotorStep::new(),
-	    current_time: 1500,
+            dshot_input: DshotInput::new(),
+            dshot_telemetry: TelemetryScheduler::new(),
+            pulse_receiver,
+            pulse_input: PulseInput::new(PULSE_INPUT),
+            kiss_uart,
         },
     )
 }
+
+/// Set up motor `n` (see [`MOTORS`]) with its controller and
+/// commutation timer
+fn new_motor<D, P, TIM>(
+    n: usize,
+    mut controller: bldc::ThreePhaseController<D, P, AdcSampler>,
+    mut counter: CounterUs<TIM>,
+) -> bldc::Motor<D, P, AdcSampler, CommutationCounter<TIM>>
+where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    TIM: timer::Instance,
+{
+    // Start from the defaults of the parameters (PWM frequency,
+    // duty cycle, start-up sequence, limits, ...), which can then
+    // be changed with the `set` command
+    if let Err(error) = Parameter::reset_all(&mut controller) {
+        defmt::panic!("Invalid default parameter: {}", error.name());
+    }
+    defmt::info!(
+        "Motor {} PWM frequency: {} Hz",
+        n,
+        controller.pwm().frequency_hz()
+    );
+
+    // Derive the start-up sequence and control settings from the
+    // measured motor parameters (see the `identify` command)
+    if let Some(parameters) = &MOTORS[n].parameters {
+        controller.apply_parameters(parameters);
+    }
+
+    // The time between open-loop commutation steps, until the
+    // motor is started (align, then open-loop ramp, then closed
+    // loop once the back-EMF can be measured). The commutation
+    // timer is 16-bit at 1 MHz, so all the start-up times must
+    // be less than 65 ms.
+    controller.open_loop_step_us = Some(OPEN_LOOP_STEP_US);
+
+    // Set up the motor commutation timer
+    counter.listen(Event::Update);
+    let mut timer = CommutationCounter(counter);
+    if let Err(error) = timer.start(OPEN_LOOP_STEP_US) {
+        defmt::panic!(
+            "Invalid commutation timer period (at most {} us)",
+            error.max_us
+        );
+    }
+    bldc::Motor::new(controller, timer)
+}
@@ -0,0 +1,85 @@
+//! KISS ESC serial telemetry output
+//!
+//! The telemetry is sent on PC6 (D1, CN4 pin 2), the TX pin of
+//! USART6, in half-duplex mode (a single wire to the telemetry
+//! input of the flight controller). Every
+//! [`TELEMETRY_PERIOD_MS`], a frame with the state of motor 0 is
+//! sent (see [`bldc::kiss_telemetry`] for the format).
+
+use crate::app::{kiss_telemetry_task, Mono};
+use bldc::kiss_telemetry::BAUD_RATE;
+use bldc::KissTelemetry;
+use cortex_m::asm::nop;
+use rtic::Mutex;
+use rtic_monotonics::systick::prelude::*;
+use stm32f7xx_hal::{
+    gpio::PC6,
+    pac::{RCC, USART6},
+    rcc::Clocks,
+};
+
+/// The time between telemetry frames
+pub const TELEMETRY_PERIOD_MS: u32 = 100;
+
+/// Sends telemetry frames on USART6
+pub struct KissTelemetryUart {
+    usart: USART6,
+}
+
+impl KissTelemetryUart {
+    /// Set up the pin and enable the USART6 clock. The UART does
+    /// not send anything until the clocks are configured (see
+    /// [`set_clocks`](Self::set_clocks)).
+    pub fn new(rcc: &RCC, usart: USART6, pin: PC6) -> Self {
+        const USART6_AF: u8 = 8;
+        let _ = pin.into_alternate::<USART6_AF>();
+
+        // Enable the USART clock (delay after two clock cycles
+        // before accessing peripheral registers)
+        rcc.apb2enr.modify(|_, w| w.usart6en().set_bit());
+        nop();
+        nop();
+
+        Self { usart }
+    }
+
+    /// Set the baud rate from the USART6 clock (pclk2), and enable
+    /// the transmitter
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+
+        // Oversampling by 16, rounded to the nearest divider
+        let pclk2 = clocks.pclk2().raw();
+        let brr = (pclk2 + BAUD_RATE / 2) / BAUD_RATE;
+        self.usart.brr.write(|w| unsafe { w.bits(brr) });
+
+        // Half-duplex (single wire on the TX pin), 8N1, transmit
+        // only
+        self.usart.cr3.write(|w| w.hdsel().set_bit());
+        self.usart.cr1.write(|w| {
+            w.te().set_bit();
+            w.ue().set_bit()
+        });
+    }
+
+    /// Send a frame (waiting until each byte can be written)
+    pub fn send(&mut self, frame: &[u8]) {
+        for &byte in frame {
+            while !self.usart.isr.read().txe().bit() {}
+            self.usart.tdr.write(|w| w.tdr().bits(byte as u16));
+        }
+    }
+}
+
+/// Periodically send the telemetry of motor 0
+pub async fn kiss_telemetry_task(mut cx: kiss_telemetry_task::Context<'_>) {
+    loop {
+        let telemetry = cx.shared.motor0.lock(|motor| {
+            motor.controller.add_consumption(TELEMETRY_PERIOD_MS * 1000);
+            KissTelemetry::from_controller(&motor.controller)
+        });
+        cx.local.kiss_uart.send(&telemetry.encode());
+
+        Mono::delay(TELEMETRY_PERIOD_MS.millis()).await;
+    }
+}
@@ -3,47 +3,75 @@
 
 extern crate alloc;
 
+pub mod dshot;
 pub mod heap;
 pub mod init;
+pub mod kiss_telemetry;
 pub mod motor;
+pub mod pulse_input;
+pub mod serial_port;
+pub mod status;
 pub mod uart_serial;
 
 mod panic_etc;
 
-pub const CLOCK_FREQ_HZ: u32 = 216_000_000;
-pub const SYSTICK_RATE_HZ: u32 = 1000;
+use bldc::Parameter;
+
+// The build-time constants are read-only parameters (see the
+// `list` command)
+pub const CLOCK_FREQ_HZ: u32 = Parameter::ClockFrequency.info().default as u32;
+pub const SYSTICK_RATE_HZ: u32 = Parameter::SystickRate.info().default as u32;
 
 #[rtic::app(device = stm32f7xx_hal::pac, dispatchers = [EXTI0, EXTI1, EXTI2])]
 mod app {
 
-    use crate::motor::{MotorStep, ThreePhaseController};
-    use crate::uart_serial::SerialTx;
+    use crate::dshot::DshotReceiver;
+    use crate::kiss_telemetry::KissTelemetryUart;
+    use crate::motor::config::NUM_MOTORS;
+    use crate::motor::{Motor0, Motor1};
+    use crate::pulse_input::PulseReceiver;
+    use crate::serial_port::{SerialPort, SerialRx, SerialTx};
+    use bldc::{
+        DshotInput, IdentifyError, MotorFault, MotorParameters, PhaseDriver, PhaseVoltageSampler,
+        PulseInput, StartupState, TelemetryScheduler, ThreePhaseController, ThreePhasePwm,
+    };
     use rtic_monotonics::systick::prelude::*;
     use stm32f7xx_hal::gpio::{Output, PI1};
-    use stm32f7xx_hal::pac::{TIM3, USART1};
-    use stm32f7xx_hal::serial::Rx;
-    use stm32f7xx_hal::timer::{self, CounterUs};
 
+    use crate::dshot::{dshot_reply_task, dshot_task};
     use crate::init::init;
-    use crate::motor::{adc_task, dma_task};
+    use crate::kiss_telemetry::kiss_telemetry_task;
+    use crate::motor::{
+        adc_task, break_task, commutate_motor0, commutate_motor1, dma_motor0, dma_motor1,
+    };
+    use crate::pulse_input::pulse_input_task;
+    use crate::serial_port::serial_interrupt;
     use crate::uart_serial::serial_task;
     use crate::SYSTICK_RATE_HZ;
 
     systick_monotonic!(Mono, SYSTICK_RATE_HZ);
 
+    /// Each motor (see `motor::config`) is a shared resource of its
+    /// own, so that its interrupts do not need to lock anything
+    /// else
     #[shared]
     pub struct Shared {
-        pub three_phase_controller: ThreePhaseController,
-        pub commutator_counter: CounterUs<TIM3>,
+        pub motor0: Motor0,
+        pub motor1: Motor1,
+        pub dshot_receiver: DshotReceiver,
     }
 
     #[local]
     pub struct Local {
         pub green_led: PI1<Output>,
         pub serial_tx: SerialTx,
-        pub serial_rx: Rx<USART1>,
-        pub motor_step: MotorStep,
-	pub current_time: u32,
+        pub serial_rx: SerialRx,
+        pub serial_port: SerialPort,
+        pub dshot_input: DshotInput,
+        pub dshot_telemetry: TelemetryScheduler,
+        pub pulse_receiver: PulseReceiver,
+        pub pulse_input: PulseInput,
+        pub kiss_uart: KissTelemetryUart,
     }
 
     extern "Rust" {
@@ -51,14 +79,55 @@ mod app {
         #[init]
         fn init(cx: init::Context) -> (Shared, Local);
 
-        #[task(priority = 1, local=[serial_rx, serial_tx], shared=[three_phase_controller, commutator_counter])]
+        #[task(priority = 1, local=[serial_rx, serial_tx], shared=[motor0, motor1])]
         async fn serial_task(cx: serial_task::Context);
 
-        #[task(binds = ADC, priority = 3, shared=[three_phase_controller])]
+        // USART1 interrupt (serial CLI bytes received or sent)
+        #[task(binds = USART1, priority = 2, local=[serial_port])]
+        fn serial_interrupt(cx: serial_interrupt::Context);
+
+        // KISS ESC telemetry output
+        #[task(priority = 1, local=[kiss_uart], shared=[motor0])]
+        async fn kiss_telemetry_task(cx: kiss_telemetry_task::Context);
+
+        // ADC overrun (of any motor)
+        #[task(binds = ADC, priority = 3, shared=[motor0, motor1])]
         fn adc_task(cx: adc_task::Context);
 
-        #[task(binds = DMA2_STREAM0, priority = 3, shared=[three_phase_controller])]
-        fn dma_task(cx: dma_task::Context);
+        // Motor 0 samples transferred (ADC3)
+        #[task(binds = DMA2_STREAM0, priority = 3, shared=[motor0])]
+        fn dma_motor0(cx: dma_motor0::Context);
+
+        // Motor 1 samples transferred (ADC1)
+        #[task(binds = DMA2_STREAM4, priority = 3, shared=[motor1])]
+        fn dma_motor1(cx: dma_motor1::Context);
+
+        // Break input of the TIM1 complementary outputs (bound
+        // without the complementary-pwm feature too, since RTIC
+        // does not allow cfg attributes on extern tasks, but never
+        // raised then)
+        #[task(binds = TIM1_BRK_TIM9, priority = 10, shared=[motor0])]
+        fn break_task(cx: break_task::Context);
+
+        // DShot frame captured (throttle input for motor 0)
+        #[task(binds = DMA1_STREAM7, priority = 3, local=[dshot_input, dshot_telemetry], shared=[motor0, dshot_receiver])]
+        fn dshot_task(cx: dshot_task::Context);
+
+        // Bidirectional DShot reply sent
+        #[task(binds = DMA1_STREAM6, priority = 3, shared=[dshot_receiver])]
+        fn dshot_reply_task(cx: dshot_reply_task::Context);
+
+        // PWM, OneShot125 or Multishot throttle pulse captured
+        #[task(binds = TIM8_BRK_TIM12, priority = 3, local=[pulse_receiver, pulse_input], shared=[motor0])]
+        fn pulse_input_task(cx: pulse_input_task::Context);
+
+        // Motor 0 commutation timer interrupt service routine
+        #[task(binds = TIM3, priority = 10, shared=[motor0])]
+        fn commutate_motor0(cx: commutate_motor0::Context);
+
+        // Motor 1 commutation timer interrupt service routine
+        #[task(binds = TIM7, priority = 10, shared=[motor1])]
+        fn commutate_motor1(cx: commutate_motor1::Context);
     }
 
     // Optional idle, can be removed if not needed.
@@ -69,56 +138,99 @@ mod app {
         }
     }
 
-    #[task(priority = 2, shared=[three_phase_controller, commutator_counter], local=[current_time])]
+    #[task(priority = 2, shared=[motor0, motor1])]
     async fn hello_loop(mut cx: hello_loop::Context) {
-	let time = cx.local.current_time; 
+        let mut logs = [MotorLog::new(); NUM_MOTORS];
         loop {
-	    if *time > 300 {
-		defmt::info!("Setting timer to {}", time);
-		cx.shared.commutator_counter.lock(|counter| {
-                    counter.start(time.micros()).unwrap();
-		});
-
-		*time -= 1;
-	    }
-	    
-            cx.shared.three_phase_controller.lock(|c| {
-
-		
-		// defmt::info!(
-                //     "Neutral voltage: {}, ADC: {}",
-                //     c.neutral_voltage,
-                //     *c.adc_buffer
-                // );
-            });
-
-	    Mono::delay(10.millis()).await;
+            let state = cx
+                .shared
+                .motor0
+                .lock(|motor| LoggedState::read(&motor.controller));
+            logs[0].update(0, state);
+            let state = cx
+                .shared
+                .motor1
+                .lock(|motor| LoggedState::read(&motor.controller));
+            logs[1].update(1, state);
+
+            Mono::delay(10.millis()).await;
         }
     }
 
-    /// Motor commutation timer interrupt service routine
-    ///
-    /// This ISR is responsible for updating the currents
-    /// in the three phases of the BLDC (commutation). It
-    /// is the lowest level control loop involved in the motor
-    /// control, responsible for the sensorless control to
-    /// detect the motor position and keep the commutation
-    /// in sync with the motor position.
-    #[task(binds = TIM3, priority = 10, shared = [three_phase_controller, commutator_counter], local = [motor_step])]
-    fn commutate_bldc(mut cx: commutate_bldc::Context) {
-        let step = cx.local.motor_step;
-        cx.shared
-            .three_phase_controller
-            .lock(|three_phase_controller| {
-                // Currently not checking if BLDC enabled, so
-                // commutation will happen even if PWM is off.
-                three_phase_controller.set_step(&step);
-                step.next();
-            });
-
-        // Clear to prevent immediate re-entry into ISR
-        cx.shared.commutator_counter.lock(|counter| {
-            counter.clear_interrupt(timer::Event::Update);
-        });
+    /// What is logged about a motor, copied out of its controller
+    struct LoggedState {
+        state: StartupState,
+        fault: Option<MotorFault>,
+        identifying: bool,
+        result: Option<Result<MotorParameters, IdentifyError>>,
+    }
+
+    impl LoggedState {
+        fn read<D, P, S>(controller: &ThreePhaseController<D, P, S>) -> Self
+        where
+            D: PhaseDriver,
+            P: ThreePhasePwm,
+            S: PhaseVoltageSampler,
+        {
+            Self {
+                state: controller.startup.state(),
+                fault: controller.fault(),
+                identifying: controller.identify.is_active(),
+                result: controller.identify.result(),
+            }
+        }
+    }
+
+    /// Logs the changes of state of one motor
+    #[derive(Clone, Copy)]
+    struct MotorLog {
+        state: Option<StartupState>,
+        fault: Option<MotorFault>,
+        identifying: bool,
+    }
+
+    impl MotorLog {
+        const fn new() -> Self {
+            Self {
+                state: None,
+                fault: None,
+                identifying: false,
+            }
+        }
+
+        fn update(&mut self, motor: usize, now: LoggedState) {
+            if self.state != Some(now.state) {
+                defmt::info!(
+                    "Motor {} start-up state: {}",
+                    motor,
+                    defmt::Debug2Format(&now.state)
+                );
+                self.state = Some(now.state);
+            }
+
+            if self.fault != now.fault {
+                match now.fault {
+                    Some(fault) => defmt::warn!("Motor {} fault: {}", motor, fault.name()),
+                    None => defmt::info!("Motor {} fault cleared", motor),
+                }
+                self.fault = now.fault;
+            }
+
+            // Log the parameters in the form MotorConfig takes them
+            if self.identifying && !now.identifying {
+                match now.result {
+                    Some(Ok(parameters)) => defmt::info!(
+                        "Motor {} parameters: {}",
+                        motor,
+                        defmt::Debug2Format(&parameters)
+                    ),
+                    Some(Err(error)) => {
+                        defmt::warn!("Motor {} identification failed: {}", motor, error.name())
+                    }
+                    None => {}
+                }
+            }
+            self.identifying = now.identifying;
+        }
     }
 }
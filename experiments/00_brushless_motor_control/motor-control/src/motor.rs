@@ -1,415 +1,243 @@
-use core::{
-    pin::Pin,
-    ptr::{addr_of, addr_of_mut},
+use adc::AdcSampler;
+use bldc::{
+    CommutationTimer, PhaseDriver, PhaseVoltageSampler, PwmError, ThreePhasePwm, TimeoutTooLong,
 };
-
-use alloc::{boxed::Box, vec::Vec};
-use cortex_m::asm::nop;
+#[cfg(not(feature = "complementary-pwm"))]
 use pwm::ThreeChannelPwm;
 use rtic::Mutex;
 use stm32f7xx_hal::{
-    gpio::{Output, PA0, PA15, PA8, PB4, PF10, PF9, PH6, PI0, PI2},
-    pac::{ADC3, DMA2, RCC, TIM1, TIM2, TIM5},
+    gpio::{ErasedPin, Output},
+    pac::{TIM3, TIM7},
+    prelude::*,
+    timer::{self, CounterUs, Event},
 };
+use tim8_pwm::Tim8Pwm;
 
-use crate::app::{adc_task, dma_task};
+pub use bldc::MotorStep;
 
-pub mod pwm;
+use crate::app::{
+    adc_task, break_task, commutate_motor0, commutate_motor1, dma_motor0, dma_motor1,
+};
 
-pub fn dma_task(mut cx: dma_task::Context<'_>) {
-    //defmt::info!("DMA interrupt");
+pub mod adc;
+#[cfg(feature = "complementary-pwm")]
+pub mod complementary_pwm;
+pub mod config;
+pub mod pwm;
+pub mod tim8_pwm;
 
-    cx.shared.three_phase_controller.lock(|c| {
-        if c.dma.lisr.read().tcif0().bit() {
-            //defmt::info!("DMA transfer complete");
+/// The half bridge signal inputs of motor 0 (GPIO pins driving the
+/// L298)
+#[cfg(not(feature = "complementary-pwm"))]
+pub type Driver = EnablePins;
 
-            // Clear the interrupt flag
-            c.dma.lifcr.write(|w| w.ctcif0().set_bit());
+/// The half bridge enable inputs of motor 0 (TIM1, TIM2 and TIM5
+/// driving the L298)
+#[cfg(not(feature = "complementary-pwm"))]
+pub type Pwm = ThreeChannelPwm;
 
-            // Calculate neutral voltage
-            c.neutral_voltage = c.adc_buffer.iter().sum::<u16>() / 3;
+/// The output compare modes of the TIM1 complementary outputs
+#[cfg(feature = "complementary-pwm")]
+pub type Driver = complementary_pwm::ComplementaryPhases;
 
-            // Print the values
-            //defmt::info!("{}", *three_phase_controller.adc_buffer);
-        }
+/// The TIM1 complementary outputs (with dead-time)
+#[cfg(feature = "complementary-pwm")]
+pub type Pwm = complementary_pwm::ComplementaryPwm;
 
-        if c.dma.lisr.read().teif0().bit() {
-            defmt::info!("DMA transfer error");
+/// Three-phase motor controller for motor 0 on the STM32F746
+/// DISCO board
+///
+/// The half bridges are driven by [`Driver`] and [`Pwm`], and the
+/// phase voltages are measured using ADC3 and DMA2.
+pub type ThreePhaseController = bldc::ThreePhaseController<Driver, Pwm, AdcSampler>;
 
-            // Clear the interrupt flag
-            c.dma.lifcr.write(|w| w.cteif0().set_bit());
-        }
+/// Motor 0 (see [`config::MOTORS`]), commutated by TIM3
+pub type Motor0 = bldc::Motor<Driver, Pwm, AdcSampler, CommutationCounter<TIM3>>;
 
-        if c.dma.lisr.read().dmeif0().bit() {
-            defmt::info!("DMA direct mode error");
+/// Motor 1, driven by TIM8 and the L298 signal inputs, sampled by
+/// ADC1, and commutated by TIM7
+pub type Motor1 = bldc::Motor<EnablePins, Tim8Pwm, AdcSampler, CommutationCounter<TIM7>>;
 
-            // Clear the interrupt flag
-            c.dma.lifcr.write(|w| w.cdmeif0().set_bit());
-        }
-    });
+/// Set the duty cycle directly (the `pwm-duty` command), which
+/// turns off speed control
+pub fn set_duty<D, P, S>(
+    controller: &mut bldc::ThreePhaseController<D, P, S>,
+    duty: f32,
+) -> Result<(), PwmError>
+where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    S: PhaseVoltageSampler,
+{
+    controller.speed.set_target_rpm(None);
+    controller.set_duty(duty)
 }
 
-pub fn adc_task(mut cx: adc_task::Context<'_>) {
-    cx.shared
-        .three_phase_controller
-        .lock(|three_phase_controller| {
-            // Check if the overrun bit is set
-            if three_phase_controller.adc.sr.read().ovr().bit() {
-                defmt::info!("ADC overrun");
-
-                // Clear the overrun interrupt flag
-                three_phase_controller
-                    .adc
-                    .sr
-                    .modify(|_, w| w.ovr().clear_bit());
-            }
+/// Set the duty cycle from a throttle input (between 0.0 and 1.0),
+/// and start the motor if it is stopped (and the controller is
+/// armed)
+///
+/// The throttle is mapped onto the same duty cycle range as the
+/// speed controller uses.
+pub fn set_throttle(controller: &mut ThreePhaseController, throttle: f32) {
+    let config = controller.speed.config();
+    let duty = config.min_duty + throttle * (config.max_duty - config.min_duty);
+    set_duty(controller, duty).ok();
+    controller.start().ok();
+}
 
-            // Check if the end of conversion bit is set
-            if three_phase_controller.adc.sr.read().eoc().bit() {
-                defmt::info!("ADC end of conversion");
+/// Commutation timer interrupt for one motor
+///
+/// This is responsible for updating the currents in the three
+/// phases of the motor (commutation). It is the lowest level
+/// control loop involved in the motor control, responsible for
+/// keeping the commutation in sync with the motor position.
+pub fn commutate<D, P, TIM>(
+    motor: &mut impl Mutex<T = bldc::Motor<D, P, AdcSampler, CommutationCounter<TIM>>>,
+) where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    TIM: timer::Instance,
+{
+    motor.lock(|motor| {
+        // Clear to prevent immediate re-entry into ISR
+        motor.timer.clear_interrupt();
+        motor.on_commutation_timer();
+    });
+}
 
-                // Clear the overrun interrupt flag
-                three_phase_controller
-                    .adc
-                    .sr
-                    .modify(|_, w| w.eoc().clear_bit());
+/// DMA transfer interrupt for one motor (new phase voltage samples)
+pub fn on_dma_interrupt<D, P, TIM>(
+    motor: &mut impl Mutex<T = bldc::Motor<D, P, AdcSampler, CommutationCounter<TIM>>>,
+) where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    TIM: timer::Instance,
+{
+    motor.lock(
+        |motor| match motor.controller.sampler_mut().on_dma_interrupt() {
+            Ok(true) => {
+                // In closed-loop mode, commutate 30 electrical degrees
+                // after the zero crossing
+                motor.on_samples();
             }
-        });
+            Ok(false) => {}
+            // The phase voltages can no longer be trusted
+            Err(fault) => motor.controller.set_fault(fault),
+        },
+    );
 }
 
-/// Simple wrapper for the numbers 0 to 5
-pub struct MotorStep {
-    step: u8,
+/// ADC interrupt for one motor
+pub fn on_adc_interrupt<D, P, TIM>(
+    motor: &mut impl Mutex<T = bldc::Motor<D, P, AdcSampler, CommutationCounter<TIM>>>,
+) where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    TIM: timer::Instance,
+{
+    motor.lock(|motor| {
+        if motor.controller.sampler_mut().on_adc_interrupt() {
+            motor.controller.on_adc_overrun();
+        }
+    });
 }
 
-impl Default for MotorStep {
-    fn default() -> Self {
-        Self::new()
-    }
+pub fn commutate_motor0(mut cx: commutate_motor0::Context<'_>) {
+    commutate(&mut cx.shared.motor0);
 }
 
-impl MotorStep {
-    pub fn new() -> Self {
-        Self { step: 0 }
-    }
-
-    pub fn next(&mut self) {
-        self.step = (self.step + 1) % 6;
-    }
-
-    pub fn prev(&mut self) {
-        self.step = (self.step - 1) % 6;
-    }
+pub fn commutate_motor1(mut cx: commutate_motor1::Context<'_>) {
+    commutate(&mut cx.shared.motor1);
 }
 
-/// Three-phase motor controller supporting half bridge drivers
-///
-/// This struct is specific to the STM32F746 DISCO board. The
-/// pins on the Arduino header are listed in the comment.
-///
-/// The struct controls three half-bridge drivers which have an
-/// enable and signal input (as opposed to high and low signal
-/// control for the high and low side MOSFETS). When enable is
-/// low, both transistors in the bridge are turned off, and the
-/// output is floating. When enable is on, the signal is used to
-/// turn on either the high side or low side MOSFET (the high
-/// side phase is in phase with signal).
-///
-///
-///
-///
-pub struct ThreePhaseController {
-    pwm_channels: ThreeChannelPwm,
-
-    // Enable 1, CN4, pin 4
-    en1: PB4<Output>,
-
-    // Enable 2, CN4, pin 7
-    en2: PH6<Output>,
-
-    // Enable 3, CN7, pin 1
-    en3: PI2<Output>,
-
-    // Duty cycle (sets motor power)
-    duty: f32,
-
-    adc: ADC3,
-
-    // The DMA peripheral handling the ADC-to-memory transfers
-    dma: DMA2,
-
-    // The buffer into which ADC conversion are transferred by DMA
-    pub adc_buffer: Box<[u16; 3]>,
-
-    pub neutral_voltage: u16,
+pub fn dma_motor0(mut cx: dma_motor0::Context<'_>) {
+    on_dma_interrupt(&mut cx.shared.motor0);
 }
 
-impl ThreePhaseController {
-    pub fn set_period(&mut self, period: u16) {
-        self.pwm_channels.set_period(period);
-    }
-
-    pub fn new(
-        en1: PB4<Output>,
-        en2: PH6<Output>,
-        en3: PI2<Output>,
-        rcc: &RCC,
-        tim1: TIM1,
-        pin1: PA8,
-        tim2: TIM2,
-        pin2: PA15,
-        tim3: TIM5,
-        pin3: PI0,
-        adc: ADC3,
-        apin1: PA0,
-        apin2: PF10,
-        apin3: PF9,
-        dma: DMA2,
-    ) -> Self {
-        let pwm_channels = ThreeChannelPwm::new(rcc, tim1, pin1, tim2, pin2, tim3, pin3);
-
-        // Set up ADC3 clocks-
-        rcc.apb2enr.modify(|_, w| w.adc3en().bit(true));
-
-        // ADC setup (PAC, not HAL). References to page numbers
-        // refer to the RM0385 rev 8 reference manual.
-
-        // Set up the analog input GPIO pins
-        apin1.into_analog();
-        apin2.into_analog();
-        apin3.into_analog();
-
-        // Turn ADC on by setting ADON in CR2 register (p. 415)
-        adc.cr2.modify(|_, w| w.adon().bit(true));
-
-        // ADC channels are multiplexed, and multiple conversions
-        // may be performed in sequence. To set up a regular group
-        // with three conversions (p. 419), write 2 to L[3:0] in SQR1.
-        adc.sqr1.modify(|_, w| w.l().bits(2));
-
-        // To set the order of conversions, write:
-        //
-        // - 0 to SQ1[4:0] in SQR3, first conversion is channel 0 (IN0).
-        // - 8 to SQ2[4:0] in SQR3, second conversion is channel 8 (IN8)
-        // - 7 to SQ3[4:0] in SQR3, second conversion is channel 7 (IN7)
-        adc.sqr3.modify(|_, w| unsafe { w.sq1().bits(0) }); // PA0
-        adc.sqr3.modify(|_, w| unsafe { w.sq2().bits(8) }); // PF10
-        adc.sqr3.modify(|_, w| unsafe { w.sq3().bits(7) }); // PF9
-
-        adc.cr2.modify(|_, w| {
-            // Set the ADC to trigger on rising edge of TIM1 channel 1
-            w.exten().bits(0b01);
-            unsafe {
-                w.extsel().bits(0b0000);
-            }
-
-            // Enable DMA mode on the ADC side
-            w.dma().set_bit();
-
-            // Set the ADC to continue issuing DMA requests on new conversions
-            w.dds().set_bit()
-        });
-
-        // Set sampling times per channel
-
-        adc.smpr2.modify(|_, w| {
-            // Can't seem to write these fields using the normal APIn1
-            // Something to do with enumerated values?
-            let sample_cycles = 0b000;
-            let smp0 = sample_cycles << 0;
-            let smp8 = sample_cycles << 24;
-            let smp7 = sample_cycles << 21;
-
-            unsafe { w.bits(smp0 | smp8 | smp7) }
-        });
-
-        adc.cr1.modify(|_, w| {
-            // Enable scan mode (convert all channels in regular sequence)
-            w.scan().set_bit();
-
-            // Set ADC resolution
-            //w.res().bits(0b11); // 6 bit
-
-            //w.eocie().set_bit(); // end-of-conversion
-            w.ovrie().set_bit() // overrun detection
-        });
-
-        // Turn on the DMA2 clock
-        rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
-        nop();
-        nop();
-
-        // Set the source address (the DR register of the ADC)
-        // TODO: there must be a way to get the DR address from the PAC
-        let adc_base_addr = ADC3::ptr() as u32;
-        let adc_dr_addr = adc_base_addr + 0x4c;
-        dma.st[0].par.write(|w| unsafe { w.bits(adc_dr_addr) });
-
-        // Make DMA destination memory location
-        // Boxed Vec ensures that the Vec memory is not moved when
-        // the
-        let adc_buffer = Box::new([0u16; 3]);
-
-        // Set the memory destination address
-        dma.st[0]
-            .m0ar
-            .write(|w| unsafe { w.bits((*adc_buffer).as_ptr() as u32) });
-
-        // Set to transfer three value (after each ADC channel conversion)
-        dma.st[0].ndtr.write(|w| w.ndt().bits(3));
-
-        // Set control register
-        dma.st[0].cr.modify(|_, w| {
-            // Configure DMA 2 (stream 0) to transfer from ADC to memory
-            unsafe { w.dir().bits(0b00) };
-
-            // Set both the peripheral size and memory size to half word (16 bits),
-            // and set memory address to auto-increment
-            unsafe {
-                w.psize().bits(0b01);
-                w.msize().bits(0b01);
-            }
-            w.minc().set_bit();
-
-            // Set channel 2 on stream zero (tied to ADC3, see table 26 p. 226)
-            w.chsel().bits(2);
-
-            // Set the DMA to use circular mode
-            w.circ().set_bit();
-
-            // Set interrupts
-            w.tcie().set_bit(); // transfer complete
-            w.teie().set_bit(); // transfer error
-            w.dmeie().set_bit(); // direct mode error
-
-            // Enable DMA stream 0
-            w.en().set_bit()
-        });
+pub fn dma_motor1(mut cx: dma_motor1::Context<'_>) {
+    on_dma_interrupt(&mut cx.shared.motor1);
+}
 
-        Self {
-            pwm_channels,
-            en1,
-            en2,
-            en3,
-            duty: 0.0,
-            adc,
-            dma,
-            adc_buffer,
-            neutral_voltage: 0,
+/// TIM1 break interrupt (the break input has turned off the
+/// complementary outputs)
+#[cfg(feature = "complementary-pwm")]
+pub fn break_task(mut cx: break_task::Context<'_>) {
+    cx.shared.motor0.lock(|motor| {
+        if motor.controller.pwm().on_break_interrupt() {
+            motor.controller.set_fault(bldc::MotorFault::BreakInput);
         }
-    }
+    });
+}
 
-    /// Have a think about whether to use floats or not
-    pub fn set_duty(&mut self, duty: f32) {
-        self.duty = duty;
-    }
+/// The break interrupt is not enabled without the complementary
+/// outputs
+#[cfg(not(feature = "complementary-pwm"))]
+pub fn break_task(_: break_task::Context<'_>) {}
 
-    pub fn enable(&mut self, enable: bool) {
-        self.pwm_channels.enable(enable);
-    }
+/// ADC interrupt (shared by the three ADCs, so every motor checks
+/// its own)
+pub fn adc_task(mut cx: adc_task::Context<'_>) {
+    on_adc_interrupt(&mut cx.shared.motor0);
+    on_adc_interrupt(&mut cx.shared.motor1);
+}
 
-    /// If the half bridge is enabled (i.e. not high-Z), set
-    /// it to pull-up (high-side is on and low-side is off),
-    /// or pull-down (high-side is off and low-side is on).
-    /// Note that calling this function and passing pull-up
-    /// as false pulls down instead. If the high-Z state is
-    /// enabled, then this function has no immediate effect
-    /// (but the state will persist if high-Z is removed).
-    fn pull_phase_up(&mut self, which: u8, pull_up: bool) {
-	if pull_up {
-	    match which {
-		// high-side always on
-		0 => self.en1.set_high(),
-		1 => self.en2.set_high(),
-		2 => self.en3.set_high(),
-		_ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
-	    }	    
-	} else {
-	    match which {
-		// high-side always on
-		0 => self.en1.set_low(),
-		1 => self.en2.set_low(),
-		2 => self.en3.set_low(),
-		_ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
-	    }	    
-	}
+/// Commutation timer (a 1 MHz counter)
+///
+/// The counter restarts at every commutation, so its current
+/// value is the time since commutation. It is 16-bit, so the
+/// time between commutations must be less than 65 ms.
+pub struct CommutationCounter<TIM>(pub CounterUs<TIM>);
+
+impl<TIM: timer::Instance> CommutationCounter<TIM> {
+    pub fn clear_interrupt(&mut self) {
+        self.0.clear_interrupt(Event::Update);
     }
-    
-    /// Set one of the phases as a power input
-    fn set_line_phase(&mut self, which: u8) {
+}
 
-	// Now, en is tied to the high/low pin and pwm
-	// is tied to the high-Z (i.e. turn both MOSFETS off)
-	// To set a phase as the input, we want to alternate
-	// it between the high-side on and high-Z states (so
-	// it alternates driving and floating).
-        self.pwm_channels.set_duty(which, self.duty); // module high-Z
-	self.pull_phase_up(which, true);
+impl<TIM: timer::Instance> CommutationTimer for CommutationCounter<TIM> {
+    fn elapsed_us(&self) -> u32 {
+        self.0.now().ticks()
     }
 
-    /// Set one of the phases as a neutral (return) path
-    fn set_neutral_phase(&mut self, which: u8) {
-
-	// Now, en is tied to the high/low pin and pwm
-	// is tied to the high-Z (i.e. turn both MOSFETS off)
-	// To set a phase as the neutral, we want it always
-	// enabled (not high-Z) and always pulled low
-        self.pwm_channels.set_duty(which, 1.0); // never high-Z
-	self.pull_phase_up(which, false);
-
+    fn start(&mut self, timeout_us: u32) -> Result<(), TimeoutTooLong> {
+        // The HAL rejects a timeout which does not fit in the
+        // 16-bit auto-reload register
+        self.0
+            .start(timeout_us.micros())
+            .map_err(|_| TimeoutTooLong {
+                max_us: u16::MAX as u32,
+            })
     }
+}
 
-    /// Set one of the phases as floating
-    fn set_floating_phase(&mut self, which: u8) {
-	// Now, en is tied to the high/low pin and pwm
-	// is tied to the high-Z (i.e. turn both MOSFETS off)
-	// To set a phase as floating, make it always high-Z
-        self.pwm_channels.set_duty(which, 0.0); // always high-Z
+/// GPIO pins driving the signal inputs of the half bridges
+///
+/// The pins of each motor are listed in [`config::MOTORS`].
+pub struct EnablePins {
+    pub en1: ErasedPin<Output>,
+    pub en2: ErasedPin<Output>,
+    pub en3: ErasedPin<Output>,
+}
 
-	// Pull down for definiteness (no effect, but ties to
-	// ground if subsequently high-Z is removed)
-	self.pull_phase_up(which, false);
-    }
-    
-    pub fn set_step(&mut self, step: &MotorStep) {
-        match step.step {
-            0 => {
-		self.set_floating_phase(2);
-		self.set_neutral_phase(1);
-		self.set_line_phase(0);
+impl PhaseDriver for EnablePins {
+    fn pull_phase_up(&mut self, which: usize, pull_up: bool) {
+        if pull_up {
+            match which {
+                // high-side always on
+                0 => self.en1.set_high(),
+                1 => self.en2.set_high(),
+                2 => self.en3.set_high(),
+                _ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
+            }
+        } else {
+            match which {
+                // high-side always on
+                0 => self.en1.set_low(),
+                1 => self.en2.set_low(),
+                2 => self.en3.set_low(),
+                _ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
             }
-
-            1 => {
-		self.set_floating_phase(0);
-		self.set_neutral_phase(1);
-		self.set_line_phase(2);
-	    }
-
-            2 => {
-		self.set_floating_phase(1);
-		self.set_neutral_phase(0);
-		self.set_line_phase(2);
-	    }
-
-            3 => {
-		self.set_floating_phase(2);
-		self.set_neutral_phase(0);
-		self.set_line_phase(1);
-	    }
-
-            4 => {
-		self.set_floating_phase(0);
-		self.set_neutral_phase(2);
-		self.set_line_phase(1);
-	    }
-
-            5 => {
-		self.set_floating_phase(1);
-		self.set_neutral_phase(2);
-		self.set_line_phase(0);
-	    }
-
-            _ => panic!("Invalid value for MotorStep"),
         }
     }
 }
@@ -0,0 +1,334 @@
+//! Phase voltage, current and supply voltage sampling using one
+//! ADC and a DMA2 stream per motor
+//!
+//! DMA2 is in double-buffer mode, so it fills the two buffers in
+//! [`DmaBuffers`] in turn, and the buffer completed in one PWM
+//! period is not written again until the next. The transfer
+//! complete interrupt copies it into the motor's entry in
+//! [`SAMPLES`], with a timestamp from the DWT cycle counter, where
+//! the rest of the firmware can read it without locking the motor.
+
+use super::config::NUM_MOTORS;
+use super::pwm::HSI_HZ;
+use bldc::config::{AdcInput, AdcScale, AdcSequence, MAX_CONVERSIONS};
+use bldc::{AdcSnapshot, MotorConfig, MotorFault, PhaseVoltageSampler, SnapshotCell, Timestamper};
+use core::ops::Deref;
+use cortex_m::asm::nop;
+use cortex_m::peripheral::DWT;
+use stm32_adc::{
+    Adc, AdcConfig, DmaConfig, DmaRequests, DmaStream, ExternalTrigger, Trigger, TriggerEdge,
+};
+use stm32f7xx_hal::{
+    pac::{adc1, dma2, ADC1, ADC2, ADC3, DMA2, RCC},
+    rcc::Clocks,
+};
+
+/// The two buffers DMA2 transfers the conversions of one motor
+/// into (in the order of the sequence, and unused after its end)
+pub type DmaBuffers = [[u16; MAX_CONVERSIONS]; 2];
+
+/// The latest conversions of each motor (published on every PWM
+/// period)
+pub static SAMPLES: [SnapshotCell; NUM_MOTORS] = [const { SnapshotCell::new() }; NUM_MOTORS];
+
+/// A reference to the registers of a peripheral, which can be
+/// moved into a shared resource (unlike a plain reference, as the
+/// registers are not `Sync`)
+pub struct Registers<T: 'static>(&'static T);
+
+impl<T> Clone for Registers<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Registers<T> {}
+
+// SAFETY: the registers are only accessed from the resource which
+// holds the reference (see where they are created)
+unsafe impl<T> Send for Registers<T> {}
+
+impl<T> Deref for Registers<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+/// An ADC which can sample the inputs of one motor, and the DMA2
+/// stream and channel tied to it (see table 28 on p. 226)
+pub trait SamplerAdc: Deref<Target = adc1::RegisterBlock> {
+    /// The ADC number (as in [`bldc::config::AdcChannel`])
+    const NUMBER: u8;
+
+    /// The DMA2 stream (its interrupt is bound to the motor's DMA
+    /// task)
+    const DMA_STREAM: usize;
+
+    /// The DMA2 channel selecting this ADC on the stream
+    const DMA_CHANNEL: u8;
+
+    /// Turn on the ADC clock
+    fn enable_clock(rcc: &RCC);
+
+    /// The ADC registers (the sampler owns the ADC from then on)
+    fn registers(self) -> Registers<adc1::RegisterBlock>;
+}
+
+macro_rules! sampler_adc {
+    ($ADC:ident, $number:literal, $adcen:ident, $stream:literal, $channel:literal) => {
+        impl SamplerAdc for $ADC {
+            const NUMBER: u8 = $number;
+            const DMA_STREAM: usize = $stream;
+            const DMA_CHANNEL: u8 = $channel;
+
+            fn enable_clock(rcc: &RCC) {
+                rcc.apb2enr.modify(|_, w| w.$adcen().set_bit());
+            }
+
+            fn registers(self) -> Registers<adc1::RegisterBlock> {
+                // SAFETY: the peripheral is consumed, so nothing else
+                // can access the ADC
+                Registers(unsafe { &*$ADC::ptr() })
+            }
+        }
+    };
+}
+
+sampler_adc!(ADC1, 1, adc1en, 4, 0);
+sampler_adc!(ADC2, 2, adc2en, 2, 1);
+sampler_adc!(ADC3, 3, adc3en, 0, 2);
+
+/// Turn on the DMA2 clock, and give back its registers, to be
+/// shared by the samplers (nothing else uses DMA2)
+pub fn share_dma2(rcc: &RCC, _dma2: DMA2) -> Registers<dma2::RegisterBlock> {
+    rcc.ahb1enr.modify(|_, w| w.dma2en().set_bit());
+    nop();
+    nop();
+
+    // SAFETY: the peripheral is consumed, and each sampler only
+    // uses the registers and interrupt flags of its own stream
+    Registers(unsafe { &*DMA2::ptr() })
+}
+
+/// The ADC trigger of a PWM timer: TRGO2, which is the channel 4
+/// compare output (see the PWM)
+fn adc_trigger(timer: u8) -> ExternalTrigger {
+    match timer {
+        1 => ExternalTrigger::Tim1Trgo2,
+        8 => ExternalTrigger::Tim8Trgo2,
+        _ => defmt::panic!("TIM{} cannot trigger the ADC", timer),
+    }
+}
+
+/// Samples the three phase voltages (and the motor current and
+/// supply voltage, if they are configured) of one motor on every
+/// PWM period
+///
+/// The ADC is triggered by channel 4 of the motor's first PWM
+/// timer (a compare output with no pin, moved through the PWM
+/// period to follow the duty cycle, see [`bldc::AdcTrigger`]), and
+/// converts the channels in the order of
+/// [`MotorConfig::adc_sequence`]. A DMA2 stream transfers the
+/// results into one of two buffers, and raises an interrupt when
+/// all the conversions are complete. Both are set up with the
+/// [`stm32_adc`] drivers.
+///
+/// There is only one current channel, so the phase currents are
+/// not measured, and the controller cannot be switched to FOC.
+pub struct AdcSampler {
+    adc: Adc<Registers<adc1::RegisterBlock>>,
+
+    // The DMA stream handling the ADC-to-memory transfers (the
+    // other motors use other streams of DMA2)
+    dma: DmaStream<Registers<dma2::RegisterBlock>>,
+
+    // The buffers into which ADC conversions are transferred by
+    // DMA (alternately)
+    buffers: &'static mut DmaBuffers,
+
+    // The conversions from the last completed buffer (what the
+    // controller reads), also published to `samples`
+    latest: AdcSnapshot,
+    samples: &'static SnapshotCell,
+    timestamps: Timestamper,
+
+    // The order of the conversions
+    sequence: AdcSequence,
+
+    // The scales of the current and supply voltage conversions
+    current_scale: Option<AdcScale>,
+    bus_voltage_scale: Option<AdcScale>,
+}
+
+impl AdcSampler {
+    /// Set up `adc` to convert the channels in the motor
+    /// configuration `config` in sequence (their pins must already
+    /// be analog inputs), and its DMA2 stream to write to
+    /// `buffers` for as long as the sampler exists. The conversions
+    /// are published to `samples`.
+    ///
+    /// The streams only use their own registers and interrupt
+    /// flags, so the samplers of all the motors share DMA2 (see
+    /// [`share_dma2`]).
+    pub fn new<A: SamplerAdc>(
+        rcc: &RCC,
+        adc: A,
+        dma2: Registers<dma2::RegisterBlock>,
+        buffers: &'static mut DmaBuffers,
+        samples: &'static SnapshotCell,
+        config: &MotorConfig,
+    ) -> Self {
+        let sequence = config.adc_sequence();
+        if config.adc() != A::NUMBER {
+            defmt::panic!(
+                "Motor configured for ADC{}, not ADC{}",
+                config.adc(),
+                A::NUMBER
+            );
+        }
+
+        // Set up the ADC clock, and wait two cycles before
+        // accessing its registers
+        A::enable_clock(rcc);
+        nop();
+        nop();
+
+        // Convert the sequence on the rising edge of the PWM
+        // timer's TRGO2, with a DMA request after every conversion.
+        // The sequence has been checked by check_configs.
+        let adc_config = AdcConfig::new(sequence.to_sequence().unwrap())
+            .trigger(Trigger::External {
+                source: adc_trigger(config.pwm[0].timer),
+                edge: TriggerEdge::Rising,
+            })
+            .dma(DmaRequests::Continuous)
+            .overrun_interrupt(true);
+        let mut adc = Adc::new(adc.registers());
+        adc.configure(&adc_config);
+
+        // Transfer the conversions into the two buffers in turn.
+        // The buffers are static, so they never move.
+        let dma_config = DmaConfig {
+            channel: A::DMA_CHANNEL,
+            transfers: sequence.len() as u16,
+            transfer_complete_interrupt: true,
+            error_interrupts: true,
+        };
+        let mut dma = DmaStream::new(dma2, A::DMA_STREAM);
+        unsafe {
+            dma.start(
+                &dma_config,
+                adc.data_address(),
+                stm32_adc::DmaBuffers::Double(
+                    buffers[0].as_ptr() as u32,
+                    buffers[1].as_ptr() as u32,
+                ),
+            );
+        }
+
+        Self {
+            adc,
+            dma,
+            buffers,
+            latest: AdcSnapshot::default(),
+            samples,
+            timestamps: Timestamper::new(HSI_HZ),
+            sequence,
+            current_scale: config.current.map(|current| current.scale),
+            bus_voltage_scale: config.bus_voltage.map(|bus_voltage| bus_voltage.scale),
+        }
+    }
+
+    /// Time the samples from the core clock (the DWT cycle counter
+    /// must be enabled)
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.timestamps
+            .set_clock(clocks.sysclk().raw(), DWT::cycle_count());
+    }
+
+    /// The conversions in the last completed buffer
+    pub fn latest(&self) -> &AdcSnapshot {
+        &self.latest
+    }
+
+    /// Handle the DMA2 stream interrupt
+    ///
+    /// Returns true if a new set of samples has been
+    /// transferred (and published), or the fault if the transfer
+    /// failed.
+    pub fn on_dma_interrupt(&mut self) -> Result<bool, MotorFault> {
+        let events = self.dma.take_events();
+        if events.transfer_error {
+            defmt::info!("DMA transfer error");
+        }
+        if events.direct_mode_error {
+            defmt::info!("DMA direct mode error");
+        }
+        if events.is_error() {
+            return Err(MotorFault::DmaTransferError);
+        }
+
+        if events.transfer_complete {
+            self.copy_completed_buffer();
+        }
+        Ok(events.transfer_complete)
+    }
+
+    /// Handle the ADC interrupt
+    ///
+    /// Returns true if there was an overrun (a conversion was
+    /// overwritten before it was transferred). An overrun stops
+    /// the DMA requests, so the transfers are started again from
+    /// the first buffer.
+    pub fn on_adc_interrupt(&mut self) -> bool {
+        self.adc.recover_overrun(&mut self.dma)
+    }
+}
+
+impl AdcSampler {
+    /// Copy the buffer DMA2 has just filled, and publish it
+    ///
+    /// CT has already switched to the other buffer, which DMA2 is
+    /// filling now, and does not switch back until the next PWM
+    /// period.
+    fn copy_completed_buffer(&mut self) {
+        let completed = 1 - self.dma.current_target();
+
+        // DMA2 writes to the buffers behind the compiler's back
+        let raw = unsafe { core::ptr::read_volatile(&self.buffers[completed]) };
+        let timestamp_us = self.timestamps.update(DWT::cycle_count());
+        self.samples.publish(&raw, timestamp_us);
+        self.latest = AdcSnapshot {
+            raw,
+            timestamp_us,
+            count: self.samples.count(),
+        };
+    }
+
+    /// The latest conversion of `input` and its scale, if it is
+    /// in the sequence
+    fn scaled(&self, input: AdcInput, scale: Option<AdcScale>) -> Option<f32> {
+        let raw = self.sequence.read(&self.latest.raw, input)?;
+        Some(scale?.convert(raw))
+    }
+}
+
+impl PhaseVoltageSampler for AdcSampler {
+    fn phase_voltages(&self) -> [u16; 3] {
+        [0, 1, 2].map(|phase| {
+            self.sequence
+                .read(&self.latest.raw, AdcInput::PhaseVoltage(phase))
+                .unwrap_or(0)
+        })
+    }
+
+    fn current(&self) -> Option<f32> {
+        self.scaled(AdcInput::Current, self.current_scale)
+    }
+
+    fn bus_voltage(&self) -> Option<f32> {
+        self.scaled(AdcInput::BusVoltage, self.bus_voltage_scale)
+    }
+}
@@ -0,0 +1,310 @@
+//! Complementary PWM with dead-time using the TIM1 outputs
+//!
+//! This is for half-bridge drivers with separate high-side and
+//! low-side inputs (e.g. the FD6288T and IR2109 in `reference/`),
+//! instead of the enable and signal inputs of the L298. TIM1
+//! channels 1 to 3 drive the high-side inputs, and the
+//! complementary outputs CH1N to CH3N drive the low-side inputs.
+//! The timer inserts a dead-time at every switching edge, so that
+//! the two MOSFETs in a half bridge are never on together.
+//!
+//! Each phase is set by the output compare mode (written by
+//! [`ComplementaryPhases`]) and whether the complementary output
+//! is enabled (written by [`ComplementaryPwm`], which turns it off
+//! when the duty cycle is 0.0):
+//!
+//! | Phase    | OCxM           | CCxNE | High side   | Low side        |
+//! |----------|----------------|-------|-------------|-----------------|
+//! | Line     | PWM mode 1     | 1     | PWM         | PWM (inverted)  |
+//! | Neutral  | Force inactive | 1     | Off         | On              |
+//! | Floating | (either)       | 0     | Off         | Off             |
+//!
+//! In the line phase, the low-side MOSFET is on while the high
+//! side is off (synchronous rectification), instead of leaving
+//! the current to flow through its body diode.
+//!
+//! The break input (BKIN, active low) turns off all six outputs in
+//! hardware, without waiting for the firmware. The outputs are
+//! turned back on at the next update event after the break input
+//! is released (automatic output enable), but the controller keeps
+//! the half bridges in high-Z until the fault is cleared.
+//!
+//! The TIM1 outputs are connected to the SDRAM on the STM32F746
+//! DISCO board, so this is for boards which have them free (enable
+//! with the `complementary-pwm` feature).
+
+use super::pwm::HSI_HZ;
+use bldc::{PhaseDriver, PwmError, PwmTiming, ThreePhasePwm};
+use cortex_m::asm::nop;
+use stm32f7xx_hal::{
+    gpio::{PE10, PE11, PE12, PE13, PE15, PE8, PE9},
+    pac::{tim1, RCC, TIM1},
+    rcc::Clocks,
+};
+
+// Output compare modes (OCxM bits)
+const OC_MODE_FORCE_INACTIVE: u8 = 0b100;
+const OC_MODE_PWM1: u8 = 0b110;
+const OC_MODE_PWM2: u8 = 0b111;
+
+// Break interrupt flag (SR)
+const SR_BIF: u32 = 1 << 7;
+
+/// The TIM1 pins, for phases 0, 1 and 2 (all alternate function 1)
+pub struct ComplementaryPins {
+    /// High-side outputs (CH1, CH2 and CH3)
+    pub high: (PE9, PE11, PE13),
+    /// Low-side outputs (CH1N, CH2N and CH3N)
+    pub low: (PE8, PE10, PE12),
+    /// Break input (BKIN)
+    pub break_input: PE15,
+}
+
+/// Three complementary PWM outputs on TIM1
+pub struct ComplementaryPwm {
+    tim: TIM1,
+    timer_clock_hz: u32,
+    timing: PwmTiming,
+    dead_time_ns: u32,
+}
+
+/// Selects the high side (PWM) or low side (always on) of each
+/// half bridge driven by [`ComplementaryPwm`]
+pub struct ComplementaryPhases {
+    _private: (),
+}
+
+impl ComplementaryPwm {
+    /// Set up TIM1 for complementary PWM, with a dead-time of
+    /// `dead_time_ns` (converted to the DTG[7:0] bits for the timer
+    /// clock, see [`bldc::dead_time_bits`]). Returns the PWM (the
+    /// enable inputs, as far as the controller is concerned) and
+    /// the phase driver (the signal inputs), which share the timer.
+    pub fn new(
+        rcc: &RCC,
+        tim: TIM1,
+        pins: ComplementaryPins,
+        dead_time_ns: u32,
+    ) -> (Self, ComplementaryPhases) {
+        const TIM1_AF: u8 = 1;
+        let _ = pins.high.0.into_alternate::<TIM1_AF>();
+        let _ = pins.high.1.into_alternate::<TIM1_AF>();
+        let _ = pins.high.2.into_alternate::<TIM1_AF>();
+        let _ = pins.low.0.into_alternate::<TIM1_AF>();
+        let _ = pins.low.1.into_alternate::<TIM1_AF>();
+        let _ = pins.low.2.into_alternate::<TIM1_AF>();
+        let _ = pins.break_input.into_alternate::<TIM1_AF>();
+
+        // Enable the timer clock (delay after two clock
+        // cycles before accessing peripheral registers)
+        rcc.apb2enr.modify(|_, w| w.tim1en().bit(true));
+        nop();
+        nop();
+
+        // Start with all three phases low-side (and then floating,
+        // below). The compare registers are preloaded.
+        tim.ccmr1_output().write(|w| {
+            w.oc1m().bits(OC_MODE_FORCE_INACTIVE);
+            w.oc1pe().bit(true);
+            w.oc2m().bits(OC_MODE_FORCE_INACTIVE);
+            w.oc2pe().bit(true)
+        });
+        tim.ccmr2_output().write(|w| {
+            w.oc3m().bits(OC_MODE_FORCE_INACTIVE);
+            w.oc3pe().bit(true);
+
+            // Channel 4 triggers the ADC (it has no output pin). In
+            // PWM mode 2, OC4REF rises when the counter reaches CCR4.
+            w.oc4m().bits(OC_MODE_PWM2);
+            w.oc4pe().bit(true)
+        });
+
+        // Enable the high-side outputs. The low-side outputs are
+        // enabled by set_duty (when the phase is not floating).
+        // Both are active high.
+        tim.ccer.write(|w| {
+            w.cc1e().bit(true);
+            w.cc2e().bit(true);
+            w.cc3e().bit(true)
+        });
+
+        tim.cr1.write(|w| w.arpe().bit(true));
+
+        // Set OC1REF as trigger output, and OC4REF as TRGO2 (the
+        // ADC trigger, as for the L298 PWM)
+        tim.cr2.write(|w| {
+            w.mms().bits(0b1);
+            // SAFETY: 0b0111 (OC4REF) is a valid MMS2 value (the
+            // PAC has no enumeration for it)
+            unsafe { w.mms2().bits(0b0111) }
+        });
+
+        // The dead-time is set below, once the timer clock is known
+        tim.bdtr.write(|w| {
+            // Break input enabled, active low. When the break input
+            // is asserted, MOE is cleared and the outputs go to
+            // their idle (low) state.
+            w.bke().bit(true);
+            w.bkp().bit(false);
+
+            // Set MOE again at the next update event once the break
+            // input is released
+            w.aoe().bit(true);
+
+            // When an output is disabled (e.g. CCxNE is 0 for a
+            // floating phase) or MOE is cleared, drive it to its
+            // inactive level rather than leaving it floating
+            w.ossr().bit(true);
+            w.ossi().bit(true);
+
+            // Main output enable
+            w.moe().bit(true)
+        });
+
+        // Interrupt on break (TIM1_BRK_TIM9), so that the firmware
+        // can latch a fault
+        tim.dier.modify(|_, w| w.bie().bit(true));
+
+        let mut pwm = Self {
+            tim,
+            timer_clock_hz: HSI_HZ,
+            timing: PwmTiming {
+                prescaler: 0,
+                arr: 0,
+            },
+            dead_time_ns,
+        };
+        pwm.set_dead_time();
+
+        for which in 0..3 {
+            pwm.set_duty(which, 0.0);
+        }
+
+        (pwm, ComplementaryPhases { _private: () })
+    }
+
+    /// Use the TIM1 clock frequency from the configured clocks
+    /// (call before setting the PWM frequency), and set the
+    /// dead-time again for it
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.timer_clock_hz = clocks.timclk2().raw();
+        self.set_dead_time();
+    }
+
+    // Set the DTG[7:0] bits for the dead-time at the timer clock
+    // frequency (the bits can be changed as long as the LOCK bits
+    // are 0, which they are)
+    fn set_dead_time(&mut self) {
+        let dead_time = match bldc::dead_time_bits(self.dead_time_ns, self.timer_clock_hz) {
+            Ok(bits) => bits,
+            Err(error) => defmt::panic!("Invalid dead-time: {}", defmt::Debug2Format(&error)),
+        };
+        // Dead-time inserted at each switching edge (any DTG value
+        // is valid)
+        self.tim
+            .bdtr
+            .modify(|_, w| unsafe { w.dtg().bits(dead_time) });
+    }
+
+    /// The PWM frequency achieved
+    pub fn frequency_hz(&self) -> f32 {
+        self.timing.frequency_hz(self.timer_clock_hz)
+    }
+
+    /// Handle the break interrupt. Returns true if the break input
+    /// has turned the outputs off.
+    pub fn on_break_interrupt(&self) -> bool {
+        if self.tim.sr.read().bif().bit() {
+            // Clear the interrupt flag. The flags are cleared by
+            // writing 0 (and unchanged by writing 1), so write 1 to
+            // the others rather than read-modify-write, which could
+            // clear a flag set in between.
+            //
+            // SAFETY: every bit of SR is a flag or reserved, and
+            // writing 1 leaves a flag unchanged
+            self.tim.sr.write(|w| unsafe { w.bits(!SR_BIF) });
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl ThreePhasePwm for ComplementaryPwm {
+    fn enable(&mut self, enable: bool) {
+        self.tim.cr1.modify(|_, w| w.cen().bit(enable));
+    }
+
+    fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        let timing = PwmTiming::new(frequency_hz, self.timer_clock_hz)?;
+        self.timing = timing;
+        self.tim.psc.write(|w| w.psc().bits(timing.prescaler));
+        self.tim.arr.write(|w| w.arr().bits(timing.arr));
+        Ok(timing)
+    }
+
+    fn frequency_hz(&self) -> f32 {
+        ComplementaryPwm::frequency_hz(self)
+    }
+
+    fn set_duty(&mut self, which: usize, duty: f32) {
+        // The controller only sets valid duty cycles. If one gets
+        // through anyway, float the phase rather than panic.
+        let compare = self.timing.compare(duty).unwrap_or(0);
+
+        // A duty cycle of zero turns off the low side as well (the
+        // half bridge is floating)
+        let low_side = compare > 0;
+
+        match which {
+            0 => {
+                self.tim.ccr1().write(|w| w.ccr().bits(compare));
+                self.tim.ccer.modify(|_, w| w.cc1ne().bit(low_side));
+            }
+            1 => {
+                self.tim.ccr2().write(|w| w.ccr().bits(compare));
+                self.tim.ccer.modify(|_, w| w.cc2ne().bit(low_side));
+            }
+            2 => {
+                self.tim.ccr3().write(|w| w.ccr().bits(compare));
+                self.tim.ccer.modify(|_, w| w.cc3ne().bit(low_side));
+            }
+            _ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
+        }
+    }
+
+    fn set_adc_trigger(&mut self, position: f32) {
+        let compare = self.timing.trigger_compare(position);
+        self.tim.ccr4().write(|w| w.ccr().bits(compare));
+    }
+}
+
+impl ComplementaryPhases {
+    fn tim(&self) -> &tim1::RegisterBlock {
+        // SAFETY: ComplementaryPwm owns TIM1, and this only writes
+        // the output compare mode bits, which ComplementaryPwm does
+        // not touch after it is created. Both are only accessed
+        // through the motor's lock.
+        unsafe { &*TIM1::ptr() }
+    }
+}
+
+impl PhaseDriver for ComplementaryPhases {
+    fn pull_phase_up(&mut self, which: usize, pull_up: bool) {
+        // Pulled up: PWM between the high side and low side.
+        // Pulled down: the low side is always on.
+        let mode = if pull_up {
+            OC_MODE_PWM1
+        } else {
+            OC_MODE_FORCE_INACTIVE
+        };
+
+        let tim = self.tim();
+        match which {
+            0 => tim.ccmr1_output().modify(|_, w| w.oc1m().bits(mode)),
+            1 => tim.ccmr1_output().modify(|_, w| w.oc2m().bits(mode)),
+            2 => tim.ccmr2_output().modify(|_, w| w.oc3m().bits(mode)),
+            _ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
+        }
+    }
+}
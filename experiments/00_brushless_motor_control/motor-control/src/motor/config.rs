@@ -0,0 +1,333 @@
+//! Peripherals used by each motor
+//!
+//! Each entry of [`MOTORS`] describes the timers, pins and ADC
+//! channels used by one motor (including the current and supply
+//! voltage measurements, with their scales), and the measured
+//! parameters of the motor itself. Every motor has its own
+//! controller, PWM, sampler (an ADC and a DMA2 stream) and
+//! commutation timer, as its own shared resource in the RTIC app,
+//! with its commutation timer and DMA stream interrupts bound to
+//! tasks that call [`commutate`](super::commutate) and
+//! [`on_dma_interrupt`](super::on_dma_interrupt). To add a motor,
+//! add its entry here, and set up its peripherals, resource and
+//! tasks in the same way.
+//!
+//! Each sampler needs an ADC of its own, so the STM32F746 can
+//! sample three motors at most. Motor 0 is wired to the Arduino
+//! header of the DISCO board (the pins are listed in the
+//! comments). With the `complementary-pwm` feature, it is driven
+//! by the TIM1 complementary outputs instead, and the enable pins
+//! are the low-side outputs CH1N to CH3N. Motor 1 uses TIM8 and
+//! ADC1, whose pins are not on the header.
+
+use bldc::config::{
+    AdcChannel, AdcScale, AdcTrigger, MotorConfig, Pin, SampleTime, ScaledChannel, TimerChannel,
+};
+use bldc::{MotorParameters, PulseInputConfig};
+
+/// The number of motors driven by the firmware
+pub const NUM_MOTORS: usize = 2;
+
+/// The endpoints of the PWM, OneShot125 and Multishot throttle
+/// input (see [`crate::pulse_input`]). To keep a calibration,
+/// replace these with the endpoints it logs.
+pub const PULSE_INPUT: PulseInputConfig = PulseInputConfig::NOMINAL;
+
+// The phase voltages are measured through resistor dividers, so
+// the sampling capacitor needs longer than the minimum 3 cycles
+// to charge. With the ADC clock at 10 MHz, the whole sequence
+// (the current, three phase voltages and the supply voltage)
+// takes (3 + 12) + 4 * (15 + 12) cycles, or 12.3 us.
+const PHASE_SAMPLE_TIME: SampleTime = SampleTime::Cycles15;
+
+// The phase voltage dividers (10k over 2k2)
+const DIVIDER_RATIO: f32 = 2.2 / (10.0 + 2.2);
+
+// The phase voltage inputs (CN5 on the Arduino header)
+const MOTOR0_PHASE_VOLTAGES: [AdcChannel; 3] = [
+    AdcChannel {
+        adc: 3,
+        channel: 0,
+        pin: Pin {
+            port: 'A',
+            number: 0,
+        },
+        sample_time: PHASE_SAMPLE_TIME,
+    },
+    AdcChannel {
+        adc: 3,
+        channel: 8,
+        pin: Pin {
+            port: 'F',
+            number: 10,
+        },
+        sample_time: PHASE_SAMPLE_TIME,
+    },
+    AdcChannel {
+        adc: 3,
+        channel: 7,
+        pin: Pin {
+            port: 'F',
+            number: 9,
+        },
+        sample_time: PHASE_SAMPLE_TIME,
+    },
+];
+
+// The motor current: the L298 SENSE A and SENSE B pins joined,
+// and to ground through a 0.5 ohm shunt (A3 on the Arduino header,
+// CN5). The shunt is low impedance, so the shortest sample time
+// is enough.
+const MOTOR0_CURRENT: Option<ScaledChannel> = Some(ScaledChannel {
+    channel: AdcChannel {
+        adc: 3,
+        channel: 6,
+        pin: Pin {
+            port: 'F',
+            number: 8,
+        },
+        sample_time: SampleTime::Cycles3,
+    },
+    scale: AdcScale::shunt(0.5, 1.0, 0.0),
+});
+
+// The supply voltage, through the same divider as the phase
+// voltages (A4 on the Arduino header, CN5)
+const MOTOR0_BUS_VOLTAGE: Option<ScaledChannel> = Some(ScaledChannel {
+    channel: AdcChannel {
+        adc: 3,
+        channel: 5,
+        pin: Pin {
+            port: 'F',
+            number: 7,
+        },
+        sample_time: PHASE_SAMPLE_TIME,
+    },
+    scale: AdcScale::divider(DIVIDER_RATIO),
+});
+
+// The motor parameters, as logged when the `identify` command
+// finishes (None for the default start-up sequence and control
+// settings)
+const MOTOR0_PARAMETERS: Option<MotorParameters> = None;
+
+// Motor 1, wired to a second L298 as motor 0 is to the first
+// (whether or not motor 0 uses the complementary outputs): TIM8
+// for the PWM, GPIO pins on the Arduino header for the enable
+// inputs, and ADC1 for the phase voltages and the current
+const MOTOR1: MotorConfig = MotorConfig {
+    pwm: [
+        TimerChannel {
+            timer: 8,
+            channel: 1,
+            pin: Pin {
+                port: 'I',
+                number: 5,
+            },
+        },
+        TimerChannel {
+            timer: 8,
+            channel: 2,
+            pin: Pin {
+                port: 'I',
+                number: 6,
+            },
+        },
+        TimerChannel {
+            timer: 8,
+            channel: 3,
+            pin: Pin {
+                port: 'I',
+                number: 7,
+            },
+        },
+    ],
+    enable_pins: [
+        // CN4, pin 3
+        Pin {
+            port: 'G',
+            number: 6,
+        },
+        // CN4, pin 5
+        Pin {
+            port: 'G',
+            number: 7,
+        },
+        // CN4, pin 8
+        Pin {
+            port: 'I',
+            number: 3,
+        },
+    ],
+    phase_voltages: [
+        AdcChannel {
+            adc: 1,
+            channel: 4,
+            pin: Pin {
+                port: 'A',
+                number: 4,
+            },
+            sample_time: PHASE_SAMPLE_TIME,
+        },
+        AdcChannel {
+            adc: 1,
+            channel: 6,
+            pin: Pin {
+                port: 'A',
+                number: 6,
+            },
+            sample_time: PHASE_SAMPLE_TIME,
+        },
+        AdcChannel {
+            adc: 1,
+            channel: 12,
+            pin: Pin {
+                port: 'C',
+                number: 2,
+            },
+            sample_time: PHASE_SAMPLE_TIME,
+        },
+    ],
+    // Through a 0.5 ohm shunt, as for motor 0
+    current: Some(ScaledChannel {
+        channel: AdcChannel {
+            adc: 1,
+            channel: 5,
+            pin: Pin {
+                port: 'A',
+                number: 5,
+            },
+            sample_time: SampleTime::Cycles3,
+        },
+        scale: AdcScale::shunt(0.5, 1.0, 0.0),
+    }),
+    // The same supply as motor 0, which measures it
+    bus_voltage: None,
+    adc_trigger: AdcTrigger::OnTime(50),
+    commutation_timer: 7,
+    parameters: None,
+};
+
+#[cfg(not(feature = "complementary-pwm"))]
+pub const MOTORS: [MotorConfig; NUM_MOTORS] = [
+    MotorConfig {
+        pwm: [
+            // Signal 1
+            TimerChannel {
+                timer: 1,
+                channel: 1,
+                pin: Pin {
+                    port: 'A',
+                    number: 8,
+                },
+            },
+            // Signal 2
+            TimerChannel {
+                timer: 2,
+                channel: 1,
+                pin: Pin {
+                    port: 'A',
+                    number: 15,
+                },
+            },
+            // Signal 3
+            TimerChannel {
+                timer: 5,
+                channel: 4,
+                pin: Pin {
+                    port: 'I',
+                    number: 0,
+                },
+            },
+        ],
+        enable_pins: [
+            // Enable 1, CN4, pin 4
+            Pin {
+                port: 'B',
+                number: 4,
+            },
+            // Enable 2, CN4, pin 7
+            Pin {
+                port: 'H',
+                number: 6,
+            },
+            // Enable 3, CN7, pin 1
+            Pin {
+                port: 'I',
+                number: 2,
+            },
+        ],
+        phase_voltages: MOTOR0_PHASE_VOLTAGES,
+        current: MOTOR0_CURRENT,
+        bus_voltage: MOTOR0_BUS_VOLTAGE,
+        // Convert in the middle of the on-time, away from the
+        // switching edges (the controller takes its trigger from the
+        // adc-trigger parameters, which default to the same)
+        adc_trigger: AdcTrigger::OnTime(50),
+        commutation_timer: 3,
+        parameters: MOTOR0_PARAMETERS,
+    },
+    MOTOR1,
+];
+
+#[cfg(feature = "complementary-pwm")]
+pub const MOTORS: [MotorConfig; NUM_MOTORS] = [
+    MotorConfig {
+        pwm: [
+            // TIM1 CH1 (high side 1)
+            TimerChannel {
+                timer: 1,
+                channel: 1,
+                pin: Pin {
+                    port: 'E',
+                    number: 9,
+                },
+            },
+            // TIM1 CH2 (high side 2)
+            TimerChannel {
+                timer: 1,
+                channel: 2,
+                pin: Pin {
+                    port: 'E',
+                    number: 11,
+                },
+            },
+            // TIM1 CH3 (high side 3)
+            TimerChannel {
+                timer: 1,
+                channel: 3,
+                pin: Pin {
+                    port: 'E',
+                    number: 13,
+                },
+            },
+        ],
+        enable_pins: [
+            // TIM1 CH1N (low side 1)
+            Pin {
+                port: 'E',
+                number: 8,
+            },
+            // TIM1 CH2N (low side 2)
+            Pin {
+                port: 'E',
+                number: 10,
+            },
+            // TIM1 CH3N (low side 3)
+            Pin {
+                port: 'E',
+                number: 12,
+            },
+        ],
+        phase_voltages: MOTOR0_PHASE_VOLTAGES,
+        current: MOTOR0_CURRENT,
+        bus_voltage: MOTOR0_BUS_VOLTAGE,
+        // Convert in the middle of the on-time, away from the
+        // switching edges (the controller takes its trigger from the
+        // adc-trigger parameters, which default to the same)
+        adc_trigger: AdcTrigger::OnTime(50),
+        commutation_timer: 3,
+        parameters: MOTOR0_PARAMETERS,
+    },
+    MOTOR1,
+];
@@ -1,20 +1,38 @@
 //! Simple implementation of synchronised PWM and ADC
 //!
 
+use bldc::{PwmError, PwmTiming, ThreePhasePwm};
 use cortex_m::asm::nop;
 use stm32f7xx_hal::{
     gpio::{PA15, PA8, PI0},
     pac::{RCC, TIM1, TIM2, TIM5},
+    rcc::Clocks,
 };
 
+/// The timer clock frequency before the clocks are configured
+/// (the internal 16 MHz oscillator)
+pub const HSI_HZ: u32 = 16_000_000;
+
 pub struct ThreeChannelPwm {
     pwm1: Pwm1,
     pwm2: Pwm2,
     pwm3: Pwm3,
-    period: u16,
+
+    // Clock frequencies of the timers on APB2 (TIM1) and APB1
+    // (TIM2 and TIM5)
+    apb2_timer_clock_hz: u32,
+    apb1_timer_clock_hz: u32,
+
+    // Prescaler and period of the timers on APB2 and APB1
+    apb2_timing: PwmTiming,
+    apb1_timing: PwmTiming,
 }
 
 impl ThreeChannelPwm {
+    /// Set up the three timers. The PWM frequency must be set
+    /// (once the clocks are configured, see
+    /// [`set_clocks`](Self::set_clocks)) before the outputs do
+    /// anything.
     pub fn new(
         rcc: &RCC,
         tim1: TIM1,
@@ -28,46 +46,106 @@ impl ThreeChannelPwm {
         let pwm2 = Pwm2::new(rcc, tim2, pin2);
         let pwm3 = Pwm3::new(rcc, tim3, pin3);
 
-        let period = 10000;
-        let mut three_channel_pwm = Self {
+        let stopped = PwmTiming {
+            prescaler: 0,
+            arr: 0,
+        };
+        Self {
             pwm1,
             pwm2,
             pwm3,
-            period,
-        };
+            apb2_timer_clock_hz: HSI_HZ,
+            apb1_timer_clock_hz: HSI_HZ,
+            apb2_timing: stopped,
+            apb1_timing: stopped,
+        }
+    }
 
-        three_channel_pwm.set_period(period);
-        three_channel_pwm
+    /// Use the timer clock frequencies from the configured clocks
+    /// (call before setting the PWM frequency)
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.apb2_timer_clock_hz = clocks.timclk2().raw();
+        self.apb1_timer_clock_hz = clocks.timclk1().raw();
     }
 
     pub fn enable(&self, enable: bool) {
         self.pwm1.enable(enable);
     }
 
-    pub fn set_period(&mut self, period: u16) {
-        self.period = period;
-        self.pwm1.set_period(period);
-        self.pwm2.set_period(period);
-        self.pwm3.set_period(period);
+    /// Set the PWM frequency of all three timers
+    ///
+    /// Returns the TIM1 settings. The three timers must end up
+    /// with the same frequency, so this fails if the APB1 and APB2
+    /// timer clocks do not allow that.
+    pub fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        let apb2_timing = PwmTiming::new(frequency_hz, self.apb2_timer_clock_hz)?;
+        let apb1_timing = PwmTiming::new(frequency_hz, self.apb1_timer_clock_hz)?;
+        if apb2_timing.frequency_hz(self.apb2_timer_clock_hz)
+            != apb1_timing.frequency_hz(self.apb1_timer_clock_hz)
+        {
+            return Err(PwmError::MismatchedTimers);
+        }
+
+        self.apb2_timing = apb2_timing;
+        self.apb1_timing = apb1_timing;
+        self.pwm1.set_timing(apb2_timing);
+        self.pwm2.set_timing(apb1_timing);
+        self.pwm3.set_timing(apb1_timing);
+        Ok(apb2_timing)
+    }
+
+    /// The PWM frequency achieved
+    pub fn frequency_hz(&self) -> f32 {
+        self.apb2_timing.frequency_hz(self.apb2_timer_clock_hz)
+    }
+
+    /// Trigger the ADC at `position` (between 0.0 and 1.0) through
+    /// the PWM period
+    pub fn set_adc_trigger(&self, position: f32) {
+        self.pwm1
+            .set_trigger(self.apb2_timing.trigger_compare(position));
     }
 
     pub fn set_duty(&self, which: u8, duty: f32) {
+        let timing = match which {
+            0 => self.apb2_timing,
+            _ => self.apb1_timing,
+        };
 
-	// This floating point stuff is giving problems
-	let duty = if duty == 1.0 {
-	    u16::MAX
-	} else {
-	    (duty * self.period as f32) as u16
-	};
+        // The controller only sets valid duty cycles. If one gets
+        // through anyway, turn the output off rather than panic.
+        let compare = timing.compare(duty).unwrap_or(0);
         match which {
-            0 => self.pwm1.set_duty(duty),
-            1 => self.pwm2.set_duty(duty),
-            2 => self.pwm3.set_duty(duty),
+            0 => self.pwm1.set_duty(compare),
+            1 => self.pwm2.set_duty(compare),
+            2 => self.pwm3.set_duty(compare),
             _ => panic!("Invalid value 'which' in set_duty. Must be 0, 1 or 2."),
         }
     }
 }
 
+impl ThreePhasePwm for ThreeChannelPwm {
+    fn enable(&mut self, enable: bool) {
+        ThreeChannelPwm::enable(self, enable);
+    }
+
+    fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        ThreeChannelPwm::set_frequency(self, frequency_hz)
+    }
+
+    fn frequency_hz(&self) -> f32 {
+        ThreeChannelPwm::frequency_hz(self)
+    }
+
+    fn set_duty(&mut self, which: usize, duty: f32) {
+        ThreeChannelPwm::set_duty(self, which as u8, duty);
+    }
+
+    fn set_adc_trigger(&mut self, position: f32) {
+        ThreeChannelPwm::set_adc_trigger(self, position);
+    }
+}
+
 struct Pwm1 {
     tim: TIM1,
 }
@@ -89,20 +167,32 @@ impl Pwm1 {
             w.oc1pe().bit(true)
         });
 
+        // Channel 4 triggers the ADC (it has no output pin). In PWM
+        // mode 2, OC4REF rises when the counter reaches CCR4.
+        tim.ccmr2_output().write(|w| {
+            w.oc4m().bits(0b111);
+            w.oc4pe().bit(true)
+        });
+
         // Enable capture/compare output
         tim.ccer.write(|w| w.cc1e().bit(true));
 
         tim.cr1.write(|w| w.arpe().bit(true));
 
-        // Set OC1REF as trigger output (high-going PWM signal)
-        tim.cr2.write(|w| w.mms().bits(0b1));
+        // Set OC1REF as trigger output (high-going PWM signal), and
+        // OC4REF as TRGO2 (the ADC trigger)
+        tim.cr2.write(|w| {
+            w.mms().bits(0b1);
+            // SAFETY: 0b0111 (OC4REF) is a valid MMS2 value (the
+            // PAC has no enumeration for it)
+            unsafe { w.mms2().bits(0b0111) }
+        });
 
         // Main output enable
         tim.bdtr.write(|w| w.moe().bit(true));
 
         let pwm = Self { tim };
 
-        pwm.set_period(0);
         pwm.set_duty(0);
 
         pwm
@@ -112,13 +202,18 @@ impl Pwm1 {
         self.tim.cr1.write(|w| w.cen().bit(enable));
     }
 
-    fn set_period(&self, period: u16) {
-        self.tim.arr.write(|w| w.arr().bits(period));
+    fn set_timing(&self, timing: PwmTiming) {
+        self.tim.psc.write(|w| w.psc().bits(timing.prescaler));
+        self.tim.arr.write(|w| w.arr().bits(timing.arr));
     }
 
     fn set_duty(&self, duty: u16) {
         self.tim.ccr1().write(|w| w.ccr().bits(duty));
     }
+
+    fn set_trigger(&self, compare: u16) {
+        self.tim.ccr4().write(|w| w.ccr().bits(compare));
+    }
 }
 
 struct Pwm2 {
@@ -162,14 +257,14 @@ impl Pwm2 {
 
         let pwm = Self { tim };
 
-        pwm.set_period(0);
         pwm.set_duty(0);
 
         pwm
     }
 
-    fn set_period(&self, period: u16) {
-        self.tim.arr.write(|w| w.arr().bits(period as u32));
+    fn set_timing(&self, timing: PwmTiming) {
+        self.tim.psc.write(|w| w.psc().bits(timing.prescaler));
+        self.tim.arr.write(|w| w.arr().bits(timing.arr as u32));
     }
 
     fn set_duty(&self, duty: u16) {
@@ -215,14 +310,14 @@ impl Pwm3 {
 
         let pwm = Self { tim };
 
-        pwm.set_period(0);
         pwm.set_duty(0);
 
         pwm
     }
 
-    fn set_period(&self, period: u16) {
-        self.tim.arr.write(|w| w.arr().bits(period as u32));
+    fn set_timing(&self, timing: PwmTiming) {
+        self.tim.psc.write(|w| w.psc().bits(timing.prescaler));
+        self.tim.arr.write(|w| w.arr().bits(timing.arr as u32));
     }
 
     fn set_duty(&self, duty: u16) {
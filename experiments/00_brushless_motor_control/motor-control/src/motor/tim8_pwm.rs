@@ -0,0 +1,133 @@
+//! Three PWM outputs on TIM8, for motor 1
+//!
+//! Motor 0 needs three timers to reach the Arduino header, but
+//! TIM8 channels 1 to 3 are all on port I (PI5, PI6 and PI7), so
+//! one timer drives all three phases of motor 1, and the outputs
+//! need no synchronising. Channel 4 has no output pin, and
+//! triggers ADC1 as TIM1 channel 4 does ADC3 for motor 0.
+
+use super::pwm::HSI_HZ;
+use bldc::{PwmError, PwmTiming, ThreePhasePwm};
+use cortex_m::asm::nop;
+use stm32f7xx_hal::{
+    gpio::{PI5, PI6, PI7},
+    pac::{RCC, TIM8},
+    rcc::Clocks,
+};
+
+/// Three PWM outputs on TIM8 channels 1 to 3
+pub struct Tim8Pwm {
+    tim: TIM8,
+    timer_clock_hz: u32,
+    timing: PwmTiming,
+}
+
+impl Tim8Pwm {
+    /// Set up TIM8 and its pins (for phases 0, 1 and 2). The PWM
+    /// frequency must be set (once the clocks are configured, see
+    /// [`set_clocks`](Self::set_clocks)) before the outputs do
+    /// anything.
+    pub fn new(rcc: &RCC, tim: TIM8, pins: (PI5, PI6, PI7)) -> Self {
+        const TIM8_AF: u8 = 3;
+        let _ = pins.0.into_alternate::<TIM8_AF>();
+        let _ = pins.1.into_alternate::<TIM8_AF>();
+        let _ = pins.2.into_alternate::<TIM8_AF>();
+
+        // Enable the timer clock (delay after two clock
+        // cycles before accessing peripheral registers)
+        rcc.apb2enr.modify(|_, w| w.tim8en().set_bit());
+        nop();
+        nop();
+
+        // Set PWM mode on channels 1 to 3
+        tim.ccmr1_output().write(|w| {
+            w.oc1m().bits(0b110);
+            w.oc1pe().bit(true);
+            w.oc2m().bits(0b110);
+            w.oc2pe().bit(true)
+        });
+        tim.ccmr2_output().write(|w| {
+            w.oc3m().bits(0b110);
+            w.oc3pe().bit(true);
+
+            // Channel 4 triggers the ADC (it has no output pin). In
+            // PWM mode 2, OC4REF rises when the counter reaches CCR4.
+            w.oc4m().bits(0b111);
+            w.oc4pe().bit(true)
+        });
+
+        // Enable capture/compare outputs
+        tim.ccer.write(|w| {
+            w.cc1e().bit(true);
+            w.cc2e().bit(true);
+            w.cc3e().bit(true)
+        });
+
+        tim.cr1.write(|w| w.arpe().bit(true));
+
+        // Set OC4REF as TRGO2 (the ADC trigger)
+        //
+        // SAFETY: 0b0111 (OC4REF) is a valid MMS2 value (the PAC
+        // has no enumeration for it)
+        tim.cr2.write(|w| unsafe { w.mms2().bits(0b0111) });
+
+        // Main output enable
+        tim.bdtr.write(|w| w.moe().bit(true));
+
+        let mut pwm = Self {
+            tim,
+            timer_clock_hz: HSI_HZ,
+            timing: PwmTiming {
+                prescaler: 0,
+                arr: 0,
+            },
+        };
+
+        for which in 0..3 {
+            pwm.set_duty(which, 0.0);
+        }
+
+        pwm
+    }
+
+    /// Use the TIM8 clock frequency from the configured clocks
+    /// (call before setting the PWM frequency)
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.timer_clock_hz = clocks.timclk2().raw();
+    }
+}
+
+impl ThreePhasePwm for Tim8Pwm {
+    fn enable(&mut self, enable: bool) {
+        self.tim.cr1.modify(|_, w| w.cen().bit(enable));
+    }
+
+    fn set_frequency(&mut self, frequency_hz: u32) -> Result<PwmTiming, PwmError> {
+        let timing = PwmTiming::new(frequency_hz, self.timer_clock_hz)?;
+        self.timing = timing;
+        self.tim.psc.write(|w| w.psc().bits(timing.prescaler));
+        self.tim.arr.write(|w| w.arr().bits(timing.arr));
+        Ok(timing)
+    }
+
+    fn frequency_hz(&self) -> f32 {
+        self.timing.frequency_hz(self.timer_clock_hz)
+    }
+
+    fn set_duty(&mut self, which: usize, duty: f32) {
+        // The controller only sets valid duty cycles. If one gets
+        // through anyway, turn the output off rather than panic.
+        let compare = self.timing.compare(duty).unwrap_or(0);
+        match which {
+            0 => self.tim.ccr1().write(|w| w.ccr().bits(compare)),
+            1 => self.tim.ccr2().write(|w| w.ccr().bits(compare)),
+            2 => self.tim.ccr3().write(|w| w.ccr().bits(compare)),
+            _ => panic!("Invalid phase number (wanted 0, 1, or 2)"),
+        }
+    }
+
+    fn set_adc_trigger(&mut self, position: f32) {
+        let compare = self.timing.trigger_compare(position);
+        self.tim.ccr4().write(|w| w.ccr().bits(compare));
+    }
+}
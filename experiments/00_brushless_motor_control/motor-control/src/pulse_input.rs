@@ -0,0 +1,181 @@
+//! PWM, OneShot125 and Multishot throttle input
+//!
+//! The pulse throttle input is connected to PB14 (D12, CN7 pin 5),
+//! which is TIM12 channel 1. TIM12 is in PWM input mode: the
+//! rising edge of a pulse resets the counter, and channel 2
+//! captures the counter at the falling edge, so CCR2 is the pulse
+//! width. The capture interrupt passes the width to
+//! [`bldc::PulseInput`], which detects the protocol and applies
+//! the throttle to motor 0 (see [`crate::motor::set_throttle`]).
+//!
+//! The counter counts at 1 MHz until the protocol is detected, and
+//! then faster for the shorter OneShot125 and Multishot pulses (to
+//! keep about 1000 steps of throttle). If the counter overflows
+//! before the next pulse, the input has been lost.
+//!
+//! The endpoints are in [`crate::motor::config::PULSE_INPUT`].
+//! When they are calibrated, the new endpoints are logged, and
+//! last until the next reset unless they are copied there.
+
+use crate::app::pulse_input_task;
+use crate::motor::pwm::HSI_HZ;
+use crate::motor::set_throttle;
+use bldc::{PulseAction, PulseProtocol};
+use cortex_m::asm::nop;
+use rtic::Mutex;
+use stm32f7xx_hal::{
+    gpio::PB14,
+    pac::{RCC, TIM12},
+    rcc::Clocks,
+};
+
+/// What the TIM12 interrupt was for
+pub enum PulseEvent {
+    /// A pulse of `width_ns` has been captured
+    Pulse { width_ns: u32 },
+    /// No pulse for a whole counter period
+    Timeout,
+}
+
+/// Measures throttle pulse widths using TIM12
+pub struct PulseReceiver {
+    tim: TIM12,
+    timer_clock_hz: u32,
+
+    // The counter frequency
+    counter_hz: u32,
+}
+
+impl PulseReceiver {
+    pub fn new(rcc: &RCC, tim: TIM12, pin: PB14) -> Self {
+        const TIM12_CH1_AF: u8 = 9;
+        let _ = pin.into_alternate::<TIM12_CH1_AF>();
+
+        // Enable the timer clock (delay after two clock cycles
+        // before accessing peripheral registers)
+        rcc.apb1enr.modify(|_, w| w.tim12en().bit(true));
+        nop();
+        nop();
+
+        // Channel 1 and channel 2 both capture TI1 (with a short
+        // filter to reject glitches)
+        tim.ccmr1_input().write(|w| unsafe {
+            w.cc1s().bits(0b01);
+            w.ic1f().bits(0b0010);
+            w.cc2s().bits(0b10)
+        });
+
+        // Channel 1 on the rising edge, channel 2 on the falling
+        // edge
+        tim.ccer.write(|w| {
+            w.cc1p().clear_bit();
+            w.cc1e().set_bit();
+            w.cc2p().set_bit();
+            w.cc2e().set_bit()
+        });
+
+        // Reset the counter on the rising edge of TI1 (TI1FP1)
+        tim.smcr.write(|w| unsafe {
+            w.ts().bits(0b101);
+            w.sms().bits(0b100)
+        });
+
+        // Interrupt when the pulse width is captured, and when the
+        // counter overflows. Only overflows set the update flag
+        // (not the resets on each pulse).
+        tim.arr.write(|w| w.arr().bits(0xffff));
+        tim.dier.write(|w| {
+            w.cc2ie().set_bit();
+            w.uie().set_bit()
+        });
+        tim.cr1.write(|w| {
+            w.urs().set_bit();
+            w.cen().set_bit()
+        });
+
+        let mut receiver = Self {
+            tim,
+            timer_clock_hz: HSI_HZ,
+            counter_hz: 0,
+        };
+        receiver.set_protocol(None);
+        receiver
+    }
+
+    /// Use the TIM12 clock frequency from the configured clocks
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.timer_clock_hz = clocks.timclk1().raw();
+        self.counter_hz = 0;
+        self.set_protocol(None);
+    }
+
+    /// Set the counter frequency for the detected protocol
+    pub fn set_protocol(&mut self, protocol: Option<PulseProtocol>) {
+        let counter_hz = match protocol {
+            None | Some(PulseProtocol::Pwm) => 1_000_000,
+            Some(PulseProtocol::OneShot125) => 8_000_000,
+            Some(PulseProtocol::Multishot) => self.timer_clock_hz,
+        };
+        if counter_hz == self.counter_hz {
+            return;
+        }
+        self.counter_hz = counter_hz;
+
+        let prescaler = (self.timer_clock_hz / counter_hz).max(1) - 1;
+        self.tim.psc.write(|w| w.psc().bits(prescaler as u16));
+
+        // Load the prescaler now (this does not set the update
+        // flag, because of URS)
+        self.tim.egr.write(|w| w.ug().set_bit());
+    }
+
+    /// Handle the TIM12 interrupt
+    pub fn on_interrupt(&mut self) -> Option<PulseEvent> {
+        let sr = self.tim.sr.read();
+        if sr.cc2if().bit() {
+            // Reading CCR2 clears the flag
+            let ticks = self.tim.ccr2().read().ccr().bits() as u64;
+            let width_ns = (ticks * 1_000_000_000 / self.counter_hz as u64) as u32;
+            Some(PulseEvent::Pulse { width_ns })
+        } else if sr.uif().bit() {
+            self.tim.sr.modify(|_, w| w.uif().clear_bit());
+            Some(PulseEvent::Timeout)
+        } else {
+            None
+        }
+    }
+}
+
+/// TIM12 interrupt (a throttle pulse has been captured, or the
+/// input has timed out)
+pub fn pulse_input_task(mut cx: pulse_input_task::Context<'_>) {
+    let receiver = cx.local.pulse_receiver;
+    let input = cx.local.pulse_input;
+    let action = match receiver.on_interrupt() {
+        Some(PulseEvent::Pulse { width_ns }) => input.update(width_ns),
+        Some(PulseEvent::Timeout) if input.protocol().is_some() => {
+            defmt::info!("Throttle input lost");
+            input.signal_lost()
+        }
+        _ => return,
+    };
+    receiver.set_protocol(input.protocol());
+
+    match action {
+        PulseAction::Stop => cx.shared.motor0.lock(|motor| motor.controller.stop()),
+        PulseAction::Throttle(throttle) => cx
+            .shared
+            .motor0
+            .lock(|motor| set_throttle(&mut motor.controller, throttle)),
+        PulseAction::Calibrated {
+            protocol,
+            calibration,
+        } => defmt::info!(
+            "Throttle calibrated ({}): {} to {} ns",
+            defmt::Debug2Format(&protocol),
+            calibration.min_ns,
+            calibration.max_ns
+        ),
+        PulseAction::None => {}
+    }
+}
@@ -0,0 +1,260 @@
+//! Interrupt-driven USART1 driver for the serial CLI
+//!
+//! USART1 is connected to the ST-LINK virtual COM port (RX on PB7,
+//! TX on PA9, 115200 baud). The USART1 interrupt moves the received
+//! bytes into one ring buffer (RXNE), and the bytes to send out of
+//! another (TXE), and wakes the task waiting on them. The task side
+//! ([`SerialRx`] and [`SerialTx`]) implements the
+//! `embedded-io-async` traits, so that the CLI task awaits the
+//! bytes instead of spinning, and the RTIC scheduler can run the
+//! lower-priority tasks (or sleep) in the meantime.
+
+use crate::app::serial_interrupt;
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use cortex_m::asm::nop;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::spsc::{Consumer, Producer, Queue};
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+use stm32f7xx_hal::{
+    gpio::{PA9, PB7},
+    pac::{Interrupt, RCC, USART1},
+    rcc::Clocks,
+};
+
+/// The baud rate of the virtual COM port
+pub const BAUD_RATE: u32 = 115_200;
+
+/// The size of the receive ring buffer (one less byte fits)
+pub const RX_BUFFER_LEN: usize = 64;
+
+/// The size of the transmit ring buffer (one less byte fits)
+pub const TX_BUFFER_LEN: usize = 256;
+
+pub type RxQueue = Queue<u8, RX_BUFFER_LEN>;
+pub type TxQueue = Queue<u8, TX_BUFFER_LEN>;
+
+// The tasks waiting for received bytes, and for room to send
+static RX_WAKER: CriticalSectionWakerRegistration = CriticalSectionWakerRegistration::new();
+static TX_WAKER: CriticalSectionWakerRegistration = CriticalSectionWakerRegistration::new();
+
+// Set by the interrupt when received bytes are lost (the USART
+// overran, or the receive buffer was full)
+static RX_LOST: AtomicBool = AtomicBool::new(false);
+
+// Set by the task when it has buffered bytes to send. The interrupt
+// then enables the transmit interrupt, so that CR1 is only modified
+// at the USART1 priority.
+static TX_START: AtomicBool = AtomicBool::new(false);
+
+/// Received bytes were lost since the last read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Overrun;
+
+impl embedded_io_async::Error for Overrun {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// The interrupt side of the driver (owns USART1)
+pub struct SerialPort {
+    usart: USART1,
+    rx: Producer<'static, u8, RX_BUFFER_LEN>,
+    tx: Consumer<'static, u8, TX_BUFFER_LEN>,
+}
+
+/// Receives bytes from USART1
+pub struct SerialRx {
+    queue: Consumer<'static, u8, RX_BUFFER_LEN>,
+}
+
+/// Sends bytes on USART1
+pub struct SerialTx {
+    queue: Producer<'static, u8, TX_BUFFER_LEN>,
+}
+
+impl SerialPort {
+    /// Set up the pins, enable the USART1 clock, and split the
+    /// ring buffers between the interrupt and the task. The USART
+    /// is not enabled until the clocks are configured (see
+    /// [`set_clocks`](Self::set_clocks)).
+    pub fn new(
+        rcc: &RCC,
+        usart: USART1,
+        rx_pin: PB7,
+        tx_pin: PA9,
+        rx_queue: &'static mut RxQueue,
+        tx_queue: &'static mut TxQueue,
+    ) -> (Self, SerialRx, SerialTx) {
+        const USART1_AF: u8 = 7;
+        let _ = rx_pin.into_alternate::<USART1_AF>();
+        let _ = tx_pin.into_alternate::<USART1_AF>();
+
+        // Enable the USART clock (delay after two clock cycles
+        // before accessing peripheral registers)
+        rcc.apb2enr.modify(|_, w| w.usart1en().set_bit());
+        nop();
+        nop();
+
+        let (rx_producer, rx_consumer) = rx_queue.split();
+        let (tx_producer, tx_consumer) = tx_queue.split();
+        (
+            Self {
+                usart,
+                rx: rx_producer,
+                tx: tx_consumer,
+            },
+            SerialRx { queue: rx_consumer },
+            SerialTx { queue: tx_producer },
+        )
+    }
+
+    /// Set the baud rate from the USART1 clock (pclk2), and enable
+    /// the receiver (with its interrupt) and transmitter
+    pub fn set_clocks(&mut self, clocks: &Clocks) {
+        self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+
+        // Oversampling by 16, rounded to the nearest divider
+        let pclk2 = clocks.pclk2().raw();
+        let brr = (pclk2 + BAUD_RATE / 2) / BAUD_RATE;
+        self.usart.brr.write(|w| unsafe { w.bits(brr) });
+
+        // 8N1. The transmit interrupt is enabled while there are
+        // bytes to send.
+        self.usart.cr3.reset();
+        self.usart.cr1.write(|w| {
+            w.rxneie().set_bit();
+            w.re().set_bit();
+            w.te().set_bit();
+            w.ue().set_bit()
+        });
+    }
+
+    /// Handle the USART1 interrupt
+    pub fn on_interrupt(&mut self) {
+        let isr = self.usart.isr.read();
+
+        // An overrun also raises the receive interrupt, and must be
+        // cleared (the byte in the data register is still valid)
+        if isr.ore().bit_is_set() {
+            self.usart.icr.write(|w| w.orecf().set_bit());
+            RX_LOST.store(true, Ordering::Relaxed);
+        }
+        if isr.rxne().bit_is_set() {
+            let byte = self.usart.rdr.read().rdr().bits() as u8;
+            if self.rx.enqueue(byte).is_err() {
+                RX_LOST.store(true, Ordering::Relaxed);
+            }
+            RX_WAKER.wake();
+        }
+
+        if TX_START.swap(false, Ordering::Relaxed) {
+            self.usart.cr1.modify(|_, w| w.txeie().set_bit());
+        }
+        if isr.txe().bit_is_set() && self.usart.cr1.read().txeie().bit_is_set() {
+            match self.tx.dequeue() {
+                Some(byte) => self.usart.tdr.write(|w| w.tdr().bits(byte as u16)),
+                // Nothing left to send
+                None => self.usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+            }
+            TX_WAKER.wake();
+        }
+    }
+}
+
+impl ErrorType for SerialRx {
+    type Error = Overrun;
+}
+
+impl Read for SerialRx {
+    /// Wait for at least one byte, and read as many as are
+    /// buffered (up to the length of `buf`)
+    ///
+    /// Returns [`Overrun`] once if bytes were lost since the last
+    /// read (the bytes which were kept are read by the next call).
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Overrun> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        poll_fn(|cx| {
+            // Register before looking, so that a byte received in
+            // between still wakes the task
+            RX_WAKER.register(cx.waker());
+            if RX_LOST.swap(false, Ordering::Relaxed) {
+                return Poll::Ready(Err(Overrun));
+            }
+            let mut count = 0;
+            while count < buf.len() {
+                match self.queue.dequeue() {
+                    Some(byte) => buf[count] = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            if count == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(count))
+            }
+        })
+        .await
+    }
+}
+
+impl SerialTx {
+    /// Let the interrupt take the bytes in the buffer (pend it,
+    /// and it enables itself on TXE)
+    fn start(&self) {
+        TX_START.store(true, Ordering::Relaxed);
+        rtic::pend(Interrupt::USART1);
+    }
+}
+
+impl ErrorType for SerialTx {
+    type Error = Infallible;
+}
+
+impl Write for SerialTx {
+    /// Wait until there is room in the buffer, and buffer as much
+    /// of `buf` as fits
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        poll_fn(|cx| {
+            TX_WAKER.register(cx.waker());
+            let mut count = 0;
+            while count < buf.len() && self.queue.enqueue(buf[count]).is_ok() {
+                count += 1;
+            }
+            if count == 0 {
+                Poll::Pending
+            } else {
+                self.start();
+                Poll::Ready(Ok(count))
+            }
+        })
+        .await
+    }
+
+    /// Wait until every buffered byte has been handed to the USART
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        poll_fn(|cx| {
+            TX_WAKER.register(cx.waker());
+            if self.queue.len() == 0 {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// USART1 interrupt (a byte was received, or can be sent)
+pub fn serial_interrupt(cx: serial_interrupt::Context<'_>) {
+    cx.local.serial_port.on_interrupt();
+}
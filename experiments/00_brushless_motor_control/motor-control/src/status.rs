@@ -0,0 +1,398 @@
+//! Motor status and settings, as shown by the serial CLI
+//!
+//! The `status` and `config` commands copy what they show out of
+//! the motor (see [`Status::read`] and [`Settings::read`]), and
+//! write it out afterwards, so that the motor is not locked while
+//! the UART is busy. The `stream` command does the same with a
+//! [`Sample`] (see [`write_csv_row`]), and the `list` command with
+//! the values of the parameters (see [`ParameterList::read`]).
+
+use crate::motor::adc::AdcSampler;
+use crate::motor::config::MOTORS;
+use bldc::config::{AdcChannel, AdcTrigger, MotorConfig, Pin, ScaledChannel, MAX_CONVERSIONS};
+use bldc::{
+    BrakeConfig, ControlMode, Direction, FaultConfig, MotorFault, Parameter, ParameterInfo,
+    ParameterType, PhaseDriver, PhaseVoltageSampler, Sample, SpeedConfig, StartupConfig,
+    StartupState, StreamField, StreamFields, ThreePhaseController, ThreePhasePwm,
+};
+use ufmt::{uDisplay, uWrite, uwrite, uwriteln, Formatter};
+
+/// An `f32` shown with a fixed number of decimal places (`ufmt`
+/// cannot format floating-point numbers)
+pub struct Fixed(pub f32, pub u8);
+
+impl uDisplay for Fixed {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        let Fixed(value, decimals) = *self;
+        let scale = 10u64.pow(decimals as u32);
+        let scaled = (value.abs() * scale as f32 + 0.5) as u64;
+        if value < 0.0 && scaled != 0 {
+            f.write_char('-')?;
+        }
+        uwrite!(f, "{}", scaled / scale)?;
+        if decimals > 0 {
+            f.write_char('.')?;
+            let mut divisor = scale / 10;
+            while divisor > 0 {
+                let digit = (scaled / divisor % 10) as u8;
+                f.write_char((b'0' + digit) as char)?;
+                divisor /= 10;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A parameter value (the option for a choice), with its units
+pub struct ShowValue(pub ParameterInfo, pub f32);
+
+impl uDisplay for ShowValue {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        let ShowValue(info, value) = *self;
+        match info.option(value) {
+            Some(option) => f.write_str(option)?,
+            None => uwrite!(f, "{}", Fixed(value, info.decimals))?,
+        }
+        if !info.units.is_empty() {
+            uwrite!(f, " {}", info.units)?;
+        }
+        Ok(())
+    }
+}
+
+struct ShowPin(Pin);
+
+impl uDisplay for ShowPin {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        uwrite!(f, "P{}{}", self.0.port, self.0.number)
+    }
+}
+
+struct ShowChannel(AdcChannel);
+
+impl uDisplay for ShowChannel {
+    fn fmt<W>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: uWrite + ?Sized,
+    {
+        let channel = self.0;
+        uwrite!(
+            f,
+            "ADC{} IN{} {}",
+            channel.adc,
+            channel.channel,
+            ShowPin(channel.pin)
+        )
+    }
+}
+
+/// What the `status` command shows
+pub struct Status {
+    armed: bool,
+    mode: ControlMode,
+    state: StartupState,
+    direction: Direction,
+    step: u8,
+    duty: f32,
+    target_rpm: Option<f32>,
+    // The measured step period in closed loop, or the open-loop
+    // step period
+    step_period_us: Option<u32>,
+    closed_loop: bool,
+    neutral_voltage: u16,
+    current: Option<f32>,
+    bus_voltage: Option<f32>,
+    raw: [u16; MAX_CONVERSIONS],
+    conversions: usize,
+    fault: Option<MotorFault>,
+}
+
+impl Status {
+    pub fn read<D, P>(controller: &ThreePhaseController<D, P, AdcSampler>) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+    {
+        let step_period_us = controller.step_period_us();
+        Self {
+            armed: controller.is_armed(),
+            mode: controller.mode(),
+            state: controller.startup.state(),
+            direction: controller.direction(),
+            step: controller.step().step(),
+            duty: controller.applied_duty(),
+            target_rpm: controller.speed.target_rpm(),
+            step_period_us: step_period_us.or(controller.open_loop_step_us),
+            closed_loop: step_period_us.is_some(),
+            neutral_voltage: controller.neutral_voltage,
+            current: controller.current(),
+            bus_voltage: controller.bus_voltage(),
+            raw: controller.sampler().latest().raw,
+            conversions: MOTORS[0].adc_sequence().len(),
+            fault: controller.fault(),
+        }
+    }
+
+    pub fn write<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        uwriteln!(
+            w,
+            "{}, {} ({}), {}",
+            if self.armed { "armed" } else { "disarmed" },
+            self.state.name(),
+            self.mode.name(),
+            self.direction.name()
+        )?;
+        uwrite!(w, "Step {}, duty {}", self.step, Fixed(self.duty, 3))?;
+        if let Some(rpm) = self.target_rpm {
+            uwrite!(w, ", target {} RPM", rpm as u32)?;
+        }
+        match self.step_period_us {
+            Some(period) if self.closed_loop => uwriteln!(w, ", period {} us", period)?,
+            Some(period) => uwriteln!(w, ", period {} us (open loop)", period)?,
+            None => uwriteln!(w, "")?,
+        }
+        uwrite!(w, "Neutral {}", self.neutral_voltage)?;
+        if let Some(current) = self.current {
+            uwrite!(w, ", current {} A", Fixed(current, 2))?;
+        }
+        if let Some(voltage) = self.bus_voltage {
+            uwrite!(w, ", supply {} V", Fixed(voltage, 2))?;
+        }
+        uwrite!(w, "\nADC")?;
+        for raw in &self.raw[..self.conversions] {
+            uwrite!(w, " {}", raw)?;
+        }
+        uwrite!(
+            w,
+            "\nFault: {}",
+            self.fault.map_or("none", |fault| fault.name())
+        )
+    }
+}
+
+/// What the `config` command shows: the peripherals of the motor
+/// (its entry in [`MOTORS`]), and its settings
+pub struct Settings {
+    motor: &'static MotorConfig,
+    frequency_hz: f32,
+    adc_trigger: AdcTrigger,
+    startup: StartupConfig,
+    speed: SpeedConfig,
+    brake: BrakeConfig,
+    faults: FaultConfig,
+}
+
+impl Settings {
+    /// The settings of motor `n`
+    pub fn read<D, P, S>(n: usize, controller: &ThreePhaseController<D, P, S>) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        Self {
+            motor: &MOTORS[n],
+            frequency_hz: controller.pwm().frequency_hz(),
+            adc_trigger: controller.adc_trigger,
+            startup: *controller.startup.config(),
+            speed: *controller.speed.config(),
+            brake: *controller.brake.config(),
+            faults: *controller.faults.config(),
+        }
+    }
+
+    pub fn write<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        let motor = self.motor;
+        uwrite!(w, "PWM")?;
+        for channel in motor.pwm {
+            uwrite!(
+                w,
+                " TIM{} CH{} {}",
+                channel.timer,
+                channel.channel,
+                ShowPin(channel.pin)
+            )?;
+        }
+        uwrite!(w, ", enable")?;
+        for pin in motor.enable_pins {
+            uwrite!(w, " {}", ShowPin(pin))?;
+        }
+        uwriteln!(w, ", {} Hz", self.frequency_hz as u32)?;
+
+        uwrite!(w, "Phase voltages")?;
+        for channel in motor.phase_voltages {
+            uwrite!(w, " {}", ShowChannel(channel))?;
+        }
+        uwriteln!(w, "")?;
+        for (name, channel) in [("Current", motor.current), ("Supply", motor.bus_voltage)] {
+            match channel {
+                Some(ScaledChannel { channel, .. }) => {
+                    uwriteln!(w, "{} {}", name, ShowChannel(channel))?
+                }
+                None => uwriteln!(w, "{} not measured", name)?,
+            }
+        }
+        match self.adc_trigger {
+            AdcTrigger::OnTime(percent) => uwrite!(w, "ADC trigger {}% of on-time", percent)?,
+            AdcTrigger::OffTime(percent) => uwrite!(w, "ADC trigger {}% of off-time", percent)?,
+        }
+        uwriteln!(w, ", commutation TIM{}", motor.commutation_timer)?;
+
+        let startup = &self.startup;
+        uwriteln!(
+            w,
+            "Align at {} for {} us",
+            Fixed(startup.align_duty, 3),
+            startup.align_time_us
+        )?;
+        uwriteln!(
+            w,
+            "Ramp {} to {} us at {} to {} over {} steps",
+            startup.ramp_start_period_us,
+            startup.ramp_end_period_us,
+            Fixed(startup.ramp_start_duty, 3),
+            Fixed(startup.ramp_end_duty, 3),
+            startup.ramp_steps
+        )?;
+
+        let speed = &self.speed;
+        uwriteln!(
+            w,
+            "Speed: {} pole pairs, kp {}, ki {}, duty {} to {}",
+            speed.pole_pairs,
+            Fixed(speed.kp, 6),
+            Fixed(speed.ki, 6),
+            Fixed(speed.min_duty, 3),
+            Fixed(speed.max_duty, 3)
+        )?;
+        uwriteln!(
+            w,
+            "Brake: {} (regenerative duty {}), stop after {} us",
+            self.brake.mode.name(),
+            Fixed(self.brake.regenerative_duty, 3),
+            self.brake.stop_time_us
+        )?;
+        uwrite!(
+            w,
+            "Limits: {} A, {} V",
+            Fixed(self.faults.max_current, 2),
+            Fixed(self.faults.max_bus_voltage, 2)
+        )
+    }
+}
+
+/// Write the CSV column headings of the streamed fields
+pub fn write_csv_header<W: uWrite + ?Sized>(
+    fields: StreamFields,
+    w: &mut W,
+) -> Result<(), W::Error> {
+    let columns = fields.iter().flat_map(|field| field.columns().iter());
+    for (i, column) in columns.enumerate() {
+        if i > 0 {
+            w.write_char(',')?;
+        }
+        w.write_str(column)?;
+    }
+    w.write_str("\r\n")
+}
+
+/// Write the selected fields of a sample as a CSV line (a
+/// measurement which is not available is left empty)
+pub fn write_csv_row<W: uWrite + ?Sized>(sample: &Sample, w: &mut W) -> Result<(), W::Error> {
+    for (i, field) in sample.fields.iter().enumerate() {
+        if i > 0 {
+            w.write_char(',')?;
+        }
+        match field {
+            StreamField::Time => uwrite!(w, "{}", sample.time_ms)?,
+            StreamField::PhaseVoltages => {
+                let [a, b, c] = sample.phase_voltages;
+                uwrite!(w, "{},{},{}", a, b, c)?
+            }
+            StreamField::Neutral => uwrite!(w, "{}", sample.neutral_voltage)?,
+            StreamField::Step => uwrite!(w, "{}", sample.step)?,
+            StreamField::Duty => uwrite!(w, "{}", Fixed(sample.duty, 3))?,
+            StreamField::Period => {
+                if let Some(period) = sample.step_period_us {
+                    uwrite!(w, "{}", period)?
+                }
+            }
+            StreamField::Rpm => {
+                if let Some(rpm) = sample.rpm {
+                    uwrite!(w, "{}", Fixed(rpm, 0))?
+                }
+            }
+            StreamField::Current => {
+                if let Some(current) = sample.current {
+                    uwrite!(w, "{}", Fixed(current, 2))?
+                }
+            }
+            StreamField::BusVoltage => {
+                if let Some(voltage) = sample.bus_voltage {
+                    uwrite!(w, "{}", Fixed(voltage, 2))?
+                }
+            }
+            StreamField::State => w.write_str(sample.state.name())?,
+            StreamField::Fault => w.write_str(sample.fault.map_or("none", |f| f.name()))?,
+        }
+    }
+    w.write_str("\r\n")
+}
+
+/// What the `list` command shows: every parameter, with its range
+/// and default
+pub struct ParameterList([f32; Parameter::ALL.len()]);
+
+impl ParameterList {
+    pub fn read<D, P, S>(controller: &ThreePhaseController<D, P, S>) -> Self
+    where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        S: PhaseVoltageSampler,
+    {
+        Self(Parameter::ALL.map(|parameter| parameter.get(controller)))
+    }
+
+    pub fn write<W: uWrite + ?Sized>(&self, w: &mut W) -> Result<(), W::Error> {
+        for (i, (parameter, value)) in Parameter::ALL.iter().zip(self.0).enumerate() {
+            let info = parameter.info();
+            if i > 0 {
+                uwriteln!(w, "")?;
+            }
+            uwrite!(w, "{} = {}", info.name, ShowValue(info, value))?;
+            if info.read_only {
+                w.write_str(" (read-only)")?;
+            } else {
+                w.write_str(" (")?;
+                match info.kind {
+                    ParameterType::Choice(options) => {
+                        for (i, option) in options.iter().enumerate() {
+                            if i > 0 {
+                                w.write_char('|')?;
+                            }
+                            w.write_str(option)?;
+                        }
+                    }
+                    _ => uwrite!(
+                        w,
+                        "{} to {}",
+                        Fixed(info.min, info.decimals),
+                        Fixed(info.max, info.decimals)
+                    )?,
+                }
+                uwrite!(w, ", default {})", ShowValue(info, info.default))?;
+            }
+            uwrite!(w, "\n  {}", info.description)?;
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,59 @@
+//! Serial command line interface (on USART1, see
+//! [`crate::serial_port`])
+//!
+//! The port also takes the binary protocol (see
+//! [`bldc::protocol`]): the frames are answered by the task, and the
+//! rest of the bytes go to the CLI.
+
+use core::cell::RefCell;
 use core::convert::Infallible;
 
-use crate::app::serial_task;
+use crate::app::{serial_task, Mono};
+use crate::motor::adc::AdcSampler;
+use crate::motor::config::NUM_MOTORS;
+use crate::motor::set_duty;
+use crate::serial_port::{Overrun, SerialTx};
+use crate::status::{
+    write_csv_header, write_csv_row, Fixed, ParameterList, Settings, ShowValue, Status,
+};
+use crate::SYSTICK_RATE_HZ;
+use bldc::protocol::{Frame, FrameDecoder, Received, Request, Response};
+use bldc::{
+    BrakeConfig, BrakeMode, CommutationTimer, Direction, IdentifyConfig, Motor, Parameter,
+    PhaseDriver, PwmError, Sample, StreamFields, ThreePhasePwm,
+};
 use embedded_cli::cli::CliBuilder;
 use embedded_cli::Command;
 use embedded_io::{ErrorType, Write};
-use hal::gpio::{PA9, PB7};
-use hal::rcc::Clocks;
-use hal::serial::{self, Rx, Serial, Tx};
+use embedded_io_async::{Read, Write as _};
 use rtic::Mutex;
-use stm32f7xx_hal as hal;
-use stm32f7xx_hal::pac::USART1;
-use stm32f7xx_hal::prelude::*;
-use ufmt::uwrite;
+use rtic_monotonics::systick::prelude::*;
+use ufmt::{uWrite, uwrite};
+
+/// Run `$body` with `$motor` bound to the resource of the motor
+/// selected by `$selected` (the motors have different types, so
+/// the body is expanded once for each)
+macro_rules! with_motor {
+    ($shared:expr, $selected:expr, |$motor:ident| $body:expr) => {
+        match $selected {
+            0 => {
+                let $motor = &mut $shared.motor0;
+                $body
+            }
+            _ => {
+                let $motor = &mut $shared.motor1;
+                $body
+            }
+        }
+    };
+}
+
+/// Lock the motor selected by `$selected`, and call `$f` with it
+macro_rules! lock_motor {
+    ($shared:expr, $selected:expr, $f:expr) => {
+        with_motor!($shared, $selected, |motor| motor.lock($f))
+    };
+}
 
 #[derive(Command)]
 enum Base<'a> {
@@ -21,52 +63,171 @@ enum Base<'a> {
         name: Option<&'a str>,
     },
 
+    /// Select the motor which the other commands act on
+    Motor {
+        /// The motor number (shows the selected motor if omitted)
+        number: Option<usize>,
+    },
+
+    /// Turn on the PWM outputs, so that the motor can be started
+    Arm,
+
+    /// Stop the motor, and turn off the PWM outputs
+    Disarm,
+
+    /// Start the motor (align, open-loop ramp, then closed loop)
+    Start,
+
+    /// Stop the motor (braked as set by the brake command)
+    Stop,
+
     /// Set the value of the PWM duty cycle for BLDC control
     PwmDuty {
         /// The duty cycle value, between 0.0 and 1.0
         duty: f32,
     },
 
-    /// Set the commutation step time for BLDC control
+    /// Set the PWM frequency
+    PwmFrequency {
+        /// The frequency in Hz
+        frequency: u32,
+    },
+
+    /// Set the target speed for closed-loop speed control
+    Rpm {
+        /// The mechanical RPM (turns off speed control if omitted)
+        rpm: Option<f32>,
+    },
+
+    /// Set the time until the next commutation
     StepTime {
-        /// The commutation step period in microseconds
+        /// The time in microseconds (1 to 65535)
         time: u32,
     },
 
-    /// Stop CLI and exit
-    Exit,
+    /// Set the direction of rotation (a running motor is braked
+    /// to a stop and started again)
+    Direction {
+        /// forward or reverse (shows the direction if omitted)
+        direction: Option<&'a str>,
+    },
+
+    /// Set how the motor is braked when it is stopped
+    Brake {
+        /// coast, active or regenerative (shows the mode if omitted)
+        mode: Option<&'a str>,
+
+        /// The low-side duty cycle for regenerative braking
+        duty: Option<f32>,
+    },
+
+    /// Measure the motor parameters (the motor is held still, and
+    /// then started and stopped again)
+    Identify {
+        /// The rated Kv of the motor, to find the number of pole
+        /// pairs
+        kv: Option<f32>,
+    },
+
+    /// Show the measured motor parameters
+    Parameters,
+
+    /// Show the motor state, measurements and latest ADC
+    /// conversions
+    Status,
+
+    /// Show the peripherals and settings of the motor
+    Config,
+
+    /// Stream the selected variables every PERIOD ms (press enter
+    /// to stop a CSV stream)
+    Stream {
+        /// The period in ms (0 stops the stream)
+        period: u32,
+
+        /// Comma-separated list of time, phases, neutral, step,
+        /// duty, period, rpm, current, voltage, state and fault
+        /// (all by default)
+        fields: Option<&'a str>,
+
+        /// csv (the default) or binary (protocol frames)
+        format: Option<&'a str>,
+    },
+
+    /// Show a parameter (see list)
+    Get {
+        /// The name of the parameter
+        name: &'a str,
+    },
+
+    /// Set a parameter
+    Set {
+        /// The name of the parameter
+        name: &'a str,
+
+        /// A number, or the name of an option
+        value: &'a str,
+    },
+
+    /// Show all the parameters, with their ranges and defaults
+    List,
+
+    /// Set a parameter (or all of them) back to its default
+    Reset {
+        /// The name of the parameter (all of them if omitted)
+        name: Option<&'a str>,
+    },
+
+    /// Show the latched motor fault
+    Fault,
+
+    /// Clear the motor fault (the motor stays stopped and disarmed
+    /// until `arm` and `start`)
+    ClearFault,
 }
 
-pub struct SerialTx {
-    tx: Tx<USART1>,
+/// The most output one command can produce (the `list` command
+/// produces the most)
+const OUTPUT_LEN: usize = 4096;
+
+/// The longest command
+const COMMAND_LEN: usize = Parameter::CommandBufferLen.info().default as usize;
+
+/// Collects the output of the CLI, which writes synchronously, so
+/// that it can be sent afterwards without waiting for the UART
+struct CliOutput {
+    buffer: [u8; OUTPUT_LEN],
+    len: usize,
+    // Bytes which did not fit
+    dropped: usize,
 }
 
-impl SerialTx {
-    pub fn new(tx: serial::Tx<USART1>) -> Self {
-        Self { tx }
+impl CliOutput {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; OUTPUT_LEN],
+            len: 0,
+            dropped: 0,
+        }
     }
 }
 
-impl ErrorType for SerialTx {
+/// The CLI's handle on the [`CliOutput`] (shared with the task,
+/// which sends it)
+struct OutputWriter<'a>(&'a RefCell<CliOutput>);
+
+impl ErrorType for OutputWriter<'_> {
     type Error = Infallible;
 }
 
-impl Write for SerialTx {
+impl Write for OutputWriter<'_> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        if buf.is_empty() {
-            Ok(0)
-        } else {
-            let mut sent_counter: usize = 0;
-            for ch in buf {
-                // Loop calling write until it succeeds. The HAL
-                // serial write call does not block if a character
-                // is currently being transmitted; it returns without
-                // sending anything. Keep retrying until ch is sent.
-                while self.tx.write(*ch).is_err() {}
-                sent_counter += 1;
-            }
-            Ok(sent_counter)
-        }
+        let output = &mut *self.0.borrow_mut();
+        let count = buf.len().min(OUTPUT_LEN - output.len);
+        output.buffer[output.len..output.len + count].copy_from_slice(&buf[..count]);
+        output.len += count;
+        output.dropped += buf.len() - count;
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
@@ -74,41 +235,164 @@ impl Write for SerialTx {
     }
 }
 
-pub fn init_uart_serial(
-    usart1: USART1,
-    rx: PB7,
-    tx: PA9,
-    clocks: &Clocks,
-) -> (Rx<USART1>, SerialTx) {
-    let serial = Serial::new(
-        usart1,
-        (tx.into_alternate(), rx.into_alternate()),
-        clocks,
-        serial::Config::default(), // Default to 115_200 bauds
-    );
-
-    let (tx, rx) = serial.split();
-
-    (rx, SerialTx::new(tx))
+impl uWrite for OutputWriter<'_> {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Infallible> {
+        Write::write_all(self, s.as_bytes())
+    }
+}
+
+/// Add a binary protocol frame to the output
+fn queue_frame(output: &RefCell<CliOutput>, frame: &Frame) {
+    let Ok(()) = OutputWriter(output).write_all(frame.as_bytes());
+}
+
+/// The shortest stream period (a line or frame with every field
+/// takes about 6 ms at 115200 baud)
+const MIN_STREAM_PERIOD_MS: u32 = 10;
+
+/// How the streamed samples are sent
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StreamFormat {
+    Csv,
+    /// [`Response::Sample`] frames
+    Binary,
+}
+
+/// Sending a [`Sample`] of the selected fields of a motor every
+/// `period_ms` (the `stream` command, or [`Request::Stream`])
+struct Stream {
+    motor: usize,
+    period_ms: u32,
+    fields: StreamFields,
+    format: StreamFormat,
+    next: <Mono as Monotonic>::Instant,
+    // The CSV column headings are still to be sent
+    header: bool,
+}
+
+impl Stream {
+    fn new(motor: usize, period_ms: u32, fields: StreamFields, format: StreamFormat) -> Self {
+        let period_ms = period_ms.max(MIN_STREAM_PERIOD_MS);
+        Self {
+            motor,
+            period_ms,
+            fields,
+            format,
+            next: Mono::now() + period_ms.millis(),
+            header: format == StreamFormat::Csv,
+        }
+    }
+
+    /// Add the next sample to the output, and work out when the one
+    /// after is due
+    fn send<D, P, T>(
+        &mut self,
+        motor: &mut impl Mutex<T = Motor<D, P, AdcSampler, T>>,
+        output: &RefCell<CliOutput>,
+    ) where
+        D: PhaseDriver,
+        P: ThreePhasePwm,
+        T: CommutationTimer,
+    {
+        let time_ms = 1000 * Mono::now().ticks() / SYSTICK_RATE_HZ;
+        let sample =
+            motor.lock(|motor| Sample::from_controller(&motor.controller, self.fields, time_ms));
+        let writer = &mut OutputWriter(output);
+        match self.format {
+            StreamFormat::Csv => {
+                if self.header {
+                    // Over the CLI prompt
+                    let Ok(()) = writer.write_str("\r");
+                    let Ok(()) = write_csv_header(self.fields, writer);
+                    self.header = false;
+                }
+                let Ok(()) = write_csv_row(&sample, writer);
+            }
+            StreamFormat::Binary => queue_frame(output, &Response::Sample(sample).encode()),
+        }
+
+        // Skip the periods which were missed
+        let now = Mono::now();
+        self.next += self.period_ms.millis();
+        if self.next < now {
+            self.next = now + self.period_ms.millis();
+        }
+    }
+}
+
+/// Answer a binary protocol request for motor `n`, and start or
+/// stop streaming
+fn respond<D, P, T>(
+    n: usize,
+    motor: &mut impl Mutex<T = Motor<D, P, AdcSampler, T>>,
+    payload: &[u8],
+    stream: &mut Option<Stream>,
+) -> Response
+where
+    D: PhaseDriver,
+    P: ThreePhasePwm,
+    T: CommutationTimer,
+{
+    let request = match Request::decode(payload) {
+        Ok(request) => request,
+        Err(error) => return Response::Error(error.into()),
+    };
+    if let Request::Stream { period_ms, fields } = request {
+        *stream =
+            (period_ms > 0).then(|| Stream::new(n, period_ms as u32, fields, StreamFormat::Binary));
+    }
+    motor.lock(|motor| request.respond(&mut motor.controller))
+}
+
+/// Send the collected output, waiting for room in the transmit
+/// buffer
+async fn send(tx: &mut SerialTx, output: &RefCell<CliOutput>) {
+    // Copy a chunk at a time, so that the output is not borrowed
+    // while waiting
+    let mut sent = 0;
+    loop {
+        let mut chunk = [0; 32];
+        let count = {
+            let output = output.borrow();
+            let count = (output.len - sent).min(chunk.len());
+            chunk[..count].copy_from_slice(&output.buffer[sent..sent + count]);
+            count
+        };
+        if count == 0 {
+            break;
+        }
+        let Ok(()) = tx.write_all(&chunk[..count]).await;
+        sent += count;
+    }
+
+    let output = &mut *output.borrow_mut();
+    if output.dropped > 0 {
+        defmt::warn!("CLI output truncated ({} bytes dropped)", output.dropped);
+    }
+    output.len = 0;
+    output.dropped = 0;
 }
 
 pub async fn serial_task(mut cx: serial_task::Context<'_>) {
     defmt::info!("Starting serial task");
     let rx = cx.local.serial_rx;
     let tx = cx.local.serial_tx;
+    let output = RefCell::new(CliOutput::new());
 
     // create static buffers for use in cli (so we're not using stack memory)
     // History buffer is 1 byte longer so max command fits in it (it requires
     // extra byte at end)
     // SAFETY: buffers are passed to cli and are used by cli only
     let (command_buffer, history_buffer) = unsafe {
-        static mut COMMAND_BUFFER: [u8; 40] = [0; 40];
-        static mut HISTORY_BUFFER: [u8; 41] = [0; 41];
+        static mut COMMAND_BUFFER: [u8; COMMAND_LEN] = [0; COMMAND_LEN];
+        static mut HISTORY_BUFFER: [u8; COMMAND_LEN + 1] = [0; COMMAND_LEN + 1];
         (COMMAND_BUFFER.as_mut(), HISTORY_BUFFER.as_mut())
     };
 
     let mut cli = CliBuilder::default()
-        .writer(tx)
+        .writer(OutputWriter(&output))
         .command_buffer(command_buffer)
         .history_buffer(history_buffer)
         .build()
@@ -128,41 +412,356 @@ Use left and right to move inside input."
         .unwrap();
         Ok(())
     });
+    send(tx, &output).await;
 
+    // The motor the commands act on (see the `motor` command)
+    let mut selected = 0;
+    let mut decoder = FrameDecoder::new();
+    let mut stream: Option<Stream> = None;
+    let mut received = [0; 16];
     loop {
-        // Blocking loop waiting for character
-        let byte = loop {
-            if let Ok(ch) = rx.read() {
-                break ch;
+        // Wait for the USART1 interrupt to receive something, or
+        // until the next sample is due
+        let read = match &mut stream {
+            Some(stream) => match Mono::timeout_at(stream.next, rx.read(&mut received)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    with_motor!(cx.shared, stream.motor, |motor| stream.send(motor, &output));
+                    send(tx, &output).await;
+                    continue;
+                }
+            },
+            None => rx.read(&mut received).await,
+        };
+        let count = match read {
+            Ok(count) => count,
+            Err(Overrun) => {
+                defmt::warn!("Serial input overrun (bytes lost)");
+                continue;
             }
         };
 
-        let _ = cli.process_byte::<Base, _>(
-            byte,
-            &mut Base::processor(|cli, command| {
-                match command {
-                    Base::Hello { name } => {
-                        // last write in command callback may or may not
-                        // end with newline. so both uwrite!() and uwriteln!()
-                        // will give identical results
-                        uwrite!(cli.writer(), "Hello, {}", name.unwrap_or("World"))?;
-                    }
-                    Base::Exit => {
-                        // We can write via normal function if formatting not needed
-                        cli.writer().write_str("Cli can't shutdown now")?;
-                    }
-                    Base::PwmDuty { duty } => cx
-                        .shared
-                        .three_phase_controller
-                        .lock(|bldc| bldc.set_duty(duty)),
-                    Base::StepTime { time } => {
-                        cx.shared.commutator_counter.lock(|counter| {
-                            counter.start(time.micros()).unwrap();
-                        });
+        for &byte in &received[..count] {
+            let byte = match decoder.push(byte) {
+                Some(Received::Text(byte)) => {
+                    // Enter (or any other key) stops a CSV stream
+                    if stream
+                        .as_ref()
+                        .is_some_and(|s| s.format == StreamFormat::Csv)
+                    {
+                        stream = None;
                     }
+                    byte
+                }
+                Some(Received::Payload(payload)) => {
+                    let response = with_motor!(cx.shared, selected, |motor| respond(
+                        selected,
+                        motor,
+                        payload,
+                        &mut stream
+                    ));
+                    queue_frame(&output, &response.encode());
+                    continue;
+                }
+                Some(Received::Error(error)) => {
+                    queue_frame(&output, &Response::Error(error.into()).encode());
+                    continue;
                 }
-                Ok(())
-            }),
-        );
+                None => continue,
+            };
+            let _ = cli.process_byte::<Base, _>(
+                byte,
+                &mut Base::processor(|cli, command| {
+                    match command {
+                        Base::Hello { name } => {
+                            // last write in command callback may or may not
+                            // end with newline. so both uwrite!() and uwriteln!()
+                            // will give identical results
+                            uwrite!(cli.writer(), "Hello, {}", name.unwrap_or("World"))?;
+                        }
+                        Base::Motor { number } => {
+                            match number {
+                                Some(number) if number >= NUM_MOTORS => {
+                                    uwrite!(cli.writer(), "There are {} motors", NUM_MOTORS)?;
+                                    return Ok(());
+                                }
+                                Some(number) => selected = number,
+                                None => {}
+                            }
+                            uwrite!(cli.writer(), "Motor {}", selected)?;
+                        }
+                        Base::Arm => {
+                            lock_motor!(cx.shared, selected, |motor| motor.controller.arm())
+                        }
+                        Base::Disarm => {
+                            lock_motor!(cx.shared, selected, |motor| motor.controller.disarm())
+                        }
+                        Base::Start => {
+                            let result =
+                                lock_motor!(cx.shared, selected, |motor| motor.controller.start());
+                            if let Err(error) = result {
+                                uwrite!(cli.writer(), "Cannot start: {}", error.name())?;
+                            }
+                        }
+                        Base::Stop => {
+                            lock_motor!(cx.shared, selected, |motor| motor.controller.stop())
+                        }
+                        Base::PwmDuty { duty } => {
+                            let result = lock_motor!(cx.shared, selected, |motor| set_duty(
+                                &mut motor.controller,
+                                duty
+                            ));
+                            if result.is_err() {
+                                cli.writer()
+                                    .write_str("The duty cycle must be between 0.0 and 1.0")?;
+                            }
+                        }
+                        Base::PwmFrequency { frequency } => {
+                            let result = lock_motor!(cx.shared, selected, |motor| {
+                                let timing = motor.controller.set_pwm_frequency(frequency)?;
+                                let achieved = motor.controller.pwm().frequency_hz();
+                                Ok::<_, PwmError>((achieved, timing.resolution()))
+                            });
+                            match result {
+                                Ok((achieved, steps)) => uwrite!(
+                                    cli.writer(),
+                                    "PWM frequency {} Hz ({} steps of duty cycle)",
+                                    achieved as u32,
+                                    steps
+                                )?,
+                                Err(PwmError::FrequencyTooHigh { max_hz }) => {
+                                    uwrite!(cli.writer(), "Too high (maximum {} Hz)", max_hz)?
+                                }
+                                Err(PwmError::FrequencyTooLow { min_hz }) => {
+                                    uwrite!(cli.writer(), "Too low (minimum {} Hz)", min_hz)?
+                                }
+                                Err(_) => cli
+                                    .writer()
+                                    .write_str("The PWM timers cannot be set to this frequency")?,
+                            }
+                        }
+                        Base::Rpm { rpm } => {
+                            if rpm.is_some_and(|rpm| rpm <= 0.0) {
+                                cli.writer().write_str("The RPM must be positive")?;
+                                return Ok(());
+                            }
+                            lock_motor!(cx.shared, selected, |motor| motor
+                                .controller
+                                .speed
+                                .set_target_rpm(rpm));
+                        }
+                        Base::Direction { direction } => {
+                            let direction = match direction {
+                                Some("forward") => Some(Direction::Forward),
+                                Some("reverse") => Some(Direction::Reverse),
+                                Some(_) => {
+                                    cli.writer().write_str("Expected forward or reverse")?;
+                                    return Ok(());
+                                }
+                                None => None,
+                            };
+                            let direction = lock_motor!(cx.shared, selected, |motor| {
+                                if let Some(direction) = direction {
+                                    motor.controller.set_direction(direction);
+                                }
+                                motor.controller.direction()
+                            });
+                            uwrite!(cli.writer(), "{}", direction.name())?;
+                        }
+                        Base::Brake { mode, duty } => {
+                            let mode = match mode {
+                                Some("coast") => Some(BrakeMode::Coast),
+                                Some("active") => Some(BrakeMode::Active),
+                                Some("regenerative") => Some(BrakeMode::Regenerative),
+                                Some(_) => {
+                                    cli.writer()
+                                        .write_str("Expected coast, active or regenerative")?;
+                                    return Ok(());
+                                }
+                                None => None,
+                            };
+                            if duty.is_some_and(|duty| !(0.0..=1.0).contains(&duty)) {
+                                cli.writer()
+                                    .write_str("The duty cycle must be between 0.0 and 1.0")?;
+                                return Ok(());
+                            }
+                            let mode = lock_motor!(cx.shared, selected, |motor| {
+                                let brake = &mut motor.controller.brake;
+                                let config = *brake.config();
+                                brake.set_config(BrakeConfig {
+                                    mode: mode.unwrap_or(config.mode),
+                                    regenerative_duty: duty.unwrap_or(config.regenerative_duty),
+                                    ..config
+                                });
+                                brake.config().mode
+                            });
+                            uwrite!(cli.writer(), "{}", mode.name())?;
+                        }
+                        Base::Identify { kv } => {
+                            if kv.is_some_and(|kv| kv <= 0.0) {
+                                cli.writer().write_str("The Kv must be positive")?;
+                                return Ok(());
+                            }
+                            lock_motor!(cx.shared, selected, |motor| {
+                                let identify = &mut motor.controller.identify;
+                                identify.set_config(IdentifyConfig {
+                                    rated_kv: kv,
+                                    ..*identify.config()
+                                });
+                                motor.controller.start_identification();
+                            });
+                            cli.writer().write_str("Identifying")?;
+                        }
+                        Base::Parameters => {
+                            let (active, result) = lock_motor!(cx.shared, selected, |motor| {
+                                let identify = &motor.controller.identify;
+                                (identify.is_active(), identify.result())
+                            });
+                            match result {
+                                _ if active => cli.writer().write_str("Identifying")?,
+                                None => cli.writer().write_str("Not identified")?,
+                                Some(Err(error)) => {
+                                    uwrite!(cli.writer(), "Failed: {}", error.name())?
+                                }
+                                Some(Ok(parameters)) => {
+                                    uwrite!(
+                                        cli.writer(),
+                                        "R {} ohm, L {} uH, ",
+                                        Fixed(parameters.phase_resistance, 4),
+                                        Fixed(parameters.phase_inductance * 1e6, 2)
+                                    )?;
+                                    match parameters.pole_pairs {
+                                        Some(pole_pairs) => uwrite!(
+                                            cli.writer(),
+                                            "Kv {} ({} pole pairs)",
+                                            parameters.kv(pole_pairs) as u32,
+                                            pole_pairs
+                                        )?,
+                                        None => uwrite!(
+                                            cli.writer(),
+                                            "{} eRPM/V",
+                                            parameters.kv(1) as u32
+                                        )?,
+                                    }
+                                }
+                            }
+                        }
+                        Base::Status => {
+                            let status = lock_motor!(cx.shared, selected, |motor| Status::read(
+                                &motor.controller
+                            ));
+                            status.write(cli.writer())?;
+                        }
+                        Base::Config => {
+                            let settings = lock_motor!(
+                                cx.shared,
+                                selected,
+                                |motor| Settings::read(selected, &motor.controller)
+                            );
+                            settings.write(cli.writer())?;
+                        }
+                        Base::Stream {
+                            period,
+                            fields,
+                            format,
+                        } => {
+                            let Some(fields) =
+                                fields.map_or(Some(StreamFields::all()), StreamFields::parse)
+                            else {
+                                cli.writer().write_str("Unknown field (see help stream)")?;
+                                return Ok(());
+                            };
+                            let format = match format {
+                                None | Some("csv") => StreamFormat::Csv,
+                                Some("binary") => StreamFormat::Binary,
+                                Some(_) => {
+                                    cli.writer().write_str("Expected csv or binary")?;
+                                    return Ok(());
+                                }
+                            };
+                            stream =
+                                (period > 0).then(|| Stream::new(selected, period, fields, format));
+                        }
+                        Base::Get { name } => {
+                            let Some(parameter) = Parameter::from_name(name) else {
+                                cli.writer().write_str("Unknown parameter (see list)")?;
+                                return Ok(());
+                            };
+                            let value = lock_motor!(cx.shared, selected, |motor| parameter
+                                .get(&motor.controller));
+                            uwrite!(cli.writer(), "{}", ShowValue(parameter.info(), value))?;
+                        }
+                        Base::Set { name, value } => {
+                            let Some(parameter) = Parameter::from_name(name) else {
+                                cli.writer().write_str("Unknown parameter (see list)")?;
+                                return Ok(());
+                            };
+                            let info = parameter.info();
+                            let Some(value) = info.parse(value) else {
+                                uwrite!(cli.writer(), "Not a valid {} value", info.kind.name())?;
+                                return Ok(());
+                            };
+                            let result = lock_motor!(cx.shared, selected, |motor| parameter
+                                .set(&mut motor.controller, value));
+                            if let Err(error) = result {
+                                uwrite!(cli.writer(), "Cannot set: {}", error.name())?;
+                            }
+                        }
+                        Base::List => {
+                            let list =
+                                lock_motor!(cx.shared, selected, |motor| ParameterList::read(
+                                    &motor.controller
+                                ));
+                            list.write(cli.writer())?;
+                        }
+                        Base::Reset { name } => {
+                            let result = match name.map(Parameter::from_name) {
+                                Some(Some(parameter)) => {
+                                    lock_motor!(cx.shared, selected, |motor| parameter
+                                        .reset(&mut motor.controller))
+                                }
+                                Some(None) => {
+                                    cli.writer().write_str("Unknown parameter (see list)")?;
+                                    return Ok(());
+                                }
+                                None => lock_motor!(cx.shared, selected, |motor| {
+                                    Parameter::reset_all(&mut motor.controller)
+                                }),
+                            };
+                            if let Err(error) = result {
+                                uwrite!(cli.writer(), "Cannot reset: {}", error.name())?;
+                            }
+                        }
+                        Base::Fault => {
+                            let fault =
+                                lock_motor!(cx.shared, selected, |motor| motor.controller.fault());
+                            uwrite!(cli.writer(), "{}", fault.map_or("none", |f| f.name()))?;
+                        }
+                        Base::ClearFault => {
+                            lock_motor!(cx.shared, selected, |motor| motor
+                                .controller
+                                .clear_fault());
+                        }
+                        Base::StepTime { time } => {
+                            if time == 0 {
+                                cli.writer().write_str("The time must be at least 1 us")?;
+                                return Ok(());
+                            }
+                            let result =
+                                lock_motor!(cx.shared, selected, |motor| motor.timer.start(time));
+                            if let Err(error) = result {
+                                uwrite!(
+                                    cli.writer(),
+                                    "The time must be at most {} us",
+                                    error.max_us
+                                )?;
+                            }
+                        }
+                    }
+                    Ok(())
+                }),
+            );
+        }
+        send(tx, &output).await;
     }
 }
@@ -16,7 +16,8 @@ mod app {
     use crate::uart_serial::SerialTx;
     use rtic_monotonics::systick::prelude::*;
     use stm32f7xx_hal::gpio::{Output, PinState, PI1};
-    use stm32f7xx_hal::pac::{ADC3, TIM2, USART1};
+    use stm32_adc::Adc;
+    use stm32f7xx_hal::pac::{TIM2, USART1};
     use stm32f7xx_hal::timer;
     use stm32f7xx_hal::timer::CounterUs;
     use stm32f7xx_hal::serial::Rx;
@@ -37,7 +38,7 @@ mod app {
         pub serial_tx: SerialTx,
         pub serial_rx: Rx<USART1>,
         pub counter: CounterUs<TIM2>,
-        pub adc: ADC3,
+        pub adc: Adc,
     }
 
     extern "Rust" {
@@ -0,0 +1,254 @@
+//! Driver for one ADC
+
+use core::ops::Deref;
+
+use stm32f7::stm32f7x6::{adc1, dma2, ADC3};
+
+use crate::config::AdcConfig;
+use crate::dma::DmaStream;
+
+// SR bits (p. 414)
+const SR_OVR: u32 = 1 << 5;
+
+/// Converts the regular sequence of one ADC (ADC1, ADC2 or ADC3,
+/// which share the register block of ADC1)
+pub struct Adc<A = ADC3> {
+    adc: A,
+}
+
+impl<A: Deref<Target = adc1::RegisterBlock>> Adc<A> {
+    /// Take over `adc` (its clock must be enabled)
+    pub fn new(adc: A) -> Self {
+        Self { adc }
+    }
+
+    /// Give back the PAC peripheral
+    pub fn free(self) -> A {
+        self.adc
+    }
+
+    /// Turn the ADC on with the settings in `config`
+    ///
+    /// The ADC is turned off while it is set up, so that a trigger
+    /// cannot start a half-configured sequence.
+    pub fn configure(&mut self, config: &AdcConfig) {
+        let registers = config.registers();
+        // SAFETY: the values are built from the fields of the
+        // registers, and the reserved bits are left at 0
+        unsafe {
+            self.adc.cr2.write(|w| w.bits(0));
+            self.adc.cr1.write(|w| w.bits(registers.cr1));
+            self.adc.smpr1.write(|w| w.bits(registers.smpr1));
+            self.adc.smpr2.write(|w| w.bits(registers.smpr2));
+            self.adc.sqr1.write(|w| w.bits(registers.sqr1));
+            self.adc.sqr2.write(|w| w.bits(registers.sqr2));
+            self.adc.sqr3.write(|w| w.bits(registers.sqr3));
+            self.adc.cr2.write(|w| w.bits(registers.cr2));
+        }
+    }
+
+    /// The address of the data register (the peripheral address for
+    /// DMA transfers)
+    pub fn data_address(&self) -> u32 {
+        &self.adc.dr as *const _ as u32
+    }
+
+    /// Turn the ADC on or off (off saves power, and stops the
+    /// conversions)
+    pub fn enable(&mut self, enable: bool) {
+        self.adc.cr2.modify(|_, w| w.adon().bit(enable));
+    }
+
+    /// Start the regular sequence (p. 420)
+    pub fn start(&mut self) {
+        self.adc.cr2.modify(|_, w| w.swstart().set_bit());
+    }
+
+    /// Whether the sequence has been converted since the data
+    /// register was last read
+    pub fn is_complete(&self) -> bool {
+        self.adc.sr.read().eoc().bit_is_set()
+    }
+
+    /// The last conversion (reading it clears the end of conversion
+    /// flag)
+    pub fn read(&mut self) -> u16 {
+        self.adc.dr.read().data().bits()
+    }
+
+    /// Start the sequence, wait until it is converted, and return
+    /// the last conversion (for a sequence of one channel)
+    pub fn convert(&mut self) -> u16 {
+        self.start();
+        while !self.is_complete() {}
+        self.read()
+    }
+
+    /// Check for an overrun (a conversion overwritten before it was
+    /// read or transferred), clearing the flag
+    pub fn take_overrun(&mut self) -> bool {
+        let overrun = self.adc.sr.read().ovr().bit_is_set();
+        if overrun {
+            // The status bits are cleared by writing 0, and writing
+            // 1 has no effect (so the other flags are left as they
+            // are, even if they are set in between)
+            self.adc.sr.write(|w| unsafe { w.bits(!SR_OVR) });
+        }
+        overrun
+    }
+
+    /// Make DMA requests again after an overrun, which stops them
+    /// (p. 405). Restart the DMA stream first.
+    pub fn restart_dma(&mut self) {
+        self.adc.cr2.modify(|_, w| w.dma().clear_bit());
+        self.adc.cr2.modify(|_, w| w.dma().set_bit());
+    }
+
+    /// Check for an overrun, and if there was one, start the
+    /// transfers again: `dma` (the stream the ADC makes its requests
+    /// to) from the beginning of its first buffer, then the DMA
+    /// requests (p. 405)
+    ///
+    /// Returns true if there was an overrun.
+    pub fn recover_overrun<D>(&mut self, dma: &mut DmaStream<D>) -> bool
+    where
+        D: Deref<Target = dma2::RegisterBlock>,
+    {
+        let overrun = self.take_overrun();
+        if overrun {
+            dma.restart();
+            self.restart_dma();
+        }
+        overrun
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        DmaRequests, ExternalTrigger, SampleTime, Sequence, Trigger, TriggerEdge, CR2_ADON, CR2_DMA,
+    };
+    use crate::dma::{DmaBuffers, DmaConfig};
+
+    // SR bits (p. 414)
+    const SR_EOC: u32 = 1 << 1;
+
+    // A register block in memory standing in for the ADC (all the
+    // registers are plain 32-bit cells, so zero is a valid value)
+    fn registers() -> adc1::RegisterBlock {
+        unsafe { core::mem::zeroed() }
+    }
+
+    #[test]
+    fn writes_configuration() {
+        let sequence = Sequence::new()
+            .with(6, SampleTime::Cycles3)
+            .and_then(|s| s.with(0, SampleTime::Cycles15))
+            .and_then(|s| s.with(15, SampleTime::Cycles15))
+            .unwrap();
+        let config = AdcConfig::new(sequence)
+            .trigger(Trigger::External {
+                source: ExternalTrigger::Tim1Trgo2,
+                edge: TriggerEdge::Rising,
+            })
+            .dma(DmaRequests::Continuous)
+            .overrun_interrupt(true);
+
+        let block = registers();
+        let mut adc = Adc::new(&block);
+        adc.configure(&config);
+        let registers = config.registers();
+        assert_eq!(block.cr1.read().bits(), registers.cr1);
+        assert_eq!(block.cr2.read().bits(), registers.cr2);
+        assert!(block.cr1.read().ovrie().bit_is_set());
+        assert!(block.cr2.read().dma().bit_is_set());
+        assert!(block.cr2.read().adon().bit_is_set());
+        assert_eq!(block.cr2.read().exten().bits(), 0b01);
+        // The PAC does not split the sample time registers into
+        // fields
+        assert_eq!(block.smpr1.read().bits(), 0b001 << 15);
+        assert_eq!(block.smpr2.read().bits(), 0b001);
+        assert_eq!(block.sqr1.read().l().bits(), 2);
+        assert_eq!(block.sqr3.read().sq1().bits(), 6);
+        assert_eq!(block.sqr3.read().sq2().bits(), 0);
+        assert_eq!(block.sqr3.read().sq3().bits(), 15);
+
+        assert_eq!(adc.data_address(), &block.dr as *const _ as u32);
+    }
+
+    #[test]
+    fn software_conversion() {
+        let block = registers();
+        unsafe {
+            block.sr.write(|w| w.bits(SR_EOC));
+            // The data register is read-only
+            *block.dr.as_ptr() = 0x0abc;
+        }
+        let mut adc = Adc::new(&block);
+        adc.enable(true);
+        assert_eq!(adc.convert(), 0x0abc);
+        let cr2 = block.cr2.read();
+        assert!(cr2.adon().bit_is_set());
+        assert!(cr2.swstart().bit_is_set());
+    }
+
+    #[test]
+    fn clears_overrun() {
+        let block = registers();
+        unsafe {
+            block.sr.write(|w| w.bits(SR_OVR | SR_EOC));
+            block.cr2.write(|w| w.bits(CR2_ADON | CR2_DMA));
+        }
+        let mut adc = Adc::new(&block);
+        assert!(adc.take_overrun());
+
+        // Only the overrun flag is written with 0
+        assert_eq!(block.sr.read().bits(), !SR_OVR);
+        unsafe { block.sr.write(|w| w.bits(0)) };
+        assert!(!adc.take_overrun());
+
+        adc.restart_dma();
+        assert_eq!(block.cr2.read().bits(), CR2_ADON | CR2_DMA);
+    }
+
+    #[test]
+    fn recovers_from_overrun() {
+        let sequence = Sequence::new().with(0, SampleTime::Cycles3).unwrap();
+        let config = AdcConfig::new(sequence).dma(DmaRequests::Continuous);
+        let dma_config = DmaConfig {
+            channel: 2,
+            transfers: 4,
+            transfer_complete_interrupt: true,
+            error_interrupts: true,
+        };
+        let adc_block = registers();
+        let dma_block: dma2::RegisterBlock = unsafe { core::mem::zeroed() };
+        let mut adc = Adc::new(&adc_block);
+        let mut dma = DmaStream::new(&dma_block, 0);
+        adc.configure(&config);
+        unsafe {
+            dma.start(
+                &dma_config,
+                adc.data_address(),
+                DmaBuffers::Double(0x2000_0000, 0x2000_0010),
+            );
+        }
+        assert!(!adc.recover_overrun(&mut dma));
+
+        // An overrun part-way through the second buffer
+        let stream = &dma_block.st[0];
+        stream.ndtr.write(|w| w.ndt().bits(1));
+        stream.cr.modify(|_, w| w.ct().set_bit());
+        unsafe { adc_block.sr.write(|w| w.bits(SR_OVR)) };
+
+        assert!(adc.recover_overrun(&mut dma));
+        assert!(adc_block.sr.read().ovr().bit_is_clear());
+        assert!(adc_block.cr2.read().dma().bit_is_set());
+        assert!(adc_block.cr2.read().adon().bit_is_set());
+        assert_eq!(stream.ndtr.read().ndt().bits(), 4);
+        assert!(stream.cr.read().ct().bit_is_clear());
+        assert!(stream.cr.read().en().bit_is_set());
+        assert_eq!(stream.par.read().pa().bits(), adc.data_address());
+    }
+}
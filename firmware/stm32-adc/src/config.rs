@@ -0,0 +1,484 @@
+//! ADC configuration, and the register values it gives
+
+/// The most conversions in a regular sequence
+pub const MAX_SEQUENCE_LEN: usize = 16;
+
+/// The highest input channel number (IN18)
+pub const MAX_CHANNEL: u8 = 18;
+
+// CR1 bits (p. 415)
+const CR1_EOCIE: u32 = 1 << 5;
+const CR1_SCAN: u32 = 1 << 8;
+const CR1_RES_SHIFT: u32 = 24;
+const CR1_OVRIE: u32 = 1 << 26;
+
+// CR2 bits (p. 417)
+pub(crate) const CR2_ADON: u32 = 1 << 0;
+const CR2_CONT: u32 = 1 << 1;
+pub(crate) const CR2_DMA: u32 = 1 << 8;
+const CR2_DDS: u32 = 1 << 9;
+const CR2_EXTSEL_SHIFT: u32 = 24;
+const CR2_EXTEN_SHIFT: u32 = 28;
+
+// The L[3:0] field of SQR1 (the sequence length minus one)
+const SQR1_L_SHIFT: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcError {
+    /// There is no input channel with this number
+    InvalidChannel(u8),
+    /// The sequence already has [`MAX_SEQUENCE_LEN`] conversions
+    SequenceFull,
+    /// A channel appears twice in the sequence with different
+    /// sample times (there is only one sample time per channel)
+    SampleTimeConflict(u8),
+}
+
+/// The time the ADC samples an input for (in ADC clock cycles)
+///
+/// A longer sample time lets the sampling capacitor settle when
+/// the source impedance is high (e.g. a resistor divider), at the
+/// cost of a longer conversion.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SampleTime {
+    #[default]
+    Cycles3,
+    Cycles15,
+    Cycles28,
+    Cycles56,
+    Cycles84,
+    Cycles112,
+    Cycles144,
+    Cycles480,
+}
+
+impl SampleTime {
+    /// The SMPx[2:0] bits in the SMPR1 or SMPR2 register
+    pub fn bits(&self) -> u32 {
+        *self as u32
+    }
+
+    /// The number of ADC clock cycles
+    pub fn cycles(&self) -> u32 {
+        match self {
+            SampleTime::Cycles3 => 3,
+            SampleTime::Cycles15 => 15,
+            SampleTime::Cycles28 => 28,
+            SampleTime::Cycles56 => 56,
+            SampleTime::Cycles84 => 84,
+            SampleTime::Cycles112 => 112,
+            SampleTime::Cycles144 => 144,
+            SampleTime::Cycles480 => 480,
+        }
+    }
+}
+
+/// The resolution of the conversions
+///
+/// A lower resolution converts faster (the conversion takes one
+/// ADC clock cycle per bit, after sampling).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    #[default]
+    Bits12,
+    Bits10,
+    Bits8,
+    Bits6,
+}
+
+impl Resolution {
+    /// The RES[1:0] bits in CR1
+    pub fn bits(&self) -> u32 {
+        *self as u32
+    }
+
+    /// The number of ADC clock cycles the conversion takes after
+    /// sampling
+    pub fn conversion_cycles(&self) -> u32 {
+        match self {
+            Resolution::Bits12 => 12,
+            Resolution::Bits10 => 10,
+            Resolution::Bits8 => 8,
+            Resolution::Bits6 => 6,
+        }
+    }
+}
+
+/// The timer events and EXTI line which can start a regular
+/// sequence (the EXTSEL[3:0] values, p. 418)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalTrigger {
+    Tim1Ch1 = 0b0000,
+    Tim1Ch2 = 0b0001,
+    Tim1Ch3 = 0b0010,
+    Tim2Ch2 = 0b0011,
+    Tim5Trgo = 0b0100,
+    Tim4Ch4 = 0b0101,
+    Tim3Ch4 = 0b0110,
+    Tim8Trgo = 0b0111,
+    Tim8Trgo2 = 0b1000,
+    Tim1Trgo = 0b1001,
+    Tim1Trgo2 = 0b1010,
+    Tim2Trgo = 0b1011,
+    Tim4Trgo = 0b1100,
+    Tim6Trgo = 0b1101,
+    Exti11 = 0b1111,
+}
+
+/// Which edges of an external trigger start a sequence (the
+/// EXTEN[1:0] values)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEdge {
+    Rising = 0b01,
+    Falling = 0b10,
+    Both = 0b11,
+}
+
+/// What starts a regular sequence
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Only [`Adc::start`](crate::Adc::start)
+    #[default]
+    Software,
+    /// A timer event or EXTI line (or software)
+    External {
+        source: ExternalTrigger,
+        edge: TriggerEdge,
+    },
+}
+
+/// Whether the conversions make DMA requests
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DmaRequests {
+    #[default]
+    Off,
+    /// Until the DMA transfer count runs out (the DMA bit has to be
+    /// set again for the next transfer)
+    Single,
+    /// After every conversion (for a DMA stream in circular mode)
+    Continuous,
+}
+
+/// The channels of a regular sequence, in conversion order, each
+/// with its sample time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence {
+    conversions: [(u8, SampleTime); MAX_SEQUENCE_LEN],
+    len: usize,
+}
+
+impl Sequence {
+    pub const fn new() -> Self {
+        Self {
+            conversions: [(0, SampleTime::Cycles3); MAX_SEQUENCE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Add a conversion of `channel` (e.g. 0 for IN0) to the end
+    /// of the sequence
+    pub fn push(&mut self, channel: u8, sample_time: SampleTime) -> Result<(), AdcError> {
+        if channel > MAX_CHANNEL {
+            return Err(AdcError::InvalidChannel(channel));
+        }
+        if self.len == MAX_SEQUENCE_LEN {
+            return Err(AdcError::SequenceFull);
+        }
+        if self.iter().any(|(c, t)| c == channel && t != sample_time) {
+            return Err(AdcError::SampleTimeConflict(channel));
+        }
+        self.conversions[self.len] = (channel, sample_time);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The sequence with a conversion added (see [`Sequence::push`])
+    pub fn with(mut self, channel: u8, sample_time: SampleTime) -> Result<Self, AdcError> {
+        self.push(channel, sample_time)?;
+        Ok(self)
+    }
+
+    /// The number of conversions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The channels and their sample times, in conversion order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, SampleTime)> + Clone + '_ {
+        self.conversions[..self.len].iter().copied()
+    }
+
+    /// The SQR1, SQR2 and SQR3 register values (p. 423)
+    ///
+    /// SQR1 holds the sequence length minus one in L[3:0]. The
+    /// channel of conversion n (from 1) is in SQn[4:0]: SQ1 to SQ6
+    /// in SQR3, SQ7 to SQ12 in SQR2, and SQ13 to SQ16 in SQR1 (five
+    /// bits per conversion).
+    pub fn sequence_registers(&self) -> [u32; 3] {
+        let mut sqr = [(self.len.max(1) as u32 - 1) << SQR1_L_SHIFT, 0, 0];
+        for (n, (channel, _)) in self.iter().enumerate() {
+            let (register, shift) = (2 - n / 6, 5 * (n % 6));
+            sqr[register] |= (channel as u32) << shift;
+        }
+        sqr
+    }
+
+    /// The SMPR1 and SMPR2 register values (p. 422). SMPR2 holds
+    /// channels 0 to 9, and SMPR1 channels 10 to 18 (three bits per
+    /// channel).
+    pub fn sample_time_registers(&self) -> (u32, u32) {
+        self.iter()
+            .fold((0, 0), |(smpr1, smpr2), (channel, time)| match channel {
+                0..=9 => (smpr1, smpr2 | time.bits() << (3 * channel)),
+                _ => (smpr1 | time.bits() << (3 * (channel - 10)), smpr2),
+            })
+    }
+
+    /// The number of ADC clock cycles to convert the whole
+    /// sequence at `resolution`
+    pub fn conversion_cycles(&self, resolution: Resolution) -> u32 {
+        self.iter()
+            .map(|(_, time)| time.cycles() + resolution.conversion_cycles())
+            .sum()
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The register values of an ADC
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub cr1: u32,
+    pub cr2: u32,
+    pub smpr1: u32,
+    pub smpr2: u32,
+    pub sqr1: u32,
+    pub sqr2: u32,
+    pub sqr3: u32,
+}
+
+/// How an ADC converts its regular sequence
+///
+/// Start from [`AdcConfig::new`], which converts the sequence
+/// once at 12 bits each time the software starts it, and change
+/// the settings with the builder methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdcConfig {
+    pub sequence: Sequence,
+    pub resolution: Resolution,
+    pub trigger: Trigger,
+    /// Start the sequence again as soon as it ends
+    pub continuous: bool,
+    pub dma: DmaRequests,
+    /// Interrupt at the end of the sequence
+    pub eoc_interrupt: bool,
+    /// Interrupt when a conversion is lost (overrun)
+    pub overrun_interrupt: bool,
+}
+
+impl AdcConfig {
+    pub const fn new(sequence: Sequence) -> Self {
+        Self {
+            sequence,
+            resolution: Resolution::Bits12,
+            trigger: Trigger::Software,
+            continuous: false,
+            dma: DmaRequests::Off,
+            eoc_interrupt: false,
+            overrun_interrupt: false,
+        }
+    }
+
+    pub const fn resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    pub const fn trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    pub const fn continuous(mut self, continuous: bool) -> Self {
+        self.continuous = continuous;
+        self
+    }
+
+    pub const fn dma(mut self, dma: DmaRequests) -> Self {
+        self.dma = dma;
+        self
+    }
+
+    pub const fn eoc_interrupt(mut self, enable: bool) -> Self {
+        self.eoc_interrupt = enable;
+        self
+    }
+
+    pub const fn overrun_interrupt(mut self, enable: bool) -> Self {
+        self.overrun_interrupt = enable;
+        self
+    }
+
+    /// The register values for this configuration (with the ADC
+    /// turned on)
+    pub fn registers(&self) -> Registers {
+        let mut cr1 = self.resolution.bits() << CR1_RES_SHIFT;
+        if self.sequence.len() > 1 {
+            cr1 |= CR1_SCAN;
+        }
+        if self.eoc_interrupt {
+            cr1 |= CR1_EOCIE;
+        }
+        if self.overrun_interrupt {
+            cr1 |= CR1_OVRIE;
+        }
+
+        let mut cr2 = CR2_ADON;
+        if self.continuous {
+            cr2 |= CR2_CONT;
+        }
+        cr2 |= match self.dma {
+            DmaRequests::Off => 0,
+            DmaRequests::Single => CR2_DMA,
+            DmaRequests::Continuous => CR2_DMA | CR2_DDS,
+        };
+        if let Trigger::External { source, edge } = self.trigger {
+            cr2 |= (source as u32) << CR2_EXTSEL_SHIFT | (edge as u32) << CR2_EXTEN_SHIFT;
+        }
+
+        let (smpr1, smpr2) = self.sequence.sample_time_registers();
+        let [sqr1, sqr2, sqr3] = self.sequence.sequence_registers();
+        Registers {
+            cr1,
+            cr2,
+            smpr1,
+            smpr2,
+            sqr1,
+            sqr2,
+            sqr3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_sequence() {
+        let sequence = Sequence::new()
+            .with(6, SampleTime::Cycles3)
+            .and_then(|s| s.with(0, SampleTime::Cycles15))
+            .and_then(|s| s.with(8, SampleTime::Cycles15))
+            .and_then(|s| s.with(12, SampleTime::Cycles480))
+            .unwrap();
+        assert_eq!(sequence.len(), 4);
+        assert!(sequence.iter().map(|(c, _)| c).eq([6, 0, 8, 12]));
+
+        let mut full = sequence;
+        assert_eq!(
+            full.push(19, SampleTime::Cycles3),
+            Err(AdcError::InvalidChannel(19))
+        );
+        assert_eq!(
+            full.push(8, SampleTime::Cycles3),
+            Err(AdcError::SampleTimeConflict(8))
+        );
+        for _ in 4..MAX_SEQUENCE_LEN {
+            full.push(0, SampleTime::Cycles15).unwrap();
+        }
+        assert_eq!(
+            full.push(0, SampleTime::Cycles15),
+            Err(AdcError::SequenceFull)
+        );
+    }
+
+    #[test]
+    fn sequence_registers() {
+        // One conversion is a length of zero in L[3:0]
+        let single = Sequence::new().with(0, SampleTime::Cycles3).unwrap();
+        assert_eq!(single.sequence_registers(), [0, 0, 0]);
+
+        let mut sequence = Sequence::new();
+        for channel in 1..=13 {
+            sequence.push(channel, SampleTime::Cycles3).unwrap();
+        }
+        assert_eq!(
+            sequence.sequence_registers(),
+            [
+                12 << 20 | 13,
+                7 | 8 << 5 | 9 << 10 | 10 << 15 | 11 << 20 | 12 << 25,
+                1 | 2 << 5 | 3 << 10 | 4 << 15 | 5 << 20 | 6 << 25,
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_time_registers() {
+        let sequence = Sequence::new()
+            .with(0, SampleTime::Cycles15)
+            .and_then(|s| s.with(8, SampleTime::Cycles480))
+            .and_then(|s| s.with(7, SampleTime::Cycles56))
+            .and_then(|s| s.with(12, SampleTime::Cycles28))
+            .unwrap();
+        assert_eq!(
+            sequence.sample_time_registers(),
+            (0b010 << 6, 0b001 | 0b111 << 24 | 0b011 << 21)
+        );
+        assert_eq!(
+            sequence.conversion_cycles(Resolution::Bits12),
+            15 + 480 + 56 + 28 + 4 * 12
+        );
+    }
+
+    #[test]
+    fn software_triggered_registers() {
+        // A single conversion started by software (no scan)
+        let sequence = Sequence::new().with(0, SampleTime::Cycles3).unwrap();
+        let registers = AdcConfig::new(sequence).registers();
+        assert_eq!(
+            registers,
+            Registers {
+                cr2: CR2_ADON,
+                ..Registers::default()
+            }
+        );
+    }
+
+    #[test]
+    fn timer_triggered_dma_registers() {
+        // The phase voltages of the DISCO board motor, triggered by
+        // TIM1 TRGO2 and transferred by DMA
+        let sequence = Sequence::new()
+            .with(0, SampleTime::Cycles15)
+            .and_then(|s| s.with(8, SampleTime::Cycles15))
+            .and_then(|s| s.with(7, SampleTime::Cycles15))
+            .unwrap();
+        let config = AdcConfig::new(sequence)
+            .resolution(Resolution::Bits10)
+            .trigger(Trigger::External {
+                source: ExternalTrigger::Tim1Trgo2,
+                edge: TriggerEdge::Rising,
+            })
+            .dma(DmaRequests::Continuous)
+            .overrun_interrupt(true);
+
+        let registers = config.registers();
+        assert_eq!(registers.cr1, 0b01 << 24 | 1 << 26 | 1 << 8);
+        assert_eq!(
+            registers.cr2,
+            0b01 << 28 | 0b1010 << 24 | 1 << 9 | 1 << 8 | 1
+        );
+        assert_eq!(registers.sqr1, 2 << 20);
+        assert_eq!(registers.sqr3, 8 << 5 | 7 << 10);
+        assert_eq!(registers.smpr2, 0b001 | 0b001 << 24 | 0b001 << 21);
+    }
+}
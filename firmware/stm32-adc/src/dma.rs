@@ -0,0 +1,348 @@
+//! DMA transfers from a peripheral (e.g. an ADC) into memory
+
+use core::ops::Deref;
+
+use stm32f7::stm32f7x6::{dma2, DMA2};
+
+// SxCR bits (p. 247)
+const CR_DMEIE: u32 = 1 << 1;
+const CR_TEIE: u32 = 1 << 2;
+const CR_TCIE: u32 = 1 << 4;
+const CR_CIRC: u32 = 1 << 8;
+const CR_MINC: u32 = 1 << 10;
+const CR_PSIZE_HALF_WORD: u32 = 0b01 << 11;
+const CR_MSIZE_HALF_WORD: u32 = 0b01 << 13;
+const CR_DBM: u32 = 1 << 18;
+const CR_CHSEL_SHIFT: u32 = 25;
+
+// The interrupt flags of a stream, relative to its first flag
+const FLAG_DMEIF: u32 = 1 << 2;
+const FLAG_TEIF: u32 = 1 << 3;
+const FLAG_TCIF: u32 = 1 << 5;
+
+/// How a stream transfers 16-bit values from a peripheral into
+/// memory (in circular mode, so it restarts after `transfers`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConfig {
+    /// The request channel (CHSEL, e.g. 2 for ADC3 on DMA2
+    /// stream 0, see table 28 on p. 226)
+    pub channel: u8,
+    /// The number of values in each buffer
+    pub transfers: u16,
+    /// Interrupt when a buffer is full
+    pub transfer_complete_interrupt: bool,
+    /// Interrupt on transfer and direct mode errors
+    pub error_interrupts: bool,
+}
+
+impl DmaConfig {
+    /// The SxCR register value (without the enable bit)
+    pub fn control_register(&self, double_buffer: bool) -> u32 {
+        let mut cr = (self.channel as u32 & 0b111) << CR_CHSEL_SHIFT
+            | CR_MSIZE_HALF_WORD
+            | CR_PSIZE_HALF_WORD
+            | CR_MINC
+            | CR_CIRC;
+        if double_buffer {
+            cr |= CR_DBM;
+        }
+        if self.transfer_complete_interrupt {
+            cr |= CR_TCIE;
+        }
+        if self.error_interrupts {
+            cr |= CR_TEIE | CR_DMEIE;
+        }
+        cr
+    }
+}
+
+/// Where a stream transfers to (the addresses of buffers of
+/// [`DmaConfig::transfers`] half words)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaBuffers {
+    /// One buffer, filled again and again
+    Single(u32),
+    /// Two buffers, filled in turn (so the one just filled can be
+    /// read while the other is being filled)
+    Double(u32, u32),
+}
+
+/// The interrupt flags of a stream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DmaEvents {
+    pub transfer_complete: bool,
+    pub transfer_error: bool,
+    pub direct_mode_error: bool,
+}
+
+impl DmaEvents {
+    pub fn is_error(&self) -> bool {
+        self.transfer_error || self.direct_mode_error
+    }
+}
+
+/// One stream of a DMA controller (DMA1 and DMA2 share the register
+/// block of DMA2)
+pub struct DmaStream<D = DMA2> {
+    dma: D,
+    stream: usize,
+    // The number of values in each buffer, when started
+    transfers: u16,
+}
+
+impl<D: Deref<Target = dma2::RegisterBlock>> DmaStream<D> {
+    /// Stream `stream` (0 to 7) of `dma` (its clock must be
+    /// enabled)
+    ///
+    /// The PAC does not split the controller into streams, but the
+    /// stream only uses its own registers and interrupt flags, so
+    /// several streams can share one controller if `dma` is a
+    /// reference to its registers.
+    pub fn new(dma: D, stream: usize) -> Self {
+        assert!(stream < 8);
+        Self {
+            dma,
+            stream,
+            transfers: 0,
+        }
+    }
+
+    /// Give back the PAC peripheral
+    pub fn free(self) -> D {
+        self.dma
+    }
+
+    fn registers(&self) -> &dma2::ST {
+        &self.dma.st[self.stream]
+    }
+
+    // The interrupt flags of the stream, shifted down to bit 0
+    // (p. 244)
+    fn flags(&self) -> u32 {
+        let status = match self.stream {
+            0..=3 => self.dma.lisr.read().bits(),
+            _ => self.dma.hisr.read().bits(),
+        };
+        status >> self.flag_shift()
+    }
+
+    fn clear(&mut self, flags: u32) {
+        let bits = flags << self.flag_shift();
+        // SAFETY: writing 1 clears a flag, and writing 0 has no
+        // effect
+        unsafe {
+            match self.stream {
+                0..=3 => self.dma.lifcr.write(|w| w.bits(bits)),
+                _ => self.dma.hifcr.write(|w| w.bits(bits)),
+            }
+        }
+    }
+
+    fn flag_shift(&self) -> u32 {
+        [0, 6, 16, 22][self.stream % 4]
+    }
+
+    /// Start transferring from `peripheral_address` (e.g.
+    /// [`Adc::data_address`](crate::Adc::data_address)) into
+    /// `buffers`, starting with the first
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be valid for as long as the stream runs
+    /// (e.g. statics), and only read with volatile reads.
+    pub unsafe fn start(
+        &mut self,
+        config: &DmaConfig,
+        peripheral_address: u32,
+        buffers: DmaBuffers,
+    ) {
+        self.stop();
+        self.clear(0b11_1101);
+
+        let (m0ar, m1ar, double_buffer) = match buffers {
+            DmaBuffers::Single(address) => (address, 0, false),
+            DmaBuffers::Double(first, second) => (first, second, true),
+        };
+        let stream = self.registers();
+        stream.par.write(|w| w.pa().bits(peripheral_address));
+        stream.m0ar.write(|w| w.m0a().bits(m0ar));
+        stream.m1ar.write(|w| w.m1a().bits(m1ar));
+        stream.ndtr.write(|w| w.ndt().bits(config.transfers));
+        stream
+            .cr
+            .write(|w| w.bits(config.control_register(double_buffer)));
+        stream.cr.modify(|_, w| w.en().set_bit());
+        self.transfers = config.transfers;
+    }
+
+    /// Start again from the beginning of the first buffer, with the
+    /// settings of the last [`start`](Self::start) (e.g. after an
+    /// ADC overrun, which leaves the stream part-way through a
+    /// buffer, p. 405)
+    pub fn restart(&mut self) {
+        self.stop();
+        self.clear(0b11_1101);
+        let transfers = self.transfers;
+        let stream = self.registers();
+        stream.ndtr.write(|w| w.ndt().bits(transfers));
+        // The target can only be changed while the stream is
+        // disabled
+        stream.cr.modify(|_, w| w.ct().clear_bit());
+        stream.cr.modify(|_, w| w.en().set_bit());
+    }
+
+    /// Stop the transfers (waiting for the current one to finish)
+    pub fn stop(&mut self) {
+        let stream = self.registers();
+        stream.cr.modify(|_, w| w.en().clear_bit());
+        while stream.cr.read().en().bit_is_set() {}
+    }
+
+    /// The buffer being filled in double-buffer mode (0 or 1). The
+    /// other one has been filled.
+    pub fn current_target(&self) -> usize {
+        self.registers().cr.read().ct().bit_is_set() as usize
+    }
+
+    /// The interrupt flags which are set, clearing them
+    pub fn take_events(&mut self) -> DmaEvents {
+        let flags = self.flags();
+        let events = DmaEvents {
+            transfer_complete: flags & FLAG_TCIF != 0,
+            transfer_error: flags & FLAG_TEIF != 0,
+            direct_mode_error: flags & FLAG_DMEIF != 0,
+        };
+        self.clear(flags & (FLAG_TCIF | FLAG_TEIF | FLAG_DMEIF));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A register block in memory standing in for the DMA controller
+    // (the flag clear registers are write-only, so the tests read
+    // them through their pointers)
+    fn registers() -> dma2::RegisterBlock {
+        unsafe { core::mem::zeroed() }
+    }
+
+    #[test]
+    fn control_register() {
+        let config = DmaConfig {
+            channel: 2,
+            transfers: 5,
+            transfer_complete_interrupt: true,
+            error_interrupts: true,
+        };
+        assert_eq!(
+            config.control_register(true),
+            2 << 25
+                | 1 << 18
+                | 0b01 << 13
+                | 0b01 << 11
+                | 1 << 10
+                | 1 << 8
+                | 1 << 4
+                | 1 << 2
+                | 1 << 1
+        );
+    }
+
+    #[test]
+    fn starts_double_buffered_stream() {
+        let config = DmaConfig {
+            channel: 2,
+            transfers: 5,
+            transfer_complete_interrupt: true,
+            error_interrupts: false,
+        };
+        let block = registers();
+        unsafe {
+            DmaStream::new(&block, 1).start(
+                &config,
+                0x4001_224c,
+                DmaBuffers::Double(0x2000_0000, 0x2000_0010),
+            );
+        }
+        let stream = &block.st[1];
+        assert_eq!(stream.par.read().pa().bits(), 0x4001_224c);
+        assert_eq!(stream.m0ar.read().m0a().bits(), 0x2000_0000);
+        assert_eq!(stream.m1ar.read().m1a().bits(), 0x2000_0010);
+        assert_eq!(stream.ndtr.read().ndt().bits(), 5);
+        let cr = stream.cr.read();
+        assert_eq!(cr.chsel().bits(), 2);
+        assert!(cr.dbm().bit_is_set());
+        assert!(cr.circ().bit_is_set());
+        assert!(cr.tcie().bit_is_set());
+        assert!(cr.teie().bit_is_clear());
+        assert!(cr.en().bit_is_set());
+
+        // The flags of stream 1 are cleared
+        assert_eq!(unsafe { *block.lifcr.as_ptr() }, 0b11_1101 << 6);
+        assert_eq!(block.st[0].cr.read().bits(), 0);
+    }
+
+    #[test]
+    fn restarts_from_first_buffer() {
+        let config = DmaConfig {
+            channel: 2,
+            transfers: 5,
+            transfer_complete_interrupt: true,
+            error_interrupts: true,
+        };
+        let block = registers();
+        let mut dma = DmaStream::new(&block, 0);
+        unsafe {
+            dma.start(
+                &config,
+                0x4001_224c,
+                DmaBuffers::Double(0x2000_0000, 0x2000_0010),
+            );
+        }
+
+        // Stopped part-way through the second buffer
+        let stream = &block.st[0];
+        stream.ndtr.write(|w| w.ndt().bits(3));
+        stream.cr.modify(|_, w| w.ct().set_bit());
+        unsafe { block.lifcr.write(|w| w.bits(0)) };
+
+        dma.restart();
+        assert_eq!(stream.ndtr.read().ndt().bits(), 5);
+        let cr = stream.cr.read();
+        assert!(cr.ct().bit_is_clear());
+        assert!(cr.en().bit_is_set());
+        assert_eq!(cr.bits(), config.control_register(true) | 1);
+        assert_eq!(stream.par.read().pa().bits(), 0x4001_224c);
+        assert_eq!(unsafe { *block.lifcr.as_ptr() }, 0b11_1101);
+    }
+
+    #[test]
+    fn takes_stream_events() {
+        let block = registers();
+
+        // Stream 5 is in the high register, with stream 4's flags
+        // from bit 0
+        unsafe {
+            *block.hisr.as_ptr() = (FLAG_TCIF | FLAG_TEIF) << 6 | FLAG_TCIF;
+        }
+        let events = DmaStream::new(&block, 5).take_events();
+        assert_eq!(
+            events,
+            DmaEvents {
+                transfer_complete: true,
+                transfer_error: true,
+                direct_mode_error: false,
+            }
+        );
+        assert!(events.is_error());
+        assert_eq!(
+            unsafe { *block.hifcr.as_ptr() },
+            (FLAG_TCIF | FLAG_TEIF) << 6
+        );
+
+        block.st[0].cr.modify(|_, w| w.ct().set_bit());
+        assert_eq!(DmaStream::new(&block, 0).current_target(), 1);
+    }
+}
@@ -0,0 +1,31 @@
+//! ADC and DMA driver for the STM32F746
+//!
+//! The ADC is set up from an [`AdcConfig`]: the regular sequence
+//! of channels ([`Sequence`]), the resolution, the trigger and
+//! whether conversions are transferred by DMA. The configuration
+//! is turned into register values ([`Registers`]) without touching
+//! the hardware, so the values can be checked on the host with
+//! `cargo test`. [`Adc`] writes them to an ADC, and [`DmaStream`]
+//! transfers the conversions into memory.
+//!
+//! The drivers are built on the register blocks of the `stm32f7`
+//! PAC (the one the HAL uses), and take the PAC peripheral by value,
+//! so nothing else can access it. The caller enables its clock in
+//! the RCC. The host tests give them register blocks in memory
+//! instead.
+//!
+//! References to page numbers refer to the RM0385 rev 8 reference
+//! manual.
+
+#![no_std]
+
+pub mod adc;
+pub mod config;
+pub mod dma;
+
+pub use adc::Adc;
+pub use config::{
+    AdcConfig, AdcError, DmaRequests, ExternalTrigger, Registers, Resolution, SampleTime, Sequence,
+    Trigger, TriggerEdge,
+};
+pub use dma::{DmaBuffers, DmaConfig, DmaEvents, DmaStream};